@@ -36,7 +36,15 @@ export function CertificateDetails(props: CertificateDetailsProps) {
 	const api = React.useContext(API);
 
 	const override = React.useCallback(async (e: React.MouseEvent<HTMLButtonElement>) => {
-		await api.override([props.job]);
+		const reason = window.prompt("Why should the challenge pass?")?.trim();
+		if (reason)
+			await api.override([props.job], reason).catch(err => window.alert(err.message));
+	}, [api, props.job]);
+
+	const decline = React.useCallback(async (e: React.MouseEvent<HTMLButtonElement>) => {
+		const reason = window.prompt("Why is the challenge declined? The client is told this reason")?.trim();
+		if (reason)
+			await api.decline([props.job], reason).catch(err => window.alert(err.message));
 	}, [api, props.job]);
 
 	const status = React.useMemo(() => typeof props.job.status == 'string' ? props.job.status as keyof Job['status']: Object.keys(props.job.status)[0] as keyof Job['status'], [props.job.status]);
@@ -61,6 +69,7 @@ export function CertificateDetails(props: CertificateDetailsProps) {
 
 			<div className="button-group">
 				<button className="danger" data-icon={"\ue5cd"}
+						onClick={e => decline(e)}
 						title={"Inform the issuer of a failed validation and remove the entry from the job queue"}>{"Decline challenge"}</button>
 				<button className="success" data-icon={"\ue8e8"}
 						onClick={e => override(e)}
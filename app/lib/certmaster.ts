@@ -80,10 +80,32 @@ export default class CertmasterApi extends Api {
 		return JSON.parse(window.localStorage.getItem('tracked-alt-names') || '[]');
 	}
 
-	async override(req: Job[]): Promise<void> {
-		await this.fetchJson("/challenge", "POST", {}, {
-			jobs: req.map(i => i.alias)
+	/**
+	 * The operator's bearer token, which reviews are attributed to. Set through `localStorage.setItem("operator-token", ...)`
+	 */
+	authorization(): Record<string, string> {
+		const token = window.localStorage.getItem("operator-token");
+
+		return token ? {authorization: `Bearer ${token}`} : {};
+	}
+
+	async review(req: Job[], decision: ReviewDecision, reason: string): Promise<void> {
+		const res = await this.fetchJson<{ success: boolean, error?: string }>("/challenge", "POST", this.authorization(), {
+			jobs: req.map(i => i.alias),
+			decision,
+			reason
 		});
+
+		if (!res.success)
+			throw new Error(res.error);
+	}
+
+	async override(req: Job[], reason: string): Promise<void> {
+		await this.review(req, "approve", reason);
+	}
+
+	async decline(req: Job[], reason: string): Promise<void> {
+		await this.review(req, "reject", reason);
 	}
 }
 
@@ -103,6 +125,8 @@ export interface Job {
 	cn?: string
 }
 
+export type ReviewDecision = "approve" | "reject";
+
 export type JobStatus =
 	"Pending" |
 	"ChallengePending" |
@@ -0,0 +1,49 @@
+//! Throughput of the runner's worker pool for handlers which mostly wait, the way they wait on Redis, hooks and
+//! challenge responses. Events are spread over a number of jobs, whose events have to stay in order.
+//!
+//! `cargo bench --bench event_pool`
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use certmaster::pool::KeyedPool;
+
+const EVENTS: usize = 400;
+const JOBS: usize = 100;
+const HANDLER_TIME: Duration = Duration::from_millis(5);
+
+#[tokio::main]
+async fn main() {
+    for concurrency in [1, 2, 4, 8, 16, 32] {
+        let handled = Arc::new(Mutex::new(HashMap::<usize, Vec<usize>>::new()));
+        let mut pool = KeyedPool::new(concurrency);
+
+        let start = Instant::now();
+        for event in 0..EVENTS {
+            let job = event % JOBS;
+            let handled = handled.clone();
+
+            pool.spawn(Some(job), async move {
+                tokio::time::sleep(HANDLER_TIME).await;
+                handled.lock().unwrap().entry(job).or_default().push(event);
+                Ok(())
+            })
+                .await
+                .expect("Event failed");
+        }
+
+        pool.join_all().await.expect("Event failed");
+        let elapsed = start.elapsed();
+
+        let in_order = handled.lock()
+            .unwrap()
+            .values()
+            .all(|events| events.is_sorted());
+
+        assert!(in_order, "Events of a job were handled out of order");
+
+        println!("concurrency {concurrency:>2}: {rate:>8.1} events/s ({ms} ms for {EVENTS} events)",
+            rate = EVENTS as f64 / elapsed.as_secs_f64(),
+            ms = elapsed.as_millis());
+    }
+}
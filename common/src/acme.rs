@@ -0,0 +1,416 @@
+use async_trait::async_trait;
+use redis::{AsyncCommands, FromRedisValue};
+use redis_derive::FromRedisValue;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::{decode_base64url, encode_base64url, ChallengeType, CsrId, Error, Key, RedisConnection, Result};
+
+/// How long an issued nonce may be used for.
+pub const ACME_NONCE_TTL_SECS: u64 = 3600;
+
+/// # JSON Web Signature
+/// Every ACME request body is a JWS in flattened JSON serialisation ([RFC 8555 §6.2](https://www.rfc-editor.org/rfc/rfc8555#section-6.2)).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Jws {
+    pub protected: String,
+    pub payload: String,
+    pub signature: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct JwsHeader {
+    pub alg: String,
+    pub nonce: Option<String>,
+    pub url: String,
+
+    /// Exactly one of `jwk` and `kid` is set. `jwk` is only used before an account exists.
+    pub jwk: Option<Value>,
+    pub kid: Option<String>,
+}
+
+impl Jws {
+    pub fn header(&self) -> Result<JwsHeader> {
+        Ok(serde_json::from_slice(&decode_base64url(&self.protected)?)?)
+    }
+
+    /// The decoded payload, or `None` for POST-as-GET requests, whose payload is empty.
+    pub fn payload<T: DeserializeOwned>(&self) -> Result<Option<T>> {
+        if self.payload.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(serde_json::from_slice(&decode_base64url(&self.payload)?)?))
+    }
+
+    /// Checks the signature against the given key. Fails if the algorithm or the key isn't supported, and returns
+    /// `false` if the signature is simply wrong.
+    pub fn verify(&self, alg: &str, jwk: &Value) -> Result<bool> {
+        let field = |name: &str| jwk
+            .get(name)
+            .and_then(Value::as_str)
+            .ok_or_else(|| Error::other(format!("The JWK is missing '{name}'")))
+            .and_then(decode_base64url);
+
+        let signature = decode_base64url(&self.signature)?;
+        let message = format!("{protected}.{payload}", protected = self.protected, payload = self.payload);
+
+        let verified = match (alg, jwk.get("kty").and_then(Value::as_str)) {
+            ("ES256", Some("EC")) => UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, [&[4][..], &field("x")?, &field("y")?].concat())
+                .verify(message.as_bytes(), &signature),
+            ("ES384", Some("EC")) => UnparsedPublicKey::new(&signature::ECDSA_P384_SHA384_FIXED, [&[4][..], &field("x")?, &field("y")?].concat())
+                .verify(message.as_bytes(), &signature),
+            ("RS256", Some("RSA")) => RsaPublicKeyComponents { n: field("n")?, e: field("e")? }
+                .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message.as_bytes(), &signature),
+            ("EdDSA", Some("OKP")) => UnparsedPublicKey::new(&signature::ED25519, field("x")?)
+                .verify(message.as_bytes(), &signature),
+            (alg, kty) => return Error::custom(format!("Unsupported JWS algorithm {alg} for key type {kty:?}")),
+        };
+
+        Ok(verified.is_ok())
+    }
+}
+
+/// The JWK thumbprint as per [RFC 7638](https://www.rfc-editor.org/rfc/rfc7638), used to recognise account keys.
+pub fn jwk_thumbprint(jwk: &Value) -> Result<String> {
+    let members: &[&str] = match jwk.get("kty").and_then(Value::as_str) {
+        Some("EC") => &["crv", "kty", "x", "y"],
+        Some("RSA") => &["e", "kty", "n"],
+        Some("OKP") => &["crv", "kty", "x"],
+        kty => return Error::custom(format!("Unsupported key type {kty:?}")),
+    };
+
+    let canonical = members
+        .iter()
+        .map(|&member| match jwk.get(member) {
+            Some(Value::String(value)) => Ok(format!("{member}:{value}", member = serde_json::to_string(member)?, value = serde_json::to_string(value)?)),
+            _ => Error::custom(format!("The JWK is missing '{member}'")),
+        })
+        .collect::<Result<Vec<_>>>()?
+        .join(",");
+
+    Ok(encode_base64url(ring::digest::digest(&ring::digest::SHA256, format!("{{{canonical}}}").as_bytes())))
+}
+
+/// The value a client has to provision to complete a challenge with the given token.
+pub fn key_authorization(token: &str, jwk: &Value) -> Result<String> {
+    Ok(format!("{token}.{thumbprint}", thumbprint = jwk_thumbprint(jwk)?))
+}
+
+/// A random identifier for nonces, accounts, orders, authorizations and challenge tokens.
+pub fn random_token() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| Error::other("Failed to generate random token"))?;
+
+    Ok(encode_base64url(bytes))
+}
+
+/// The status of any ACME object. Not every object uses every status.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AcmeStatus {
+    Pending,
+    Ready,
+    Processing,
+    Valid,
+    Invalid,
+    Deactivated,
+    Expired,
+    Revoked,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct Identifier {
+    #[serde(rename = "type")]
+    pub kind: IdentifierType,
+    pub value: String,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdentifierType {
+    Dns,
+    Ip,
+}
+
+#[derive(Debug, Clone, FromRedisValue, Serialize, Deserialize)]
+pub struct AcmeAccount {
+    pub id: String,
+    /// The account key as a JWK in JSON form
+    pub key: String,
+    pub thumbprint: String,
+    pub status: AcmeStatus,
+    pub contact: Vec<String>,
+    pub terms_of_service_agreed: bool,
+    /// Unix timestamp in seconds
+    pub created_at: i64,
+}
+
+impl AcmeAccount {
+    pub fn jwk(&self) -> Result<Value> {
+        Ok(serde_json::from_str(&self.key)?)
+    }
+}
+
+/// # ACME Order
+/// Once finalized, an order is backed by a regular job, which it tracks through the job's alias.
+#[derive(Debug, Clone, FromRedisValue, Serialize, Deserialize)]
+pub struct AcmeOrder {
+    pub id: String,
+    pub account: String,
+    pub status: AcmeStatus,
+    pub identifiers: Vec<Identifier>,
+    pub authorizations: Vec<String>,
+    /// Unix timestamp in seconds
+    pub expires: i64,
+
+    pub alias: Option<String>,
+    pub serial: Option<CsrId>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, FromRedisValue, Serialize, Deserialize)]
+pub struct AcmeAuthorization {
+    pub id: String,
+    pub account: String,
+    /// Wildcard identifiers are stored without their `*.` prefix.
+    pub identifier: Identifier,
+    pub wildcard: bool,
+    pub status: AcmeStatus,
+    /// Unix timestamp in seconds
+    pub expires: i64,
+    pub challenges: Vec<AcmeChallenge>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AcmeChallenge {
+    #[serde(rename = "type")]
+    pub kind: ChallengeType,
+    pub token: String,
+    pub status: AcmeStatus,
+    /// Unix timestamp in seconds
+    pub validated: Option<i64>,
+    /// Why validation failed
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// # ACME Store
+/// Accounts live under `acme-account:{id}` and are indexed by key thumbprint under `acme-account-key:{thumbprint}`.
+/// Orders and authorizations live under `acme-order:{id}` and `acme-authz:{id}`, and every account's orders are
+/// listed in `acme-account-orders:{id}`. Nonces are kept under `acme-nonce:{nonce}` until used or expired.
+#[async_trait]
+pub trait AcmeStore {
+    async fn new_nonce(&mut self) -> Result<String>;
+    /// Nonces are single-use. Returns `false` if the nonce was never issued, has expired or has already been used.
+    async fn consume_nonce(&mut self, nonce: &str) -> Result<bool>;
+
+    /// Writes a new account. Returns `false` without changing anything if its key already belongs to an account.
+    async fn create_account(&mut self, account: &AcmeAccount) -> Result<bool>;
+    async fn update_account(&mut self, account: &AcmeAccount) -> Result<()>;
+    async fn get_account(&mut self, id: &str) -> Result<Option<AcmeAccount>>;
+    async fn account_by_key(&mut self, thumbprint: &str) -> Result<Option<AcmeAccount>>;
+    /// Moves the account to the key in `account.thumbprint`. Returns `false` if that key already belongs to an account.
+    async fn change_account_key(&mut self, account: &AcmeAccount, old_thumbprint: &str) -> Result<bool>;
+
+    async fn store_order(&mut self, order: &AcmeOrder) -> Result<()>;
+    async fn get_order(&mut self, id: &str) -> Result<Option<AcmeOrder>>;
+    async fn orders_of_account(&mut self, account: &str) -> Result<Vec<AcmeOrder>>;
+
+    async fn store_authorization(&mut self, authz: &AcmeAuthorization) -> Result<()>;
+    async fn get_authorization(&mut self, id: &str) -> Result<Option<AcmeAuthorization>>;
+    async fn get_authorizations(&mut self, ids: &[String]) -> Result<Vec<AcmeAuthorization>>;
+}
+
+#[async_trait]
+impl AcmeStore for RedisConnection {
+    async fn new_nonce(&mut self) -> Result<String> {
+        let nonce = random_token()?;
+        let _: () = self.set_ex(Key::AcmeNonce(&nonce), 1, ACME_NONCE_TTL_SECS).await?;
+
+        Ok(nonce)
+    }
+
+    async fn consume_nonce(&mut self, nonce: &str) -> Result<bool> {
+        let deleted: u64 = self.del(Key::AcmeNonce(nonce)).await?;
+        Ok(deleted > 0)
+    }
+
+    async fn create_account(&mut self, account: &AcmeAccount) -> Result<bool> {
+        let indexed: bool = self.set_nx(Key::AcmeAccountByKey(&account.thumbprint), &account.id)
+            .await?;
+
+        if indexed {
+            self.update_account(account).await?;
+        }
+
+        Ok(indexed)
+    }
+
+    async fn update_account(&mut self, account: &AcmeAccount) -> Result<()> {
+        Ok(self.set(Key::AcmeAccount(&account.id), ron::to_string(account)?).await?)
+    }
+
+    async fn get_account(&mut self, id: &str) -> Result<Option<AcmeAccount>> {
+        Ok(self.get(Key::AcmeAccount(id)).await?)
+    }
+
+    async fn account_by_key(&mut self, thumbprint: &str) -> Result<Option<AcmeAccount>> {
+        let id: Option<String> = self.get(Key::AcmeAccountByKey(thumbprint)).await?;
+
+        match id {
+            Some(id) => self.get_account(&id).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn change_account_key(&mut self, account: &AcmeAccount, old_thumbprint: &str) -> Result<bool> {
+        let indexed: bool = self.set_nx(Key::AcmeAccountByKey(&account.thumbprint), &account.id)
+            .await?;
+
+        if indexed {
+            let _: () = self.del(Key::AcmeAccountByKey(old_thumbprint)).await?;
+            self.update_account(account).await?;
+        }
+
+        Ok(indexed)
+    }
+
+    async fn store_order(&mut self, order: &AcmeOrder) -> Result<()> {
+        let _: () = redis::pipe()
+            .atomic()
+            .set(Key::AcmeOrder(&order.id), ron::to_string(order)?)
+            .sadd(Key::AcmeAccountOrders(&order.account), &order.id)
+            .query_async(self)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_order(&mut self, id: &str) -> Result<Option<AcmeOrder>> {
+        Ok(self.get(Key::AcmeOrder(id)).await?)
+    }
+
+    async fn orders_of_account(&mut self, account: &str) -> Result<Vec<AcmeOrder>> {
+        let ids: Vec<String> = self.smembers(Key::AcmeAccountOrders(account)).await?;
+
+        if ids.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let orders: Vec<Option<AcmeOrder>> = self.mget(ids.iter().map(|id| Key::AcmeOrder(id)).collect::<Vec<_>>())
+            .await?;
+
+        Ok(orders.into_iter().flatten().collect())
+    }
+
+    async fn store_authorization(&mut self, authz: &AcmeAuthorization) -> Result<()> {
+        Ok(self.set(Key::AcmeAuthorization(&authz.id), ron::to_string(authz)?).await?)
+    }
+
+    async fn get_authorization(&mut self, id: &str) -> Result<Option<AcmeAuthorization>> {
+        Ok(self.get(Key::AcmeAuthorization(id)).await?)
+    }
+
+    async fn get_authorizations(&mut self, ids: &[String]) -> Result<Vec<AcmeAuthorization>> {
+        if ids.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let authz: Vec<Option<AcmeAuthorization>> = self.mget(ids.iter().map(|id| Key::AcmeAuthorization(id)).collect::<Vec<_>>())
+            .await?;
+
+        Ok(authz.into_iter().flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use serde_json::json;
+    use super::*;
+
+    fn signed(key: &Ed25519KeyPair, payload: &str) -> Jws {
+        let protected = encode_base64url(r#"{"alg":"EdDSA","url":"https://example.com/acme/new-account"}"#);
+        let payload = encode_base64url(payload);
+        let signature = key.sign(format!("{protected}.{payload}").as_bytes());
+
+        Jws {
+            protected,
+            payload,
+            signature: encode_base64url(signature.as_ref()),
+        }
+    }
+
+    fn ed25519() -> (Ed25519KeyPair, Value) {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let jwk = json!({ "kty": "OKP", "crv": "Ed25519", "x": encode_base64url(key.public_key().as_ref()) });
+
+        (key, jwk)
+    }
+
+    #[test]
+    fn thumbprint_matches_rfc_7638() {
+        // The example of RFC 7638 §3.1
+        let jwk = json!({
+            "kty": "RSA",
+            "n": "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw",
+            "e": "AQAB",
+            "alg": "RS256",
+            "kid": "2011-04-29",
+        });
+
+        assert_eq!(jwk_thumbprint(&jwk).unwrap(), "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs");
+        assert_eq!(key_authorization("token", &jwk).unwrap(), "token.NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs");
+    }
+
+    #[test]
+    fn thumbprint_needs_the_required_members() {
+        assert!(jwk_thumbprint(&json!({ "kty": "EC", "crv": "P-256", "x": "AAAA" })).is_err());
+        assert!(jwk_thumbprint(&json!({ "kty": "oct", "k": "AAAA" })).is_err());
+    }
+
+    #[test]
+    fn signatures_are_verified() {
+        let (key, jwk) = ed25519();
+        let jws = signed(&key, "{}");
+
+        assert_eq!(jws.header().unwrap().alg, "EdDSA");
+        assert!(jws.verify("EdDSA", &jwk).unwrap());
+
+        let (_, other) = ed25519();
+        assert!(!jws.verify("EdDSA", &other).unwrap());
+
+        let tampered = Jws {
+            payload: encode_base64url(r#"{"status":"deactivated"}"#),
+            ..jws.clone()
+        };
+        assert!(!tampered.verify("EdDSA", &jwk).unwrap());
+    }
+
+    #[test]
+    fn unsupported_algorithms_fail() {
+        let (key, jwk) = ed25519();
+        let jws = signed(&key, "{}");
+
+        assert!(jws.verify("HS256", &jwk).is_err());
+        assert!(jws.verify("ES256", &jwk).is_err(), "The algorithm has to match the key type");
+    }
+
+    #[test]
+    fn empty_payloads_are_post_as_get() {
+        let (key, _) = ed25519();
+        let jws = Jws {
+            payload: String::new(),
+            ..signed(&key, "")
+        };
+
+        assert!(jws.payload::<Value>().unwrap().is_none());
+        assert_eq!(signed(&key, r#"{"a":1}"#).payload::<Value>().unwrap(), Some(json!({ "a": 1 })));
+    }
+}
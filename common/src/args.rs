@@ -1,5 +1,5 @@
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, OnceLock};
 use clap::Parser;
 use crate::Config;
@@ -16,22 +16,69 @@ pub struct Args {
 pub async fn read_config() -> Arc<Config> {
     let args = Args::parse();
 
-    let config = tokio::fs::read_to_string(&args.config)
+    load_config(&args.config).await
+}
+
+/// Reads the config at `path`, resolving the paths in it relative to the file, and makes it the one [get_config] returns.
+pub async fn load_config(path: &Path) -> Arc<Config> {
+    let config = tokio::fs::read_to_string(path)
         .await
         .expect("Failed to read config file");
 
     let mut config = toml::from_str::<Config>(&config)
         .expect("Failed to parse config file");
 
-    config.ca.key = crate::resolve_path(config.ca.key, Some(&args.config)).await
+    config.ca.key = crate::resolve_path(config.ca.key, Some(path)).await
         .expect("Failed to resolve issuer key");
 
-    config.ca.certificate = crate::resolve_path(config.ca.certificate, Some(&args.config)).await
+    config.ca.certificate = crate::resolve_path(config.ca.certificate, Some(path)).await
         .expect("Failed to resolve issuer certificate");
 
+    if let Some(certificate) = config.ocsp.certificate {
+        config.ocsp.certificate = Some(crate::resolve_path(certificate, Some(path)).await
+            .expect("Failed to resolve OCSP responder certificate"));
+    }
+
+    if let Some(key) = config.ocsp.key {
+        config.ocsp.key = Some(crate::resolve_path(key, Some(path)).await
+            .expect("Failed to resolve OCSP responder key"));
+    }
+
+    if let Some(password_file) = config.redis.password_file {
+        config.redis.password_file = Some(crate::resolve_path(password_file, Some(path)).await
+            .expect("Failed to resolve Redis password file"));
+    }
+
+    if let Some(ca_certificate) = config.redis.tls.ca_certificate {
+        config.redis.tls.ca_certificate = Some(crate::resolve_path(ca_certificate, Some(path)).await
+            .expect("Failed to resolve Redis CA certificate"));
+    }
+
+    if let Some(certificate) = config.redis.tls.client_certificate {
+        config.redis.tls.client_certificate = Some(crate::resolve_path(certificate, Some(path)).await
+            .expect("Failed to resolve Redis client certificate"));
+    }
+
+    if let Some(key) = config.redis.tls.client_key {
+        config.redis.tls.client_key = Some(crate::resolve_path(key, Some(path)).await
+            .expect("Failed to resolve Redis client key"));
+    }
+
+    if config.storage.backend == crate::StorageBackend::Sqlite {
+        // The database is created on first use, so only its directory needs to exist
+        let database = &config.storage.sqlite.path;
+        let directory = database.parent()
+            .filter(|directory| !directory.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+
+        config.storage.sqlite.path = crate::resolve_path(directory, Some(path)).await
+            .expect("Failed to resolve SQLite database directory")
+            .join(database.file_name().expect("The SQLite database path lacks a file name"));
+    }
+
     config.ca.hooks = config.ca.hooks
         .into_iter()
-        .map(|i| crate::resolve_path(i, Some(args.config.clone())))
+        .map(|i| crate::resolve_path(i, Some(path.to_owned())))
         .collect::<tokio::task::JoinSet<std::io::Result<_>>>()
         .join_all()
         .await
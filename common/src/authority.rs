@@ -0,0 +1,112 @@
+use std::sync::{Arc, RwLock};
+use rcgen::{CertificateParams, CertificateRevocationList, CertificateRevocationListParams, DnType, Issuer, KeyIdMethod, KeyPair, PublicKeyData, RevokedCertParams, SerialNumber};
+use time::{Duration, OffsetDateTime};
+use x509_parser::extensions::ParsedExtension;
+use crate::{CaConfig, CsrId, Error, Profile, Result, RevokedCertificate};
+
+/// The authority loaded by [Authority::init].
+static AUTHORITY: RwLock<Option<Arc<Authority>>> = RwLock::new(None);
+
+/// # Certificate Authority
+/// The issuer's key and certificate, along with the parts of the certificate `rcgen::Issuer` doesn't expose.
+pub struct Authority {
+    pub issuer: Issuer<'static, KeyPair>,
+    /// The issuer certificate in DER form
+    pub certificate: Vec<u8>,
+    pub not_after: OffsetDateTime,
+    pub key_identifier: KeyIdMethod,
+}
+
+impl Authority {
+    /// Reads and parses the CA key and certificate, failing unless the key is the one the certificate was issued for.
+    pub async fn load(config: &CaConfig) -> Result<Self> {
+        let cert = tokio::fs::read_to_string(&config.certificate).await?;
+        let key = tokio::fs::read_to_string(&config.key).await?;
+
+        let key = KeyPair::from_pem(&key)?;
+
+        let (_, pem) = x509_parser::pem::parse_x509_pem(cert.as_bytes())?;
+        let parsed = pem.parse_x509()?;
+
+        if parsed.public_key().subject_public_key.data.as_ref() != key.der_bytes() {
+            return Error::custom(format!("The key {key:?} doesn't belong to the certificate {cert:?}", key = config.key, cert = config.certificate));
+        }
+
+        let key_identifier = parsed
+            .iter_extensions()
+            .find_map(|ext| match ext.parsed_extension() {
+                ParsedExtension::SubjectKeyIdentifier(id) => Some(KeyIdMethod::PreSpecified(id.0.to_vec())),
+                _ => None,
+            })
+            .unwrap_or(KeyIdMethod::Sha256);
+
+        Ok(Self {
+            not_after: parsed.validity().not_after.to_datetime(),
+            key_identifier,
+            issuer: Issuer::from_ca_cert_pem(&cert, key)?,
+            certificate: pem.contents.clone(),
+        })
+    }
+
+    /// Loads the authority and caches it for [Authority::current], replacing the one cached before. Nothing is
+    /// replaced if loading fails.
+    pub async fn init(config: &CaConfig) -> Result<Arc<Self>> {
+        let authority = Arc::new(Self::load(config).await?);
+
+        *AUTHORITY.write().unwrap_or_else(|err| err.into_inner()) = Some(authority.clone());
+
+        Ok(authority)
+    }
+
+    /// The cached authority. Fails if [Authority::init] hasn't succeeded yet.
+    pub fn current() -> Result<Arc<Self>> {
+        match &*AUTHORITY.read().unwrap_or_else(|err| err.into_inner()) {
+            Some(authority) => Ok(authority.clone()),
+            None => Error::custom("The CA key and certificate haven't been loaded"),
+        }
+    }
+
+    /// Signs a CRL listing every given revocation, valid until `next_update` from now.
+    pub fn sign_crl(&self, revoked: &[RevokedCertificate], crl_number: u64, next_update: Duration) -> Result<CertificateRevocationList> {
+        let now = OffsetDateTime::now_utc();
+
+        let revoked_certs = revoked
+            .iter()
+            .map(|revoked| Ok(RevokedCertParams {
+                serial_number: SerialNumber::from(revoked.serial),
+                revocation_time: OffsetDateTime::from_unix_timestamp(revoked.revocation_date)?,
+                reason_code: Some(revoked.reason.into()),
+                invalidity_date: revoked.invalidity_date
+                    .map(OffsetDateTime::from_unix_timestamp)
+                    .transpose()?,
+            }))
+            .collect::<Result<Vec<_>>>()?;
+
+        let params = CertificateRevocationListParams {
+            this_update: now,
+            next_update: now + next_update,
+            crl_number: SerialNumber::from(crl_number),
+            issuing_distribution_point: None,
+            revoked_certs,
+            key_identifier_method: self.key_identifier.clone(),
+        };
+
+        Ok(params.signed_by(&self.issuer)?)
+    }
+
+    /// Issues a TLS client certificate for `name` on a fresh key, with the given serial and following the profile like
+    /// any other certificate. Returns the certificate and key in PEM.
+    pub fn issue_client_certificate(&self, name: &str, serial: CsrId, profile: &Profile) -> Result<(String, String)> {
+        let key = KeyPair::generate()?;
+
+        let mut params = CertificateParams::default();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.serial_number = Some(serial.into());
+        params.use_authority_key_identifier_extension = true;
+        profile.apply(&mut params, self.not_after);
+
+        let certificate = params.signed_by(&key, &self.issuer)?;
+
+        Ok((certificate.pem(), key.serialize_pem()))
+    }
+}
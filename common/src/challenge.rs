@@ -0,0 +1,141 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+use rcgen::{CertificateSigningRequestParams, SanType};
+use serde::{Deserialize, Serialize};
+use ring::digest::{digest, SHA256};
+use crate::{encode_base64url, random_token, Error, Result};
+
+/// How a requester proves control over the names in its CSR.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ChallengeType {
+    #[serde(rename = "http-01")]
+    Http01,
+    #[serde(rename = "dns-01")]
+    Dns01,
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+impl FromStr for ChallengeType {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s {
+            "http-01" => ChallengeType::Http01,
+            "dns-01" => ChallengeType::Dns01,
+            "tls-alpn-01" => ChallengeType::TlsAlpn01,
+            kind => return Err(format!("'{kind}' is not a supported challenge type")),
+        })
+    }
+}
+
+/// # Name Challenge
+/// A token issued for a single name of a job. The requester passes the challenge by publishing the key authorization
+/// where the challenge type expects it.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct NameChallenge {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: ChallengeType,
+    pub token: String,
+    /// Jobs submitted over ACME use `{token}.{account key thumbprint}`. Everyone else publishes the bare token.
+    pub key_authorization: String,
+
+    /// The record a dns-01 challenge is looked up under, and the value it has to hold. Only informs the requester;
+    /// validation derives the record from the name and key authorization through [`NameChallenge::dns01_record`].
+    #[serde(default)]
+    pub txt_record: Option<TxtRecord>,
+
+    /// Unix timestamp in seconds. Set for challenges which were validated before the job was submitted, as over ACME.
+    #[serde(default)]
+    pub validated_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TxtRecord {
+    pub name: String,
+    pub value: String,
+}
+
+impl NameChallenge {
+    pub fn new(name: impl Into<String>, kind: ChallengeType, token: String, key_authorization: String) -> Self {
+        let mut challenge = Self {
+            name: name.into(),
+            kind,
+            token,
+            key_authorization,
+            txt_record: None,
+            validated_at: None,
+        };
+
+        if kind == ChallengeType::Dns01 {
+            challenge.txt_record = Some(challenge.dns01_record());
+        }
+
+        challenge
+    }
+
+    /// The `_acme-challenge` TXT record of the name, which has to hold the digest of the key authorization. Wildcards
+    /// are validated through the record of the name they cover.
+    pub fn dns01_record(&self) -> TxtRecord {
+        TxtRecord {
+            name: format!("_acme-challenge.{name}", name = self.name.trim_start_matches("*.")),
+            value: encode_base64url(digest(&SHA256, self.key_authorization.as_bytes())),
+        }
+    }
+
+    /// A challenge with a fresh token, which also serves as key authorization.
+    pub fn issue(name: impl Into<String>, kind: ChallengeType) -> Result<Self> {
+        let token = random_token()?;
+
+        Ok(Self::new(name, kind, token.clone(), token))
+    }
+
+    /// Issues one challenge for every name in the CSR. Fails if the challenge type can't validate one of them.
+    pub fn for_request(params: &CertificateSigningRequestParams, kind: ChallengeType) -> Result<Vec<Self>> {
+        params.params.subject_alt_names
+            .iter()
+            .map(|name| match (name, kind) {
+                (SanType::DnsName(name), ChallengeType::Http01 | ChallengeType::TlsAlpn01) if name.as_str().starts_with("*.") => {
+                    Error::custom(format!("Wildcard name {name} can only be validated over dns-01", name = name.as_str()))
+                },
+                (SanType::DnsName(name), _) => Self::issue(name.as_str().to_ascii_lowercase(), kind),
+                (SanType::IpAddress(ip), ChallengeType::Http01) => Self::issue(ip.to_string(), kind),
+                (name, kind) => Error::custom(format!("{name:?} can't be validated over {kind:?}")),
+            })
+            .collect()
+    }
+
+    /// Checks that every name in the CSR has exactly one challenge, and that no challenge is for a name it doesn't ask for.
+    pub fn check_coverage(params: &CertificateSigningRequestParams, challenges: &[Self]) -> std::result::Result<(), String> {
+        let names = params.params.subject_alt_names
+            .iter()
+            .map(|name| match name {
+                SanType::DnsName(name) => Ok(name.as_str().to_ascii_lowercase()),
+                SanType::IpAddress(ip) => Ok(ip.to_string()),
+                name => Err(format!("{name:?} can't be validated by a challenge")),
+            })
+            .collect::<std::result::Result<HashSet<_>, _>>()?;
+
+        for challenge in challenges {
+            if !names.contains(&challenge.name.to_ascii_lowercase()) {
+                return Err(format!("The CSR doesn't ask for {name}, which has a challenge", name = challenge.name));
+            }
+        }
+
+        for name in &names {
+            match challenges.iter().filter(|challenge| challenge.name.eq_ignore_ascii_case(name)).count() {
+                1 => {},
+                0 => return Err(format!("{name} has no challenge")),
+                _ => return Err(format!("{name} has more than one challenge")),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The path an HTTP-01 key authorization is served under.
+    pub fn http01_path(&self) -> String {
+        format!("/.well-known/acme-challenge/{token}", token = self.token)
+    }
+}
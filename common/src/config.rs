@@ -1,9 +1,13 @@
+use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Config {
+    #[serde(default)]
+    pub storage: StorageConfig,
+
     #[serde(default)]
     pub redis: RedisConfig,
 
@@ -18,6 +22,36 @@ pub struct Config {
 
     #[serde(default)]
     pub modules: ModuleList,
+
+    #[serde(default)]
+    pub policy: crate::PolicyConfig,
+
+    #[serde(default)]
+    pub profiles: HashMap<String, crate::Profile>,
+
+    #[serde(default)]
+    pub crl: CrlConfig,
+
+    #[serde(default)]
+    pub ocsp: OcspConfig,
+
+    #[serde(default)]
+    pub acme: AcmeConfig,
+
+    #[serde(default)]
+    pub challenge: ChallengeConfig,
+
+    #[serde(default)]
+    pub gc: GcConfig,
+
+    #[serde(default)]
+    pub supervisor: SupervisorConfig,
+
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+
+    #[serde(default)]
+    pub worker: WorkerConfig,
 }
 
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
@@ -46,19 +80,151 @@ impl Default for ModuleList {
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct RedisConfig {
     pub url: String,
+    /// The pub/sub channel job updates are announced on. `job-updates` if unset.
     pub channel: Option<String>,
+    /// Overrides the database selected in `url`.
     pub db: Option<u32>,
+    /// Prefixes every key and channel with `{namespace}:`, so several CAs can share one Redis.
+    pub namespace: Option<String>,
+
+    /// The ACL user to authenticate as. Overrides the user given in `url`.
+    pub username: Option<String>,
+    /// A file holding the password, so it needn't be part of `url`. Trailing line breaks are ignored. Read on every
+    /// connect, so the password can be rotated without a restart.
+    pub password_file: Option<PathBuf>,
+    /// Only applies to `rediss://` URLs.
+    #[serde(default)]
+    pub tls: RedisTlsConfig,
+
+    #[serde(default)]
+    pub mode: RedisMode,
+    /// The sentinels to ask for the master in sentinel mode, or the nodes to discover a cluster through, which defaults
+    /// to `url`. In sentinel mode, `url` only provides the credentials, database and protocol for the master.
+    #[serde(default)]
+    pub nodes: Vec<String>,
+    /// The name Sentinel monitors the master under.
+    pub master_name: Option<String>,
+    /// Prefixes every key with `{hash_tag}:`, putting all keys into one cluster slot. Defaults to `certmaster` in
+    /// cluster mode. A cluster then serves certmaster from a single master and its replicas, which buys failover but
+    /// no sharding: Jobs are written in one transaction with the job list and the event stream, so their keys can't be
+    /// spread across slots. CAs sharing a cluster can still be spread out by giving each a tag of its own.
+    pub hash_tag: Option<String>,
 
     #[serde(default = "task_queue_key_default")]
     pub task_stream_key: String,
     #[serde(default = "job_list_key_default")]
     pub job_list_key: String,
+    #[serde(default = "dead_letter_stream_key_default")]
+    pub dead_letter_stream_key: String,
+
+    /// How long to wait before reconnecting to Redis the first time. Doubles with every failed attempt.
+    #[serde(default = "reconnect_backoff_ms_default")]
+    pub reconnect_backoff_ms: u64,
+    #[serde(default = "max_reconnect_backoff_secs_default")]
+    pub max_reconnect_backoff_secs: u64,
+    #[serde(default = "health_check_interval_secs_default")]
+    pub health_check_interval_secs: u64,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RedisMode {
+    #[default]
+    Standalone,
+    Sentinel,
+    Cluster,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RedisTlsConfig {
+    /// The certificates Redis' certificate is checked against, in PEM. The system's trust store if unset.
+    pub ca_certificate: Option<PathBuf>,
+
+    /// A client certificate and its key in PEM, for servers requiring `tls-auth-clients`.
+    pub client_certificate: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+
+    /// Issues a client certificate from the CA at startup instead, following `client_profile`, and renews it on the next
+    /// reconnect once half of its lifetime has passed. Its common name is `username`, or `certmaster` if unset, for
+    /// servers mapping certificates to ACL users. Only processes running the `ca` module can do so.
+    #[serde(default)]
+    pub issue_client_certificate: bool,
+    #[serde(default = "client_profile_default")]
+    pub client_profile: String,
+}
+
+#[inline]
+fn client_profile_default() -> String { "client".into() }
+
+impl Default for RedisTlsConfig {
+    fn default() -> RedisTlsConfig {
+        RedisTlsConfig {
+            ca_certificate: None,
+            client_certificate: None,
+            client_key: None,
+            issue_client_certificate: false,
+            client_profile: client_profile_default(),
+        }
+    }
 }
 
 #[inline]
 fn task_queue_key_default() -> String { "event-queue".into() }
 #[inline]
 fn job_list_key_default() -> String { "job-list".into() }
+#[inline]
+fn dead_letter_stream_key_default() -> String { "dead-letters".into() }
+#[inline]
+fn reconnect_backoff_ms_default() -> u64 { 100 }
+#[inline]
+fn max_reconnect_backoff_secs_default() -> u64 { 30 }
+#[inline]
+fn health_check_interval_secs_default() -> u64 { 5 }
+
+/// # Storage
+/// Where jobs, events, certificates and ACME state are kept.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct StorageConfig {
+    #[serde(default)]
+    pub backend: StorageBackend,
+    #[serde(default)]
+    pub sqlite: SqliteConfig,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StorageBackend {
+    /// Configured in `[redis]`.
+    #[default]
+    Redis,
+    /// A single database file, for deployments where every process runs on the same host.
+    Sqlite,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SqliteConfig {
+    /// Created if missing.
+    #[serde(default = "sqlite_path_default")]
+    pub path: PathBuf,
+    /// How often readers look for events and job updates written by other processes. Writes within the same process
+    /// wake them right away.
+    #[serde(default = "sqlite_poll_interval_ms_default")]
+    pub poll_interval_ms: u64,
+}
+
+#[inline]
+fn sqlite_path_default() -> PathBuf { "./certmaster.db".into() }
+#[inline]
+fn sqlite_poll_interval_ms_default() -> u64 { 250 }
+
+impl Default for SqliteConfig {
+    fn default() -> SqliteConfig {
+        SqliteConfig {
+            path: sqlite_path_default(),
+            poll_interval_ms: sqlite_poll_interval_ms_default(),
+        }
+    }
+}
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct InboxConfig {
@@ -73,18 +239,421 @@ pub struct CaConfig {
     pub hooks: Vec<PathBuf>,
 
     pub certificate: PathBuf,
-    pub key: PathBuf
+    pub key: PathBuf,
+
+    /// The profile used for requests which don't name one.
+    pub default_profile: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct WebConfig {
     pub socket: SocketAddr,
+
+    /// Maps operator names to the hex SHA-256 digest of their bearer token. Requests acting on behalf of someone, such
+    /// as revocations and reviews, are attributed to the operator whose token they carry.
+    #[serde(default)]
+    pub operators: HashMap<String, String>,
 }
 
 impl Default for WebConfig {
     fn default() -> WebConfig {
         WebConfig {
             socket: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 9999),
+            operators: HashMap::new(),
         }
     }
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CrlConfig {
+    /// How long each CRL remains valid. A fresh CRL is published after half of this period and on every revocation.
+    #[serde(default = "next_update_secs_default")]
+    pub next_update_secs: u64,
+}
+
+#[inline]
+fn next_update_secs_default() -> u64 { 86400 }
+
+impl Default for CrlConfig {
+    fn default() -> CrlConfig {
+        CrlConfig {
+            next_update_secs: next_update_secs_default(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OcspConfig {
+    /// A delegated responder certificate issued by the CA with the `ocsp-signing` extended key usage, and its key.
+    /// Responses are signed with the CA key if these are unset. Both are reloaded along with the CA files.
+    pub certificate: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+
+    /// How long each response remains valid.
+    #[serde(default = "ocsp_next_update_secs_default")]
+    pub next_update_secs: u64,
+
+    /// How long HTTP caches may keep a response. Capped at `next_update_secs`. Responses to requests carrying a nonce
+    /// are never cached.
+    #[serde(default = "ocsp_max_age_secs_default")]
+    pub max_age_secs: u64,
+}
+
+#[inline]
+fn ocsp_next_update_secs_default() -> u64 { 3600 }
+#[inline]
+fn ocsp_max_age_secs_default() -> u64 { 600 }
+
+impl Default for OcspConfig {
+    fn default() -> OcspConfig {
+        OcspConfig {
+            certificate: None,
+            key: None,
+            next_update_secs: ocsp_next_update_secs_default(),
+            max_age_secs: ocsp_max_age_secs_default(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AcmeConfig {
+    /// The URL under which clients reach the web API, such as `https://ca.example.com`. Every ACME resource URL is built
+    /// from it, and requests are only accepted if the URL they were signed for matches.
+    #[serde(default = "acme_base_url_default")]
+    pub base_url: String,
+
+    /// The issuance profile certificates ordered over ACME are signed under.
+    pub profile: Option<String>,
+
+    /// If set, new accounts have to agree to the terms of service found under this URL.
+    pub terms_of_service: Option<String>,
+
+    /// How long clients have to complete an order.
+    #[serde(default = "acme_order_lifetime_secs_default")]
+    pub order_lifetime_secs: u64,
+}
+
+#[inline]
+fn acme_base_url_default() -> String { "http://localhost:9999".into() }
+#[inline]
+fn acme_order_lifetime_secs_default() -> u64 { 7 * 86400 }
+
+impl Default for AcmeConfig {
+    fn default() -> AcmeConfig {
+        AcmeConfig {
+            base_url: acme_base_url_default(),
+            profile: None,
+            terms_of_service: None,
+            order_lifetime_secs: acme_order_lifetime_secs_default(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ChallengeConfig {
+    /// The challenge issued to requests which don't ask for one. Without it, such jobs wait for a manual decision.
+    pub default_type: Option<crate::ChallengeType>,
+
+    /// Addresses to contact instead of what the system resolver returns for a name.
+    #[serde(default)]
+    pub hosts: HashMap<String, IpAddr>,
+
+    #[serde(default)]
+    pub http01: Http01Config,
+
+    #[serde(default)]
+    pub dns01: Dns01Config,
+
+    #[serde(default)]
+    pub tls_alpn01: TlsAlpn01Config,
+
+    #[serde(default)]
+    pub expiry: ChallengeExpiry,
+}
+
+impl ChallengeConfig {
+    /// How long validating a challenge of the type may take. Missing dns-01 records are looked for until
+    /// `dns01.deadline_secs`, while http-01 and tls-alpn-01 challenges are done once all their attempts are.
+    pub fn deadline_secs(&self, kind: crate::ChallengeType) -> u64 {
+        let (timeout_secs, attempts, retry_interval_secs) = match kind {
+            crate::ChallengeType::Dns01 => return self.dns01.deadline_secs,
+            crate::ChallengeType::Http01 => (self.http01.timeout_secs, self.http01.attempts, self.http01.retry_interval_secs),
+            crate::ChallengeType::TlsAlpn01 => (self.tls_alpn01.timeout_secs, self.tls_alpn01.attempts, self.tls_alpn01.retry_interval_secs),
+        };
+
+        let attempts = u64::from(attempts.max(1));
+        attempts * timeout_secs + (attempts - 1) * retry_interval_secs
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Http01Config {
+    #[serde(default = "http01_port_default")]
+    pub port: u16,
+
+    /// How long a single attempt may take, from connecting to reading the response.
+    #[serde(default = "http01_timeout_secs_default")]
+    pub timeout_secs: u64,
+
+    #[serde(default = "http01_attempts_default")]
+    pub attempts: u32,
+
+    #[serde(default = "http01_retry_interval_secs_default")]
+    pub retry_interval_secs: u64,
+}
+
+#[inline]
+fn http01_port_default() -> u16 { 80 }
+#[inline]
+fn http01_timeout_secs_default() -> u64 { 10 }
+#[inline]
+fn http01_attempts_default() -> u32 { 3 }
+#[inline]
+fn http01_retry_interval_secs_default() -> u64 { 5 }
+
+impl Default for Http01Config {
+    fn default() -> Http01Config {
+        Http01Config {
+            port: http01_port_default(),
+            timeout_secs: http01_timeout_secs_default(),
+            attempts: http01_attempts_default(),
+            retry_interval_secs: http01_retry_interval_secs_default(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Dns01Config {
+    /// The nameserver TXT records are looked up with. Defaults to the nameservers in `/etc/resolv.conf`.
+    pub resolver: Option<SocketAddr>,
+
+    /// How long a single query may take.
+    #[serde(default = "dns01_timeout_secs_default")]
+    pub timeout_secs: u64,
+
+    #[serde(default = "dns01_poll_interval_secs_default")]
+    pub poll_interval_secs: u64,
+
+    /// How long requesters have to publish their records, counted from when the job reaches the challenge stage.
+    #[serde(default = "dns01_deadline_secs_default")]
+    pub deadline_secs: u64,
+}
+
+#[inline]
+fn dns01_timeout_secs_default() -> u64 { 5 }
+#[inline]
+fn dns01_poll_interval_secs_default() -> u64 { 30 }
+#[inline]
+fn dns01_deadline_secs_default() -> u64 { 3600 }
+
+impl Default for Dns01Config {
+    fn default() -> Dns01Config {
+        Dns01Config {
+            resolver: None,
+            timeout_secs: dns01_timeout_secs_default(),
+            poll_interval_secs: dns01_poll_interval_secs_default(),
+            deadline_secs: dns01_deadline_secs_default(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TlsAlpn01Config {
+    #[serde(default = "tls_alpn01_port_default")]
+    pub port: u16,
+
+    /// How long a single attempt may take, from connecting to receiving the certificate.
+    #[serde(default = "http01_timeout_secs_default")]
+    pub timeout_secs: u64,
+
+    #[serde(default = "http01_attempts_default")]
+    pub attempts: u32,
+
+    #[serde(default = "http01_retry_interval_secs_default")]
+    pub retry_interval_secs: u64,
+}
+
+#[inline]
+fn tls_alpn01_port_default() -> u16 { 443 }
+
+impl Default for TlsAlpn01Config {
+    fn default() -> TlsAlpn01Config {
+        TlsAlpn01Config {
+            port: tls_alpn01_port_default(),
+            timeout_secs: http01_timeout_secs_default(),
+            attempts: http01_attempts_default(),
+            retry_interval_secs: http01_retry_interval_secs_default(),
+        }
+    }
+}
+
+/// How long jobs may wait for their challenge before they fail as expired, counted from when they were submitted.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChallengeExpiry {
+    /// Jobs waiting for reviewers, which are those without a challenge type and those whose challenges have passed
+    /// but which lack approvals.
+    #[serde(default = "expiry_manual_secs_default")]
+    pub manual_secs: u64,
+    #[serde(default = "expiry_secs_default")]
+    pub http01_secs: u64,
+    #[serde(default = "expiry_secs_default")]
+    pub dns01_secs: u64,
+    #[serde(default = "expiry_secs_default")]
+    pub tls_alpn01_secs: u64,
+
+    /// How often to look for expired jobs.
+    #[serde(default = "expiry_interval_secs_default")]
+    pub interval_secs: u64,
+}
+
+#[inline]
+fn expiry_manual_secs_default() -> u64 { 7 * 86400 }
+#[inline]
+fn expiry_secs_default() -> u64 { 86400 }
+#[inline]
+fn expiry_interval_secs_default() -> u64 { 60 }
+
+impl ChallengeExpiry {
+    pub fn expiry_secs(&self, kind: Option<crate::ChallengeType>) -> u64 {
+        match kind {
+            None => self.manual_secs,
+            Some(crate::ChallengeType::Http01) => self.http01_secs,
+            Some(crate::ChallengeType::Dns01) => self.dns01_secs,
+            Some(crate::ChallengeType::TlsAlpn01) => self.tls_alpn01_secs,
+        }
+    }
+}
+
+impl Default for ChallengeExpiry {
+    fn default() -> ChallengeExpiry {
+        ChallengeExpiry {
+            manual_secs: expiry_manual_secs_default(),
+            http01_secs: expiry_secs_default(),
+            dns01_secs: expiry_secs_default(),
+            tls_alpn01_secs: expiry_secs_default(),
+            interval_secs: expiry_interval_secs_default(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GcConfig {
+    /// How long jobs are kept after they finished, went stale or failed.
+    #[serde(default = "gc_retention_days_default")]
+    pub retention_days: u64,
+
+    #[serde(default = "gc_interval_secs_default")]
+    pub interval_secs: u64,
+
+    /// Only report what would be deleted.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[inline]
+fn gc_retention_days_default() -> u64 { 30 }
+#[inline]
+fn gc_interval_secs_default() -> u64 { 3600 }
+
+impl Default for GcConfig {
+    fn default() -> GcConfig {
+        GcConfig {
+            retention_days: gc_retention_days_default(),
+            interval_secs: gc_interval_secs_default(),
+            dry_run: false,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SupervisorConfig {
+    /// How long to wait before restarting a module which stopped. Doubles with every restart in a row.
+    #[serde(default = "supervisor_restart_backoff_ms_default")]
+    pub restart_backoff_ms: u64,
+
+    #[serde(default = "supervisor_max_restart_backoff_secs_default")]
+    pub max_restart_backoff_secs: u64,
+
+    /// Modules which ran for at least this long before stopping are restarted with the initial backoff again.
+    #[serde(default = "supervisor_healthy_after_secs_default")]
+    pub healthy_after_secs: u64,
+}
+
+#[inline]
+fn supervisor_restart_backoff_ms_default() -> u64 { 500 }
+#[inline]
+fn supervisor_max_restart_backoff_secs_default() -> u64 { 60 }
+#[inline]
+fn supervisor_healthy_after_secs_default() -> u64 { 300 }
+
+impl Default for SupervisorConfig {
+    fn default() -> SupervisorConfig {
+        SupervisorConfig {
+            restart_backoff_ms: supervisor_restart_backoff_ms_default(),
+            max_restart_backoff_secs: supervisor_max_restart_backoff_secs_default(),
+            healthy_after_secs: supervisor_healthy_after_secs_default(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShutdownConfig {
+    /// How long work in progress may take to finish once the process was asked to stop, before it's cut short.
+    #[serde(default = "shutdown_grace_period_secs_default")]
+    pub grace_period_secs: u64,
+}
+
+#[inline]
+fn shutdown_grace_period_secs_default() -> u64 { 30 }
+
+impl Default for ShutdownConfig {
+    fn default() -> ShutdownConfig {
+        ShutdownConfig {
+            grace_period_secs: shutdown_grace_period_secs_default(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WorkerConfig {
+    /// The name the worker reads the event stream under. Defaults to the host name, so that a restarted worker picks up
+    /// the events it was handed before. Replicas sharing a stream need distinct names.
+    #[serde(default)]
+    pub consumer: Option<String>,
+
+    /// How many events are handled at once. Events concerning the same job are still handled one after the other.
+    #[serde(default = "worker_concurrency_default")]
+    pub concurrency: usize,
+
+    /// Events which a worker hasn't acknowledged for this long are claimed by another.
+    #[serde(default = "worker_claim_idle_secs_default")]
+    pub claim_idle_secs: u64,
+
+    #[serde(default = "worker_claim_interval_secs_default")]
+    pub claim_interval_secs: u64,
+
+    /// Events which failed or were delivered this often are moved to the dead-letter stream.
+    #[serde(default = "worker_max_deliveries_default")]
+    pub max_deliveries: usize,
+}
+
+#[inline]
+fn worker_concurrency_default() -> usize { 8 }
+#[inline]
+fn worker_claim_idle_secs_default() -> u64 { 300 }
+#[inline]
+fn worker_claim_interval_secs_default() -> u64 { 60 }
+#[inline]
+fn worker_max_deliveries_default() -> usize { 5 }
+
+impl Default for WorkerConfig {
+    fn default() -> WorkerConfig {
+        WorkerConfig {
+            consumer: None,
+            concurrency: worker_concurrency_default(),
+            claim_idle_secs: worker_claim_idle_secs_default(),
+            claim_interval_secs: worker_claim_interval_secs_default(),
+            max_deliveries: worker_max_deliveries_default(),
+        }
+    }
+}
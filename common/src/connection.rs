@@ -0,0 +1,433 @@
+//! # Connection Manager
+//! Everything in a process shares one connection. Whenever it breaks, it's replaced by a new one, which is retried with
+//! exponential backoff until Redis can be reached again. Connections handed out before keep failing, so callers get a
+//! fresh one from [RedisConfig::connect] after an error.
+//!
+//! Behind Sentinel, a new connection goes to whichever server is master by then. A master demoted by a failover still
+//! answers, so it's checked for its role rather than just pinged. A cluster connection follows failovers by itself.
+//!
+//! Credentials and certificates are read anew for every connection, so they can be rotated without a restart.
+
+use crate::{Authority, CertificateStore, CsrId, Error, IssuedCertificate, RedisConfig, RedisConnection, RedisMode, Result};
+use redis::cluster::ClusterClient;
+use redis::sentinel::{SentinelClientBuilder, SentinelServerType};
+use redis::{ClientTlsConfig, ConnectionAddr, ConnectionInfo, IntoConnectionInfo, RedisConnectionInfo, TlsCertificates, TlsMode, Value};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Serialize;
+use std::sync::{LazyLock, Mutex, Once, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use time::OffsetDateTime;
+
+static REDIS: LazyLock<tokio::sync::RwLock<Option<RedisConnection>>> = LazyLock::new(|| tokio::sync::RwLock::new(None));
+/// Held while reconnecting, so a broken connection is only replaced once.
+static RECONNECTING: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+static HEALTH: RwLock<RedisHealth> = RwLock::new(RedisHealth {
+    connected: false,
+    since: None,
+    last_error: None,
+    reconnects: 0,
+});
+static MONITOR: Once = Once::new();
+/// The client certificate issued by the CA, along with when to renew it.
+static CLIENT_CERTIFICATE: Mutex<Option<(ClientTlsConfig, OffsetDateTime)>> = Mutex::new(None);
+/// The client certificate issued last, until it's stored with the other issued certificates. That takes the connection
+/// it's for, so it's recorded once that's established.
+static UNRECORDED_CLIENT_CERTIFICATE: Mutex<Option<IssuedCertificate>> = Mutex::new(None);
+
+/// A connection which doesn't answer a ping within this time is considered broken.
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The state of the shared connection.
+#[derive(Debug, Clone, Serialize)]
+pub struct RedisHealth {
+    pub connected: bool,
+    /// Unix timestamp in seconds of when the connection was established or lost
+    pub since: Option<i64>,
+    pub last_error: Option<String>,
+    /// How often the connection had to be replaced
+    pub reconnects: u64,
+}
+
+pub fn redis_health() -> RedisHealth {
+    HEALTH.read().unwrap_or_else(|err| err.into_inner()).clone()
+}
+
+fn set_health(error: Option<String>, reconnected: bool) {
+    let mut health = HEALTH.write().unwrap_or_else(|err| err.into_inner());
+
+    if health.connected != error.is_none() {
+        health.since = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|now| now.as_secs() as i64);
+    }
+
+    health.connected = error.is_none();
+    if reconnected {
+        health.reconnects += 1;
+    }
+
+    if error.is_some() {
+        health.last_error = error;
+    }
+}
+
+/// Serials of client certificates are drawn at random from [2^62, 2^63), far above the job IDs other certificates are
+/// issued under, which count up from 1, while still fitting a signed 64-bit integer.
+fn client_certificate_serial() -> Result<CsrId> {
+    let mut bytes = [0u8; 8];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| Error::other("Failed to generate a serial number"))?;
+
+    Ok((u64::from_be_bytes(bytes) >> 2) | (1 << 62))
+}
+
+/// Stores the client certificate issued last, if it hasn't been yet. Should that fail, it's tried again on the next
+/// connection.
+async fn record_client_certificate(connection: &mut RedisConnection) {
+    let Some(issued) = UNRECORDED_CLIENT_CERTIFICATE.lock().unwrap_or_else(|err| err.into_inner()).take() else {
+        return;
+    };
+
+    match connection.store_certificate(&issued).await {
+        Ok(_) => log::debug!("Recorded the Redis client certificate with serial {serial}", serial = issued.serial),
+        Err(err) => {
+            log::warn!("Failed to record the Redis client certificate with serial {serial}: {err}", serial = issued.serial);
+            UNRECORDED_CLIENT_CERTIFICATE.lock().unwrap_or_else(|err| err.into_inner()).get_or_insert(issued);
+        },
+    }
+}
+
+/// Whether the connection still reaches a server which takes writes.
+async fn ping(connection: &mut RedisConnection, mode: RedisMode) -> std::result::Result<(), String> {
+    let command = match mode {
+        RedisMode::Sentinel => "ROLE",
+        RedisMode::Standalone | RedisMode::Cluster => "PING",
+    };
+
+    let reply = match tokio::time::timeout(PING_TIMEOUT, redis::cmd(command).query_async::<Value>(connection)).await {
+        Ok(Ok(reply)) => reply,
+        Ok(Err(err)) => return Err(err.to_string()),
+        Err(_) => return Err(format!("No answer to {command} within {PING_TIMEOUT:?}")),
+    };
+
+    match reply {
+        Value::Array(role) if mode == RedisMode::Sentinel => match role.first() {
+            Some(Value::BulkString(role)) if role == b"master" => Ok(()),
+            _ => Err("No longer the master".to_owned()),
+        },
+        _ => Ok(()),
+    }
+}
+
+impl RedisConfig {
+    /// The shared connection. If there's none yet, this waits until one could be established.
+    pub async fn connect(&self) -> RedisConnection {
+        if let Some(connection) = REDIS.read().await.as_ref() {
+            return connection.clone();
+        }
+
+        let connection = self.reconnect().await;
+
+        MONITOR.call_once(|| {
+            tokio::spawn(monitor());
+        });
+
+        connection
+    }
+
+    /// Replaces the shared connection, retrying with backoff until Redis can be reached. If the current connection
+    /// turns out to be working, for example because it was replaced in the meantime, it's kept.
+    pub async fn reconnect(&self) -> RedisConnection {
+        let _reconnecting = RECONNECTING.lock().await;
+
+        let current = REDIS.read().await.clone();
+        if let Some(mut connection) = current.clone() && ping(&mut connection, self.mode).await.is_ok() {
+            return connection;
+        }
+
+        let initial = Duration::from_millis(self.reconnect_backoff_ms.max(1));
+        let max = Duration::from_secs(self.max_reconnect_backoff_secs).max(initial);
+
+        let mut backoff = initial;
+        loop {
+            log::trace!("Establishing connection to Redis");
+
+            match self.connect_dedicated().await {
+                Ok(connection) => {
+                    *REDIS.write().await = Some(connection.clone());
+                    set_health(None, current.is_some());
+
+                    if current.is_some() {
+                        log::info!("Reconnected to Redis");
+                    }
+
+                    return connection;
+                },
+                Err(err) => {
+                    log::warn!("Failed to connect to Redis - Retrying in {backoff:?}: {err}");
+                    set_health(Some(err.inner().to_string()), false);
+
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(max);
+                }
+            }
+        }
+    }
+
+    /// A connection of its own rather than the shared one. Blocking commands on the shared connection would hold up
+    /// everyone else using it.
+    pub async fn connect_dedicated(&self) -> Result<RedisConnection> {
+        let mut connection = self.open_connection().await?;
+        record_client_certificate(&mut connection).await;
+
+        Ok(connection)
+    }
+
+    async fn open_connection(&self) -> Result<RedisConnection> {
+        match self.mode {
+            RedisMode::Standalone | RedisMode::Sentinel => Ok(RedisConnection::Single(self.client()
+                .await?
+                .get_multiplexed_async_connection()
+                .await?)),
+            RedisMode::Cluster => {
+                let nodes = self.cluster_nodes()?;
+                let tls = nodes.first().and_then(|node| tls_mode(&node.addr)).is_some();
+
+                let mut builder = ClusterClient::builder(nodes);
+                if tls && let Some(certificates) = self.tls_certificates().await? {
+                    builder = builder.certs(certificates);
+                }
+
+                Ok(RedisConnection::Cluster(builder.build()?.get_async_connection().await?))
+            },
+        }
+    }
+
+    /// A client for a single server: the configured one, the current master behind Sentinel, or the first node of a
+    /// cluster, which is enough for pub/sub, as messages are broadcast to every node.
+    pub async fn client(&self) -> Result<redis::Client> {
+        match self.mode {
+            RedisMode::Standalone => self.open(self.connection_info()?).await,
+            RedisMode::Sentinel => {
+                let Some(master_name) = &self.master_name else {
+                    return Error::custom("Sentinel mode requires `redis.master_name`");
+                };
+
+                if self.nodes.is_empty() {
+                    return Error::custom("Sentinel mode requires the sentinels in `redis.nodes`");
+                }
+
+                let sentinels = self.nodes
+                    .iter()
+                    .map(|node| node.as_str().into_connection_info())
+                    .collect::<redis::RedisResult<Vec<_>>>()?;
+                let master = self.connection_info()?;
+                let certificates = self.tls_certificates().await?;
+
+                let mut builder = SentinelClientBuilder::new(sentinels.iter().map(|sentinel| sentinel.addr.clone()), master_name.clone(), SentinelServerType::Master)?
+                    .set_client_to_redis_db(master.redis.db)
+                    .set_client_to_redis_protocol(master.redis.protocol);
+
+                if let Some(username) = master.redis.username {
+                    builder = builder.set_client_to_redis_username(username);
+                }
+
+                if let Some(password) = master.redis.password {
+                    builder = builder.set_client_to_redis_password(password);
+                }
+
+                if let Some(mode) = tls_mode(&master.addr) {
+                    builder = builder.set_client_to_redis_tls_mode(mode);
+
+                    if let Some(certificates) = &certificates {
+                        builder = builder.set_client_to_redis_certificates(certificates.clone());
+                    }
+                }
+
+                // Sentinels usually have users of their own, so they keep the credentials given in their URLs
+                if let Some(sentinel) = sentinels.first() {
+                    if let Some(username) = &sentinel.redis.username {
+                        builder = builder.set_client_to_sentinel_username(username.clone());
+                    }
+
+                    if let Some(password) = &sentinel.redis.password {
+                        builder = builder.set_client_to_sentinel_password(password.clone());
+                    }
+
+                    if tls_mode(&sentinel.addr).is_some() && let Some(certificates) = certificates {
+                        builder = builder.set_client_to_sentinel_certificates(certificates);
+                    }
+                }
+
+                Ok(builder.build()?.async_get_client().await?)
+            },
+            RedisMode::Cluster => self.open(self.cluster_nodes()?.remove(0)).await,
+        }
+    }
+
+    /// A client for the given server, using the configured certificates if it's reached over TLS.
+    async fn open(&self, info: ConnectionInfo) -> Result<redis::Client> {
+        if tls_mode(&info.addr).is_some() && let Some(certificates) = self.tls_certificates().await? {
+            return Ok(redis::Client::build_with_tls(info, certificates)?);
+        }
+
+        Ok(redis::Client::open(info)?)
+    }
+
+    fn cluster_nodes(&self) -> Result<Vec<ConnectionInfo>> {
+        if self.database() != 0 {
+            return Error::custom("Redis Cluster only has database 0");
+        }
+
+        let nodes = match self.nodes.is_empty() {
+            true => std::slice::from_ref(&self.url),
+            false => self.nodes.as_slice(),
+        };
+
+        nodes
+            .iter()
+            .map(|node| {
+                let mut info = node.as_str().into_connection_info()?;
+                self.authenticate(&mut info.redis)?;
+                Ok(info)
+            })
+            .collect()
+    }
+
+    /// `url`, with `db`, `username` and `password_file` taking precedence over what's given there.
+    pub fn connection_info(&self) -> Result<ConnectionInfo> {
+        let mut info = self.url.as_str().into_connection_info()?;
+        if let Some(db) = self.db {
+            info.redis.db = db as i64;
+        }
+
+        self.authenticate(&mut info.redis)?;
+
+        Ok(info)
+    }
+
+    fn authenticate(&self, info: &mut RedisConnectionInfo) -> Result<()> {
+        if let Some(username) = &self.username {
+            info.username = Some(username.clone());
+        }
+
+        if let Some(path) = &self.password_file {
+            let password = std::fs::read_to_string(path)
+                .map_err(|err| Error::other(format!("Failed to read the Redis password from {path:?}: {err}")))?;
+
+            info.password = Some(password.trim_end_matches(['\r', '\n']).to_owned());
+        }
+
+        Ok(())
+    }
+
+    /// The certificates to use on `rediss://` connections, unless the defaults will do.
+    async fn tls_certificates(&self) -> Result<Option<TlsCertificates>> {
+        let root_cert = match &self.tls.ca_certificate {
+            Some(path) => Some(tokio::fs::read(path).await?),
+            None => None,
+        };
+
+        let client_tls = match (&self.tls.client_certificate, &self.tls.client_key) {
+            _ if self.tls.issue_client_certificate => Some(self.issued_client_certificate().await?),
+            (Some(certificate), Some(key)) => Some(ClientTlsConfig {
+                client_cert: tokio::fs::read(certificate).await?,
+                client_key: tokio::fs::read(key).await?,
+            }),
+            (None, None) => None,
+            _ => return Error::custom("A client certificate requires both `redis.tls.client_certificate` and `redis.tls.client_key`"),
+        };
+
+        if root_cert.is_none() && client_tls.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(TlsCertificates { client_tls, root_cert }))
+    }
+
+    /// A client certificate issued by the CA, which is reused until half of its lifetime has passed. It's recorded with
+    /// the other issued certificates once connected, see [record_client_certificate].
+    async fn issued_client_certificate(&self) -> Result<ClientTlsConfig> {
+        let now = OffsetDateTime::now_utc();
+
+        if let Some((certificate, renew_at)) = &*CLIENT_CERTIFICATE.lock().unwrap_or_else(|err| err.into_inner())
+            && now < *renew_at {
+            return Ok(certificate.clone());
+        }
+
+        let config = crate::get_config();
+        if !config.modules.ca {
+            return Error::custom("`redis.tls.issue_client_certificate` only works in processes running the `ca` module - Configure `client_certificate` and `client_key` instead");
+        }
+
+        let authority = Authority::current()?;
+        let profile = config.profile(Some(&self.tls.client_profile))?;
+
+        let name = self.username.as_deref().unwrap_or("certmaster");
+        let serial = client_certificate_serial()?;
+        let (certificate, key) = authority.issue_client_certificate(name, serial, &profile)?;
+
+        let issued = IssuedCertificate::from_pem(serial, Some(self.tls.client_profile.clone()), certificate.clone())?;
+        let renew_at = now + (OffsetDateTime::from_unix_timestamp(issued.not_after)? - now) / 2;
+        log::info!("Issued a Redis client certificate for {name:?} with serial {serial}, to be renewed after {renew_at}");
+
+        let certificate = ClientTlsConfig {
+            client_cert: certificate.into_bytes(),
+            client_key: key.into_bytes(),
+        };
+
+        *CLIENT_CERTIFICATE.lock().unwrap_or_else(|err| err.into_inner()) = Some((certificate.clone(), renew_at));
+        *UNRECORDED_CLIENT_CERTIFICATE.lock().unwrap_or_else(|err| err.into_inner()) = Some(issued);
+
+        Ok(certificate)
+    }
+
+    /// The database in use.
+    pub fn database(&self) -> i64 {
+        match self.db {
+            Some(db) => db as i64,
+            None => self.url.as_str().into_connection_info().map_or(0, |info| info.redis.db),
+        }
+    }
+
+    /// The hash tag all keys share, if any. A cluster needs one, as transactions may only touch keys in one slot, so
+    /// certmaster's data always lives on a single shard of it.
+    pub fn hash_tag(&self) -> Option<&str> {
+        match (&self.hash_tag, self.mode) {
+            (Some(tag), _) => Some(tag),
+            (None, RedisMode::Cluster) => Some("certmaster"),
+            (None, _) => None,
+        }
+    }
+}
+
+fn tls_mode(addr: &ConnectionAddr) -> Option<TlsMode> {
+    match addr {
+        ConnectionAddr::TcpTls { insecure: true, .. } => Some(TlsMode::Insecure),
+        ConnectionAddr::TcpTls { .. } => Some(TlsMode::Secure),
+        _ => None,
+    }
+}
+
+/// Pings the shared connection periodically and replaces it once it stops answering.
+async fn monitor() {
+    let config = crate::get_config();
+    let mut interval = tokio::time::interval(Duration::from_secs(config.redis.health_check_interval_secs.max(1)));
+
+    loop {
+        interval.tick().await;
+
+        let Some(mut connection) = REDIS.read().await.clone() else {
+            continue;
+        };
+
+        if let Err(err) = ping(&mut connection, config.redis.mode).await {
+            log::error!("Lost the connection to Redis: {err}");
+            set_health(Some(err), false);
+
+            config.redis.reconnect().await;
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use async_trait::async_trait;
+use redis::streams::{StreamId, StreamRangeReply};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use crate::{Error, EventEntry, Key, RedisConnection, Result, NEW_CSR_EVENT_GROUP};
+
+const DEAD_LETTER_FIELD: &str = "dead-letter";
+
+/// An event which the worker failed to handle too often, along with the error it last failed with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    /// The ID in the dead-letter stream. Only set once read back.
+    #[serde(default)]
+    pub id: String,
+    /// The ID the event had in the event stream.
+    pub event_id: String,
+    /// The raw fields of the event, usually its name and RON payload.
+    pub fields: Vec<(String, String)>,
+    pub error: String,
+    pub attempts: usize,
+    /// Unix timestamp in seconds
+    pub failed_at: i64,
+}
+
+impl DeadLetter {
+    pub(crate) fn new(entry: &EventEntry, error: String, attempts: usize) -> Result<Self> {
+        Ok(Self {
+            id: String::new(),
+            event_id: entry.id.clone(),
+            fields: entry.fields.clone(),
+            error,
+            attempts,
+            failed_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64,
+        })
+    }
+
+    fn from_entry(entry: &StreamId) -> Result<Self> {
+        let Some(letter) = entry.get::<String>(DEAD_LETTER_FIELD) else {
+            return Error::custom(format!("Dead-letter entry {id} is malformed", id = entry.id));
+        };
+
+        Ok(Self {
+            id: entry.id.clone(),
+            ..ron::from_str(&letter)?
+        })
+    }
+}
+
+/// # Dead-Letter Queue
+/// Events which repeatedly fail are moved from the event stream into the dead-letter stream, where they wait to be
+/// inspected, and then replayed or discarded.
+#[async_trait]
+pub trait DeadLetterQueue {
+    /// Moves an entry of the event stream to the dead-letter stream, acknowledging it. Returns its new ID.
+    async fn dead_letter(&mut self, entry: &EventEntry, error: String, attempts: usize) -> Result<String>;
+    async fn get_dead_letters(&mut self) -> Result<Vec<DeadLetter>>;
+    async fn get_dead_letter(&mut self, id: &str) -> Result<Option<DeadLetter>>;
+    /// Puts the event back into the event stream. Returns its new ID there, or `None` if there's no such dead letter.
+    async fn replay_dead_letter(&mut self, id: &str) -> Result<Option<String>>;
+    /// Returns `false` if there's no such dead letter.
+    async fn discard_dead_letter(&mut self, id: &str) -> Result<bool>;
+}
+
+#[async_trait]
+impl DeadLetterQueue for RedisConnection {
+    async fn dead_letter(&mut self, entry: &EventEntry, error: String, attempts: usize) -> Result<String> {
+        let letter = DeadLetter::new(entry, error, attempts)?;
+
+        let (id,): (String,) = redis::pipe()
+            .atomic()
+            .xadd(Key::DeadLetters, "*", &[(DEAD_LETTER_FIELD, ron::to_string(&letter)?)])
+            .xack(Key::EventStream, NEW_CSR_EVENT_GROUP, &[&entry.id]).ignore()
+            .query_async(self)
+            .await?;
+
+        Ok(id)
+    }
+
+    async fn get_dead_letters(&mut self) -> Result<Vec<DeadLetter>> {
+        let entries: StreamRangeReply = self.xrange_all(Key::DeadLetters).await?;
+        entries.ids
+            .iter()
+            .map(DeadLetter::from_entry)
+            .collect()
+    }
+
+    async fn get_dead_letter(&mut self, id: &str) -> Result<Option<DeadLetter>> {
+        let entries: StreamRangeReply = self.xrange(Key::DeadLetters, id, id).await?;
+        entries.ids
+            .first()
+            .map(DeadLetter::from_entry)
+            .transpose()
+    }
+
+    async fn replay_dead_letter(&mut self, id: &str) -> Result<Option<String>> {
+        let Some(letter) = self.get_dead_letter(id).await? else {
+            return Ok(None);
+        };
+
+        let (event_id,): (String,) = redis::pipe()
+            .atomic()
+            .xadd(Key::EventStream, "*", &letter.fields)
+            .xdel(Key::DeadLetters, &[id]).ignore()
+            .query_async(self)
+            .await?;
+
+        Ok(Some(event_id))
+    }
+
+    async fn discard_dead_letter(&mut self, id: &str) -> Result<bool> {
+        let deleted: usize = self.xdel(Key::DeadLetters, &[id]).await?;
+        Ok(deleted > 0)
+    }
+}
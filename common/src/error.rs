@@ -28,6 +28,10 @@ macro_rules! multi_error {
                 }
             }
 
+            impl Error {
+                pub fn inner(&self) -> &Inner { &self.inner }
+            }
+
             impl std::error::Error for Error {}
             impl std::fmt::Display for Error {
                 fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { std::fmt::Debug::fmt(self, f) }
@@ -57,7 +61,15 @@ multi_error! { global();
     RcGenError = rcgen::Error;
     Base64DecodeError = base64::DecodeError;
     RonDeSpannedError = ron::de::SpannedError;
-    RonDeError = ron::de::Error
+    RonDeError = ron::de::Error;
+    PemError = x509_parser::nom::Err<x509_parser::error::PEMError>;
+    X509Error = x509_parser::error::X509Error;
+    X509NomError = x509_parser::nom::Err<x509_parser::error::X509Error>;
+    TimeRangeError = time::error::ComponentRange;
+    Asn1Error = yasna::ASN1Error;
+    JsonError = serde_json::Error;
+    JoinError = tokio::task::JoinError;
+    SqliteError = rusqlite::Error
 }
 
 pub type Result<T> = ::std::result::Result<T, global::Error>;
@@ -75,6 +87,16 @@ impl global::Error {
     pub fn other(str: impl AsRef<str>) -> Self {
         str.as_ref().to_owned().into()
     }
+
+    /// Whether the connection to Redis broke or couldn't be established, rather than a command failing. A master
+    /// demoted by a failover counts as well, as only a new connection reaches the new master.
+    pub fn is_connection_error(&self) -> bool {
+        match self.inner() {
+            global::Inner::RedisError(err) => err.is_connection_dropped() || err.is_connection_refusal() || err.is_io_error() || err.is_timeout()
+                || matches!(err.kind(), redis::ErrorKind::ReadOnly | redis::ErrorKind::MasterDown),
+            _ => false,
+        }
+    }
 }
 
 impl std::error::Error for ManualError {}
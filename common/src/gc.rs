@@ -0,0 +1,132 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use async_trait::async_trait;
+use redis::streams::{StreamInfoGroupsReply, StreamPendingReply, StreamTrimOptions, StreamTrimmingMode};
+use redis::AsyncCommands;
+use serde::Serialize;
+use crate::{Csr, GcConfig, JobStatus, Key, RedisConnection, Result};
+
+/// What a garbage collection run deleted, or would have deleted in a dry run.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct GcReport {
+    pub dry_run: bool,
+    /// The keys of the collected jobs, which are also dropped from the job list.
+    pub jobs: Vec<String>,
+    pub aliases: Vec<String>,
+
+    /// Every consumer group has acknowledged the stream entries before this ID.
+    pub stream_min_id: Option<String>,
+    /// `None` in a dry run.
+    pub stream_entries_trimmed: Option<usize>,
+}
+
+/// # Garbage Collector
+/// Deletes jobs which finished, went stale or failed longer than the retention period ago, along with their aliases.
+/// Issued certificates and revocations are never collected. The event stream is trimmed up to the oldest entry which a
+/// consumer group has yet to acknowledge.
+#[async_trait]
+pub trait GarbageCollector {
+    async fn collect_garbage(&mut self, gc: &GcConfig, dry_run: bool) -> Result<GcReport>;
+}
+
+#[async_trait]
+impl GarbageCollector for RedisConnection {
+    async fn collect_garbage(&mut self, gc: &GcConfig, dry_run: bool) -> Result<GcReport> {
+        let mut report = GcReport {
+            dry_run,
+            ..GcReport::default()
+        };
+
+        let cutoff = retention_cutoff(gc)?;
+
+        // Jobs only change status after they were submitted, so anything submitted since can't be due yet
+        let candidates: Vec<(String, f64)> = self.zrangebyscore_withscores(Key::JobList, f64::NEG_INFINITY, cutoff as f64)
+            .await?;
+
+        for (job, submitted) in candidates {
+            let csr: Option<Csr> = self.get(&job).await?;
+
+            match csr {
+                Some(csr) => {
+                    if !is_collectable(&csr, submitted, cutoff) {
+                        continue;
+                    }
+
+                    report.aliases.push(Key::Alias(&csr.client_alias).to_string());
+                    report.jobs.push(job);
+                },
+                // Listed, but already gone
+                None => report.jobs.push(job),
+            }
+        }
+
+        if !dry_run && !report.jobs.is_empty() {
+            let mut pipe = redis::pipe();
+            pipe.atomic()
+                .del(&report.jobs).ignore()
+                .zrem(Key::JobList, &report.jobs).ignore();
+
+            if !report.aliases.is_empty() {
+                pipe.del(&report.aliases).ignore();
+            }
+
+            let _: () = pipe.query_async(self).await?;
+        }
+
+        report.stream_min_id = acknowledged_until(self, Key::EventStream).await?;
+
+        if let Some(min_id) = &report.stream_min_id && !dry_run {
+            let trimmed: usize = self.xtrim_options(Key::EventStream, &StreamTrimOptions::minid(StreamTrimmingMode::Exact, min_id))
+                .await?;
+            report.stream_entries_trimmed = Some(trimmed);
+        }
+
+        Ok(report)
+    }
+}
+
+/// Jobs which last changed before this Unix timestamp are past the retention period.
+pub(crate) fn retention_cutoff(gc: &GcConfig) -> Result<i64> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .as_secs()
+        .saturating_sub(gc.retention_days * 86400) as i64)
+}
+
+/// Whether the job finished, went stale or failed before the cutoff.
+pub(crate) fn is_collectable(csr: &Csr, submitted: f64, cutoff: i64) -> bool {
+    matches!(csr.status, JobStatus::Finished | JobStatus::Stale | JobStatus::ChallengeFailed { .. } | JobStatus::SigningError { .. })
+        && csr.updated_at.unwrap_or(submitted as i64) <= cutoff
+}
+
+/// The oldest stream entry still pending in a consumer group, or otherwise the last one delivered to it, across all
+/// groups. `None` if there's no stream or no group reading it.
+async fn acknowledged_until(redis: &mut RedisConnection, stream: Key<'_>) -> Result<Option<String>> {
+    let exists: bool = redis.exists(stream).await?;
+    if !exists {
+        return Ok(None);
+    }
+
+    let groups: StreamInfoGroupsReply = redis.xinfo_groups(stream).await?;
+
+    let mut min_id: Option<(u64, u64)> = None;
+    for group in groups.groups {
+        let pending: StreamPendingReply = redis.xpending(stream, &group.name).await?;
+        let id = match pending {
+            StreamPendingReply::Data(data) => data.start_id,
+            StreamPendingReply::Empty => group.last_delivered_id,
+        };
+
+        let Some(id) = parse_stream_id(&id) else {
+            continue;
+        };
+
+        min_id = Some(min_id.map_or(id, |min_id| min_id.min(id)));
+    }
+
+    Ok(min_id.map(|(millis, seq)| format!("{millis}-{seq}")))
+}
+
+fn parse_stream_id(id: &str) -> Option<(u64, u64)> {
+    let (millis, seq) = id.split_once('-').unwrap_or((id, "0"));
+    Some((millis.parse().ok()?, seq.parse().ok()?))
+}
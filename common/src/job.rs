@@ -1,19 +1,40 @@
-use crate::{encode_base64, CertmasterEvent};
+use crate::{encode_base64, CertmasterEvent, ChallengeType, NameChallenge};
 use redis::FromRedisValue;
 use redis_derive::FromRedisValue;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub const NEW_CSR_EVENT_GROUP: &str = "new-csr";
 pub const CHALLENGE_EVENT_GROUP: &str = "challenge";
 pub const JOB_PROGRESS_EVENT_GROUP: &str = "job-progress";
 pub const FINISHED_EVENT_GROUP: &str = "finished";
+pub const REVOCATION_EVENT_GROUP: &str = "revocation";
+pub const CHALLENGE_REVIEW_EVENT_GROUP: &str = "challenge-review";
+pub const ACME_CHALLENGE_EVENT_GROUP: &str = "acme-challenge";
+pub const CHALLENGE_VALIDATION_EVENT_GROUP: &str = "challenge-validation";
 
 #[derive(Debug, Clone, FromRedisValue, Serialize, Deserialize)]
 pub struct NewCsr {
     /// The client ID is an ID generated by the client. When combined with a hash of the PEM string, it can be used to locate the job.
     pub client_id: u64,
     pub pem: PEMString,
+
+    /// The issuance profile to sign the certificate under. Falls back to the configured default profile.
+    #[serde(default)]
+    pub profile: Option<String>,
+
+    /// How the requester proves control over the requested names. Falls back to the configured default challenge type;
+    /// without one, the job waits for someone to pass the challenge by hand.
+    #[serde(default)]
+    pub challenge: Option<ChallengeType>,
+
+    /// Challenges which were already issued elsewhere, such as over ACME. Takes precedence over `challenge`, and has to
+    /// hold exactly one challenge for every name in the CSR. Never taken from requests to the web API.
+    #[serde(default)]
+    pub challenges: Vec<NameChallenge>,
 }
 
 impl CertmasterEvent for NewCsr {
@@ -59,6 +80,23 @@ impl CertmasterEvent for PendingChallenge {
     }
 }
 
+/// Checks the challenges of a job which haven't passed yet. Dispatched again for as long as dns-01 records are missing.
+#[derive(Debug, Clone, FromRedisValue, Serialize, Deserialize)]
+pub struct ChallengeValidation {
+    pub id: CsrId,
+    /// Unix timestamp in seconds, after which missing dns-01 records fail the challenge
+    pub deadline: i64,
+    /// Unix timestamp in seconds before which workers hold the event back
+    #[serde(default)]
+    pub not_before: i64,
+}
+
+impl CertmasterEvent for ChallengeValidation {
+    fn event_name() -> &'static str {
+        CHALLENGE_VALIDATION_EVENT_GROUP
+    }
+}
+
 #[derive(Debug, FromRedisValue, Serialize, Deserialize)]
 pub struct JobProgress {
     pub id: CsrId,
@@ -71,6 +109,56 @@ impl CertmasterEvent for JobProgress {
     }
 }
 
+/// A reviewer's decision on a job awaiting its challenge.
+#[derive(Debug, Clone, FromRedisValue, Serialize, Deserialize)]
+pub struct ChallengeReview {
+    pub id: CsrId,
+    pub decision: ReviewDecision,
+    pub reason: String,
+    pub reviewer: String,
+}
+
+impl CertmasterEvent for ChallengeReview {
+    fn event_name() -> &'static str {
+        CHALLENGE_REVIEW_EVENT_GROUP
+    }
+}
+
+/// An ACME client is ready for the challenge at `index` of the authorization to be validated.
+#[derive(Debug, Clone, FromRedisValue, Serialize, Deserialize)]
+pub struct AcmeChallengeReady {
+    pub authorization: String,
+    pub index: usize,
+    /// Unix timestamp in seconds, after which a missing dns-01 record fails the challenge
+    pub deadline: i64,
+    /// Unix timestamp in seconds before which workers hold the event back
+    #[serde(default)]
+    pub not_before: i64,
+}
+
+impl CertmasterEvent for AcmeChallengeReady {
+    fn event_name() -> &'static str {
+        ACME_CHALLENGE_EVENT_GROUP
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReviewDecision {
+    Approve,
+    Reject,
+}
+
+/// A decision as recorded against the job.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Review {
+    pub reviewer: String,
+    pub decision: ReviewDecision,
+    pub reason: String,
+    /// Unix timestamp in seconds
+    pub reviewed_at: i64,
+}
+
 #[derive(Debug, FromRedisValue, Serialize, Deserialize)]
 pub struct Completion {
     pub id: CsrId,
@@ -84,6 +172,98 @@ impl CertmasterEvent for Completion {
     }
 }
 
+#[derive(Debug, Clone, FromRedisValue, Serialize, Deserialize)]
+pub struct Revocation {
+    pub serial: CsrId,
+    pub reason: RevocationReason,
+    /// The Unix timestamp from which the key is known or suspected to have been compromised.
+    #[serde(default)]
+    pub invalidity_date: Option<i64>,
+    pub requested_by: String,
+}
+
+impl CertmasterEvent for Revocation {
+    fn event_name() -> &'static str {
+        REVOCATION_EVENT_GROUP
+    }
+}
+
+/// The reason codes defined in [RFC 5280 §5.3.1](https://www.rfc-editor.org/rfc/rfc5280#section-5.3.1).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RevocationReason {
+    Unspecified,
+    KeyCompromise,
+    CaCompromise,
+    AffiliationChanged,
+    Superseded,
+    CessationOfOperation,
+    CertificateHold,
+    RemoveFromCrl,
+    PrivilegeWithdrawn,
+    AaCompromise,
+}
+
+impl RevocationReason {
+    pub fn code(&self) -> u8 {
+        match self {
+            RevocationReason::Unspecified => 0,
+            RevocationReason::KeyCompromise => 1,
+            RevocationReason::CaCompromise => 2,
+            RevocationReason::AffiliationChanged => 3,
+            RevocationReason::Superseded => 4,
+            RevocationReason::CessationOfOperation => 5,
+            RevocationReason::CertificateHold => 6,
+            RevocationReason::RemoveFromCrl => 8,
+            RevocationReason::PrivilegeWithdrawn => 9,
+            RevocationReason::AaCompromise => 10,
+        }
+    }
+
+    /// `removeFromCRL` only takes a held certificate off a delta CRL, so it can't be the reason for a revocation.
+    pub fn can_revoke(&self) -> bool {
+        *self != RevocationReason::RemoveFromCrl
+    }
+}
+
+impl From<RevocationReason> for rcgen::RevocationReason {
+    fn from(value: RevocationReason) -> Self {
+        match value {
+            RevocationReason::Unspecified => rcgen::RevocationReason::Unspecified,
+            RevocationReason::KeyCompromise => rcgen::RevocationReason::KeyCompromise,
+            RevocationReason::CaCompromise => rcgen::RevocationReason::CaCompromise,
+            RevocationReason::AffiliationChanged => rcgen::RevocationReason::AffiliationChanged,
+            RevocationReason::Superseded => rcgen::RevocationReason::Superseded,
+            RevocationReason::CessationOfOperation => rcgen::RevocationReason::CessationOfOperation,
+            RevocationReason::CertificateHold => rcgen::RevocationReason::CertificateHold,
+            RevocationReason::RemoveFromCrl => rcgen::RevocationReason::RemoveFromCrl,
+            RevocationReason::PrivilegeWithdrawn => rcgen::RevocationReason::PrivilegeWithdrawn,
+            RevocationReason::AaCompromise => rcgen::RevocationReason::AaCompromise,
+        }
+    }
+}
+
+impl FromStr for RevocationReason {
+    type Err = String;
+
+    /// Accepts either the kebab-case name (`key-compromise`) or the numeric reason code.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "unspecified" | "0" => RevocationReason::Unspecified,
+            "key-compromise" | "1" => RevocationReason::KeyCompromise,
+            "ca-compromise" | "2" => RevocationReason::CaCompromise,
+            "affiliation-changed" | "3" => RevocationReason::AffiliationChanged,
+            "superseded" | "4" => RevocationReason::Superseded,
+            "cessation-of-operation" | "5" => RevocationReason::CessationOfOperation,
+            "certificate-hold" | "6" => RevocationReason::CertificateHold,
+            "remove-from-crl" | "8" => RevocationReason::RemoveFromCrl,
+            "privilege-withdrawn" | "9" => RevocationReason::PrivilegeWithdrawn,
+            "aa-compromise" | "10" => RevocationReason::AaCompromise,
+            reason => return Err(format!("'{reason}' is not a valid revocation reason")),
+        })
+    }
+}
+
 pub type PEMString = String;
 pub type CsrId = u64;
 
@@ -96,13 +276,51 @@ pub struct Csr {
     #[serde(rename = "alias")]
     pub client_alias: String,
 
+    #[serde(default)]
+    pub profile: Option<String>,
+
+    #[serde(default)]
+    pub challenge: Option<ChallengeType>,
+    /// One challenge per requested name, issued once the job reaches the challenge stage.
+    #[serde(default)]
+    pub challenges: Vec<NameChallenge>,
+    #[serde(default)]
+    pub reviews: Vec<Review>,
+
     pub status: JobStatus,
+    /// Unix timestamp in seconds of the last status change
+    #[serde(default)]
+    pub updated_at: Option<i64>,
 }
 
 impl Csr {
     pub fn pem(&self) -> &PEMString {
         &self.pem
     }
+
+    pub fn set_status(&mut self, status: JobStatus) {
+        self.status = status;
+        self.updated_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs() as i64)
+            .ok();
+    }
+
+    /// Everyone who approved the job so far.
+    pub fn approvers(&self) -> HashSet<&str> {
+        self.reviews
+            .iter()
+            .filter(|review| review.decision == ReviewDecision::Approve)
+            .map(|review| review.reviewer.as_str())
+            .collect()
+    }
+
+    /// Whether every challenge issued for the job has passed. Jobs without challenges rely on their reviews alone.
+    pub fn challenges_validated(&self) -> bool {
+        self.challenges
+            .iter()
+            .all(|challenge| challenge.validated_at.is_some())
+    }
 }
 
 impl From<NewCsr> for Csr {
@@ -114,7 +332,12 @@ impl From<NewCsr> for Csr {
             client_id: csr.client_id,
             client_alias: alt,
             pem: csr.pem,
-            status: JobStatus::Pending
+            profile: csr.profile,
+            challenge: csr.challenge,
+            challenges: csr.challenges,
+            reviews: vec![],
+            status: JobStatus::Pending,
+            updated_at: None
         }
     }
 }
@@ -125,7 +348,12 @@ impl From<PEMString> for Csr {
             client_id: 0,
             client_alias: encode_base64(format!("0;{value}")),
             pem: value,
-            status: JobStatus::Pending
+            profile: None,
+            challenge: None,
+            challenges: vec![],
+            reviews: vec![],
+            status: JobStatus::Pending,
+            updated_at: None
         }
     }
 }
@@ -144,4 +372,61 @@ pub enum JobStatus {
         reason: String,
     },
     Stale,
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn review(reviewer: &str, decision: ReviewDecision) -> Review {
+        Review {
+            reviewer: reviewer.to_owned(),
+            decision,
+            reason: "Checked the request".to_owned(),
+            reviewed_at: 0,
+        }
+    }
+
+    #[test]
+    fn approvers_are_distinct() {
+        let mut csr = Csr::from(String::new());
+        assert!(csr.approvers().is_empty());
+
+        csr.reviews = vec![
+            review("alice", ReviewDecision::Approve),
+            review("alice", ReviewDecision::Approve),
+            review("bob", ReviewDecision::Approve),
+        ];
+        assert_eq!(csr.approvers(), HashSet::from(["alice", "bob"]));
+    }
+
+    #[test]
+    fn rejections_are_not_approvals() {
+        let mut csr = Csr::from(String::new());
+        csr.reviews = vec![
+            review("alice", ReviewDecision::Approve),
+            review("bob", ReviewDecision::Approve),
+            review("carol", ReviewDecision::Reject),
+        ];
+
+        assert_eq!(csr.approvers(), HashSet::from(["alice", "bob"]));
+    }
+
+    #[test]
+    fn challenges_are_validated_once_all_have_passed() {
+        let mut csr = Csr::from(String::new());
+        assert!(csr.challenges_validated(), "Jobs without challenges rely on their reviews alone");
+
+        csr.challenges = vec![
+            NameChallenge::issue("app.test", ChallengeType::Http01).unwrap(),
+            NameChallenge::issue("www.app.test", ChallengeType::Http01).unwrap(),
+        ];
+        assert!(!csr.challenges_validated());
+
+        csr.challenges[0].validated_at = Some(1);
+        assert!(!csr.challenges_validated());
+
+        csr.challenges[1].validated_at = Some(2);
+        assert!(csr.challenges_validated());
+    }
+}
@@ -0,0 +1,123 @@
+//! # Key Schema
+//! The names of everything certmaster keeps in Redis. Every key and channel is prefixed with `redis.namespace`, so
+//! several CAs can share one Redis. In cluster mode, keys also share a hash tag, keeping the keys a transaction touches
+//! in one slot. Per-job tags wouldn't do, as jobs are written together with the job list and the event stream.
+
+use std::fmt::{self, Display, Formatter};
+use redis::{RedisWrite, ToRedisArgs};
+use crate::CsrId;
+
+/// A key in Redis. Can be passed to commands as is.
+#[derive(Debug, Copy, Clone)]
+pub enum Key<'a> {
+    /// The counter jobs take their serial from
+    JobCounter,
+    /// A job by serial
+    Job(CsrId),
+    /// The state of a job as shown to its requester, by alias
+    Alias(&'a str),
+    /// The keys of all jobs, scored by when they were submitted
+    JobList,
+    EventStream,
+    DeadLetters,
+
+    Certificate(CsrId),
+    /// The serials of the certificates issued by an issuer
+    CertificatesByIssuer(&'a str),
+    /// The serials of the certificates issued for a DNS name or IP address
+    CertificatesByName(&'a str),
+    /// The serials of all certificates, scored by when they expire
+    CertificateExpiry,
+
+    RevokedSerials,
+    CrlNumber,
+    /// The number of the CRL in `CrlDer` and `CrlPem`
+    CrlPublished,
+    CrlDer,
+    CrlPem,
+
+    AcmeNonce(&'a str),
+    AcmeAccount(&'a str),
+    /// The account registered under a key thumbprint
+    AcmeAccountByKey(&'a str),
+    AcmeAccountOrders(&'a str),
+    AcmeOrder(&'a str),
+    AcmeAuthorization(&'a str),
+}
+
+impl Display for Key<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let config = crate::get_config();
+
+        if let Some(tag) = config.redis.hash_tag() {
+            write!(f, "{{{tag}}}:")?;
+        }
+
+        if let Some(namespace) = &config.redis.namespace {
+            write!(f, "{namespace}:")?;
+        }
+
+        match self {
+            Self::JobCounter => f.write_str("csr_id"),
+            Self::Job(id) => write!(f, "csr:{id}"),
+            Self::Alias(alias) => write!(f, "alt:{alias}"),
+            Self::JobList => f.write_str(&config.redis.job_list_key),
+            Self::EventStream => f.write_str(&config.redis.task_stream_key),
+            Self::DeadLetters => f.write_str(&config.redis.dead_letter_stream_key),
+
+            Self::Certificate(serial) => write!(f, "cert:{serial}"),
+            Self::CertificatesByIssuer(issuer) => write!(f, "cert-issuer:{issuer}"),
+            Self::CertificatesByName(name) => write!(f, "cert-san:{name}"),
+            Self::CertificateExpiry => f.write_str("cert-expiry"),
+
+            Self::RevokedSerials => f.write_str("revoked-serials"),
+            Self::CrlNumber => f.write_str("crl-number"),
+            Self::CrlPublished => f.write_str("crl:number"),
+            Self::CrlDer => f.write_str("crl:der"),
+            Self::CrlPem => f.write_str("crl:pem"),
+
+            Self::AcmeNonce(nonce) => write!(f, "acme-nonce:{nonce}"),
+            Self::AcmeAccount(id) => write!(f, "acme-account:{id}"),
+            Self::AcmeAccountByKey(thumbprint) => write!(f, "acme-account-key:{thumbprint}"),
+            Self::AcmeAccountOrders(account) => write!(f, "acme-account-orders:{account}"),
+            Self::AcmeOrder(id) => write!(f, "acme-order:{id}"),
+            Self::AcmeAuthorization(id) => write!(f, "acme-authz:{id}"),
+        }
+    }
+}
+
+impl ToRedisArgs for Key<'_> {
+    fn write_redis_args<W: ?Sized + RedisWrite>(&self, out: &mut W) {
+        out.write_arg(self.to_string().as_bytes())
+    }
+}
+
+/// A pub/sub channel. Unlike keys, channels aren't bound to a database, so they carry its number.
+#[derive(Debug, Copy, Clone)]
+pub enum Channel {
+    /// Announces the alias of every job whose state changed
+    JobUpdates,
+}
+
+impl Display for Channel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let config = crate::get_config();
+
+        if let Some(namespace) = &config.redis.namespace {
+            write!(f, "{namespace}:")?;
+        }
+
+        match self {
+            Self::JobUpdates => {
+                let channel = config.redis.channel.as_deref().filter(|channel| !channel.is_empty()).unwrap_or("job-updates");
+                write!(f, "{channel}@{db}", db = config.redis.database())
+            },
+        }
+    }
+}
+
+impl ToRedisArgs for Channel {
+    fn write_redis_args<W: ?Sized + RedisWrite>(&self, out: &mut W) {
+        out.write_arg(self.to_string().as_bytes())
+    }
+}
@@ -1,4 +1,4 @@
-mod convert;
+mod connection;
 mod debounce;
 mod config;
 mod args;
@@ -8,21 +8,51 @@ mod job;
 mod redis_util;
 mod error;
 mod rune;
+mod policy;
+mod profile;
+mod store;
+mod revocation;
+mod authority;
+mod ocsp;
+mod acme;
+mod challenge;
+mod gc;
+mod dead_letter;
+mod keys;
+mod storage;
+mod sqlite;
 
 use std::sync::LazyLock;
 use base64::Engine;
 pub use config::*;
+pub use connection::*;
 pub use args::*;
 pub use resolve_homedir::*;
 pub use job::*;
 pub use debounce::*;
 pub use redis_util::*;
+pub use policy::*;
+pub use profile::*;
+pub use store::*;
+pub use revocation::*;
+pub use authority::*;
+pub use ocsp::*;
+pub use acme::*;
+pub use challenge::*;
+pub use gc::*;
+pub use dead_letter::*;
+pub use keys::*;
+pub use storage::*;
+pub use sqlite::*;
 
 pub use error::*;
 
 pub use blake3;
 
 static BASE64_ENGINE: LazyLock<base64::engine::GeneralPurpose> = LazyLock::new(|| base64::engine::GeneralPurpose::new(&base64::alphabet::STANDARD, Default::default()));
+static BASE64URL_ENGINE: LazyLock<base64::engine::GeneralPurpose> = LazyLock::new(|| base64::engine::GeneralPurpose::new(&base64::alphabet::URL_SAFE, base64::engine::GeneralPurposeConfig::new()
+    .with_encode_padding(false)
+    .with_decode_padding_mode(base64::engine::DecodePaddingMode::Indifferent)));
 
 pub fn encode_base64(bin: impl AsRef<[u8]>) -> String {
     BASE64_ENGINE.encode(bin.as_ref())
@@ -32,6 +62,15 @@ pub fn decode_base64(str: impl AsRef<str>) -> Result<Vec<u8>> {
     Ok(BASE64_ENGINE.decode(str.as_ref())?)
 }
 
+/// Unpadded base64url, as used throughout JOSE and ACME.
+pub fn encode_base64url(bin: impl AsRef<[u8]>) -> String {
+    BASE64URL_ENGINE.encode(bin.as_ref())
+}
+
+pub fn decode_base64url(str: impl AsRef<str>) -> Result<Vec<u8>> {
+    Ok(BASE64URL_ENGINE.decode(str.as_ref())?)
+}
+
 pub fn get_alt_name(client_id: u64, pem: &PEMString) -> String {
     encode_base64(blake3::hash(format!("{client_id};{pem}").as_bytes()).as_bytes())
 }
\ No newline at end of file
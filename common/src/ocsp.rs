@@ -0,0 +1,632 @@
+use std::sync::{Arc, RwLock};
+use rcgen::{KeyPair, PublicKeyData, SignatureAlgorithm, SigningKey};
+use ring::digest::{digest, Algorithm, SHA1_FOR_LEGACY_USE_ONLY, SHA256};
+use time::OffsetDateTime;
+use yasna::models::{GeneralizedTime, ObjectIdentifier};
+use x509_parser::certificate::X509Certificate;
+use yasna::{ASN1Result, BERReader, Tag};
+use crate::{Authority, CsrId, Error, OcspConfig, Result, RevocationReason};
+
+const OID_SHA1: &[u64] = &[1, 3, 14, 3, 2, 26];
+const OID_SHA256: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 1];
+const OID_OCSP_BASIC: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 48, 1, 1];
+const OID_OCSP_NONCE: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 48, 1, 2];
+
+/// The `responseStatus` of an OCSP response. Anything other than `Successful` is sent without a signed body.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OcspResponseStatus {
+    Successful = 0,
+    MalformedRequest = 1,
+    InternalError = 2,
+    TryLater = 3,
+    Unauthorized = 6,
+}
+
+impl OcspResponseStatus {
+    /// An unsigned response carrying only the status.
+    pub fn to_der(self) -> Vec<u8> {
+        yasna::construct_der(|w| w.write_sequence(|w| w.next().write_enum(self as i64)))
+    }
+}
+
+/// # CertID
+/// Identifies a certificate by the hashes of its issuer's name and key together with its serial number.
+#[derive(Debug, Clone)]
+pub struct CertId {
+    hash_algorithm: ObjectIdentifier,
+    issuer_name_hash: Vec<u8>,
+    issuer_key_hash: Vec<u8>,
+    /// The INTEGER's two's complement bytes, and whether it is negative
+    serial_number: Vec<u8>,
+    negative: bool,
+
+    /// The CertID as it was received, so it can be echoed back unchanged.
+    der: Vec<u8>,
+}
+
+impl CertId {
+    /// The serial number, if it fits into a `CsrId`. Anything negative or larger can't have been issued by us.
+    pub fn serial(&self) -> Option<CsrId> {
+        if self.negative {
+            return None;
+        }
+
+        let bytes = match self.serial_number.iter().position(|&i| i != 0) {
+            Some(start) => &self.serial_number[start..],
+            None => &[],
+        };
+
+        let mut serial = [0u8; 8];
+        serial.get_mut(8usize.checked_sub(bytes.len())?..)?.copy_from_slice(bytes);
+
+        Some(CsrId::from_be_bytes(serial))
+    }
+
+    fn read(reader: BERReader) -> ASN1Result<Self> {
+        let der = reader.read_der()?;
+
+        yasna::parse_ber(&der, |r| r.read_sequence(|r| {
+            let hash_algorithm = r.next().read_sequence(|r| {
+                let oid = r.next().read_oid()?;
+                r.read_optional(|r| r.read_null())?;
+                Ok(oid)
+            })?;
+
+            let issuer_name_hash = r.next().read_bytes()?;
+            let issuer_key_hash = r.next().read_bytes()?;
+            let (serial_number, non_negative) = r.next().read_bigint_bytes()?;
+
+            Ok(Self {
+                hash_algorithm,
+                issuer_name_hash,
+                issuer_key_hash,
+                serial_number,
+                negative: !non_negative,
+                der: der.clone(),
+            })
+        }))
+    }
+}
+
+/// # OCSP Request
+/// The parts of an [RFC 6960](https://www.rfc-editor.org/rfc/rfc6960) request the responder acts upon. Request
+/// signatures are accepted but not checked.
+#[derive(Debug, Clone)]
+pub struct OcspRequest {
+    pub certificates: Vec<CertId>,
+    pub nonce: Option<Vec<u8>>,
+}
+
+impl OcspRequest {
+    pub fn from_der(der: &[u8]) -> Result<Self> {
+        Ok(yasna::parse_ber(der, |r| r.read_sequence(|r| {
+            let request = r.next().read_sequence(|r| {
+                r.read_optional(|r| r.read_tagged(Tag::context(0), |r| r.read_u8()))?;
+                r.read_optional(|r| r.read_tagged(Tag::context(1), |r| r.read_der()))?;
+
+                let mut certificates = vec![];
+                r.next().read_sequence_of(|r| r.read_sequence(|r| {
+                    certificates.push(CertId::read(r.next())?);
+                    r.read_optional(|r| r.read_tagged(Tag::context(0), |r| r.read_der()))?;
+                    Ok(())
+                }))?;
+
+                let nonce = r.read_optional(|r| r.read_tagged(Tag::context(2), read_nonce))?;
+
+                Ok(Self {
+                    certificates,
+                    nonce: nonce.flatten(),
+                })
+            })?;
+
+            r.read_optional(|r| r.read_tagged(Tag::context(0), |r| r.read_der()))?;
+
+            Ok(request)
+        }))?)
+    }
+}
+
+fn read_nonce(reader: BERReader) -> ASN1Result<Option<Vec<u8>>> {
+    let mut nonce = None;
+
+    reader.read_sequence_of(|r| r.read_sequence(|r| {
+        let oid = r.next().read_oid()?;
+        r.read_optional(|r| r.read_bool())?;
+        let value = r.next().read_bytes()?;
+
+        if oid.components().as_slice() == OID_OCSP_NONCE {
+            nonce = Some(value);
+        }
+
+        Ok(())
+    }))?;
+
+    Ok(nonce)
+}
+
+#[derive(Debug, Clone)]
+pub enum CertStatus {
+    Good,
+    Revoked {
+        /// Unix timestamp in seconds
+        revocation_date: i64,
+        reason: RevocationReason,
+    },
+    Unknown,
+}
+
+#[derive(Debug, Clone)]
+pub struct SingleResponse {
+    pub cert_id: CertId,
+    pub status: CertStatus,
+    pub this_update: OffsetDateTime,
+    pub next_update: OffsetDateTime,
+}
+
+/// The responder loaded by [OcspResponder::current], along with the authority it was loaded for.
+static RESPONDER: RwLock<Option<Arc<OcspResponder>>> = RwLock::new(None);
+
+/// # OCSP Responder
+/// Signs responses either with the CA key itself or with a delegated responder certificate issued by the CA.
+pub struct OcspResponder {
+    authority: Arc<Authority>,
+    delegate: Option<(KeyPair, Vec<u8>)>,
+
+    issuer_name: Vec<u8>,
+}
+
+impl OcspResponder {
+    /// The responder for [Authority::current]. It's loaded on first use, and again once the authority has been reloaded,
+    /// which also happens when the delegated responder's files change.
+    pub async fn current(config: &OcspConfig) -> Result<Arc<Self>> {
+        let authority = Authority::current()?;
+
+        let loaded = RESPONDER.read().unwrap_or_else(|err| err.into_inner()).clone();
+        if let Some(responder) = loaded && Arc::ptr_eq(&responder.authority, &authority) {
+            return Ok(responder);
+        }
+
+        let responder = Arc::new(Self::load(authority, config).await?);
+        *RESPONDER.write().unwrap_or_else(|err| err.into_inner()) = Some(responder.clone());
+
+        Ok(responder)
+    }
+
+    /// Reads the delegated responder certificate and key, if there are any. Fails unless the certificate was issued by
+    /// the CA for OCSP signing, and the key belongs to it.
+    pub async fn load(authority: Arc<Authority>, config: &OcspConfig) -> Result<Self> {
+        let (_, issuer) = x509_parser::parse_x509_certificate(&authority.certificate)?;
+
+        let delegate = match (&config.certificate, &config.key) {
+            (Some(certificate), Some(key)) => {
+                let cert = tokio::fs::read_to_string(certificate).await?;
+                let key = KeyPair::from_pem(&tokio::fs::read_to_string(key).await?)?;
+
+                let (_, pem) = x509_parser::pem::parse_x509_pem(cert.as_bytes())?;
+                check_delegate(&pem.contents, &key, &issuer)?;
+
+                Some((key, pem.contents))
+            },
+            (None, None) => None,
+            _ => return Error::custom("The OCSP responder needs both a certificate and a key"),
+        };
+
+        let issuer_name = issuer.subject().as_raw().to_vec();
+
+        Ok(Self {
+            authority,
+            delegate,
+            issuer_name,
+        })
+    }
+
+    fn signing_key(&self) -> &KeyPair {
+        match &self.delegate {
+            Some((key, _)) => key,
+            None => self.authority.issuer.key(),
+        }
+    }
+
+    /// Whether the CertID names our CA as the issuer. Certificates of any other issuer are `unknown` to us.
+    pub fn is_issuer(&self, cert_id: &CertId) -> bool {
+        let hash: &'static Algorithm = match cert_id.hash_algorithm.components().as_slice() {
+            OID_SHA1 => &SHA1_FOR_LEGACY_USE_ONLY,
+            OID_SHA256 => &SHA256,
+            _ => return false,
+        };
+
+        digest(hash, &self.issuer_name).as_ref() == cert_id.issuer_name_hash
+            && digest(hash, self.authority.issuer.key().der_bytes()).as_ref() == cert_id.issuer_key_hash
+    }
+
+    /// Builds and signs a successful `OCSPResponse`, echoing the request's nonce if there was one.
+    pub fn sign(&self, responses: &[SingleResponse], nonce: Option<&[u8]>) -> Result<Vec<u8>> {
+        let key = self.signing_key();
+        let key_hash = digest(&SHA1_FOR_LEGACY_USE_ONLY, key.der_bytes());
+        let produced_at = OffsetDateTime::now_utc();
+
+        let tbs = yasna::construct_der(|w| w.write_sequence(|w| {
+            w.next().write_tagged(Tag::context(2), |w| w.write_bytes(key_hash.as_ref()));
+            w.next().write_generalized_time(&generalized_time(produced_at));
+            w.next().write_sequence_of(|w| for response in responses {
+                w.next().write_sequence(|w| {
+                    w.next().write_der(&response.cert_id.der);
+
+                    match &response.status {
+                        CertStatus::Good => w.next().write_tagged_implicit(Tag::context(0), |w| w.write_null()),
+                        CertStatus::Revoked { revocation_date, reason } => w.next().write_tagged_implicit(Tag::context(1), |w| w.write_sequence(|w| {
+                            let date = OffsetDateTime::from_unix_timestamp(*revocation_date).unwrap_or(produced_at);
+                            w.next().write_generalized_time(&generalized_time(date));
+                            w.next().write_tagged(Tag::context(0), |w| w.write_enum(reason.code() as i64));
+                        })),
+                        CertStatus::Unknown => w.next().write_tagged_implicit(Tag::context(2), |w| w.write_null()),
+                    }
+
+                    w.next().write_generalized_time(&generalized_time(response.this_update));
+                    w.next().write_tagged(Tag::context(0), |w| w.write_generalized_time(&generalized_time(response.next_update)));
+                })
+            });
+
+            if let Some(nonce) = nonce {
+                w.next().write_tagged(Tag::context(1), |w| w.write_sequence(|w| w.next().write_sequence(|w| {
+                    w.next().write_oid(&ObjectIdentifier::from_slice(OID_OCSP_NONCE));
+                    w.next().write_bytes(nonce);
+                })));
+            }
+        }));
+
+        let algorithm = signature_algorithm(key.algorithm())?;
+        let signature = key.sign(&tbs)?;
+
+        let basic = yasna::construct_der(|w| w.write_sequence(|w| {
+            w.next().write_der(&tbs);
+            w.next().write_der(&algorithm);
+            w.next().write_bitvec_bytes(&signature, signature.len() * 8);
+
+            if let Some((_, certificate)) = &self.delegate {
+                w.next().write_tagged(Tag::context(0), |w| w.write_sequence(|w| w.next().write_der(certificate)));
+            }
+        }));
+
+        Ok(yasna::construct_der(|w| w.write_sequence(|w| {
+            w.next().write_enum(OcspResponseStatus::Successful as i64);
+            w.next().write_tagged(Tag::context(0), |w| w.write_sequence(|w| {
+                w.next().write_oid(&ObjectIdentifier::from_slice(OID_OCSP_BASIC));
+                w.next().write_bytes(&basic);
+            }));
+        })))
+    }
+}
+
+/// Clients only accept responses signed by a delegate if the CA issued it for OCSP signing.
+fn check_delegate(der: &[u8], key: &KeyPair, issuer: &X509Certificate) -> Result<()> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der)?;
+
+    if cert.public_key().subject_public_key.data.as_ref() != key.der_bytes() {
+        return Error::custom("The OCSP responder key doesn't belong to its certificate");
+    }
+
+    if !cert.extended_key_usage()?.is_some_and(|usage| usage.value.ocsp_signing) {
+        return Error::custom("The OCSP responder certificate lacks the ocsp-signing extended key usage");
+    }
+
+    if cert.issuer() != issuer.subject() || cert.verify_signature(Some(issuer.public_key())).is_err() {
+        return Error::custom("The OCSP responder certificate wasn't issued by the CA");
+    }
+
+    Ok(())
+}
+
+/// OCSP times are encoded without fractional seconds.
+fn generalized_time(time: OffsetDateTime) -> GeneralizedTime {
+    GeneralizedTime::from_datetime(time.replace_nanosecond(0).unwrap_or(time))
+}
+
+/// The DER `AlgorithmIdentifier` of the signature produced by a key of the given algorithm.
+fn signature_algorithm(algorithm: &SignatureAlgorithm) -> Result<Vec<u8>> {
+    let (oid, null): (&[u64], bool) = match algorithm {
+        alg if alg == &rcgen::PKCS_ECDSA_P256_SHA256 => (&[1, 2, 840, 10045, 4, 3, 2], false),
+        alg if alg == &rcgen::PKCS_ECDSA_P384_SHA384 => (&[1, 2, 840, 10045, 4, 3, 3], false),
+        alg if alg == &rcgen::PKCS_ED25519 => (&[1, 3, 101, 112], false),
+        alg if alg == &rcgen::PKCS_RSA_SHA256 => (&[1, 2, 840, 113549, 1, 1, 11], true),
+        alg if alg == &rcgen::PKCS_RSA_SHA384 => (&[1, 2, 840, 113549, 1, 1, 12], true),
+        alg if alg == &rcgen::PKCS_RSA_SHA512 => (&[1, 2, 840, 113549, 1, 1, 13], true),
+        alg => return Error::custom(format!("OCSP responses can't be signed with {alg:?} keys")),
+    };
+
+    Ok(yasna::construct_der(|w| w.write_sequence(|w| {
+        w.next().write_oid(&ObjectIdentifier::from_slice(oid));
+        if null {
+            w.next().write_null();
+        }
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use rcgen::{CertificateParams, DnType, ExtendedKeyUsagePurpose};
+    use x509_parser::asn1_rs::{Any, Class, FromDer};
+    use x509_parser::prelude::AlgorithmIdentifier;
+    use super::*;
+    use crate::CaConfig;
+
+    /// Requests made with `openssl ocsp -issuer test/authority.crt -serial 42 -serial 43 -serial 44`, and for serial 42
+    /// with `-sha256 -no_nonce`, or with `-issuer test/service.crt`.
+    const REQUEST: &[u8] = include_bytes!("../../test/ocsp-request.der");
+    const REQUEST_SHA256: &[u8] = include_bytes!("../../test/ocsp-request-sha256.der");
+    const REQUEST_OTHER_ISSUER: &[u8] = include_bytes!("../../test/ocsp-request-other-issuer.der");
+
+    /// The value of the nonce extension in [REQUEST], which is an OCTET STRING itself.
+    const NONCE: &[u8] = &[0x04, 0x10, 0xA8, 0x06, 0xD1, 0x83, 0x72, 0xA2, 0x6D, 0x26, 0x6D, 0x50, 0xD8, 0xBB, 0x5C, 0x0C, 0xC1, 0xEF];
+
+    fn test_file(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("../test").join(name)
+    }
+
+    async fn authority() -> Arc<Authority> {
+        Arc::new(Authority::load(&CaConfig {
+            hooks: vec![],
+            certificate: test_file("authority.crt"),
+            key: test_file("authority.key"),
+            default_profile: None,
+        }).await.unwrap())
+    }
+
+    /// Responses are read back with x509-parser's DER parser, rather than the encoder under test.
+    fn elements(data: &[u8]) -> Vec<(&[u8], Any<'_>)> {
+        let mut elements = vec![];
+        let mut rest = data;
+
+        while !rest.is_empty() {
+            let (next, element) = Any::from_der(rest).unwrap();
+            elements.push((&rest[..rest.len() - next.len()], element));
+            rest = next;
+        }
+
+        elements
+    }
+
+    fn context(any: &Any, tag: u32) -> bool {
+        any.header.class() == Class::ContextSpecific && any.header.tag().0 == tag
+    }
+
+    /// A response decoded as far as the tests need it.
+    struct Decoded {
+        responses: Vec<DecodedSingle>,
+        nonce: Option<Vec<u8>>,
+        responder_key_hash: Vec<u8>,
+        certificates: Vec<Vec<u8>>,
+    }
+
+    struct DecodedSingle {
+        cert_id: Vec<u8>,
+        serial: Option<CsrId>,
+        /// The context tag of the `CertStatus` choice, and its contents
+        status: u32,
+        status_data: Vec<u8>,
+    }
+
+    /// Decodes a successful response, checking its signature against the given certificate's key.
+    fn decode(response: &[u8], signer: &[u8]) -> Decoded {
+        let (_, response) = Any::from_der(response).unwrap();
+        let response = elements(response.data);
+        assert_eq!(response[0].1.as_enumerated().unwrap().0, OcspResponseStatus::Successful as u32);
+        assert!(context(&response[1].1, 0));
+
+        let (_, bytes) = Any::from_der(response[1].1.data).unwrap();
+        let bytes = elements(bytes.data);
+        assert_eq!(bytes[0].1.as_oid().unwrap().to_id_string(), "1.3.6.1.5.5.7.48.1.1");
+
+        let basic = bytes[1].1.as_octetstring().unwrap();
+        let (_, basic) = Any::from_der(basic.as_ref()).unwrap();
+        let basic = elements(basic.data);
+
+        let (_, algorithm) = AlgorithmIdentifier::from_der(basic[1].0).unwrap();
+        let (_, signer) = x509_parser::parse_x509_certificate(signer).unwrap();
+        x509_parser::verify::verify_signature(signer.public_key(), &algorithm, &basic[2].1.as_bitstring().unwrap(), basic[0].0)
+            .expect("The response signature doesn't verify");
+
+        let certificates = basic.get(3).map(|(_, certs)| {
+            assert!(context(certs, 0));
+            let (_, certs) = Any::from_der(certs.data).unwrap();
+            elements(certs.data).into_iter().map(|(raw, _)| raw.to_vec()).collect()
+        }).unwrap_or_default();
+
+        let tbs = elements(basic[0].1.data);
+        assert!(context(&tbs[0].1, 2), "The responder is identified by key");
+        let responder_key_hash = Any::from_der(tbs[0].1.data).unwrap().1.as_octetstring().unwrap().as_ref().to_vec();
+        assert!(tbs[1].1.as_generalizedtime().is_ok());
+
+        let responses = elements(tbs[2].1.data).into_iter().map(|(_, single)| {
+            let single = elements(single.data);
+            let cert_id = single[0].0.to_vec();
+            let serial = elements(single[0].1.data)[3].1.as_u64().ok();
+            let status = &single[1].1;
+            assert_eq!(status.header.class(), Class::ContextSpecific);
+            assert!(single[2].1.as_generalizedtime().is_ok());
+            assert!(context(&single[3].1, 0), "Every response has a nextUpdate");
+
+            DecodedSingle {
+                cert_id,
+                serial,
+                status: status.header.tag().0,
+                status_data: status.data.to_vec(),
+            }
+        }).collect();
+
+        let nonce = tbs.get(3).map(|(_, extensions)| {
+            assert!(context(extensions, 1));
+            let (_, extensions) = Any::from_der(extensions.data).unwrap();
+            let extensions = elements(extensions.data);
+            assert_eq!(extensions.len(), 1);
+
+            let extension = elements(extensions[0].1.data);
+            assert_eq!(extension[0].1.as_oid().unwrap().to_id_string(), "1.3.6.1.5.5.7.48.1.2");
+            extension[1].1.as_octetstring().unwrap().as_ref().to_vec()
+        });
+
+        Decoded { responses, nonce, responder_key_hash, certificates }
+    }
+
+    fn single(cert_id: CertId, status: CertStatus) -> SingleResponse {
+        let this_update = OffsetDateTime::now_utc();
+
+        SingleResponse {
+            cert_id,
+            status,
+            this_update,
+            next_update: this_update + time::Duration::hours(1),
+        }
+    }
+
+    /// A request for a single certificate with the given serial number, issued by the test CA.
+    fn request_for(serial: impl FnOnce(yasna::DERWriter)) -> Vec<u8> {
+        let template = OcspRequest::from_der(REQUEST_SHA256).unwrap().certificates.remove(0);
+
+        yasna::construct_der(|w| w.write_sequence(|w| w.next().write_sequence(|w| {
+            w.next().write_sequence_of(|w| w.next().write_sequence(|w| w.next().write_sequence(|w| {
+                w.next().write_sequence(|w| w.next().write_oid(&template.hash_algorithm));
+                w.next().write_bytes(&template.issuer_name_hash);
+                w.next().write_bytes(&template.issuer_key_hash);
+                serial(w.next());
+            })));
+        })))
+    }
+
+    #[tokio::test]
+    async fn requests_made_by_openssl_are_read() {
+        let responder = OcspResponder::load(authority().await, &OcspConfig::default()).await.unwrap();
+
+        let request = OcspRequest::from_der(REQUEST).unwrap();
+        assert_eq!(request.certificates.iter().map(CertId::serial).collect::<Vec<_>>(), vec![Some(42), Some(43), Some(44)]);
+        assert!(request.certificates.iter().all(|cert_id| responder.is_issuer(cert_id)));
+        assert_eq!(request.nonce.as_deref(), Some(NONCE));
+
+        let request = OcspRequest::from_der(REQUEST_SHA256).unwrap();
+        assert_eq!(request.certificates[0].serial(), Some(42));
+        assert!(responder.is_issuer(&request.certificates[0]));
+        assert_eq!(request.nonce, None);
+
+        let request = OcspRequest::from_der(REQUEST_OTHER_ISSUER).unwrap();
+        assert_eq!(request.certificates[0].serial(), Some(42));
+        assert!(!responder.is_issuer(&request.certificates[0]));
+
+        assert!(OcspRequest::from_der(&REQUEST[..REQUEST.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn negative_and_oversized_serials_are_not_ours() {
+        let request = OcspRequest::from_der(&request_for(|w| w.write_i64(-42))).unwrap();
+        assert_eq!(request.certificates[0].serial(), None);
+
+        let request = OcspRequest::from_der(&request_for(|w| w.write_bigint_bytes(&[1, 0, 0, 0, 0, 0, 0, 0, 42], true))).unwrap();
+        assert_eq!(request.certificates[0].serial(), None);
+
+        let request = OcspRequest::from_der(&request_for(|w| w.write_u64(u64::MAX))).unwrap();
+        assert_eq!(request.certificates[0].serial(), Some(u64::MAX));
+    }
+
+    #[tokio::test]
+    async fn responses_carry_every_status_and_echo_the_nonce() {
+        let authority = authority().await;
+        let responder = OcspResponder::load(authority.clone(), &OcspConfig::default()).await.unwrap();
+
+        let request = OcspRequest::from_der(REQUEST).unwrap();
+        let mut cert_ids = request.certificates.clone().into_iter();
+        let revoked_at = OffsetDateTime::now_utc().unix_timestamp() - 60;
+
+        let response = responder.sign(&[
+            single(cert_ids.next().unwrap(), CertStatus::Good),
+            single(cert_ids.next().unwrap(), CertStatus::Revoked { revocation_date: revoked_at, reason: RevocationReason::KeyCompromise }),
+            single(cert_ids.next().unwrap(), CertStatus::Unknown),
+        ], request.nonce.as_deref()).unwrap();
+
+        let decoded = decode(&response, &authority.certificate);
+
+        assert_eq!(decoded.nonce.as_deref(), Some(NONCE));
+        assert!(decoded.certificates.is_empty(), "The CA needn't send its own certificate");
+        assert_eq!(decoded.responder_key_hash, digest(&SHA1_FOR_LEGACY_USE_ONLY, authority.issuer.key().der_bytes()).as_ref());
+
+        let cert_ids = request.certificates.iter().map(|cert_id| cert_id.der.clone()).collect::<Vec<_>>();
+        assert_eq!(decoded.responses.iter().map(|single| single.cert_id.clone()).collect::<Vec<_>>(), cert_ids);
+        assert_eq!(decoded.responses.iter().map(|single| single.serial).collect::<Vec<_>>(), vec![Some(42), Some(43), Some(44)]);
+        assert_eq!(decoded.responses.iter().map(|single| single.status).collect::<Vec<_>>(), vec![0, 1, 2]);
+
+        let revoked = elements(&decoded.responses[1].status_data);
+        assert_eq!(revoked[0].1.as_generalizedtime().unwrap().utc_datetime().unwrap().unix_timestamp(), revoked_at);
+        assert!(context(&revoked[1].1, 0));
+        assert_eq!(Any::from_der(revoked[1].1.data).unwrap().1.as_enumerated().unwrap().0, 1);
+    }
+
+    #[tokio::test]
+    async fn responses_to_requests_without_a_nonce_carry_none() {
+        let authority = authority().await;
+        let responder = OcspResponder::load(authority.clone(), &OcspConfig::default()).await.unwrap();
+
+        let request = OcspRequest::from_der(REQUEST_SHA256).unwrap();
+        let response = responder.sign(&[single(request.certificates[0].clone(), CertStatus::Good)], None).unwrap();
+
+        let decoded = decode(&response, &authority.certificate);
+        assert_eq!(decoded.nonce, None);
+        assert_eq!(decoded.responses[0].cert_id, request.certificates[0].der);
+    }
+
+    #[test]
+    fn failures_are_sent_without_a_body() {
+        assert_eq!(OcspResponseStatus::MalformedRequest.to_der(), vec![0x30, 0x03, 0x0A, 0x01, 0x01]);
+        assert_eq!(OcspResponseStatus::TryLater.to_der(), vec![0x30, 0x03, 0x0A, 0x01, 0x03]);
+    }
+
+    /// A responder certificate signed by `issuer`, or self-signed, with its key written next to it.
+    fn delegate(directory: &Path, issuer: Option<&Authority>, usages: Vec<ExtendedKeyUsagePurpose>) -> OcspConfig {
+        let key = KeyPair::generate().unwrap();
+
+        let mut params = CertificateParams::default();
+        params.distinguished_name.push(DnType::CommonName, "Certmaster OCSP Responder");
+        params.extended_key_usages = usages;
+
+        let cert = match issuer {
+            Some(authority) => params.signed_by(&key, &authority.issuer).unwrap(),
+            None => params.self_signed(&key).unwrap(),
+        };
+
+        std::fs::write(directory.join("ocsp.crt"), cert.pem()).unwrap();
+        std::fs::write(directory.join("ocsp.key"), key.serialize_pem()).unwrap();
+
+        OcspConfig {
+            certificate: Some(directory.join("ocsp.crt")),
+            key: Some(directory.join("ocsp.key")),
+            ..OcspConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn delegated_responders_have_to_be_issued_by_the_ca_for_ocsp_signing() {
+        let directory = std::env::temp_dir().join(format!("certmaster-ocsp-{pid}", pid = std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let authority = authority().await;
+
+        let config = delegate(&directory, Some(&authority), vec![ExtendedKeyUsagePurpose::OcspSigning]);
+        let responder = OcspResponder::load(authority.clone(), &config).await.unwrap();
+
+        let request = OcspRequest::from_der(REQUEST).unwrap();
+        let response = responder.sign(&[single(request.certificates[0].clone(), CertStatus::Good)], None).unwrap();
+
+        let certificate = x509_parser::pem::parse_x509_pem(std::fs::read(directory.join("ocsp.crt")).unwrap().as_slice()).unwrap().1.contents;
+        let decoded = decode(&response, &certificate);
+        assert_eq!(decoded.certificates, vec![certificate]);
+
+        let config = delegate(&directory, Some(&authority), vec![ExtendedKeyUsagePurpose::ServerAuth]);
+        assert!(OcspResponder::load(authority.clone(), &config).await.is_err(), "The responder lacks the OCSP signing usage");
+
+        let config = delegate(&directory, None, vec![ExtendedKeyUsagePurpose::OcspSigning]);
+        assert!(OcspResponder::load(authority.clone(), &config).await.is_err(), "The responder wasn't issued by the CA");
+
+        let config = delegate(&directory, Some(&authority), vec![ExtendedKeyUsagePurpose::OcspSigning]);
+        std::fs::write(directory.join("ocsp.key"), KeyPair::generate().unwrap().serialize_pem()).unwrap();
+        assert!(OcspResponder::load(authority.clone(), &config).await.is_err(), "The key belongs to another certificate");
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,529 @@
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+use rcgen::{CertificateSigningRequestParams, DnType, DnValue, PublicKeyData, SanType};
+use serde::{Deserialize, Serialize};
+use x509_parser::oid_registry::{OID_KEY_TYPE_EC_PUBLIC_KEY, OID_PKCS1_RSAENCRYPTION, OID_SIG_ED25519, OID_SIG_ED448};
+use x509_parser::prelude::FromDer;
+use x509_parser::x509::SubjectPublicKeyInfo;
+
+/// # Issuance Policy
+/// A set of rules every CSR has to satisfy before a challenge is dispatched for it. Rules which are left unset are not
+/// enforced, so an empty `[policy]` section accepts everything.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct PolicyConfig {
+    /// DNS names must either equal one of these suffixes or be a subdomain of one. The common name is checked like a DNS
+    /// name, or like an IP address if it is one.
+    ///
+    /// Once either this or `ip_ranges` is set, subject alternative names other than DNS names and IP addresses are
+    /// rejected, as no rule covers them.
+    pub dns_suffixes: Option<Vec<String>>,
+
+    #[serde(default)]
+    pub wildcards: WildcardPolicy,
+
+    /// IP addresses must fall into one of these ranges, written in CIDR notation (`10.0.0.0/8`).
+    pub ip_ranges: Option<Vec<IpRange>>,
+
+    pub max_sans: Option<usize>,
+
+    /// The key must match at least one of these rules.
+    pub keys: Option<Vec<KeyRule>>,
+
+    /// Subject attributes by short name (`CN`, `O`, `OU`, `C`, `ST`, `L`) or dotted OID.
+    #[serde(default)]
+    pub required_subject: Vec<SubjectAttribute>,
+    #[serde(default)]
+    pub forbidden_subject: Vec<SubjectAttribute>,
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WildcardPolicy {
+    /// Wildcards are accepted as the entire left-most label, such as `*.example.com`.
+    #[default]
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyRule {
+    pub algorithm: KeyAlgorithm,
+    pub min_bits: Option<usize>,
+    pub max_bits: Option<usize>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyAlgorithm {
+    Rsa,
+    Ec,
+    Ed25519,
+    Ed448,
+}
+
+impl Display for KeyAlgorithm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            KeyAlgorithm::Rsa => "RSA",
+            KeyAlgorithm::Ec => "EC",
+            KeyAlgorithm::Ed25519 => "Ed25519",
+            KeyAlgorithm::Ed448 => "Ed448",
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpRange {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => mask(u32::from(net) as u128, u32::from(*ip) as u128, 32, self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => mask(u128::from(net), u128::from(*ip), 128, self.prefix),
+            _ => false
+        }
+    }
+}
+
+fn mask(net: u128, ip: u128, width: u8, prefix: u8) -> bool {
+    let shift = (width - prefix) as u32;
+    net.checked_shr(shift).unwrap_or(0) == ip.checked_shr(shift).unwrap_or(0)
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (addr, prefix) = value.split_once('/').unwrap_or((&value, ""));
+        let addr = IpAddr::from_str(addr).map_err(|err| format!("Invalid IP range '{value}': {err}"))?;
+        let width = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            "" => width,
+            prefix => prefix.parse::<u8>().map_err(|err| format!("Invalid IP range '{value}': {err}"))?,
+        };
+
+        if prefix > width {
+            return Err(format!("Invalid IP range '{value}': prefix exceeds {width} bits"));
+        }
+
+        Ok(IpRange { addr, prefix })
+    }
+}
+
+impl From<IpRange> for String {
+    fn from(value: IpRange) -> Self {
+        format!("{addr}/{prefix}", addr = value.addr, prefix = value.prefix)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct SubjectAttribute(pub DnType);
+
+impl TryFrom<String> for SubjectAttribute {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Ok(SubjectAttribute(match value.to_ascii_uppercase().as_str() {
+            "CN" => DnType::CommonName,
+            "C" => DnType::CountryName,
+            "O" => DnType::OrganizationName,
+            "OU" => DnType::OrganizationalUnitName,
+            "L" => DnType::LocalityName,
+            "ST" => DnType::StateOrProvinceName,
+            oid => DnType::CustomDnType(oid
+                .split('.')
+                .map(|arc| arc.parse::<u64>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| format!("Unknown subject attribute '{value}'"))?),
+        }))
+    }
+}
+
+impl From<SubjectAttribute> for String {
+    fn from(value: SubjectAttribute) -> Self {
+        value.to_string()
+    }
+}
+
+impl Display for SubjectAttribute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.0 {
+            DnType::CommonName => f.write_str("CN"),
+            DnType::CountryName => f.write_str("C"),
+            DnType::OrganizationName => f.write_str("O"),
+            DnType::OrganizationalUnitName => f.write_str("OU"),
+            DnType::LocalityName => f.write_str("L"),
+            DnType::StateOrProvinceName => f.write_str("ST"),
+            DnType::CustomDnType(oid) => f.write_str(&oid
+                .iter()
+                .map(u64::to_string)
+                .collect::<Vec<_>>()
+                .join(".")),
+            other => write!(f, "{other:?}"),
+        }
+    }
+}
+
+impl PolicyConfig {
+    /// Checks the request against every rule and returns a description of each rule it breaks. An empty list means the
+    /// request is acceptable.
+    pub fn violations(&self, csr: &CertificateSigningRequestParams) -> Vec<String> {
+        let mut violations = vec![];
+        let params = &csr.params;
+
+        if let Some(max) = self.max_sans && params.subject_alt_names.len() > max {
+            violations.push(format!("{count} subject alternative names exceed the maximum of {max}", count = params.subject_alt_names.len()));
+        }
+
+        for san in params.subject_alt_names.iter() {
+            match san {
+                SanType::DnsName(name) => self.check_dns_name(name.as_str(), &mut violations),
+                SanType::IpAddress(ip) => self.check_ip_address(ip, &mut violations),
+                // Names of other kinds would go into the certificate unchecked once the policy restricts names at all
+                other => if self.restricts_names() {
+                    violations.push(format!("{} is not allowed as a subject alternative name", describe_san(other)));
+                },
+            }
+        }
+
+        // The common name is still read as a host name by some clients, so it has to pass the same rules as a SAN
+        if let Some(cn) = params.distinguished_name.get(&DnType::CommonName) {
+            match dn_string(cn) {
+                Some(cn) => match IpAddr::from_str(cn) {
+                    Ok(ip) => self.check_ip_address(&ip, &mut violations),
+                    Err(_) => self.check_dns_name(cn, &mut violations),
+                },
+                None => if self.restricts_names() {
+                    violations.push("The common name is not a readable string".to_owned());
+                },
+            }
+        }
+
+        if let Some(rules) = &self.keys {
+            match key_info(csr) {
+                Some((algorithm, bits)) => if !rules.iter().any(|rule| rule.algorithm == algorithm
+                    && rule.min_bits.is_none_or(|min| bits >= min)
+                    && rule.max_bits.is_none_or(|max| bits <= max)) {
+                    violations.push(format!("{bits}-bit {algorithm} keys are not allowed"));
+                },
+                None => violations.push("The key algorithm is not allowed".to_owned()),
+            }
+        }
+
+        for attr in self.required_subject.iter() {
+            if params.distinguished_name.get(&attr.0).is_none() {
+                violations.push(format!("Subject attribute {attr} is required"));
+            }
+        }
+
+        for attr in self.forbidden_subject.iter() {
+            if params.distinguished_name.get(&attr.0).is_some() {
+                violations.push(format!("Subject attribute {attr} is forbidden"));
+            }
+        }
+
+        violations
+    }
+
+    fn restricts_names(&self) -> bool {
+        self.dns_suffixes.is_some() || self.ip_ranges.is_some()
+    }
+
+    fn check_ip_address(&self, ip: &IpAddr, violations: &mut Vec<String>) {
+        if let Some(ranges) = &self.ip_ranges && !ranges.iter().any(|range| range.contains(ip)) {
+            violations.push(format!("IP address {ip} is outside the allowed ranges"));
+        }
+    }
+
+    fn check_dns_name(&self, name: &str, violations: &mut Vec<String>) {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+
+        let base = match name.strip_prefix("*.") {
+            Some(_) if self.wildcards == WildcardPolicy::Deny => {
+                violations.push(format!("Wildcard name {name} is not allowed"));
+                return;
+            }
+            Some(base) if base.contains('*') || !base.contains('.') => {
+                violations.push(format!("Wildcard name {name} is malformed"));
+                return;
+            },
+            Some(base) => base,
+            None if name.contains('*') => {
+                violations.push(format!("Wildcard name {name} is malformed"));
+                return;
+            },
+            None => name.as_str(),
+        };
+
+        if let Some(suffixes) = &self.dns_suffixes && !suffixes.iter().any(|suffix| {
+            let suffix = suffix.trim_start_matches('.').to_ascii_lowercase();
+            base == suffix || base.ends_with(&format!(".{suffix}"))
+        }) {
+            violations.push(format!("DNS name {name} is outside the allowed suffixes"));
+        }
+    }
+}
+
+fn describe_san(san: &SanType) -> String {
+    match san {
+        SanType::Rfc822Name(name) => format!("Email address {}", name.as_str()),
+        SanType::URI(uri) => format!("URI {}", uri.as_str()),
+        SanType::DnsName(name) => format!("DNS name {}", name.as_str()),
+        SanType::IpAddress(ip) => format!("IP address {ip}"),
+        other => format!("{other:?}"),
+    }
+}
+
+fn dn_string(value: &DnValue) -> Option<&str> {
+    match value {
+        DnValue::Utf8String(str) => Some(str.as_str()),
+        DnValue::PrintableString(str) => Some(str.as_str()),
+        DnValue::Ia5String(str) => Some(str.as_str()),
+        _ => None,
+    }
+}
+
+fn key_info(csr: &CertificateSigningRequestParams) -> Option<(KeyAlgorithm, usize)> {
+    let spki = csr.public_key.subject_public_key_info();
+    let (_, spki) = SubjectPublicKeyInfo::from_der(&spki).ok()?;
+
+    let algorithm = &spki.algorithm.algorithm;
+    if algorithm == &OID_PKCS1_RSAENCRYPTION {
+        Some((KeyAlgorithm::Rsa, spki.parsed().ok()?.key_size()))
+    } else if algorithm == &OID_KEY_TYPE_EC_PUBLIC_KEY {
+        Some((KeyAlgorithm::Ec, spki.parsed().ok()?.key_size()))
+    } else if algorithm == &OID_SIG_ED25519 {
+        Some((KeyAlgorithm::Ed25519, 256))
+    } else if algorithm == &OID_SIG_ED448 {
+        Some((KeyAlgorithm::Ed448, 456))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{CertificateParams, DistinguishedName, KeyPair, SignatureAlgorithm, PKCS_ECDSA_P256_SHA256, PKCS_ECDSA_P384_SHA384, PKCS_ED25519};
+    use super::*;
+
+    fn request(alg: &'static SignatureAlgorithm, cn: Option<&str>, names: Vec<SanType>) -> CertificateSigningRequestParams {
+        let key = KeyPair::generate_for(alg).unwrap();
+
+        let mut params = CertificateParams::default();
+        params.distinguished_name = DistinguishedName::new();
+        if let Some(cn) = cn {
+            params.distinguished_name.push(DnType::CommonName, cn);
+        }
+        params.subject_alt_names = names;
+
+        let csr = params.serialize_request(&key).unwrap();
+        CertificateSigningRequestParams::from_der(csr.der()).unwrap()
+    }
+
+    fn dns(name: &str) -> SanType {
+        SanType::DnsName(name.try_into().unwrap())
+    }
+
+    fn ip(addr: &str) -> SanType {
+        SanType::IpAddress(addr.parse().unwrap())
+    }
+
+    fn names(names: Vec<SanType>) -> CertificateSigningRequestParams {
+        request(&PKCS_ECDSA_P256_SHA256, None, names)
+    }
+
+    fn suffixes(suffixes: &[&str]) -> PolicyConfig {
+        PolicyConfig {
+            dns_suffixes: Some(suffixes.iter().map(|suffix| suffix.to_string()).collect()),
+            ..Default::default()
+        }
+    }
+
+    fn ranges(ranges: &[&str]) -> PolicyConfig {
+        PolicyConfig {
+            ip_ranges: Some(ranges.iter().map(|range| IpRange::try_from(range.to_string()).unwrap()).collect()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn an_empty_policy_accepts_everything() {
+        let csr = request(&PKCS_ED25519, Some("anything at all"), vec![
+            dns("*.example.org"),
+            ip("192.0.2.1"),
+            SanType::Rfc822Name("someone@example.org".try_into().unwrap()),
+        ]);
+
+        assert!(PolicyConfig::default().violations(&csr).is_empty());
+    }
+
+    #[test]
+    fn names_match_whole_labels_of_a_suffix() {
+        let policy = suffixes(&["example.com", ".internal"]);
+
+        for name in ["example.com", "www.example.com", "a.b.example.com", "WWW.Example.COM.", "host.internal"] {
+            assert!(policy.violations(&names(vec![dns(name)])).is_empty(), "{name} is rejected");
+        }
+
+        for name in ["evilexample.com", "example.com.evil.net", "example.org", "internal.net"] {
+            assert_eq!(policy.violations(&names(vec![dns(name)])).len(), 1, "{name} is accepted");
+        }
+    }
+
+    #[test]
+    fn every_name_is_checked() {
+        let policy = suffixes(&["example.com"]);
+        let violations = policy.violations(&names(vec![dns("www.example.com"), dns("evilexample.com"), dns("example.net")]));
+
+        assert_eq!(violations, vec![
+            "DNS name evilexample.com is outside the allowed suffixes",
+            "DNS name example.net is outside the allowed suffixes",
+        ]);
+    }
+
+    #[test]
+    fn wildcards_cover_only_the_left_most_label() {
+        let policy = suffixes(&["example.com"]);
+
+        assert!(policy.violations(&names(vec![dns("*.example.com")])).is_empty());
+        assert!(policy.violations(&names(vec![dns("*.www.example.com")])).is_empty());
+        assert_eq!(policy.violations(&names(vec![dns("*.evilexample.com")])).len(), 1);
+
+        for name in ["*.com", "www.*.example.com", "*.*.example.com", "w*.example.com"] {
+            assert_eq!(policy.violations(&names(vec![dns(name)])), vec![format!("Wildcard name {name} is malformed")]);
+        }
+
+        let policy = PolicyConfig { wildcards: WildcardPolicy::Deny, ..policy };
+        assert_eq!(policy.violations(&names(vec![dns("*.example.com")])), vec!["Wildcard name *.example.com is not allowed"]);
+        assert!(policy.violations(&names(vec![dns("www.example.com")])).is_empty());
+    }
+
+    #[test]
+    fn addresses_have_to_fall_into_a_range() {
+        let policy = ranges(&["10.0.0.0/8", "192.0.2.1", "2001:db8::/32"]);
+
+        for addr in ["10.0.0.1", "10.255.255.255", "192.0.2.1", "2001:db8::1", "2001:db8:ffff::"] {
+            assert!(policy.violations(&names(vec![ip(addr)])).is_empty(), "{addr} is rejected");
+        }
+
+        for addr in ["11.0.0.1", "192.0.2.2", "2001:db9::1", "::ffff:10.0.0.1", "::1"] {
+            assert_eq!(policy.violations(&names(vec![ip(addr)])).len(), 1, "{addr} is accepted");
+        }
+    }
+
+    #[test]
+    fn an_empty_prefix_covers_its_whole_family() {
+        let policy = ranges(&["0.0.0.0/0"]);
+        assert!(policy.violations(&names(vec![ip("203.0.113.7")])).is_empty());
+        assert_eq!(policy.violations(&names(vec![ip("2001:db8::1")])).len(), 1);
+
+        let policy = ranges(&["::/0"]);
+        assert!(policy.violations(&names(vec![ip("2001:db8::1")])).is_empty());
+        assert_eq!(policy.violations(&names(vec![ip("203.0.113.7")])).len(), 1);
+    }
+
+    #[test]
+    fn ranges_are_validated_when_parsed() {
+        for range in ["10.0.0.0/33", "::/129", "10.0.0.0/x", "example.com/8"] {
+            assert!(IpRange::try_from(range.to_string()).is_err(), "{range} is accepted");
+        }
+
+        assert_eq!(String::from(IpRange::try_from("10.0.0.1".to_string()).unwrap()), "10.0.0.1/32");
+        assert_eq!(String::from(IpRange::try_from("::1".to_string()).unwrap()), "::1/128");
+    }
+
+    #[test]
+    fn the_common_name_is_checked_like_a_subject_alternative_name() {
+        let policy = PolicyConfig {
+            ip_ranges: ranges(&["10.0.0.0/8"]).ip_ranges,
+            ..suffixes(&["example.com"])
+        };
+
+        assert!(policy.violations(&request(&PKCS_ECDSA_P256_SHA256, Some("www.example.com"), vec![])).is_empty());
+        assert!(policy.violations(&request(&PKCS_ECDSA_P256_SHA256, Some("10.1.2.3"), vec![])).is_empty());
+
+        assert_eq!(policy.violations(&request(&PKCS_ECDSA_P256_SHA256, Some("evilexample.com"), vec![dns("www.example.com")])),
+            vec!["DNS name evilexample.com is outside the allowed suffixes"]);
+        assert_eq!(policy.violations(&request(&PKCS_ECDSA_P256_SHA256, Some("11.1.2.3"), vec![])),
+            vec!["IP address 11.1.2.3 is outside the allowed ranges"]);
+        assert_eq!(policy.violations(&request(&PKCS_ECDSA_P256_SHA256, Some("Jane Doe"), vec![])).len(), 1);
+    }
+
+    #[test]
+    fn uncovered_name_types_are_rejected_once_names_are_restricted() {
+        let csr = names(vec![
+            dns("www.example.com"),
+            SanType::Rfc822Name("admin@example.com".try_into().unwrap()),
+            SanType::URI("https://example.com/".try_into().unwrap()),
+        ]);
+
+        assert!(PolicyConfig::default().violations(&csr).is_empty());
+        assert_eq!(suffixes(&["example.com"]).violations(&csr), vec![
+            "Email address admin@example.com is not allowed as a subject alternative name",
+            "URI https://example.com/ is not allowed as a subject alternative name",
+        ]);
+        assert_eq!(ranges(&["10.0.0.0/8"]).violations(&csr).len(), 2);
+    }
+
+    #[test]
+    fn the_number_of_names_is_limited() {
+        let policy = PolicyConfig { max_sans: Some(2), ..Default::default() };
+
+        assert!(policy.violations(&names(vec![dns("a.example.com"), dns("b.example.com")])).is_empty());
+        assert_eq!(policy.violations(&names(vec![dns("a.example.com"), dns("b.example.com"), dns("c.example.com")])),
+            vec!["3 subject alternative names exceed the maximum of 2"]);
+    }
+
+    #[test]
+    fn keys_have_to_match_a_rule() {
+        let policy = PolicyConfig {
+            keys: Some(vec![
+                KeyRule { algorithm: KeyAlgorithm::Ec, min_bits: Some(384), max_bits: None },
+                KeyRule { algorithm: KeyAlgorithm::Rsa, min_bits: Some(2048), max_bits: Some(4096) },
+            ]),
+            ..Default::default()
+        };
+
+        assert!(policy.violations(&request(&PKCS_ECDSA_P384_SHA384, None, vec![])).is_empty());
+        assert_eq!(policy.violations(&request(&PKCS_ECDSA_P256_SHA256, None, vec![])), vec!["256-bit EC keys are not allowed"]);
+        assert_eq!(policy.violations(&request(&PKCS_ED25519, None, vec![])), vec!["256-bit Ed25519 keys are not allowed"]);
+
+        let policy = PolicyConfig {
+            keys: Some(vec![KeyRule { algorithm: KeyAlgorithm::Ec, min_bits: None, max_bits: Some(256) }]),
+            ..Default::default()
+        };
+
+        assert!(policy.violations(&request(&PKCS_ECDSA_P256_SHA256, None, vec![])).is_empty());
+        assert_eq!(policy.violations(&request(&PKCS_ECDSA_P384_SHA384, None, vec![])), vec!["384-bit EC keys are not allowed"]);
+    }
+
+    #[test]
+    fn subject_attributes_can_be_required_and_forbidden() {
+        let policy = PolicyConfig {
+            required_subject: vec![SubjectAttribute::try_from("cn".to_string()).unwrap()],
+            forbidden_subject: vec![SubjectAttribute::try_from("O".to_string()).unwrap()],
+            ..Default::default()
+        };
+
+        assert!(policy.violations(&request(&PKCS_ECDSA_P256_SHA256, Some("www.example.com"), vec![])).is_empty());
+        assert_eq!(policy.violations(&request(&PKCS_ECDSA_P256_SHA256, None, vec![])), vec!["Subject attribute CN is required"]);
+
+        let mut csr = request(&PKCS_ECDSA_P256_SHA256, Some("www.example.com"), vec![]);
+        csr.params.distinguished_name.push(DnType::OrganizationName, "Example");
+        assert_eq!(policy.violations(&csr), vec!["Subject attribute O is forbidden"]);
+    }
+
+    #[test]
+    fn subject_attributes_parse_from_names_and_oids() {
+        assert_eq!(SubjectAttribute::try_from("2.5.4.97".to_string()).unwrap().to_string(), "2.5.4.97");
+        assert_eq!(SubjectAttribute::try_from("st".to_string()).unwrap(), SubjectAttribute(DnType::StateOrProvinceName));
+        assert!(SubjectAttribute::try_from("email".to_string()).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use std::borrow::Cow;
+use rcgen::{BasicConstraints, CertificateParams, CrlDistributionPoint, CustomExtension, ExtendedKeyUsagePurpose, IsCa, KeyUsagePurpose};
+use serde::{Deserialize, Serialize};
+use time::{Duration, OffsetDateTime};
+use yasna::models::ObjectIdentifier;
+use yasna::Tag;
+use crate::{Config, Error, Result};
+
+const OID_AUTHORITY_INFO_ACCESS: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 1, 1];
+const OID_ACCESS_METHOD_OCSP: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 48, 1];
+
+/// # Issuance Profile
+/// Controls the validity period and the usage extensions of the certificates issued under it, regardless of what the
+/// CSR asks for.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    /// How far to move `notBefore` into the past to tolerate clock skew between the CA and relying parties.
+    #[serde(default)]
+    pub backdate_secs: u64,
+
+    /// The lifetime is capped at the issuer's own `notAfter`.
+    #[serde(default = "lifetime_days_default")]
+    pub lifetime_days: u64,
+
+    #[serde(default)]
+    pub key_usage: Vec<KeyUsage>,
+
+    #[serde(default)]
+    pub extended_key_usage: Vec<ExtendedKeyUsage>,
+
+    /// Marks issued certificates as CA certificates. Otherwise `CA:FALSE` is set explicitly.
+    #[serde(default)]
+    pub ca: bool,
+    pub path_length: Option<u8>,
+
+    /// Whether the key usages requested in the CSR are kept in addition to the profile's. If not, they are dropped.
+    #[serde(default)]
+    pub copy_extensions: bool,
+
+    /// URLs under which relying parties can fetch the CRL, such as `https://ca.example.com/crl`.
+    #[serde(default)]
+    pub crl_distribution_points: Vec<String>,
+
+    /// OCSP responder URLs written into the Authority Information Access extension, such as
+    /// `https://ca.example.com/ocsp`.
+    #[serde(default)]
+    pub ocsp_urls: Vec<String>,
+
+    /// How many distinct reviewers have to approve a job before it is signed. Jobs with challenges are validated as
+    /// usual, and signed once both their challenges have passed and their approvals are in, in whichever order.
+    #[serde(default)]
+    pub approvals: usize,
+}
+
+#[inline]
+fn lifetime_days_default() -> u64 { 365 }
+
+/// The built-in profile used when neither the request nor the config name one. It keeps whatever the CSR requested.
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            backdate_secs: 0,
+            lifetime_days: lifetime_days_default(),
+            key_usage: vec![],
+            extended_key_usage: vec![],
+            ca: false,
+            path_length: None,
+            copy_extensions: true,
+            crl_distribution_points: vec![],
+            ocsp_urls: vec![],
+            approvals: 0,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyUsage {
+    DigitalSignature,
+    ContentCommitment,
+    KeyEncipherment,
+    DataEncipherment,
+    KeyAgreement,
+    KeyCertSign,
+    CrlSign,
+    EncipherOnly,
+    DecipherOnly,
+}
+
+impl From<KeyUsage> for KeyUsagePurpose {
+    fn from(value: KeyUsage) -> Self {
+        match value {
+            KeyUsage::DigitalSignature => KeyUsagePurpose::DigitalSignature,
+            KeyUsage::ContentCommitment => KeyUsagePurpose::ContentCommitment,
+            KeyUsage::KeyEncipherment => KeyUsagePurpose::KeyEncipherment,
+            KeyUsage::DataEncipherment => KeyUsagePurpose::DataEncipherment,
+            KeyUsage::KeyAgreement => KeyUsagePurpose::KeyAgreement,
+            KeyUsage::KeyCertSign => KeyUsagePurpose::KeyCertSign,
+            KeyUsage::CrlSign => KeyUsagePurpose::CrlSign,
+            KeyUsage::EncipherOnly => KeyUsagePurpose::EncipherOnly,
+            KeyUsage::DecipherOnly => KeyUsagePurpose::DecipherOnly,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExtendedKeyUsage {
+    Any,
+    ServerAuth,
+    ClientAuth,
+    CodeSigning,
+    EmailProtection,
+    TimeStamping,
+    OcspSigning,
+}
+
+impl From<ExtendedKeyUsage> for ExtendedKeyUsagePurpose {
+    fn from(value: ExtendedKeyUsage) -> Self {
+        match value {
+            ExtendedKeyUsage::Any => ExtendedKeyUsagePurpose::Any,
+            ExtendedKeyUsage::ServerAuth => ExtendedKeyUsagePurpose::ServerAuth,
+            ExtendedKeyUsage::ClientAuth => ExtendedKeyUsagePurpose::ClientAuth,
+            ExtendedKeyUsage::CodeSigning => ExtendedKeyUsagePurpose::CodeSigning,
+            ExtendedKeyUsage::EmailProtection => ExtendedKeyUsagePurpose::EmailProtection,
+            ExtendedKeyUsage::TimeStamping => ExtendedKeyUsagePurpose::TimeStamping,
+            ExtendedKeyUsage::OcspSigning => ExtendedKeyUsagePurpose::OcspSigning,
+        }
+    }
+}
+
+impl Profile {
+    /// Overwrites the validity period and the usage extensions of `params`. `issuer_not_after` is the end of the
+    /// issuer's own validity period, which the certificate may not outlive.
+    pub fn apply(&self, params: &mut CertificateParams, issuer_not_after: OffsetDateTime) {
+        let now = OffsetDateTime::now_utc();
+
+        params.not_before = now - Duration::seconds(self.backdate_secs as i64);
+        params.not_after = (now + Duration::days(self.lifetime_days as i64)).min(issuer_not_after);
+
+        if !self.copy_extensions {
+            params.key_usages.clear();
+            params.extended_key_usages.clear();
+        }
+
+        for usage in self.key_usage.iter().map(|&i| KeyUsagePurpose::from(i)) {
+            if !params.key_usages.contains(&usage) {
+                params.key_usages.push(usage);
+            }
+        }
+
+        for usage in self.extended_key_usage.iter().map(|&i| ExtendedKeyUsagePurpose::from(i)) {
+            if !params.extended_key_usages.contains(&usage) {
+                params.extended_key_usages.push(usage);
+            }
+        }
+
+        params.crl_distribution_points = match self.crl_distribution_points.is_empty() {
+            true => vec![],
+            false => vec![CrlDistributionPoint {
+                uris: self.crl_distribution_points.clone(),
+            }],
+        };
+
+        params.custom_extensions.retain(|ext| !ext.oid_components().eq(OID_AUTHORITY_INFO_ACCESS.iter().copied()));
+        if !self.ocsp_urls.is_empty() {
+            params.custom_extensions.push(CustomExtension::from_oid_content(OID_AUTHORITY_INFO_ACCESS, self.authority_info_access()));
+        }
+
+        params.is_ca = match (self.ca, self.path_length) {
+            (true, Some(len)) => IsCa::Ca(BasicConstraints::Constrained(len)),
+            (true, None) => IsCa::Ca(BasicConstraints::Unconstrained),
+            (false, _) => IsCa::ExplicitNoCa,
+        };
+    }
+
+    /// The DER content of the Authority Information Access extension listing every OCSP URL.
+    fn authority_info_access(&self) -> Vec<u8> {
+        yasna::construct_der(|w| w.write_sequence_of(|w| for url in &self.ocsp_urls {
+            w.next().write_sequence(|w| {
+                w.next().write_oid(&ObjectIdentifier::from_slice(OID_ACCESS_METHOD_OCSP));
+                w.next().write_tagged_implicit(Tag::context(6), |w| w.write_ia5_string(url));
+            })
+        }))
+    }
+}
+
+impl Config {
+    /// Looks up a profile by name, falling back to `ca.default_profile` and then to the built-in default profile.
+    pub fn profile(&self, name: Option<&str>) -> Result<Cow<'_, Profile>> {
+        match name.or(self.ca.default_profile.as_deref()) {
+            Some(name) => self.profiles
+                .get(name)
+                .map(Cow::Borrowed)
+                .ok_or_else(|| Error::other(format!("Unknown issuance profile '{name}'"))),
+            None => Ok(Cow::Owned(Profile::default())),
+        }
+    }
+}
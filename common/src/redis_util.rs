@@ -1,40 +1,141 @@
-use crate::ClientJob;
+use crate::{CertmasterEvent, ClientJob, Csr, CsrId, EventBus, EventEntry, JobStore, NEW_CSR_EVENT_GROUP};
+use crate::Channel;
+use crate::Key;
 use crate::Result;
 use async_trait::async_trait;
-use redis::aio::MultiplexedConnection;
-use redis::AsyncCommands;
+use futures_util::stream::{BoxStream, StreamExt};
+use redis::aio::{ConnectionLike, MultiplexedConnection};
+use redis::cluster_async::ClusterConnection;
+use redis::streams::{StreamAutoClaimOptions, StreamAutoClaimReply, StreamId, StreamPendingCountReply, StreamReadOptions, StreamReadReply};
+use redis::{AsyncCommands, Cmd, Pipeline, RedisFuture, Value};
 use redis::FromRedisValue;
-use serde::de::DeserializeOwned;
-use serde::Serialize;
-use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[async_trait]
-pub trait RedisUtils {
-    async fn dispatch_event<Event: CertmasterEvent + Send>(&mut self, event: Event) -> io::Result<()>;
-    async fn get_jobs_by_alias<T: AsRef<str>>(&mut self, alias: impl Iterator<Item=T> + Send) -> Result<Vec<ClientJob>>;
+/// How many idle entries are claimed at once.
+const CLAIM_BATCH_SIZE: usize = 100;
+
+/// A connection to a single Redis server, whether configured directly or discovered through Sentinel, or to a Redis
+/// Cluster, which routes commands to the node serving their keys by itself.
+#[derive(Clone)]
+pub enum RedisConnection {
+    Single(MultiplexedConnection),
+    Cluster(ClusterConnection),
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            Self::Single(connection) => connection.req_packed_command(cmd),
+            Self::Cluster(connection) => connection.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(&'a mut self, cmd: &'a Pipeline, offset: usize, count: usize) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            Self::Single(connection) => connection.req_packed_commands(cmd, offset, count),
+            Self::Cluster(connection) => connection.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Self::Single(connection) => connection.get_db(),
+            Self::Cluster(connection) => connection.get_db(),
+        }
+    }
+}
+
+impl From<StreamId> for EventEntry {
+    fn from(entry: StreamId) -> Self {
+        Self {
+            fields: entry.map
+                .iter()
+                .map(|(key, value)| (key.clone(), String::from_redis_value(value).unwrap_or_else(|_| format!("{value:?}"))))
+                .collect(),
+            id: entry.id,
+        }
+    }
 }
-pub trait CertmasterEvent: Serialize + DeserializeOwned + FromRedisValue {
-    fn event_name() -> &'static str;
+
+/// The serial of a job from its key in the job list, which is the last segment whatever the namespace.
+fn job_id(key: &str) -> Option<CsrId> {
+    key.rsplit(':').next()?.parse().ok()
 }
 
 #[async_trait]
-impl RedisUtils for MultiplexedConnection {
-    async fn dispatch_event<Event: CertmasterEvent + Send>(&mut self, event: Event) -> io::Result<()> {
-        let config = crate::get_config();
+impl JobStore for RedisConnection {
+    async fn next_job_id(&mut self) -> Result<CsrId> {
+        Ok(self.incr(Key::JobCounter, 1).await?)
+    }
+
+    async fn get_job(&mut self, id: CsrId) -> Result<Option<Csr>> {
+        Ok(self.get(Key::Job(id)).await?)
+    }
 
-        let payload = ron::to_string(&event)
-            .map_err(io::Error::other)?;
+    async fn get_jobs(&mut self, ids: &[CsrId]) -> Result<Vec<Csr>> {
+        if ids.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let jobs: Vec<Option<Csr>> = self.mget(ids.iter().map(|id| Key::Job(*id)).collect::<Vec<_>>())
+            .await?;
+
+        Ok(jobs.into_iter().flatten().collect())
+    }
 
-        let _: () = self.xadd(&config.redis.task_stream_key, "*", &[(Event::event_name(), payload)])
-            .await
-            .map_err(io::Error::other)?;
+    async fn set_job(&mut self, id: CsrId, job: &Csr) -> Result<()> {
+        Ok(self.set(Key::Job(id), ron::to_string(job)?).await?)
+    }
+
+    async fn create_job(&mut self, id: CsrId, job: &Csr) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)?
+            .as_secs_f64();
+
+        let _: () = redis::pipe()
+            .atomic()
+            .set(Key::Job(id), ron::to_string(job)?)
+            .zadd(Key::JobList, Key::Job(id), timestamp)
+            .query_async(self)
+            .await?;
 
         Ok(())
     }
 
-    async fn get_jobs_by_alias<T: AsRef<str>>(&mut self, alias: impl Iterator<Item=T> + Send) -> Result<Vec<ClientJob>> {
-        let jobs = alias
-            .map(|i| format!("alt:{str}", str=i.as_ref()))
+    async fn list_jobs(&mut self, offset: usize, count: usize) -> Result<Vec<Csr>> {
+        if count == 0 {
+            return Ok(vec![]);
+        }
+
+        let keys: Vec<String> = self.zrevrange(Key::JobList, offset as isize, (offset + count - 1) as isize)
+            .await?;
+
+        self.get_jobs(&keys.iter().filter_map(|key| job_id(key)).collect::<Vec<_>>()).await
+    }
+
+    async fn jobs_submitted_before(&mut self, timestamp: f64) -> Result<Vec<(CsrId, f64)>> {
+        let jobs: Vec<(String, f64)> = self.zrangebyscore_withscores(Key::JobList, f64::NEG_INFINITY, timestamp)
+            .await?;
+
+        Ok(jobs
+            .into_iter()
+            .filter_map(|(key, submitted)| match job_id(&key) {
+                Some(id) => Some((id, submitted)),
+                None => {
+                    log::warn!("Unexpected key {key} in the job list");
+                    None
+                }
+            })
+            .collect())
+    }
+
+    async fn get_client_job(&mut self, alias: &str) -> Result<Option<ClientJob>> {
+        Ok(self.get(Key::Alias(alias)).await?)
+    }
+
+    async fn get_client_jobs<T: AsRef<str>>(&mut self, aliases: impl Iterator<Item=T> + Send) -> Result<Vec<ClientJob>> {
+        let jobs = aliases
+            .map(|i| Key::Alias(i.as_ref()).to_string())
             .collect::<Vec<_>>();
 
         if jobs.is_empty() {
@@ -43,4 +144,107 @@ impl RedisUtils for MultiplexedConnection {
 
         Ok(self.mget(jobs).await?)
     }
-}
\ No newline at end of file
+
+    async fn set_client_job(&mut self, job: &ClientJob) -> Result<()> {
+        let _: () = self.set(Key::Alias(&job.alias), ron::to_string(job)?).await?;
+        let _: () = self.publish(Channel::JobUpdates, &job.alias).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl EventBus for RedisConnection {
+    async fn dispatch_event<Event: CertmasterEvent + Send>(&mut self, event: Event) -> Result<()> {
+        let payload = ron::to_string(&event)?;
+
+        let _: () = self.xadd(Key::EventStream, "*", &[(Event::event_name(), payload)])
+            .await?;
+
+        Ok(())
+    }
+
+    async fn create_consumer_group(&mut self) -> Result<()> {
+        match self.xgroup_create_mkstream::<_, _, _, ()>(Key::EventStream, NEW_CSR_EVENT_GROUP, "0").await {
+            Err(err) if err.code() != Some("BUSYGROUP") => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn read_events(&mut self, consumer: &str, count: usize, block: Duration) -> Result<Vec<EventEntry>> {
+        let options = StreamReadOptions::default()
+            .block(block.as_millis() as usize)
+            .count(count.max(1))
+            .group(NEW_CSR_EVENT_GROUP, consumer);
+
+        let reply: StreamReadReply = self.xread_options(&[Key::EventStream], &[">"], &options)
+            .await?;
+
+        Ok(reply.keys.into_iter().flat_map(|k| k.ids).map(EventEntry::from).collect())
+    }
+
+    async fn read_pending_events(&mut self, consumer: &str) -> Result<Vec<EventEntry>> {
+        let reply: StreamReadReply = self
+            .xread_options(&[Key::EventStream], &["0"], &StreamReadOptions::default().group(NEW_CSR_EVENT_GROUP, consumer))
+            .await?;
+
+        Ok(reply.keys.into_iter().flat_map(|k| k.ids).map(EventEntry::from).collect())
+    }
+
+    async fn claim_idle_events(&mut self, consumer: &str, min_idle: Duration) -> Result<Vec<EventEntry>> {
+        let mut claimed = vec![];
+        let mut start = "0-0".to_owned();
+
+        loop {
+            let reply: StreamAutoClaimReply = self
+                .xautoclaim_options(Key::EventStream, NEW_CSR_EVENT_GROUP, consumer, min_idle.as_millis() as u64, &start, StreamAutoClaimOptions::default().count(CLAIM_BATCH_SIZE))
+                .await?;
+
+            if !reply.deleted_ids.is_empty() {
+                log::warn!("Pending events {ids:?} are gone from the stream", ids = reply.deleted_ids);
+            }
+
+            claimed.extend(reply.claimed.into_iter().map(EventEntry::from));
+
+            if reply.next_stream_id == "0-0" {
+                break;
+            }
+
+            start = reply.next_stream_id;
+        }
+
+        Ok(claimed)
+    }
+
+    async fn delivery_count(&mut self, id: &str) -> Result<usize> {
+        let pending: StreamPendingCountReply = self
+            .xpending_count(Key::EventStream, NEW_CSR_EVENT_GROUP, id, id, 1)
+            .await?;
+
+        Ok(pending.ids
+            .first()
+            .map(|pending| pending.times_delivered)
+            .unwrap_or_default())
+    }
+
+    async fn acknowledge_event(&mut self, id: &str) -> Result<()> {
+        Ok(self.xack(Key::EventStream, NEW_CSR_EVENT_GROUP, &[id]).await?)
+    }
+
+    /// Subscribing takes a connection of its own.
+    async fn job_updates(&mut self) -> Result<BoxStream<'static, String>> {
+        let mut pubsub = crate::get_config()
+            .redis
+            .client()
+            .await?
+            .get_async_pubsub()
+            .await?;
+
+        pubsub.subscribe(Channel::JobUpdates).await?;
+
+        Ok(pubsub
+            .into_on_message()
+            .filter_map(|message| async move { message.get_payload::<String>().ok() })
+            .boxed())
+    }
+}
@@ -0,0 +1,89 @@
+use async_trait::async_trait;
+use redis::{AsyncCommands, FromRedisValue};
+use redis_derive::FromRedisValue;
+use serde::{Deserialize, Serialize};
+use crate::{CsrId, Key, RedisConnection, RevocationReason, Result};
+
+/// An entry in the revocation registry.
+#[derive(Debug, Clone, FromRedisValue, Serialize, Deserialize)]
+pub struct RevokedCertificate {
+    pub serial: CsrId,
+    pub reason: RevocationReason,
+    /// Unix timestamps in seconds
+    pub revocation_date: i64,
+    pub invalidity_date: Option<i64>,
+    pub requested_by: String,
+}
+
+/// Sets `KEYS[2]` and `KEYS[3]` to the CRL numbered `ARGV[1]`, if that's higher than the number in `KEYS[1]`.
+const PUBLISH_CRL: &str = r"
+    local published = tonumber(redis.call('GET', KEYS[1]) or '0')
+    if tonumber(ARGV[1]) <= published then
+        return 0
+    end
+
+    redis.call('SET', KEYS[1], ARGV[1])
+    redis.call('SET', KEYS[2], ARGV[2])
+    redis.call('SET', KEYS[3], ARGV[3])
+    return 1
+";
+
+/// # Revocation Registry
+/// Every revoked serial is kept in the `revoked-serials` hash. Entries are never removed, so a serial can only be
+/// revoked once.
+#[async_trait]
+pub trait RevocationRegistry {
+    /// Records the revocation. Returns `false` without changing anything if the serial has already been revoked.
+    async fn record_revocation(&mut self, revocation: &RevokedCertificate) -> Result<bool>;
+    async fn get_revocation(&mut self, serial: CsrId) -> Result<Option<RevokedCertificate>>;
+    async fn get_revocations(&mut self) -> Result<Vec<RevokedCertificate>>;
+
+    async fn next_crl_number(&mut self) -> Result<u64>;
+    /// Replaces the published CRL, unless one with the same or a higher number has been published since. Returns whether
+    /// it was replaced.
+    async fn publish_crl(&mut self, number: u64, der: &[u8], pem: &str) -> Result<bool>;
+    async fn get_crl_der(&mut self) -> Result<Option<Vec<u8>>>;
+    async fn get_crl_pem(&mut self) -> Result<Option<String>>;
+}
+
+#[async_trait]
+impl RevocationRegistry for RedisConnection {
+    async fn record_revocation(&mut self, revocation: &RevokedCertificate) -> Result<bool> {
+        Ok(self.hset_nx(Key::RevokedSerials, revocation.serial, ron::to_string(revocation)?).await?)
+    }
+
+    async fn get_revocation(&mut self, serial: CsrId) -> Result<Option<RevokedCertificate>> {
+        Ok(self.hget(Key::RevokedSerials, serial).await?)
+    }
+
+    async fn get_revocations(&mut self) -> Result<Vec<RevokedCertificate>> {
+        Ok(self.hvals(Key::RevokedSerials).await?)
+    }
+
+    async fn next_crl_number(&mut self) -> Result<u64> {
+        Ok(self.incr(Key::CrlNumber, 1).await?)
+    }
+
+    async fn publish_crl(&mut self, number: u64, der: &[u8], pem: &str) -> Result<bool> {
+        // Replicas publish concurrently, so the number check and the writes have to happen in one step
+        let published: bool = redis::Script::new(PUBLISH_CRL)
+            .key(Key::CrlPublished)
+            .key(Key::CrlDer)
+            .key(Key::CrlPem)
+            .arg(number)
+            .arg(der)
+            .arg(pem)
+            .invoke_async(self)
+            .await?;
+
+        Ok(published)
+    }
+
+    async fn get_crl_der(&mut self) -> Result<Option<Vec<u8>>> {
+        Ok(self.get(Key::CrlDer).await?)
+    }
+
+    async fn get_crl_pem(&mut self) -> Result<Option<String>> {
+        Ok(self.get(Key::CrlPem).await?)
+    }
+}
@@ -0,0 +1,1010 @@
+//! # SQLite Storage
+//! Keeps everything in one database file, for deployments and CI which would rather not run Redis. Records are stored
+//! as RON in a `value` column, next to the columns they're looked up by. The event stream is a table in which every
+//! event records the consumer it was handed to and whether it was acknowledged, mirroring a single consumer group.
+//!
+//! Several processes on the same host can share the database. Readers notice each other's events and job updates by
+//! polling every `storage.sqlite.poll_interval_ms`, while writes within a process wake its own readers right away.
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use async_trait::async_trait;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use rusqlite::{params, Connection, OptionalExtension, Params, TransactionBehavior};
+use serde::de::DeserializeOwned;
+use tokio::sync::futures::Notified;
+use tokio::sync::Notify;
+use crate::{
+    random_token,
+    AcmeAccount,
+    AcmeAuthorization,
+    AcmeOrder,
+    AcmeStore,
+    CertificateStore,
+    CertmasterEvent,
+    ClientJob,
+    Csr,
+    CsrId,
+    DeadLetter,
+    DeadLetterQueue,
+    Error,
+    EventBus,
+    EventEntry,
+    GarbageCollector,
+    GcConfig,
+    GcReport,
+    IssuedCertificate,
+    JobStore,
+    Result,
+    RevocationRegistry,
+    RevokedCertificate,
+    SqliteConfig,
+    ACME_NONCE_TTL_SECS
+};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS counters (name TEXT PRIMARY KEY, value INTEGER NOT NULL);
+
+    CREATE TABLE IF NOT EXISTS jobs (id INTEGER PRIMARY KEY, submitted_at REAL, value TEXT NOT NULL);
+    CREATE INDEX IF NOT EXISTS jobs_submitted_at ON jobs (submitted_at);
+    CREATE TABLE IF NOT EXISTS client_jobs (alias TEXT PRIMARY KEY, value TEXT NOT NULL);
+    CREATE TABLE IF NOT EXISTS job_updates (seq INTEGER PRIMARY KEY AUTOINCREMENT, alias TEXT NOT NULL, created_at INTEGER NOT NULL);
+
+    CREATE TABLE IF NOT EXISTS events (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        fields TEXT NOT NULL,
+        consumer TEXT,
+        delivered_at INTEGER,
+        deliveries INTEGER NOT NULL DEFAULT 0,
+        acknowledged INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX IF NOT EXISTS events_consumer ON events (consumer, acknowledged);
+    CREATE TABLE IF NOT EXISTS dead_letters (id INTEGER PRIMARY KEY AUTOINCREMENT, value TEXT NOT NULL);
+
+    CREATE TABLE IF NOT EXISTS certificates (serial INTEGER PRIMARY KEY, issuer TEXT NOT NULL, not_after INTEGER NOT NULL, value TEXT NOT NULL);
+    CREATE INDEX IF NOT EXISTS certificates_issuer ON certificates (issuer);
+    CREATE INDEX IF NOT EXISTS certificates_not_after ON certificates (not_after);
+    CREATE TABLE IF NOT EXISTS certificate_names (name TEXT NOT NULL, serial INTEGER NOT NULL, PRIMARY KEY (name, serial));
+
+    CREATE TABLE IF NOT EXISTS revocations (serial INTEGER PRIMARY KEY, value TEXT NOT NULL);
+    CREATE TABLE IF NOT EXISTS crls (format TEXT PRIMARY KEY, crl BLOB NOT NULL);
+
+    CREATE TABLE IF NOT EXISTS acme_nonces (nonce TEXT PRIMARY KEY, expires_at INTEGER NOT NULL);
+    CREATE TABLE IF NOT EXISTS acme_accounts (id TEXT PRIMARY KEY, thumbprint TEXT NOT NULL UNIQUE, value TEXT NOT NULL);
+    CREATE TABLE IF NOT EXISTS acme_orders (id TEXT PRIMARY KEY, account TEXT NOT NULL, value TEXT NOT NULL);
+    CREATE INDEX IF NOT EXISTS acme_orders_account ON acme_orders (account);
+    CREATE TABLE IF NOT EXISTS acme_authorizations (id TEXT PRIMARY KEY, value TEXT NOT NULL);
+";
+
+/// How long a write waits for another process to finish its own.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Job updates are only kept for subscribers which are polling already.
+const JOB_UPDATE_RETENTION_SECS: i64 = 60;
+
+/// A connection to the database, shared by everything in a process.
+#[derive(Clone)]
+pub struct SqliteStore {
+    connection: Arc<Mutex<Connection>>,
+    /// Woken whenever an event or a job update is written.
+    written: Arc<Notify>,
+    poll_interval: Duration,
+}
+
+impl SqliteStore {
+    /// Opens the database, creating it and its tables if missing.
+    pub async fn open(config: &SqliteConfig) -> Result<Self> {
+        let path = config.path.clone();
+        let connection = tokio::task::spawn_blocking(move || -> Result<Connection> {
+            let connection = Connection::open(path)?;
+            connection.busy_timeout(BUSY_TIMEOUT)?;
+            // Readers and the writer don't block each other
+            connection.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+            connection.execute_batch(SCHEMA)?;
+
+            Ok(connection)
+        }).await??;
+
+        log::debug!("Opened SQLite database {path:?}", path = config.path);
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+            written: Arc::new(Notify::new()),
+            poll_interval: Duration::from_millis(config.poll_interval_ms.max(1)),
+        })
+    }
+
+    pub async fn ping(&mut self) -> Result<()> {
+        self.call(|connection| Ok(connection.query_row("SELECT 1", [], |_| Ok(()))?)).await
+    }
+
+    /// Runs the closure on a blocking thread, as SQLite blocks while it waits for the disk or for other processes.
+    async fn call<T: Send + 'static>(&self, f: impl FnOnce(&mut Connection) -> Result<T> + Send + 'static) -> Result<T> {
+        let connection = self.connection.clone();
+
+        tokio::task::spawn_blocking(move || f(&mut connection.lock().unwrap_or_else(|err| err.into_inner())))
+            .await?
+    }
+
+    /// Waits for a write in this process, or until it's time to look for writes of other processes.
+    async fn wait(&self, written: Pin<&mut Notified<'_>>, max: Duration) {
+        let _ = tokio::time::timeout(self.poll_interval.min(max), written).await;
+    }
+
+    /// Hands events to a consumer with the given statement, which returns their ID and fields.
+    async fn deliver(&self, sql: &'static str, params: impl Params + Send + 'static) -> Result<Vec<EventEntry>> {
+        self.call(move |connection| {
+            let mut statement = connection.prepare_cached(sql)?;
+            let mut entries = statement
+                .query_map(params, |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+
+            // `RETURNING` doesn't keep any order
+            entries.sort_by_key(|(id, _)| *id);
+            entries
+                .into_iter()
+                .map(|(id, fields)| Ok(EventEntry {
+                    id: id.to_string(),
+                    fields: ron::from_str(&fields)?,
+                }))
+                .collect()
+        }).await
+    }
+}
+
+fn now() -> Result<i64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64)
+}
+
+fn now_millis() -> Result<i64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64)
+}
+
+/// Event and dead-letter IDs are row IDs. Anything else can't exist.
+fn row_id(id: &str) -> Option<i64> {
+    id.parse().ok()
+}
+
+fn increment(connection: &Connection, counter: &str) -> Result<u64> {
+    Ok(connection.query_row(
+        "INSERT INTO counters (name, value) VALUES (?1, 1) ON CONFLICT (name) DO UPDATE SET value = value + 1 RETURNING value",
+        [counter],
+        |row| row.get::<_, i64>(0),
+    )? as u64)
+}
+
+/// The RON in the first column of the first row.
+fn query_value<T: DeserializeOwned>(connection: &Connection, sql: &str, params: impl Params) -> Result<Option<T>> {
+    connection.prepare_cached(sql)?
+        .query_row(params, |row| row.get::<_, String>(0))
+        .optional()?
+        .map(|value| Ok(ron::from_str(&value)?))
+        .transpose()
+}
+
+/// The RON in the first column of every row.
+fn query_values<T: DeserializeOwned>(connection: &Connection, sql: &str, params: impl Params) -> Result<Vec<T>> {
+    connection.prepare_cached(sql)?
+        .query_map(params, |row| row.get::<_, String>(0))?
+        .map(|value| Ok(ron::from_str(&value?)?))
+        .collect()
+}
+
+#[async_trait]
+impl JobStore for SqliteStore {
+    async fn next_job_id(&mut self) -> Result<CsrId> {
+        self.call(|connection| increment(connection, "csr_id")).await
+    }
+
+    async fn get_job(&mut self, id: CsrId) -> Result<Option<Csr>> {
+        self.call(move |connection| query_value(connection, "SELECT value FROM jobs WHERE id = ?1", [id as i64])).await
+    }
+
+    async fn get_jobs(&mut self, ids: &[CsrId]) -> Result<Vec<Csr>> {
+        let ids = ids.to_vec();
+
+        self.call(move |connection| {
+            let mut jobs = vec![];
+            for id in ids {
+                jobs.extend(query_value(connection, "SELECT value FROM jobs WHERE id = ?1", [id as i64])?);
+            }
+
+            Ok(jobs)
+        }).await
+    }
+
+    async fn set_job(&mut self, id: CsrId, job: &Csr) -> Result<()> {
+        let job = ron::to_string(job)?;
+
+        self.call(move |connection| {
+            connection.execute("INSERT INTO jobs (id, value) VALUES (?1, ?2) ON CONFLICT (id) DO UPDATE SET value = excluded.value", params![id as i64, job])?;
+            Ok(())
+        }).await
+    }
+
+    async fn create_job(&mut self, id: CsrId, job: &Csr) -> Result<()> {
+        let job = ron::to_string(job)?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)?
+            .as_secs_f64();
+
+        self.call(move |connection| {
+            connection.execute("INSERT INTO jobs (id, submitted_at, value) VALUES (?1, ?2, ?3)
+                ON CONFLICT (id) DO UPDATE SET submitted_at = excluded.submitted_at, value = excluded.value", params![id as i64, timestamp, job])?;
+            Ok(())
+        }).await
+    }
+
+    async fn list_jobs(&mut self, offset: usize, count: usize) -> Result<Vec<Csr>> {
+        self.call(move |connection| query_values(connection,
+            "SELECT value FROM jobs WHERE submitted_at IS NOT NULL ORDER BY submitted_at DESC, id DESC LIMIT ?1 OFFSET ?2",
+            [count as i64, offset as i64])).await
+    }
+
+    async fn jobs_submitted_before(&mut self, timestamp: f64) -> Result<Vec<(CsrId, f64)>> {
+        self.call(move |connection| {
+            let mut statement = connection.prepare_cached("SELECT id, submitted_at FROM jobs WHERE submitted_at <= ?1 ORDER BY submitted_at")?;
+            let jobs = statement
+                .query_map([timestamp], |row| Ok((row.get::<_, i64>(0)? as CsrId, row.get::<_, f64>(1)?)))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+
+            Ok(jobs)
+        }).await
+    }
+
+    async fn get_client_job(&mut self, alias: &str) -> Result<Option<ClientJob>> {
+        let alias = alias.to_owned();
+        self.call(move |connection| query_value(connection, "SELECT value FROM client_jobs WHERE alias = ?1", [alias])).await
+    }
+
+    async fn get_client_jobs<T: AsRef<str>>(&mut self, aliases: impl Iterator<Item=T> + Send) -> Result<Vec<ClientJob>> {
+        let aliases = aliases
+            .map(|alias| alias.as_ref().to_owned())
+            .collect::<Vec<_>>();
+
+        self.call(move |connection| {
+            let mut jobs = vec![];
+            for alias in aliases {
+                match query_value(connection, "SELECT value FROM client_jobs WHERE alias = ?1", [&alias])? {
+                    Some(job) => jobs.push(job),
+                    None => return Error::custom(format!("Unknown job {alias}")),
+                }
+            }
+
+            Ok(jobs)
+        }).await
+    }
+
+    async fn set_client_job(&mut self, job: &ClientJob) -> Result<()> {
+        let alias = job.alias.clone();
+        let job = ron::to_string(job)?;
+        let now = now()?;
+
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute("INSERT INTO client_jobs (alias, value) VALUES (?1, ?2) ON CONFLICT (alias) DO UPDATE SET value = excluded.value", params![alias, job])?;
+            transaction.execute("INSERT INTO job_updates (alias, created_at) VALUES (?1, ?2)", params![alias, now])?;
+            transaction.execute("DELETE FROM job_updates WHERE created_at < ?1", [now - JOB_UPDATE_RETENTION_SECS])?;
+            transaction.commit()?;
+
+            Ok(())
+        }).await?;
+
+        self.written.notify_waiters();
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl EventBus for SqliteStore {
+    async fn dispatch_event<Event: CertmasterEvent + Send>(&mut self, event: Event) -> Result<()> {
+        let fields = ron::to_string(&vec![(Event::event_name(), ron::to_string(&event)?)])?;
+
+        self.call(move |connection| {
+            connection.execute("INSERT INTO events (fields) VALUES (?1)", [fields])?;
+            Ok(())
+        }).await?;
+
+        self.written.notify_waiters();
+
+        Ok(())
+    }
+
+    /// The events table is created along with the database.
+    async fn create_consumer_group(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn read_events(&mut self, consumer: &str, count: usize, block: Duration) -> Result<Vec<EventEntry>> {
+        let deadline = Instant::now() + block;
+
+        loop {
+            let written = self.written.notified();
+            tokio::pin!(written);
+            written.as_mut().enable();
+
+            let events = self.deliver(
+                "UPDATE events SET consumer = ?1, delivered_at = ?2, deliveries = deliveries + 1
+                    WHERE id IN (SELECT id FROM events WHERE consumer IS NULL ORDER BY id LIMIT ?3)
+                    RETURNING id, fields",
+                (consumer.to_owned(), now_millis()?, count.max(1) as i64),
+            ).await?;
+
+            let now = Instant::now();
+            if !events.is_empty() || now >= deadline {
+                return Ok(events);
+            }
+
+            self.wait(written, deadline - now).await;
+        }
+    }
+
+    async fn read_pending_events(&mut self, consumer: &str) -> Result<Vec<EventEntry>> {
+        self.deliver(
+            "UPDATE events SET delivered_at = ?2, deliveries = deliveries + 1 WHERE consumer = ?1 AND acknowledged = 0 RETURNING id, fields",
+            (consumer.to_owned(), now_millis()?),
+        ).await
+    }
+
+    async fn claim_idle_events(&mut self, consumer: &str, min_idle: Duration) -> Result<Vec<EventEntry>> {
+        let now = now_millis()?;
+
+        self.deliver(
+            "UPDATE events SET consumer = ?1, delivered_at = ?2, deliveries = deliveries + 1
+                WHERE consumer IS NOT NULL AND acknowledged = 0 AND delivered_at <= ?3
+                RETURNING id, fields",
+            (consumer.to_owned(), now, now - min_idle.as_millis() as i64),
+        ).await
+    }
+
+    async fn delivery_count(&mut self, id: &str) -> Result<usize> {
+        let Some(id) = row_id(id) else {
+            return Ok(0);
+        };
+
+        self.call(move |connection| {
+            let deliveries = connection
+                .query_row("SELECT deliveries FROM events WHERE id = ?1 AND consumer IS NOT NULL AND acknowledged = 0", [id], |row| row.get::<_, i64>(0))
+                .optional()?;
+
+            Ok(deliveries.unwrap_or_default() as usize)
+        }).await
+    }
+
+    async fn acknowledge_event(&mut self, id: &str) -> Result<()> {
+        let Some(id) = row_id(id) else {
+            return Ok(());
+        };
+
+        self.call(move |connection| {
+            connection.execute("UPDATE events SET acknowledged = 1 WHERE id = ?1", [id])?;
+            Ok(())
+        }).await
+    }
+
+    async fn job_updates(&mut self) -> Result<BoxStream<'static, String>> {
+        let last = self.call(|connection| Ok(connection.query_row("SELECT COALESCE(MAX(seq), 0) FROM job_updates", [], |row| row.get::<_, i64>(0))?))
+            .await?;
+
+        Ok(stream::unfold((self.clone(), last, VecDeque::new()), |(store, mut last, mut updates)| async move {
+            loop {
+                if let Some(alias) = updates.pop_front() {
+                    return Some((alias, (store, last, updates)));
+                }
+
+                let written = store.written.notified();
+                tokio::pin!(written);
+                written.as_mut().enable();
+
+                let since = last;
+                let read = store.call(move |connection| {
+                    let mut statement = connection.prepare_cached("SELECT seq, alias FROM job_updates WHERE seq > ?1 ORDER BY seq")?;
+                    let updates = statement
+                        .query_map([since], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?
+                        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+                    Ok(updates)
+                }).await;
+
+                match read {
+                    Ok(read) => for (seq, alias) in read {
+                        last = seq;
+                        updates.push_back(alias);
+                    },
+                    Err(err) => {
+                        log::error!("Failed to read job updates: {err:?}");
+                        return None;
+                    }
+                }
+
+                if updates.is_empty() {
+                    store.wait(written, store.poll_interval).await;
+                }
+            }
+        }).boxed())
+    }
+}
+
+#[async_trait]
+impl CertificateStore for SqliteStore {
+    async fn store_certificate(&mut self, cert: &IssuedCertificate) -> Result<bool> {
+        let value = ron::to_string(cert)?;
+        let names = cert.names().collect::<Vec<_>>();
+        let (serial, issuer, not_after) = (cert.serial as i64, cert.issuer.clone(), cert.not_after);
+
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+
+            let stored = transaction.execute("INSERT OR IGNORE INTO certificates (serial, issuer, not_after, value) VALUES (?1, ?2, ?3, ?4)",
+                params![serial, issuer, not_after, value])?;
+            if stored == 0 {
+                return Ok(false);
+            }
+
+            for name in names {
+                transaction.execute("INSERT OR IGNORE INTO certificate_names (name, serial) VALUES (?1, ?2)", params![name, serial])?;
+            }
+
+            transaction.commit()?;
+
+            Ok(true)
+        }).await
+    }
+
+    async fn get_certificate(&mut self, serial: CsrId) -> Result<Option<IssuedCertificate>> {
+        self.call(move |connection| query_value(connection, "SELECT value FROM certificates WHERE serial = ?1", [serial as i64])).await
+    }
+
+    async fn get_certificates(&mut self, serials: &[CsrId]) -> Result<Vec<IssuedCertificate>> {
+        let serials = serials.to_vec();
+
+        self.call(move |connection| {
+            let mut certs = vec![];
+            for serial in serials {
+                certs.extend(query_value(connection, "SELECT value FROM certificates WHERE serial = ?1", [serial as i64])?);
+            }
+
+            Ok(certs)
+        }).await
+    }
+
+    async fn certificates_by_name(&mut self, name: &str) -> Result<Vec<IssuedCertificate>> {
+        let name = name.to_ascii_lowercase();
+
+        self.call(move |connection| query_values(connection,
+            "SELECT certificates.value FROM certificates JOIN certificate_names USING (serial) WHERE certificate_names.name = ?1 ORDER BY serial",
+            [name])).await
+    }
+
+    async fn certificates_by_issuer(&mut self, issuer: &str) -> Result<Vec<IssuedCertificate>> {
+        let issuer = issuer.to_owned();
+        self.call(move |connection| query_values(connection, "SELECT value FROM certificates WHERE issuer = ?1 ORDER BY serial", [issuer])).await
+    }
+
+    async fn certificates_expiring(&mut self, from: i64, to: i64) -> Result<Vec<IssuedCertificate>> {
+        self.call(move |connection| query_values(connection,
+            "SELECT value FROM certificates WHERE not_after BETWEEN ?1 AND ?2 ORDER BY not_after",
+            [from, to])).await
+    }
+}
+
+#[async_trait]
+impl RevocationRegistry for SqliteStore {
+    async fn record_revocation(&mut self, revocation: &RevokedCertificate) -> Result<bool> {
+        let (serial, value) = (revocation.serial as i64, ron::to_string(revocation)?);
+
+        self.call(move |connection| {
+            Ok(connection.execute("INSERT OR IGNORE INTO revocations (serial, value) VALUES (?1, ?2)", params![serial, value])? > 0)
+        }).await
+    }
+
+    async fn get_revocation(&mut self, serial: CsrId) -> Result<Option<RevokedCertificate>> {
+        self.call(move |connection| query_value(connection, "SELECT value FROM revocations WHERE serial = ?1", [serial as i64])).await
+    }
+
+    async fn get_revocations(&mut self) -> Result<Vec<RevokedCertificate>> {
+        self.call(|connection| query_values(connection, "SELECT value FROM revocations ORDER BY serial", [])).await
+    }
+
+    async fn next_crl_number(&mut self) -> Result<u64> {
+        self.call(|connection| increment(connection, "crl-number")).await
+    }
+
+    async fn publish_crl(&mut self, number: u64, der: &[u8], pem: &str) -> Result<bool> {
+        let (der, pem) = (der.to_vec(), pem.to_owned());
+
+        self.call(move |connection| {
+            // Taking the write lock up front, so no other process can publish between the check and the writes
+            let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+            let published = transaction.query_row("SELECT value FROM counters WHERE name = 'crl-published'", [], |row| row.get::<_, i64>(0))
+                .optional()?
+                .unwrap_or_default();
+
+            if number as i64 <= published {
+                return Ok(false);
+            }
+
+            transaction.execute("INSERT INTO counters (name, value) VALUES ('crl-published', ?1) ON CONFLICT (name) DO UPDATE SET value = excluded.value", [number as i64])?;
+            transaction.execute("INSERT INTO crls (format, crl) VALUES ('der', ?1) ON CONFLICT (format) DO UPDATE SET crl = excluded.crl", [der])?;
+            transaction.execute("INSERT INTO crls (format, crl) VALUES ('pem', ?1) ON CONFLICT (format) DO UPDATE SET crl = excluded.crl", [pem])?;
+            transaction.commit()?;
+
+            Ok(true)
+        }).await
+    }
+
+    async fn get_crl_der(&mut self) -> Result<Option<Vec<u8>>> {
+        self.call(|connection| Ok(connection.query_row("SELECT crl FROM crls WHERE format = 'der'", [], |row| row.get(0)).optional()?)).await
+    }
+
+    async fn get_crl_pem(&mut self) -> Result<Option<String>> {
+        self.call(|connection| Ok(connection.query_row("SELECT crl FROM crls WHERE format = 'pem'", [], |row| row.get(0)).optional()?)).await
+    }
+}
+
+#[async_trait]
+impl DeadLetterQueue for SqliteStore {
+    async fn dead_letter(&mut self, entry: &EventEntry, error: String, attempts: usize) -> Result<String> {
+        let letter = ron::to_string(&DeadLetter::new(entry, error, attempts)?)?;
+        let event_id = row_id(&entry.id);
+
+        self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute("INSERT INTO dead_letters (value) VALUES (?1)", [letter])?;
+            let id = transaction.last_insert_rowid();
+            transaction.execute("UPDATE events SET acknowledged = 1 WHERE id = ?1", [event_id])?;
+            transaction.commit()?;
+
+            Ok(id.to_string())
+        }).await
+    }
+
+    async fn get_dead_letters(&mut self) -> Result<Vec<DeadLetter>> {
+        self.call(|connection| {
+            let mut statement = connection.prepare_cached("SELECT id, value FROM dead_letters ORDER BY id")?;
+            let letters = statement
+                .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+
+            letters
+                .into_iter()
+                .map(|(id, letter)| Ok(DeadLetter {
+                    id: id.to_string(),
+                    ..ron::from_str(&letter)?
+                }))
+                .collect()
+        }).await
+    }
+
+    async fn get_dead_letter(&mut self, id: &str) -> Result<Option<DeadLetter>> {
+        let Some(row) = row_id(id) else {
+            return Ok(None);
+        };
+
+        let letter: Option<DeadLetter> = self.call(move |connection| query_value(connection, "SELECT value FROM dead_letters WHERE id = ?1", [row]))
+            .await?;
+
+        Ok(letter.map(|letter| DeadLetter {
+            id: id.to_owned(),
+            ..letter
+        }))
+    }
+
+    async fn replay_dead_letter(&mut self, id: &str) -> Result<Option<String>> {
+        let Some(letter) = self.get_dead_letter(id).await? else {
+            return Ok(None);
+        };
+
+        let fields = ron::to_string(&letter.fields)?;
+        let row = row_id(id);
+
+        let event_id = self.call(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute("INSERT INTO events (fields) VALUES (?1)", [fields])?;
+            let event_id = transaction.last_insert_rowid();
+            transaction.execute("DELETE FROM dead_letters WHERE id = ?1", [row])?;
+            transaction.commit()?;
+
+            Ok(event_id.to_string())
+        }).await?;
+
+        self.written.notify_waiters();
+
+        Ok(Some(event_id))
+    }
+
+    async fn discard_dead_letter(&mut self, id: &str) -> Result<bool> {
+        let Some(id) = row_id(id) else {
+            return Ok(false);
+        };
+
+        self.call(move |connection| Ok(connection.execute("DELETE FROM dead_letters WHERE id = ?1", [id])? > 0)).await
+    }
+}
+
+#[async_trait]
+impl GarbageCollector for SqliteStore {
+    async fn collect_garbage(&mut self, gc: &GcConfig, dry_run: bool) -> Result<GcReport> {
+        let cutoff = crate::gc::retention_cutoff(gc)?;
+
+        self.call(move |connection| {
+            let mut report = GcReport {
+                dry_run,
+                ..GcReport::default()
+            };
+
+            let transaction = connection.transaction()?;
+
+            // Jobs only change status after they were submitted, so anything submitted since can't be due yet
+            let candidates = transaction
+                .prepare_cached("SELECT id, submitted_at, value FROM jobs WHERE submitted_at <= ?1")?
+                .query_map([cutoff as f64], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, f64>(1)?, row.get::<_, String>(2)?)))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+
+            for (id, submitted, csr) in candidates {
+                let csr: Csr = ron::from_str(&csr)?;
+                if !crate::gc::is_collectable(&csr, submitted, cutoff) {
+                    continue;
+                }
+
+                if !dry_run {
+                    transaction.execute("DELETE FROM jobs WHERE id = ?1", [id])?;
+                    transaction.execute("DELETE FROM client_jobs WHERE alias = ?1", [&csr.client_alias])?;
+                }
+
+                report.jobs.push(format!("csr:{id}"));
+                report.aliases.push(format!("alt:{alias}", alias = csr.client_alias));
+            }
+
+            // Everything before the oldest unacknowledged event has been acknowledged
+            let min_id: Option<i64> = transaction.query_row(
+                "SELECT COALESCE((SELECT MIN(id) FROM events WHERE acknowledged = 0), (SELECT MAX(id) + 1 FROM events))",
+                [],
+                |row| row.get(0),
+            )?;
+            report.stream_min_id = min_id.map(|id| id.to_string());
+
+            if let Some(min_id) = min_id && !dry_run {
+                report.stream_entries_trimmed = Some(transaction.execute("DELETE FROM events WHERE id < ?1", [min_id])?);
+            }
+
+            transaction.commit()?;
+
+            Ok(report)
+        }).await
+    }
+}
+
+#[async_trait]
+impl AcmeStore for SqliteStore {
+    async fn new_nonce(&mut self) -> Result<String> {
+        let nonce = random_token()?;
+        let (now, stored) = (now()?, nonce.clone());
+
+        self.call(move |connection| {
+            connection.execute("DELETE FROM acme_nonces WHERE expires_at <= ?1", [now])?;
+            connection.execute("INSERT INTO acme_nonces (nonce, expires_at) VALUES (?1, ?2)", params![stored, now + ACME_NONCE_TTL_SECS as i64])?;
+            Ok(())
+        }).await?;
+
+        Ok(nonce)
+    }
+
+    async fn consume_nonce(&mut self, nonce: &str) -> Result<bool> {
+        let (nonce, now) = (nonce.to_owned(), now()?);
+
+        self.call(move |connection| {
+            Ok(connection.execute("DELETE FROM acme_nonces WHERE nonce = ?1 AND expires_at > ?2", params![nonce, now])? > 0)
+        }).await
+    }
+
+    async fn create_account(&mut self, account: &AcmeAccount) -> Result<bool> {
+        let (id, thumbprint, value) = (account.id.clone(), account.thumbprint.clone(), ron::to_string(account)?);
+
+        self.call(move |connection| {
+            Ok(connection.execute("INSERT OR IGNORE INTO acme_accounts (id, thumbprint, value) VALUES (?1, ?2, ?3)", params![id, thumbprint, value])? > 0)
+        }).await
+    }
+
+    async fn update_account(&mut self, account: &AcmeAccount) -> Result<()> {
+        let (id, value) = (account.id.clone(), ron::to_string(account)?);
+
+        self.call(move |connection| {
+            connection.execute("UPDATE acme_accounts SET value = ?2 WHERE id = ?1", params![id, value])?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_account(&mut self, id: &str) -> Result<Option<AcmeAccount>> {
+        let id = id.to_owned();
+        self.call(move |connection| query_value(connection, "SELECT value FROM acme_accounts WHERE id = ?1", [id])).await
+    }
+
+    async fn account_by_key(&mut self, thumbprint: &str) -> Result<Option<AcmeAccount>> {
+        let thumbprint = thumbprint.to_owned();
+        self.call(move |connection| query_value(connection, "SELECT value FROM acme_accounts WHERE thumbprint = ?1", [thumbprint])).await
+    }
+
+    /// The account's row holds its thumbprint, so the old one goes along with it.
+    async fn change_account_key(&mut self, account: &AcmeAccount, _old_thumbprint: &str) -> Result<bool> {
+        let (id, thumbprint, value) = (account.id.clone(), account.thumbprint.clone(), ron::to_string(account)?);
+
+        self.call(move |connection| {
+            Ok(connection.execute("UPDATE OR IGNORE acme_accounts SET thumbprint = ?2, value = ?3 WHERE id = ?1", params![id, thumbprint, value])? > 0)
+        }).await
+    }
+
+    async fn store_order(&mut self, order: &AcmeOrder) -> Result<()> {
+        let (id, account, value) = (order.id.clone(), order.account.clone(), ron::to_string(order)?);
+
+        self.call(move |connection| {
+            connection.execute("INSERT INTO acme_orders (id, account, value) VALUES (?1, ?2, ?3) ON CONFLICT (id) DO UPDATE SET value = excluded.value",
+                params![id, account, value])?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_order(&mut self, id: &str) -> Result<Option<AcmeOrder>> {
+        let id = id.to_owned();
+        self.call(move |connection| query_value(connection, "SELECT value FROM acme_orders WHERE id = ?1", [id])).await
+    }
+
+    async fn orders_of_account(&mut self, account: &str) -> Result<Vec<AcmeOrder>> {
+        let account = account.to_owned();
+        self.call(move |connection| query_values(connection, "SELECT value FROM acme_orders WHERE account = ?1 ORDER BY rowid", [account])).await
+    }
+
+    async fn store_authorization(&mut self, authz: &AcmeAuthorization) -> Result<()> {
+        let (id, value) = (authz.id.clone(), ron::to_string(authz)?);
+
+        self.call(move |connection| {
+            connection.execute("INSERT INTO acme_authorizations (id, value) VALUES (?1, ?2) ON CONFLICT (id) DO UPDATE SET value = excluded.value", params![id, value])?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_authorization(&mut self, id: &str) -> Result<Option<AcmeAuthorization>> {
+        let id = id.to_owned();
+        self.call(move |connection| query_value(connection, "SELECT value FROM acme_authorizations WHERE id = ?1", [id])).await
+    }
+
+    async fn get_authorizations(&mut self, ids: &[String]) -> Result<Vec<AcmeAuthorization>> {
+        let ids = ids.to_vec();
+
+        self.call(move |connection| {
+            let mut authz = vec![];
+            for id in ids {
+                authz.extend(query_value(connection, "SELECT value FROM acme_authorizations WHERE id = ?1", [id])?);
+            }
+
+            Ok(authz)
+        }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::*;
+    use crate::{AcmeStatus, JobStatus, NewCsr, PendingChallenge, Status};
+
+    /// A database of its own in the temporary directory, deleted again once the test is done with it.
+    struct TempStore {
+        store: SqliteStore,
+        path: PathBuf,
+    }
+
+    impl TempStore {
+        async fn open(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("certmaster-{name}-{pid}.db", pid = std::process::id()));
+            remove_database(&path);
+
+            let store = SqliteStore::open(&SqliteConfig {
+                path: path.clone(),
+                poll_interval_ms: 10,
+            }).await.unwrap();
+
+            Self { store, path }
+        }
+    }
+
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            remove_database(&self.path);
+        }
+    }
+
+    fn remove_database(path: &std::path::Path) {
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.as_os_str().to_owned();
+            file.push(suffix);
+            let _ = std::fs::remove_file(file);
+        }
+    }
+
+    fn ids(entries: &[EventEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.id.as_str()).collect()
+    }
+
+    fn job(client_id: u64, status: JobStatus, updated_at: i64) -> Csr {
+        let mut csr = Csr::from(NewCsr {
+            client_id,
+            pem: format!("CSR {client_id}"),
+            profile: None,
+            challenge: None,
+            challenges: vec![],
+        });
+
+        csr.status = status;
+        csr.updated_at = Some(updated_at);
+        csr
+    }
+
+    fn account(id: &str, thumbprint: &str) -> AcmeAccount {
+        AcmeAccount {
+            id: id.to_owned(),
+            key: "{}".to_owned(),
+            thumbprint: thumbprint.to_owned(),
+            status: AcmeStatus::Valid,
+            contact: vec![],
+            terms_of_service_agreed: true,
+            created_at: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn events_stay_pending_until_acknowledged() {
+        let mut database = TempStore::open("events").await;
+        let store = &mut database.store;
+
+        store.dispatch_event(PendingChallenge { id: 1 }).await.unwrap();
+        store.dispatch_event(PendingChallenge { id: 2 }).await.unwrap();
+
+        let read = store.read_events("a", 10, Duration::from_millis(10)).await.unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[0].fields, vec![(PendingChallenge::event_name().to_owned(), "(id:1)".to_owned())]);
+        assert_eq!(store.delivery_count(&read[0].id).await.unwrap(), 1);
+
+        // Each event goes to one consumer only
+        assert!(store.read_events("b", 10, Duration::from_millis(10)).await.unwrap().is_empty());
+
+        let pending = store.read_pending_events("a").await.unwrap();
+        assert_eq!(ids(&pending), ids(&read));
+        assert_eq!(store.delivery_count(&read[0].id).await.unwrap(), 2);
+
+        store.acknowledge_event(&read[0].id).await.unwrap();
+        assert_eq!(store.delivery_count(&read[0].id).await.unwrap(), 0);
+        assert_eq!(ids(&store.read_pending_events("a").await.unwrap()), vec![read[1].id.as_str()]);
+
+        let claimed = store.claim_idle_events("b", Duration::ZERO).await.unwrap();
+        assert_eq!(ids(&claimed), vec![read[1].id.as_str()]);
+        assert_eq!(store.delivery_count(&read[1].id).await.unwrap(), 4);
+        assert!(store.read_pending_events("a").await.unwrap().is_empty());
+        assert_eq!(ids(&store.read_pending_events("b").await.unwrap()), vec![read[1].id.as_str()]);
+    }
+
+    #[tokio::test]
+    async fn idle_events_are_only_claimed_after_the_idle_time() {
+        let mut database = TempStore::open("claims").await;
+        let store = &mut database.store;
+
+        store.dispatch_event(PendingChallenge { id: 1 }).await.unwrap();
+        store.read_events("a", 10, Duration::from_millis(10)).await.unwrap();
+
+        assert!(store.claim_idle_events("b", Duration::from_secs(60)).await.unwrap().is_empty());
+        assert_eq!(store.claim_idle_events("b", Duration::ZERO).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn dead_letters_are_replayed_as_new_events() {
+        let mut database = TempStore::open("dead-letters").await;
+        let store = &mut database.store;
+
+        store.dispatch_event(PendingChallenge { id: 1 }).await.unwrap();
+        let entry = store.read_events("a", 10, Duration::from_millis(10)).await.unwrap().remove(0);
+
+        let id = store.dead_letter(&entry, "Failed".to_owned(), 3).await.unwrap();
+        assert!(store.read_pending_events("a").await.unwrap().is_empty(), "Dead-lettered events are acknowledged");
+
+        let letters = store.get_dead_letters().await.unwrap();
+        assert_eq!(letters.len(), 1);
+        assert_eq!((letters[0].id.as_str(), letters[0].event_id.as_str()), (id.as_str(), entry.id.as_str()));
+        assert_eq!((letters[0].error.as_str(), letters[0].attempts), ("Failed", 3));
+        assert_eq!(letters[0].fields, entry.fields);
+
+        let replayed = store.replay_dead_letter(&id).await.unwrap().unwrap();
+        assert_ne!(replayed, entry.id);
+        assert!(store.get_dead_letters().await.unwrap().is_empty());
+        assert!(store.replay_dead_letter(&id).await.unwrap().is_none());
+        assert!(store.replay_dead_letter("not-an-id").await.unwrap().is_none());
+
+        let read = store.read_events("a", 10, Duration::from_millis(10)).await.unwrap();
+        assert_eq!(ids(&read), vec![replayed.as_str()]);
+        assert_eq!(read[0].fields, entry.fields);
+    }
+
+    #[tokio::test]
+    async fn garbage_collection_deletes_old_jobs_and_acknowledged_events() {
+        let mut database = TempStore::open("gc").await;
+        let store = &mut database.store;
+
+        let now = now().unwrap();
+        let old = now - 3 * 86400;
+
+        let stale = job(1, JobStatus::Stale, old);
+        let pending = job(2, JobStatus::Pending, old);
+        let recent = job(3, JobStatus::Stale, now);
+
+        for (id, csr) in [(1, &stale), (2, &pending), (3, &recent)] {
+            store.create_job(id, csr).await.unwrap();
+        }
+
+        store.set_client_job(&ClientJob {
+            client_id: 1,
+            serial: 1,
+            alias: stale.client_alias.clone(),
+            status: Status::Pending,
+        }).await.unwrap();
+
+        store.call(move |connection| {
+            connection.execute("UPDATE jobs SET submitted_at = ?1 WHERE id IN (1, 2)", [old as f64])?;
+            Ok(())
+        }).await.unwrap();
+
+        for id in 1..=3 {
+            store.dispatch_event(PendingChallenge { id }).await.unwrap();
+        }
+
+        let read = store.read_events("a", 10, Duration::from_millis(10)).await.unwrap();
+        store.acknowledge_event(&read[0].id).await.unwrap();
+        store.acknowledge_event(&read[1].id).await.unwrap();
+
+        let gc = GcConfig {
+            retention_days: 1,
+            interval_secs: 3600,
+            dry_run: false,
+        };
+
+        let report = store.collect_garbage(&gc, true).await.unwrap();
+        assert_eq!(report.jobs, vec!["csr:1"]);
+        assert_eq!(report.stream_min_id.as_deref(), Some(read[2].id.as_str()));
+        assert_eq!(report.stream_entries_trimmed, None);
+        assert!(store.get_job(1).await.unwrap().is_some(), "Dry runs delete nothing");
+
+        let report = store.collect_garbage(&gc, false).await.unwrap();
+        assert_eq!(report.jobs, vec!["csr:1"]);
+        assert_eq!(report.aliases, vec![format!("alt:{alias}", alias = stale.client_alias)]);
+        assert_eq!(report.stream_entries_trimmed, Some(2));
+
+        assert!(store.get_job(1).await.unwrap().is_none());
+        assert!(store.get_client_job(&stale.client_alias).await.unwrap().is_none());
+        assert!(store.get_job(2).await.unwrap().is_some());
+        assert!(store.get_job(3).await.unwrap().is_some());
+
+        // The unacknowledged event is kept for its consumer
+        assert_eq!(ids(&store.read_pending_events("a").await.unwrap()), vec![read[2].id.as_str()]);
+    }
+
+    #[tokio::test]
+    async fn account_keys_stay_unique() {
+        let mut database = TempStore::open("accounts").await;
+        let store = &mut database.store;
+
+        assert!(store.create_account(&account("first", "key-1")).await.unwrap());
+        assert!(store.create_account(&account("second", "key-2")).await.unwrap());
+        assert!(!store.create_account(&account("third", "key-1")).await.unwrap());
+
+        // Taken by the second account
+        assert!(!store.change_account_key(&account("first", "key-2"), "key-1").await.unwrap());
+        assert_eq!(store.account_by_key("key-1").await.unwrap().unwrap().id, "first");
+        assert_eq!(store.account_by_key("key-2").await.unwrap().unwrap().id, "second");
+
+        assert!(store.change_account_key(&account("first", "key-3"), "key-1").await.unwrap());
+        assert!(store.account_by_key("key-1").await.unwrap().is_none());
+        assert_eq!(store.account_by_key("key-3").await.unwrap().unwrap().id, "first");
+        assert_eq!(store.get_account("first").await.unwrap().unwrap().thumbprint, "key-3");
+    }
+}
@@ -0,0 +1,382 @@
+//! # Storage
+//! Everything certmaster keeps is reached through [JobStore] and [EventBus], along with [CertificateStore],
+//! [RevocationRegistry], [DeadLetterQueue], [GarbageCollector] and [AcmeStore]. Redis implements them on
+//! [RedisConnection] and SQLite on [SqliteStore]. [Storage] is whichever of the two `storage.backend` selects.
+
+use std::time::Duration;
+use async_trait::async_trait;
+use futures_util::stream::BoxStream;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::OnceCell;
+use crate::{
+    AcmeAccount,
+    AcmeAuthorization,
+    AcmeOrder,
+    AcmeStore,
+    CertificateStore,
+    ClientJob,
+    Config,
+    Csr,
+    CsrId,
+    DeadLetter,
+    DeadLetterQueue,
+    GarbageCollector,
+    GcConfig,
+    GcReport,
+    IssuedCertificate,
+    RedisConnection,
+    Result,
+    RevocationRegistry,
+    RevokedCertificate,
+    SqliteStore,
+    StorageBackend
+};
+
+static SQLITE: OnceCell<SqliteStore> = OnceCell::const_new();
+
+/// How long to wait before trying to open the database again.
+const SQLITE_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+pub trait CertmasterEvent: Serialize + DeserializeOwned {
+    fn event_name() -> &'static str;
+}
+
+/// An entry of the event stream. Its fields are usually the name of the event and its RON payload.
+#[derive(Debug, Clone)]
+pub struct EventEntry {
+    pub id: String,
+    pub fields: Vec<(String, String)>,
+}
+
+/// # Job Store
+/// Jobs by serial, listed by when they were submitted, and the state of every job as shown to its requester, by alias.
+#[async_trait]
+pub trait JobStore {
+    /// The serial of a new job.
+    async fn next_job_id(&mut self) -> Result<CsrId>;
+    async fn get_job(&mut self, id: CsrId) -> Result<Option<Csr>>;
+    /// Jobs which don't exist are left out.
+    async fn get_jobs(&mut self, ids: &[CsrId]) -> Result<Vec<Csr>>;
+    async fn set_job(&mut self, id: CsrId, job: &Csr) -> Result<()>;
+    /// Stores a new job and adds it to the job list.
+    async fn create_job(&mut self, id: CsrId, job: &Csr) -> Result<()>;
+    /// A page of the job list, newest first.
+    async fn list_jobs(&mut self, offset: usize, count: usize) -> Result<Vec<Csr>>;
+    /// The listed jobs submitted up to the given Unix timestamp, along with when they were submitted.
+    async fn jobs_submitted_before(&mut self, timestamp: f64) -> Result<Vec<(CsrId, f64)>>;
+
+    async fn get_client_job(&mut self, alias: &str) -> Result<Option<ClientJob>>;
+    /// Fails if any of the aliases is unknown.
+    async fn get_client_jobs<T: AsRef<str>>(&mut self, aliases: impl Iterator<Item=T> + Send) -> Result<Vec<ClientJob>>;
+    /// Stores the job under its alias and announces its alias to job update subscribers.
+    async fn set_client_job(&mut self, job: &ClientJob) -> Result<()>;
+}
+
+/// # Event Bus
+/// The event stream, which the workers read as one consumer group: Every event is handed to one consumer, and stays
+/// pending until that consumer acknowledges it. Job updates are announced to whoever is subscribed at the time.
+#[async_trait]
+pub trait EventBus {
+    async fn dispatch_event<Event: CertmasterEvent + Send>(&mut self, event: Event) -> Result<()>;
+    /// Creates the stream and its consumer group, unless they exist.
+    async fn create_consumer_group(&mut self) -> Result<()>;
+    /// Hands up to `count` new events to the consumer, waiting up to `block` for the first one.
+    async fn read_events(&mut self, consumer: &str, count: usize, block: Duration) -> Result<Vec<EventEntry>>;
+    /// The events handed to the consumer which it never acknowledged. Counts as another delivery.
+    async fn read_pending_events(&mut self, consumer: &str) -> Result<Vec<EventEntry>>;
+    /// Hands every event which has been pending for at least `min_idle` to the consumer, whoever it was handed to.
+    async fn claim_idle_events(&mut self, consumer: &str, min_idle: Duration) -> Result<Vec<EventEntry>>;
+    /// How often a pending event has been handed out. `0` once it's acknowledged.
+    async fn delivery_count(&mut self, id: &str) -> Result<usize>;
+    async fn acknowledge_event(&mut self, id: &str) -> Result<()>;
+    /// The aliases of jobs as their state changes, from now on.
+    async fn job_updates(&mut self) -> Result<BoxStream<'static, String>>;
+}
+
+/// The configured storage backend.
+#[derive(Clone)]
+pub enum Storage {
+    Redis(RedisConnection),
+    Sqlite(SqliteStore),
+}
+
+impl Config {
+    /// The shared connection to the storage backend. If there's none yet, this waits until one could be established.
+    pub async fn connect_storage(&self) -> Storage {
+        match self.storage.backend {
+            StorageBackend::Redis => Storage::Redis(self.redis.connect().await),
+            StorageBackend::Sqlite => Storage::Sqlite(self.open_sqlite().await),
+        }
+    }
+
+    /// Replaces the shared connection to Redis, see [crate::RedisConfig::reconnect]. The SQLite database stays open.
+    pub async fn reconnect_storage(&self) -> Storage {
+        match self.storage.backend {
+            StorageBackend::Redis => Storage::Redis(self.redis.reconnect().await),
+            StorageBackend::Sqlite => Storage::Sqlite(self.open_sqlite().await),
+        }
+    }
+
+    /// A connection for blocking reads. SQLite doesn't hold on to the database while waiting, so it shares it.
+    pub async fn connect_storage_dedicated(&self) -> Result<Storage> {
+        match self.storage.backend {
+            StorageBackend::Redis => Ok(Storage::Redis(self.redis.connect_dedicated().await?)),
+            StorageBackend::Sqlite => Ok(Storage::Sqlite(self.open_sqlite().await)),
+        }
+    }
+
+    async fn open_sqlite(&self) -> SqliteStore {
+        SQLITE.get_or_init(async || loop {
+            match SqliteStore::open(&self.storage.sqlite).await {
+                Ok(store) => break store,
+                Err(err) => {
+                    log::warn!("Failed to open {path:?} - Retrying in {SQLITE_RETRY_INTERVAL:?}: {err}", path = self.storage.sqlite.path);
+                    tokio::time::sleep(SQLITE_RETRY_INTERVAL).await;
+                }
+            }
+        })
+            .await
+            .clone()
+    }
+}
+
+impl Storage {
+    /// Whether the backend answers.
+    pub async fn ping(&mut self) -> Result<()> {
+        match self {
+            Self::Redis(redis) => Ok(redis::cmd("PING").query_async(redis).await?),
+            Self::Sqlite(sqlite) => sqlite.ping().await,
+        }
+    }
+}
+
+macro_rules! dispatch {
+    ($self:ident, $store:ident => $call:expr) => {
+        match $self {
+            Storage::Redis($store) => $call.await,
+            Storage::Sqlite($store) => $call.await,
+        }
+    };
+}
+
+#[async_trait]
+impl JobStore for Storage {
+    async fn next_job_id(&mut self) -> Result<CsrId> {
+        dispatch!(self, store => store.next_job_id())
+    }
+
+    async fn get_job(&mut self, id: CsrId) -> Result<Option<Csr>> {
+        dispatch!(self, store => store.get_job(id))
+    }
+
+    async fn get_jobs(&mut self, ids: &[CsrId]) -> Result<Vec<Csr>> {
+        dispatch!(self, store => store.get_jobs(ids))
+    }
+
+    async fn set_job(&mut self, id: CsrId, job: &Csr) -> Result<()> {
+        dispatch!(self, store => store.set_job(id, job))
+    }
+
+    async fn create_job(&mut self, id: CsrId, job: &Csr) -> Result<()> {
+        dispatch!(self, store => store.create_job(id, job))
+    }
+
+    async fn list_jobs(&mut self, offset: usize, count: usize) -> Result<Vec<Csr>> {
+        dispatch!(self, store => store.list_jobs(offset, count))
+    }
+
+    async fn jobs_submitted_before(&mut self, timestamp: f64) -> Result<Vec<(CsrId, f64)>> {
+        dispatch!(self, store => store.jobs_submitted_before(timestamp))
+    }
+
+    async fn get_client_job(&mut self, alias: &str) -> Result<Option<ClientJob>> {
+        dispatch!(self, store => store.get_client_job(alias))
+    }
+
+    async fn get_client_jobs<T: AsRef<str>>(&mut self, aliases: impl Iterator<Item=T> + Send) -> Result<Vec<ClientJob>> {
+        dispatch!(self, store => store.get_client_jobs(aliases))
+    }
+
+    async fn set_client_job(&mut self, job: &ClientJob) -> Result<()> {
+        dispatch!(self, store => store.set_client_job(job))
+    }
+}
+
+#[async_trait]
+impl EventBus for Storage {
+    async fn dispatch_event<Event: CertmasterEvent + Send>(&mut self, event: Event) -> Result<()> {
+        dispatch!(self, store => store.dispatch_event(event))
+    }
+
+    async fn create_consumer_group(&mut self) -> Result<()> {
+        dispatch!(self, store => store.create_consumer_group())
+    }
+
+    async fn read_events(&mut self, consumer: &str, count: usize, block: Duration) -> Result<Vec<EventEntry>> {
+        dispatch!(self, store => store.read_events(consumer, count, block))
+    }
+
+    async fn read_pending_events(&mut self, consumer: &str) -> Result<Vec<EventEntry>> {
+        dispatch!(self, store => store.read_pending_events(consumer))
+    }
+
+    async fn claim_idle_events(&mut self, consumer: &str, min_idle: Duration) -> Result<Vec<EventEntry>> {
+        dispatch!(self, store => store.claim_idle_events(consumer, min_idle))
+    }
+
+    async fn delivery_count(&mut self, id: &str) -> Result<usize> {
+        dispatch!(self, store => store.delivery_count(id))
+    }
+
+    async fn acknowledge_event(&mut self, id: &str) -> Result<()> {
+        dispatch!(self, store => store.acknowledge_event(id))
+    }
+
+    async fn job_updates(&mut self) -> Result<BoxStream<'static, String>> {
+        dispatch!(self, store => store.job_updates())
+    }
+}
+
+#[async_trait]
+impl CertificateStore for Storage {
+    async fn store_certificate(&mut self, cert: &IssuedCertificate) -> Result<bool> {
+        dispatch!(self, store => store.store_certificate(cert))
+    }
+
+    async fn get_certificate(&mut self, serial: CsrId) -> Result<Option<IssuedCertificate>> {
+        dispatch!(self, store => store.get_certificate(serial))
+    }
+
+    async fn get_certificates(&mut self, serials: &[CsrId]) -> Result<Vec<IssuedCertificate>> {
+        dispatch!(self, store => store.get_certificates(serials))
+    }
+
+    async fn certificates_by_name(&mut self, name: &str) -> Result<Vec<IssuedCertificate>> {
+        dispatch!(self, store => store.certificates_by_name(name))
+    }
+
+    async fn certificates_by_issuer(&mut self, issuer: &str) -> Result<Vec<IssuedCertificate>> {
+        dispatch!(self, store => store.certificates_by_issuer(issuer))
+    }
+
+    async fn certificates_expiring(&mut self, from: i64, to: i64) -> Result<Vec<IssuedCertificate>> {
+        dispatch!(self, store => store.certificates_expiring(from, to))
+    }
+}
+
+#[async_trait]
+impl RevocationRegistry for Storage {
+    async fn record_revocation(&mut self, revocation: &RevokedCertificate) -> Result<bool> {
+        dispatch!(self, store => store.record_revocation(revocation))
+    }
+
+    async fn get_revocation(&mut self, serial: CsrId) -> Result<Option<RevokedCertificate>> {
+        dispatch!(self, store => store.get_revocation(serial))
+    }
+
+    async fn get_revocations(&mut self) -> Result<Vec<RevokedCertificate>> {
+        dispatch!(self, store => store.get_revocations())
+    }
+
+    async fn next_crl_number(&mut self) -> Result<u64> {
+        dispatch!(self, store => store.next_crl_number())
+    }
+
+    async fn publish_crl(&mut self, number: u64, der: &[u8], pem: &str) -> Result<bool> {
+        dispatch!(self, store => store.publish_crl(number, der, pem))
+    }
+
+    async fn get_crl_der(&mut self) -> Result<Option<Vec<u8>>> {
+        dispatch!(self, store => store.get_crl_der())
+    }
+
+    async fn get_crl_pem(&mut self) -> Result<Option<String>> {
+        dispatch!(self, store => store.get_crl_pem())
+    }
+}
+
+#[async_trait]
+impl DeadLetterQueue for Storage {
+    async fn dead_letter(&mut self, entry: &EventEntry, error: String, attempts: usize) -> Result<String> {
+        dispatch!(self, store => store.dead_letter(entry, error, attempts))
+    }
+
+    async fn get_dead_letters(&mut self) -> Result<Vec<DeadLetter>> {
+        dispatch!(self, store => store.get_dead_letters())
+    }
+
+    async fn get_dead_letter(&mut self, id: &str) -> Result<Option<DeadLetter>> {
+        dispatch!(self, store => store.get_dead_letter(id))
+    }
+
+    async fn replay_dead_letter(&mut self, id: &str) -> Result<Option<String>> {
+        dispatch!(self, store => store.replay_dead_letter(id))
+    }
+
+    async fn discard_dead_letter(&mut self, id: &str) -> Result<bool> {
+        dispatch!(self, store => store.discard_dead_letter(id))
+    }
+}
+
+#[async_trait]
+impl GarbageCollector for Storage {
+    async fn collect_garbage(&mut self, gc: &GcConfig, dry_run: bool) -> Result<GcReport> {
+        dispatch!(self, store => store.collect_garbage(gc, dry_run))
+    }
+}
+
+#[async_trait]
+impl AcmeStore for Storage {
+    async fn new_nonce(&mut self) -> Result<String> {
+        dispatch!(self, store => store.new_nonce())
+    }
+
+    async fn consume_nonce(&mut self, nonce: &str) -> Result<bool> {
+        dispatch!(self, store => store.consume_nonce(nonce))
+    }
+
+    async fn create_account(&mut self, account: &AcmeAccount) -> Result<bool> {
+        dispatch!(self, store => store.create_account(account))
+    }
+
+    async fn update_account(&mut self, account: &AcmeAccount) -> Result<()> {
+        dispatch!(self, store => store.update_account(account))
+    }
+
+    async fn get_account(&mut self, id: &str) -> Result<Option<AcmeAccount>> {
+        dispatch!(self, store => store.get_account(id))
+    }
+
+    async fn account_by_key(&mut self, thumbprint: &str) -> Result<Option<AcmeAccount>> {
+        dispatch!(self, store => store.account_by_key(thumbprint))
+    }
+
+    async fn change_account_key(&mut self, account: &AcmeAccount, old_thumbprint: &str) -> Result<bool> {
+        dispatch!(self, store => store.change_account_key(account, old_thumbprint))
+    }
+
+    async fn store_order(&mut self, order: &AcmeOrder) -> Result<()> {
+        dispatch!(self, store => store.store_order(order))
+    }
+
+    async fn get_order(&mut self, id: &str) -> Result<Option<AcmeOrder>> {
+        dispatch!(self, store => store.get_order(id))
+    }
+
+    async fn orders_of_account(&mut self, account: &str) -> Result<Vec<AcmeOrder>> {
+        dispatch!(self, store => store.orders_of_account(account))
+    }
+
+    async fn store_authorization(&mut self, authz: &AcmeAuthorization) -> Result<()> {
+        dispatch!(self, store => store.store_authorization(authz))
+    }
+
+    async fn get_authorization(&mut self, id: &str) -> Result<Option<AcmeAuthorization>> {
+        dispatch!(self, store => store.get_authorization(id))
+    }
+
+    async fn get_authorizations(&mut self, ids: &[String]) -> Result<Vec<AcmeAuthorization>> {
+        dispatch!(self, store => store.get_authorizations(ids))
+    }
+}
@@ -0,0 +1,256 @@
+use std::net::IpAddr;
+use async_trait::async_trait;
+use redis::{AsyncCommands, FromRedisValue};
+use redis_derive::FromRedisValue;
+use serde::{Deserialize, Serialize};
+use x509_parser::extensions::GeneralName;
+use crate::{CsrId, Key, PEMString, RedisConnection, Result};
+
+/// # Issued Certificate
+/// A signed certificate as it was handed to the client. It is written once under its serial and never modified.
+#[derive(Debug, Clone, FromRedisValue, Serialize, Deserialize)]
+pub struct IssuedCertificate {
+    pub serial: CsrId,
+    pub subject: String,
+    pub issuer: String,
+    pub dns_names: Vec<String>,
+    pub ip_addresses: Vec<IpAddr>,
+
+    /// Unix timestamps in seconds
+    pub not_before: i64,
+    pub not_after: i64,
+
+    pub profile: Option<String>,
+    pub pem: PEMString,
+}
+
+impl IssuedCertificate {
+    pub fn from_pem(serial: CsrId, profile: Option<String>, pem: PEMString) -> Result<Self> {
+        let (_, parsed) = x509_parser::pem::parse_x509_pem(pem.as_bytes())?;
+        let cert = parsed.parse_x509()?;
+
+        let mut dns_names = vec![];
+        let mut ip_addresses = vec![];
+
+        if let Some(san) = cert.subject_alternative_name()? {
+            for name in san.value.general_names.iter() {
+                match name {
+                    GeneralName::DNSName(name) => dns_names.push(name.to_ascii_lowercase()),
+                    GeneralName::IPAddress(ip) => if let Ok(ip) = <[u8; 4]>::try_from(*ip) {
+                        ip_addresses.push(IpAddr::from(ip));
+                    } else if let Ok(ip) = <[u8; 16]>::try_from(*ip) {
+                        ip_addresses.push(IpAddr::from(ip));
+                    },
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(Self {
+            serial,
+            subject: cert.subject().to_string(),
+            issuer: cert.issuer().to_string(),
+            dns_names,
+            ip_addresses,
+            not_before: cert.validity().not_before.timestamp(),
+            not_after: cert.validity().not_after.timestamp(),
+            profile,
+            pem,
+        })
+    }
+
+    /// Every name the certificate is indexed under.
+    pub fn names(&self) -> impl Iterator<Item = String> + '_ {
+        self.dns_names
+            .iter()
+            .cloned()
+            .chain(self.ip_addresses.iter().map(IpAddr::to_string))
+    }
+}
+
+/// Stores the certificate `ARGV[1]` under `KEYS[1]` unless another one holds the serial, then adds serial `ARGV[2]` to
+/// the expiry index `KEYS[2]` with score `ARGV[3]`, and to the sets `KEYS[3..]`. Returns 1 if the certificate is new.
+/// Storing the same certificate again restores any indexes a store before this script went without.
+const STORE_CERTIFICATE: &str = r"
+    local existing = redis.call('GET', KEYS[1])
+    if existing and existing ~= ARGV[1] then
+        return 0
+    end
+
+    if not existing then
+        redis.call('SET', KEYS[1], ARGV[1])
+    end
+
+    redis.call('ZADD', KEYS[2], ARGV[3], ARGV[2])
+    for i = 3, #KEYS do
+        redis.call('SADD', KEYS[i], ARGV[2])
+    end
+
+    if existing then
+        return 0
+    end
+    return 1
+";
+
+/// # Certificate Store
+/// The issued-certificate repository. Certificates are stored under `cert:{serial}`, with secondary indexes by expiry
+/// (`cert-expiry`), by DNS or IP name (`cert-san:{name}`) and by issuer (`cert-issuer:{issuer}`).
+#[async_trait]
+pub trait CertificateStore {
+    /// Writes the certificate and its indexes in one step. Returns `false` without changing anything if the serial is
+    /// already taken.
+    async fn store_certificate(&mut self, cert: &IssuedCertificate) -> Result<bool>;
+    async fn get_certificate(&mut self, serial: CsrId) -> Result<Option<IssuedCertificate>>;
+    async fn get_certificates(&mut self, serials: &[CsrId]) -> Result<Vec<IssuedCertificate>>;
+    async fn certificates_by_name(&mut self, name: &str) -> Result<Vec<IssuedCertificate>>;
+    async fn certificates_by_issuer(&mut self, issuer: &str) -> Result<Vec<IssuedCertificate>>;
+    /// Certificates whose `notAfter` lies within the given range of Unix timestamps.
+    async fn certificates_expiring(&mut self, from: i64, to: i64) -> Result<Vec<IssuedCertificate>>;
+}
+
+#[async_trait]
+impl CertificateStore for RedisConnection {
+    async fn store_certificate(&mut self, cert: &IssuedCertificate) -> Result<bool> {
+        // Writing the certificate and its indexes separately could leave it stored, yet impossible to find
+        let script = redis::Script::new(STORE_CERTIFICATE);
+        let mut invocation = script.prepare_invoke();
+        invocation.key(Key::Certificate(cert.serial))
+            .key(Key::CertificateExpiry)
+            .key(Key::CertificatesByIssuer(&cert.issuer));
+
+        for name in cert.names() {
+            invocation.key(Key::CertificatesByName(&name));
+        }
+
+        let stored: bool = invocation.arg(ron::to_string(cert)?)
+            .arg(cert.serial)
+            .arg(cert.not_after)
+            .invoke_async(self)
+            .await?;
+
+        Ok(stored)
+    }
+
+    async fn get_certificate(&mut self, serial: CsrId) -> Result<Option<IssuedCertificate>> {
+        Ok(self.get(Key::Certificate(serial)).await?)
+    }
+
+    async fn get_certificates(&mut self, serials: &[CsrId]) -> Result<Vec<IssuedCertificate>> {
+        if serials.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let keys = serials
+            .iter()
+            .map(|serial| Key::Certificate(*serial))
+            .collect::<Vec<_>>();
+
+        let certs: Vec<Option<IssuedCertificate>> = self.mget(keys).await?;
+
+        Ok(certs.into_iter().flatten().collect())
+    }
+
+    async fn certificates_by_name(&mut self, name: &str) -> Result<Vec<IssuedCertificate>> {
+        let serials: Vec<CsrId> = self.smembers(Key::CertificatesByName(&name.to_ascii_lowercase()))
+            .await?;
+
+        self.get_certificates(&serials).await
+    }
+
+    async fn certificates_by_issuer(&mut self, issuer: &str) -> Result<Vec<IssuedCertificate>> {
+        let serials: Vec<CsrId> = self.smembers(Key::CertificatesByIssuer(issuer))
+            .await?;
+
+        self.get_certificates(&serials).await
+    }
+
+    async fn certificates_expiring(&mut self, from: i64, to: i64) -> Result<Vec<IssuedCertificate>> {
+        let serials: Vec<CsrId> = self.zrangebyscore(Key::CertificateExpiry, from, to)
+            .await?;
+
+        self.get_certificates(&serials).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RedisConfig;
+
+    /// A connection to the Redis server at `CERTMASTER_TEST_REDIS`, or on localhost.
+    async fn connect() -> RedisConnection {
+        let config = RedisConfig {
+            url: std::env::var("CERTMASTER_TEST_REDIS").unwrap_or_else(|_| "redis://127.0.0.1".to_owned()),
+            ..Default::default()
+        };
+
+        config.connect_dedicated().await.expect("Failed to connect to Redis")
+    }
+
+    fn certificate(serial: CsrId, name: &str) -> IssuedCertificate {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let pem = rcgen::CertificateParams::new(vec![name.to_owned()]).unwrap()
+            .self_signed(&key).unwrap()
+            .pem();
+
+        IssuedCertificate::from_pem(serial, None, pem).unwrap()
+    }
+
+    fn serials(found: Result<Vec<IssuedCertificate>>) -> Vec<CsrId> {
+        found.unwrap().iter().map(|cert| cert.serial).collect()
+    }
+
+    async fn remove(redis: &mut RedisConnection, cert: &IssuedCertificate) {
+        let _: () = redis::pipe()
+            .del(Key::Certificate(cert.serial))
+            .zrem(Key::CertificateExpiry, cert.serial)
+            .srem(Key::CertificatesByIssuer(&cert.issuer), cert.serial)
+            .srem(Key::CertificatesByName(&cert.dns_names[0]), cert.serial)
+            .query_async(redis)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server"]
+    async fn certificates_are_stored_together_with_their_indexes() {
+        let mut redis = connect().await;
+        let serial = (1 << 61) + std::process::id() as CsrId;
+
+        let cert = certificate(serial, "stored.certmaster.test");
+        remove(&mut redis, &cert).await;
+
+        assert!(redis.store_certificate(&cert).await.unwrap());
+        assert_eq!(serials(redis.certificates_by_name("stored.certmaster.test").await), vec![serial]);
+        assert!(serials(redis.certificates_by_issuer(&cert.issuer).await).contains(&serial));
+        assert!(serials(redis.certificates_expiring(cert.not_after, cert.not_after).await).contains(&serial));
+
+        let other = certificate(serial, "other.certmaster.test");
+        assert!(!redis.store_certificate(&other).await.unwrap());
+        assert_eq!(redis.get_certificate(serial).await.unwrap().unwrap().pem, cert.pem);
+        assert!(serials(redis.certificates_by_name("other.certmaster.test").await).is_empty());
+
+        remove(&mut redis, &cert).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server"]
+    async fn storing_a_certificate_again_restores_its_indexes() {
+        let mut redis = connect().await;
+        let serial = (1 << 61) + (1 << 40) + std::process::id() as CsrId;
+
+        let cert = certificate(serial, "restored.certmaster.test");
+        remove(&mut redis, &cert).await;
+
+        // What a store interrupted between the certificate and its indexes used to leave behind
+        let _: () = redis.set(Key::Certificate(serial), ron::to_string(&cert).unwrap()).await.unwrap();
+        assert!(serials(redis.certificates_by_name("restored.certmaster.test").await).is_empty());
+
+        assert!(!redis.store_certificate(&cert).await.unwrap());
+        assert_eq!(serials(redis.certificates_by_name("restored.certmaster.test").await), vec![serial]);
+        assert!(serials(redis.certificates_by_issuer(&cert.issuer).await).contains(&serial));
+        assert!(serials(redis.certificates_expiring(cert.not_after, cert.not_after).await).contains(&serial));
+
+        remove(&mut redis, &cert).await;
+    }
+}
@@ -0,0 +1,69 @@
+//! # Authority Reload
+//! Keeps the cached CA key and certificate in step with the files they were loaded from, so they can be rotated
+//! without a restart. The OCSP responder is reloaded along with them, so its files are watched too.
+
+use std::collections::HashSet;
+use std::time::Duration;
+use notify::Watcher;
+use tokio::sync::mpsc;
+use common::{Authority, Error, Result};
+
+/// Rotations usually replace the certificate and the key one after the other. Both should be in place before
+/// reloading.
+const RELOAD_DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Reloads the authority whenever its key or certificate, or those of the OCSP responder, change, until shutdown. A
+/// pair which fails to load, for example because the key doesn't match the certificate, leaves the previous authority
+/// in place.
+pub async fn reload_on_change() -> Result<()> {
+    let config = common::get_config();
+    let paths = [Some(&config.ca.certificate), Some(&config.ca.key), config.ocsp.certificate.as_ref(), config.ocsp.key.as_ref()]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+
+    let files = paths
+        .iter()
+        .filter_map(|path| path.file_name())
+        .map(ToOwned::to_owned)
+        .collect::<HashSet<_>>();
+
+    let (tx, rx) = mpsc::channel(16);
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+        Ok(event) if event.paths.iter().any(|path| path.file_name().is_some_and(|name| files.contains(name))) => {
+            let _ = tx.try_send(());
+        },
+        Ok(_) => {},
+        Err(err) => log::warn!("Failed to watch the CA files: {err:?}"),
+    })
+        .map_err(|err| Error::other(format!("Failed to watch the CA files: {err}")))?;
+
+    // Files replaced by renaming a new one over them would take a watch on the file itself with them
+    let dirs = paths
+        .iter()
+        .filter_map(|path| path.parent())
+        .collect::<HashSet<_>>();
+
+    for dir in dirs {
+        watcher.watch(dir, notify::RecursiveMode::NonRecursive)
+            .map_err(|err| Error::other(format!("Failed to watch {dir:?}: {err}")))?;
+    }
+
+    let mut rx = common::debounce(rx, RELOAD_DEBOUNCE);
+    loop {
+        tokio::select! {
+            changed = rx.recv() => if changed.is_none() {
+                break;
+            },
+            () = crate::shutdown::token().cancelled() => break,
+        }
+
+        match Authority::init(&config.ca).await {
+            Ok(_) => log::info!("Reloaded the CA key and certificate"),
+            Err(err) => log::error!("Failed to reload the CA key and certificate - Keeping the previous ones: {err:?}"),
+        }
+    }
+
+    drop(watcher);
+    Ok(())
+}
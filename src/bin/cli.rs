@@ -3,7 +3,7 @@
 use futures_util::{
     stream::StreamExt
 };
-use common::{RedisUtils, JobStatus, Result, Error, JobProgress, NewCsr, ClientJob, Status, PEMString};
+use common::{ChallengeReview, ChallengeType, DeadLetterQueue, EventBus, GarbageCollector, JobStore, ReviewDecision, CertificateStore, IssuedCertificate, Revocation, RevocationReason, RevocationRegistry, Result, Error, NewCsr, ClientJob, Status, PEMString};
 use rcgen::{string::Ia5String, Certificate, CertificateParams, DnType, SanType};
 use std::{
     str::FromStr,
@@ -15,7 +15,6 @@ use std::{
     sync::LazyLock
 };
 use std::collections::{HashSet, VecDeque};
-use redis::AsyncCommands;
 use tokio::{
     io::BufWriter,
     io::BufReader,
@@ -82,15 +81,7 @@ impl Prompt {
 pub async fn main() -> Result<()> {
     env_logger::init();
 
-    let config = common::read_config().await;
-
-    let mut redis = config.redis.connect().await;
-    let _: () = ::redis::cmd("CONFIG")
-        .arg("SET")
-        .arg("notify-keyspace-events")
-        .arg("KA")
-        .query_async(&mut redis)
-        .await?;
+    common::read_config().await;
 
     let prompt = Prompt::new().await?;
 
@@ -114,6 +105,10 @@ async fn handle_command(mut args: SplitWhitespace<'_>) -> Result<String> {
         Some("echo") => echo(args).await?,
         Some("challenge") => handle_challenge(args).await?,
         Some("request") => handle_request(args).await?,
+        Some("cert") => handle_cert(args).await?,
+        Some("revoke") => handle_revoke(args).await?,
+        Some("gc") => handle_gc(args).await?,
+        Some("dead-letter") => handle_dead_letter(args).await?,
         Some("exit") | Some("quit") => {
             std::process::exit(0);
         }
@@ -132,42 +127,264 @@ async fn echo(args: impl Iterator<Item = impl AsRef<str>>) -> Result<String> {
 
 async fn handle_challenge(mut args: impl Iterator<Item = impl AsRef<str>>) -> Result<String> {
     let config = common::get_config();
-    let mut redis = config.redis.connect().await;
+    let mut storage = config.connect_storage().await;
+
+    let decision = match args.next().as_ref().map(|i| i.as_ref()) {
+        Some("pass") => ReviewDecision::Approve,
+        Some("reject") => ReviewDecision::Reject,
+        _ => return Error::custom("Invalid Syntax"),
+    };
+
+    // Job IDs come first, up to `--` or the first word of the reason
+    let mut ids = vec![];
+    let mut reason = vec![];
+
+    for arg in args.by_ref() {
+        match arg.as_ref().parse::<u64>() {
+            Ok(id) if ids.contains(&id) => {},
+            Ok(id) => ids.push(id),
+            Err(_) if arg.as_ref() == "--" => break,
+            Err(_) => {
+                reason.push(arg.as_ref().to_owned());
+                break;
+            },
+        }
+    }
+
+    reason.extend(args.map(|arg| arg.as_ref().to_owned()));
+    let reason = reason.join(" ");
+
+    if ids.is_empty() {
+        return Error::custom("Usage: challenge <pass|reject> <id>... [--] <reason>");
+    }
+
+    if reason.is_empty() {
+        return Error::custom("A reason is required");
+    }
+
+    let reviewer = operator();
+
+    // Every job is checked before any is decided on, so a typo doesn't leave the others half-reviewed
+    for &id in &ids {
+        match storage.get_job(id).await? {
+            Some(csr) if decision == ReviewDecision::Approve && csr.approvers().contains(reviewer.as_str()) => {
+                return Error::custom(format!("{reviewer} has already approved job {id}"));
+            },
+            Some(_) => {},
+            None => return Error::custom(format!("No job with ID {id}")),
+        }
+    }
+
+    for id in ids {
+        log::info!("Deciding to {decision:?} challenge {id}");
+        storage
+            .dispatch_event(ChallengeReview {
+                id,
+                decision,
+                reason: reason.clone(),
+                reviewer: reviewer.clone(),
+            })
+            .await?;
+    }
+
+    Ok(EMPTY)
+}
+
+async fn handle_cert(mut args: impl Iterator<Item = impl AsRef<str>>) -> Result<String> {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
 
     let cmd = args.next().map(|i| i.as_ref().to_owned());
-    Ok(match cmd.as_ref().map(|i| i.as_ref()) {
-        Some("pass") => {
-            for id in args.map_while(|id| id.as_ref().parse::<u64>().ok()) {
-                log::info!("Passing challenge {id}");
-                redis
-                    .dispatch_event(JobProgress {
-                        id,
-                        status: JobStatus::ChallengePassed,
-                    })
-                    .await?;
-            }
+    let certificates = match cmd.as_ref().map(|i| i.as_ref()) {
+        Some("show") => {
+            let serials = args
+                .map(|id| id.as_ref().parse::<u64>())
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(|err| Error::other(format!("Invalid serial: {err}")))?;
+
+            return Ok(storage.get_certificates(&serials)
+                .await?
+                .into_iter()
+                .map(|cert| cert.pem)
+                .collect::<Vec<_>>()
+                .join("\n"));
+        }
+        Some("name") => {
+            let Some(name) = args.next() else {
+                return Error::custom("Expected a DNS name or IP address");
+            };
 
-            ""
+            storage.certificates_by_name(name.as_ref()).await?
+        }
+        Some("issuer") => {
+            let issuer = args.fold(String::new(), |mut a, i| {
+                if !a.is_empty() {
+                    a.push(' ');
+                }
+                a.push_str(i.as_ref());
+                a
+            });
+
+            storage.certificates_by_issuer(&issuer).await?
+        }
+        Some("expiring") => {
+            let days = match args.next().map(|i| i.as_ref().parse::<i64>()) {
+                Some(Ok(days)) => days,
+                Some(Err(err)) => return Error::custom(format!("Invalid number of days: {err}")),
+                None => return Error::custom("Expected a number of days"),
+            };
+
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs() as i64;
+
+            storage.certificates_expiring(now, now + days * 86400).await?
         }
         _ => return Error::custom("Invalid Syntax"),
+    };
+
+    Ok(certificates
+        .iter()
+        .map(|IssuedCertificate { serial, subject, not_after, .. }| format!("{serial}\t{not_after}\t{subject}"))
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+async fn handle_revoke(mut args: impl Iterator<Item = impl AsRef<str>>) -> Result<String> {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let (Some(serial), Some(reason)) = (args.next(), args.next()) else {
+        return Error::custom("Usage: revoke <serial> <reason> [invalidity date]");
+    };
+
+    let serial = serial.as_ref()
+        .parse::<u64>()
+        .map_err(|err| Error::other(format!("Invalid serial: {err}")))?;
+    let reason = reason.as_ref().parse::<RevocationReason>()?;
+    if !reason.can_revoke() {
+        return Error::custom("remove-from-crl is not a revocation reason");
     }
-    .to_owned())
+    let invalidity_date = args.next()
+        .map(|date| date.as_ref().parse::<i64>())
+        .transpose()
+        .map_err(|err| Error::other(format!("Invalid invalidity date: {err}")))?;
+
+    if storage.get_certificate(serial).await?.is_none() {
+        return Error::custom(format!("No certificate with serial {serial} has been issued"));
+    }
+
+    if storage.get_revocation(serial).await?.is_some() {
+        return Error::custom(format!("Certificate {serial} has already been revoked"));
+    }
+
+    storage.dispatch_event(Revocation {
+        serial,
+        reason,
+        invalidity_date,
+        requested_by: operator(),
+    }).await?;
+
+    log::info!("Revoking certificate {serial}");
+
+    Ok(EMPTY)
+}
+
+async fn handle_gc(mut args: impl Iterator<Item = impl AsRef<str>>) -> Result<String> {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let dry_run = match args.next().as_ref().map(|i| i.as_ref()) {
+        Some("dry-run") => true,
+        None => config.gc.dry_run,
+        _ => return Error::custom("Usage: gc [dry-run]"),
+    };
+
+    let report = storage.collect_garbage(&config.gc, dry_run).await?;
+
+    let verb = if report.dry_run { "Would delete" } else { "Deleted" };
+    let mut lines = report.jobs
+        .iter()
+        .chain(report.aliases.iter())
+        .map(|key| format!("{verb}\t{key}"))
+        .collect::<Vec<_>>();
+
+    match (&report.stream_min_id, report.stream_entries_trimmed) {
+        (Some(min_id), Some(trimmed)) => lines.push(format!("Trimmed {trimmed} events before {min_id}")),
+        (Some(min_id), None) => lines.push(format!("Would trim the events before {min_id}")),
+        (None, _) => {},
+    }
+
+    Ok(lines.join("\n"))
+}
+
+async fn handle_dead_letter(mut args: impl Iterator<Item = impl AsRef<str>>) -> Result<String> {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let cmd = args.next().map(|i| i.as_ref().to_owned());
+    let id = args.next().map(|i| i.as_ref().to_owned());
+
+    Ok(match (cmd.as_deref(), id) {
+        (Some("list"), None) => storage.get_dead_letters()
+            .await?
+            .into_iter()
+            .map(|letter| format!("{id}\t{event}\t{attempts} attempts\t{error}",
+                id = letter.id,
+                event = letter.fields.first().map(|(name, _)| name.as_str()).unwrap_or_default(),
+                attempts = letter.attempts,
+                error = letter.error.lines().next().unwrap_or_default()))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        (Some("show"), Some(id)) => match storage.get_dead_letter(&id).await? {
+            Some(letter) => format!("{letter:#?}"),
+            None => return Error::custom(format!("No dead letter with ID {id}")),
+        },
+        (Some("replay"), Some(id)) => match storage.replay_dead_letter(&id).await? {
+            Some(event_id) => format!("Replayed {id} as event {event_id}"),
+            None => return Error::custom(format!("No dead letter with ID {id}")),
+        },
+        (Some("discard"), Some(id)) => match storage.discard_dead_letter(&id).await? {
+            true => format!("Discarded {id}"),
+            false => return Error::custom(format!("No dead letter with ID {id}")),
+        },
+        _ => return Error::custom("Usage: dead-letter <list | show <id> | replay <id> | discard <id>>"),
+    })
 }
 
 async fn handle_request(mut args: impl Iterator<Item = impl AsRef<str>>) -> Result<String> {
     let config = common::get_config();
-    let mut redis = config.redis.connect().await;
+    let mut storage = config.connect_storage().await;
 
     let cmd = args.next().map(|i| i.as_ref().to_owned());
     Ok(match cmd.as_ref().map(|i| i.as_ref()) {
         Some("submit") => {
+            let mut args = args.peekable();
+            let mut profile = None;
+            let mut challenge = None;
+
+            while let Some(opt) = args.next_if(|i| i.as_ref().starts_with('-')) {
+                let Some(arg) = args.next() else {
+                    return Error::custom(format!("Expected argument after {opt}", opt=opt.as_ref()));
+                };
+
+                match opt.as_ref() {
+                    "-profile" => profile = Some(arg.as_ref().to_owned()),
+                    "-challenge" => challenge = Some(arg.as_ref().parse::<ChallengeType>()?),
+                    opt => return Error::custom(format!("Unrecognised option {opt}")),
+                }
+            }
+
             for path in args.map(|i| PathBuf::from(i.as_ref())) {
                 let pem = tokio::fs::read_to_string(&path).await?;
                 let client_id = SEQ.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-                redis
+                storage
                     .dispatch_event(NewCsr {
                         client_id,
                         pem: pem.clone(),
+                        profile: profile.clone(),
+                        challenge,
+                        challenges: vec![],
                     })
                     .await?;
 
@@ -189,11 +406,13 @@ async fn handle_request(mut args: impl Iterator<Item = impl AsRef<str>>) -> Resu
 
 async fn new_request(mut args: impl Iterator<Item = impl AsRef<str>>) -> Result<()> {
     let config = common::get_config();
-    let mut redis = config.redis.connect().await;
+    let mut storage = config.connect_storage().await;
 
     let mut cert = CertificateParams::default();
 
     let mut key = None;
+    let mut profile = None;
+    let mut challenge = None;
 
     let mut detach = false;
 
@@ -265,6 +484,20 @@ async fn new_request(mut args: impl Iterator<Item = impl AsRef<str>>) -> Result<
                     arg.as_ref(),
                 )?))
             },
+            "-profile" => {
+                let Some(arg) = args.next() else {
+                    return Error::custom("Expected argument after -profile");
+                };
+
+                profile.replace(arg.as_ref().to_owned());
+            },
+            "-challenge" => {
+                let Some(arg) = args.next() else {
+                    return Error::custom("Expected argument after -challenge");
+                };
+
+                challenge.replace(arg.as_ref().parse::<ChallengeType>()?);
+            },
             "-async" => detach = true,
             opt => log::warn!("unrecognised option {opt}"),
         };
@@ -280,9 +513,12 @@ async fn new_request(mut args: impl Iterator<Item = impl AsRef<str>>) -> Result<
     let pem = cert.serialize_request(&key)?.pem()?;
     let alt = common::get_alt_name(client_id, &pem);
 
-    redis.dispatch_event(NewCsr {
+    storage.dispatch_event(NewCsr {
         client_id,
         pem,
+        profile,
+        challenge,
+        challenges: vec![],
     }).await?;
 
     if detach {
@@ -300,35 +536,28 @@ async fn new_request(mut args: impl Iterator<Item = impl AsRef<str>>) -> Result<
 async fn wait_for_completion(args: impl Iterator<Item = impl AsRef<str>>) -> Result<HashMap<u64, PEMString>> {
     let config = common::get_config();
 
-    let mut redis = config.redis.connect().await;
-    let mut stream = redis::Client::open(config.redis.url.as_ref())?
-        .get_async_pubsub()
-        .await?;
-
-    let mut jobs = HashSet::new();
+    let mut storage = config.connect_storage().await;
+    let mut updates = storage.job_updates().await?;
 
-    for arg in args {
-        let name = arg.as_ref();
-        let arg = format!("__keyspace@0__:alt:{name}");
-        let _ : () = stream.subscribe(&arg).await?;
-
-        jobs.insert(name.to_owned());
-    }
+    let mut jobs = args
+        .map(|arg| arg.as_ref().to_owned())
+        .collect::<HashSet<_>>();
 
     let mut certificates = HashMap::new();
 
-    let mut stream = stream.on_message();
-    while let Some(event) = stream.next().await {
-        let channel = event.get_channel_name().trim_start_matches("__keyspace@0__:");
-        let status: ClientJob = redis.get(channel).await?;
-        // log::debug!("Change: {channel} => {status:#?}");
+    while let Some(alias) = updates.next().await {
+        if !jobs.contains(&alias) {
+            continue;
+        }
+
+        let Some(status) = storage.get_client_job(&alias).await? else {
+            continue;
+        };
+        // log::debug!("Change: {alias} => {status:#?}");
 
         if let ClientJob { alias, status: Status::Success { certificate }, client_id, .. } = status {
-            if jobs.remove(&alias) {
-                certificates.insert(client_id, certificate);
-            } else {
-                log::warn!("Certificate {client_id} was not expected");
-            }
+            jobs.remove(&alias);
+            certificates.insert(client_id, certificate);
 
             if jobs.is_empty() {
                 break;
@@ -338,3 +567,21 @@ async fn wait_for_completion(args: impl Iterator<Item = impl AsRef<str>>) -> Res
 
     Ok(certificates)
 }
+
+/// The account running the CLI, as recorded against revocations and reviews. It's looked up from the process' user ID
+/// rather than `$USER`, which anyone can set.
+fn operator() -> String {
+    use std::os::unix::fs::MetadataExt;
+
+    let Ok(uid) = std::fs::metadata("/proc/self").map(|meta| meta.uid()) else {
+        return "cli".to_owned();
+    };
+
+    std::fs::read_to_string("/etc/passwd")
+        .ok()
+        .and_then(|passwd| passwd.lines()
+            .map(|line| line.split(':').collect::<Vec<_>>())
+            .find(|fields| fields.get(2).and_then(|id| id.parse::<u32>().ok()) == Some(uid))
+            .and_then(|fields| fields.first().map(|name| name.to_string())))
+        .unwrap_or_else(|| format!("uid:{uid}"))
+}
@@ -0,0 +1,20 @@
+#[actix_web::main]
+pub async fn main() {
+    env_logger::init();
+
+    log::info!("Starting web API");
+
+    let config = common::read_config().await;
+
+    common::Authority::init(&config.ca)
+        .await
+        .expect("Failed to load the CA key and certificate");
+
+    tokio::spawn(certmaster::authority::reload_on_change());
+
+    if let Some(result) = certmaster::shutdown::graceful(certmaster::web::run()).await {
+        result.expect("Web API died");
+    }
+
+    drop(config);
+}
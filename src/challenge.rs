@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use ring::digest::{digest, SHA256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use common::{ChallengeConfig, ChallengeType, Dns01Config, NameChallenge};
+
+/// Challenge responses are a single key authorization. Anything beyond this is not read.
+const MAX_RESPONSE_SIZE: u64 = 8192;
+
+/// The ALPN protocol of [RFC 8737](https://www.rfc-editor.org/rfc/rfc8737) challenge responses.
+const ACME_TLS_PROTOCOL: &str = "acme-tls/1";
+/// id-pe-acmeIdentifier
+const OID_ACME_IDENTIFIER: &str = "1.3.6.1.5.5.7.1.31";
+
+/// The outcome of checking a single challenge.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum Check {
+    Passed,
+    Failed(String),
+    /// The dns-01 record isn't there yet. It's worth looking again until the deadline.
+    Pending(String),
+}
+
+impl Check {
+    /// A check still pending by the deadline has failed.
+    pub(crate) fn by_deadline(self, now: i64, deadline: i64) -> Check {
+        match self {
+            Check::Pending(reason) if now >= deadline => Check::Failed(format!("{reason} by the deadline")),
+            check => check,
+        }
+    }
+}
+
+/// Checks a challenge once. http-01 and tls-alpn-01 challenges are retried as configured before they fail, while
+/// dns-01 records which can't be found are left for the caller to poll for.
+pub(crate) async fn check(config: &ChallengeConfig, challenge: &NameChallenge) -> Check {
+    let result = match challenge.kind {
+        ChallengeType::Http01 => http01(config, challenge).await,
+        ChallengeType::Dns01 => return dns01(&config.dns01, challenge).await,
+        ChallengeType::TlsAlpn01 => tls_alpn01(config, challenge).await,
+    };
+
+    match result {
+        Ok(()) => Check::Passed,
+        Err(reason) => Check::Failed(reason),
+    }
+}
+
+/// The addresses a name is contacted under. Names listed in the configured hosts take precedence over the system
+/// resolver.
+pub(crate) async fn resolve(hosts: &HashMap<String, IpAddr>, name: &str, port: u16) -> Result<Vec<SocketAddr>, String> {
+    if let Some(ip) = hosts.get(name) {
+        return Ok(vec![SocketAddr::new(*ip, port)]);
+    }
+
+    if let Ok(ip) = name.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+
+    let addresses = tokio::net::lookup_host((name, port))
+        .await
+        .map_err(|err| format!("Failed to resolve {name}: {err}"))?
+        .collect::<Vec<_>>();
+
+    if addresses.is_empty() {
+        return Err(format!("{name} doesn't resolve to any address"));
+    }
+
+    Ok(addresses)
+}
+
+/// Fetches the key authorization from `http://{name}/.well-known/acme-challenge/{token}`. Redirects are not followed.
+async fn http01(config: &ChallengeConfig, challenge: &NameChallenge) -> Result<(), String> {
+    let hosts = &config.hosts;
+    let config = &config.http01;
+    let timeout = Duration::from_secs(config.timeout_secs);
+
+    let mut reason = String::new();
+    for attempt in 1..=config.attempts.max(1) {
+        if attempt > 1 {
+            tokio::time::sleep(Duration::from_secs(config.retry_interval_secs)).await;
+        }
+
+        reason = match tokio::time::timeout(timeout, http_get(hosts, &challenge.name, config.port, &challenge.http01_path())).await {
+            Ok(Ok(body)) if body.trim_end() == challenge.key_authorization => return Ok(()),
+            Ok(Ok(_)) => "The response doesn't contain the expected key authorization".to_owned(),
+            Ok(Err(err)) => err,
+            Err(_) => format!("No response within {secs}s", secs = config.timeout_secs),
+        };
+
+        log::debug!("Attempt {attempt} at the http-01 challenge for {name} failed: {reason}", name = challenge.name);
+    }
+
+    Err(reason)
+}
+
+/// Looks for a value of the name's `_acme-challenge` TXT record which is the digest of the key authorization. Until
+/// there is one, the challenge is pending, as records can take a while to propagate.
+async fn dns01(config: &Dns01Config, challenge: &NameChallenge) -> Check {
+    let record = challenge.dns01_record();
+
+    match crate::dns::lookup_txt(config, &record.name).await {
+        Ok(values) if values.iter().any(|value| value.trim() == record.value) => Check::Passed,
+        Ok(values) if values.is_empty() => Check::Pending(format!("{name} has no TXT records", name = record.name)),
+        Ok(_) => Check::Pending(format!("No TXT record of {name} holds the expected digest", name = record.name)),
+        Err(err) => Check::Pending(err),
+    }
+}
+
+/// Asks for the name over the `acme-tls/1` protocol and checks the certificate the server presents in response.
+async fn tls_alpn01(config: &ChallengeConfig, challenge: &NameChallenge) -> Result<(), String> {
+    let hosts = &config.hosts;
+    let config = &config.tls_alpn01;
+    let timeout = Duration::from_secs(config.timeout_secs);
+
+    let mut reason = String::new();
+    for attempt in 1..=config.attempts.max(1) {
+        if attempt > 1 {
+            tokio::time::sleep(Duration::from_secs(config.retry_interval_secs)).await;
+        }
+
+        let certificate = async {
+            let addresses = resolve(hosts, &challenge.name, config.port).await?;
+            let mut reason = String::new();
+
+            for address in addresses {
+                match crate::tls::peer_certificate(address, &challenge.name, ACME_TLS_PROTOCOL).await {
+                    Ok(certificate) => return Ok(certificate),
+                    Err(err) => reason = err,
+                }
+            }
+
+            Err(reason)
+        };
+
+        reason = match tokio::time::timeout(timeout, certificate).await {
+            Ok(Ok(certificate)) => match verify_acme_certificate(&certificate, challenge) {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            },
+            Ok(Err(err)) => err,
+            Err(_) => format!("No certificate within {secs}s", secs = config.timeout_secs),
+        };
+
+        log::debug!("Attempt {attempt} at the tls-alpn-01 challenge for {name} failed: {reason}", name = challenge.name);
+    }
+
+    Err(reason)
+}
+
+/// The certificate has to name nothing but the name being validated, and carry the digest of the key authorization in
+/// a critical `id-pe-acmeIdentifier` extension.
+fn verify_acme_certificate(der: &[u8], challenge: &NameChallenge) -> Result<(), String> {
+    let (_, certificate) = x509_parser::parse_x509_certificate(der)
+        .map_err(|err| format!("The server presented an invalid certificate: {err}"))?;
+
+    let names = certificate.subject_alternative_name()
+        .map_err(|err| format!("The certificate's subject alternative names are invalid: {err}"))?
+        .map(|san| san.value.general_names.clone())
+        .unwrap_or_default();
+
+    match names.as_slice() {
+        [x509_parser::extensions::GeneralName::DNSName(name)] if name.eq_ignore_ascii_case(&challenge.name) => {},
+        _ => return Err(format!("The certificate doesn't name {name} and nothing else", name = challenge.name)),
+    }
+
+    let Some(extension) = certificate.extensions()
+        .iter()
+        .find(|extension| extension.oid.to_id_string() == OID_ACME_IDENTIFIER) else {
+        return Err("The certificate lacks the acmeIdentifier extension".to_owned());
+    };
+
+    if !extension.critical {
+        return Err("The acmeIdentifier extension isn't marked critical".to_owned());
+    }
+
+    let mut expected = vec![0x04, 32];
+    expected.extend_from_slice(digest(&SHA256, challenge.key_authorization.as_bytes()).as_ref());
+
+    if extension.value != expected.as_slice() {
+        return Err("The acmeIdentifier extension doesn't match the key authorization".to_owned());
+    }
+
+    Ok(())
+}
+
+/// A plain HTTP/1.0 GET, so the response comes neither chunked nor kept alive. Only `200 OK` responses are accepted.
+async fn http_get(hosts: &HashMap<String, IpAddr>, name: &str, port: u16, path: &str) -> Result<String, String> {
+    let addresses = resolve(hosts, name, port).await?;
+    let mut stream = TcpStream::connect(addresses.as_slice())
+        .await
+        .map_err(|err| format!("Failed to connect to {name}:{port}: {err}"))?;
+
+    let host = match (name.parse::<IpAddr>(), port) {
+        (Ok(IpAddr::V6(ip)), 80) => format!("[{ip}]"),
+        (Ok(IpAddr::V6(ip)), port) => format!("[{ip}]:{port}"),
+        (_, 80) => name.to_owned(),
+        (_, port) => format!("{name}:{port}"),
+    };
+
+    let request = format!("GET {path} HTTP/1.0\r\nHost: {host}\r\nUser-Agent: certmaster/{version}\r\nAccept: */*\r\n\r\n", version = env!("CARGO_PKG_VERSION"));
+    stream.write_all(request.as_bytes())
+        .await
+        .map_err(|err| format!("Failed to send request: {err}"))?;
+
+    let mut response = vec![];
+    stream.take(MAX_RESPONSE_SIZE)
+        .read_to_end(&mut response)
+        .await
+        .map_err(|err| format!("Failed to read response: {err}"))?;
+
+    let response = String::from_utf8_lossy(&response);
+    let Some((head, body)) = response.split_once("\r\n\r\n") else {
+        return Err("Malformed HTTP response".to_owned());
+    };
+
+    match head.split_whitespace().nth(1) {
+        Some("200") => Ok(body.to_owned()),
+        Some(status) => Err(format!("The server responded with status {status}")),
+        None => Err("Malformed HTTP response".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::Http01Config;
+    use tokio::io::AsyncBufReadExt;
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    /// Answers a single request with the given response, and hands back the request line and `Host` header.
+    async fn serve_once(response: String) -> (u16, JoinHandle<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = tokio::io::BufReader::new(stream);
+
+            let mut request_line = String::new();
+            stream.read_line(&mut request_line).await.unwrap();
+
+            let mut host = String::new();
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+
+                match line.trim_end() {
+                    "" => break,
+                    header => if let Some(value) = header.strip_prefix("Host: ") {
+                        host = value.to_owned();
+                    },
+                }
+            }
+
+            stream.get_mut().write_all(response.as_bytes()).await.unwrap();
+            stream.get_mut().shutdown().await.unwrap();
+
+            (request_line.trim_end().to_owned(), host)
+        });
+
+        (port, server)
+    }
+
+    /// Sends `app.test` to the local listener, trying only once.
+    fn config(port: u16) -> ChallengeConfig {
+        ChallengeConfig {
+            hosts: HashMap::from([("app.test".to_owned(), IpAddr::from([127, 0, 0, 1]))]),
+            http01: Http01Config {
+                port,
+                timeout_secs: 2,
+                attempts: 1,
+                retry_interval_secs: 0,
+            },
+            ..ChallengeConfig::default()
+        }
+    }
+
+    fn ok(body: &str) -> String {
+        format!("HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {length}\r\n\r\n{body}", length = body.len())
+    }
+
+    #[tokio::test]
+    async fn http01_passes_with_key_authorization() {
+        let challenge = NameChallenge::issue("app.test", ChallengeType::Http01).unwrap();
+        let (port, server) = serve_once(ok(&format!("{key}\n", key = challenge.key_authorization))).await;
+
+        assert_eq!(check(&config(port), &challenge).await, Check::Passed);
+
+        let (request_line, host) = server.await.unwrap();
+        assert_eq!(request_line, format!("GET /.well-known/acme-challenge/{token} HTTP/1.0", token = challenge.token));
+        assert_eq!(host, format!("app.test:{port}"));
+    }
+
+    #[tokio::test]
+    async fn http01_fails_on_other_body() {
+        let challenge = NameChallenge::issue("app.test", ChallengeType::Http01).unwrap();
+        let (port, _server) = serve_once(ok(&format!("{key}.trailing", key = challenge.key_authorization))).await;
+
+        assert!(matches!(check(&config(port), &challenge).await, Check::Failed(reason) if reason.contains("expected key authorization")));
+    }
+
+    #[tokio::test]
+    async fn http01_fails_on_error_status() {
+        let challenge = NameChallenge::issue("app.test", ChallengeType::Http01).unwrap();
+        let (port, _server) = serve_once("HTTP/1.0 404 Not Found\r\n\r\n".to_owned()).await;
+
+        assert!(matches!(check(&config(port), &challenge).await, Check::Failed(reason) if reason.contains("404")));
+    }
+
+    /// Always presents the same certificate, as `with_single_cert` refuses ones with the acmeIdentifier extension.
+    #[derive(Debug)]
+    struct Presents(std::sync::Arc<rustls::sign::CertifiedKey>);
+
+    impl rustls::server::ResolvesServerCert for Presents {
+        fn resolve(&self, _: rustls::server::ClientHello<'_>) -> Option<std::sync::Arc<rustls::sign::CertifiedKey>> {
+            Some(self.0.clone())
+        }
+    }
+
+    /// Completes a TLS 1.3 handshake, presenting a self-signed certificate for `name` which carries the digest of
+    /// `key_authorization`, and selecting `protocol` if the client offers it.
+    async fn serve_tls_once(name: &str, key_authorization: &str, protocol: &str) -> u16 {
+        use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+        use tokio_rustls::TlsAcceptor;
+
+        let key = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::new(vec![name.to_owned()]).unwrap();
+        params.custom_extensions = vec![rcgen::CustomExtension::new_acme_identifier(digest(&SHA256, key_authorization.as_bytes()).as_ref())];
+        let certificate = params.self_signed(&key).unwrap();
+
+        let provider = std::sync::Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+        let signing_key = provider.key_provider
+            .load_private_key(PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())))
+            .unwrap();
+        let certified = rustls::sign::CertifiedKey::new(vec![certificate.der().clone()], signing_key);
+
+        let mut config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_no_client_auth()
+            .with_cert_resolver(std::sync::Arc::new(Presents(std::sync::Arc::new(certified))));
+        config.alpn_protocols = vec![protocol.as_bytes().to_vec()];
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            if let Ok(mut stream) = TlsAcceptor::from(std::sync::Arc::new(config)).accept(stream).await {
+                let _ = stream.shutdown().await;
+            }
+        });
+
+        port
+    }
+
+    fn tls_config(port: u16) -> ChallengeConfig {
+        ChallengeConfig {
+            hosts: HashMap::from([("app.test".to_owned(), IpAddr::from([127, 0, 0, 1]))]),
+            tls_alpn01: common::TlsAlpn01Config {
+                port,
+                timeout_secs: 2,
+                attempts: 1,
+                retry_interval_secs: 0,
+            },
+            ..ChallengeConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn tls_alpn01_passes_over_tls13() {
+        let challenge = NameChallenge::issue("app.test", ChallengeType::TlsAlpn01).unwrap();
+        let port = serve_tls_once("app.test", &challenge.key_authorization, ACME_TLS_PROTOCOL).await;
+
+        assert_eq!(check(&tls_config(port), &challenge).await, Check::Passed);
+    }
+
+    #[tokio::test]
+    async fn tls_alpn01_fails_on_other_digest() {
+        let challenge = NameChallenge::issue("app.test", ChallengeType::TlsAlpn01).unwrap();
+        let port = serve_tls_once("app.test", "something else", ACME_TLS_PROTOCOL).await;
+
+        assert!(matches!(check(&tls_config(port), &challenge).await, Check::Failed(reason) if reason.contains("doesn't match")));
+    }
+
+    #[tokio::test]
+    async fn tls_alpn01_fails_for_other_name() {
+        let challenge = NameChallenge::issue("app.test", ChallengeType::TlsAlpn01).unwrap();
+        let port = serve_tls_once("other.test", &challenge.key_authorization, ACME_TLS_PROTOCOL).await;
+
+        assert!(matches!(check(&tls_config(port), &challenge).await, Check::Failed(reason) if reason.contains("doesn't name")));
+    }
+
+    #[tokio::test]
+    async fn tls_alpn01_needs_the_protocol() {
+        let challenge = NameChallenge::issue("app.test", ChallengeType::TlsAlpn01).unwrap();
+        let port = serve_tls_once("app.test", &challenge.key_authorization, "http/1.1").await;
+
+        assert!(matches!(check(&tls_config(port), &challenge).await, Check::Failed(_)));
+    }
+
+    #[tokio::test]
+    async fn hosts_take_precedence() {
+        let hosts = HashMap::from([("app.test".to_owned(), IpAddr::from([127, 0, 0, 2]))]);
+
+        assert_eq!(resolve(&hosts, "app.test", 80).await.unwrap(), vec![SocketAddr::from(([127, 0, 0, 2], 80))]);
+        assert_eq!(resolve(&hosts, "::1", 8080).await.unwrap(), vec!["[::1]:8080".parse::<SocketAddr>().unwrap()]);
+    }
+}
@@ -0,0 +1,510 @@
+//! # DNS
+//! A minimal stub resolver. It only looks up TXT records, which is all the dns-01 challenge needs, and relies on the
+//! nameserver to recurse.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use common::Dns01Config;
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+const TYPE_CNAME: u16 = 5;
+const TYPE_TXT: u16 = 16;
+const CLASS_IN: u16 = 1;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+
+const RCODE_NXDOMAIN: u16 = 3;
+
+/// The configured resolver, or the nameservers listed in `/etc/resolv.conf`.
+async fn nameservers(config: &Dns01Config) -> Result<Vec<SocketAddr>, String> {
+    if let Some(resolver) = config.resolver {
+        return Ok(vec![resolver]);
+    }
+
+    let resolv_conf = tokio::fs::read_to_string("/etc/resolv.conf")
+        .await
+        .map_err(|err| format!("Failed to read /etc/resolv.conf: {err}"))?;
+
+    let nameservers = resolv_conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|address| address.trim().parse().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .collect::<Vec<_>>();
+
+    if nameservers.is_empty() {
+        return Err("No nameserver configured".to_owned());
+    }
+
+    Ok(nameservers)
+}
+
+/// Looks up the TXT records of a name, asking one nameserver after the other until one of them answers. A name without
+/// TXT records has none, rather than being an error.
+pub(crate) async fn lookup_txt(config: &Dns01Config, name: &str) -> Result<Vec<String>, String> {
+    let timeout = Duration::from_secs(config.timeout_secs);
+
+    let mut reason = String::new();
+    for nameserver in nameservers(config).await? {
+        reason = match tokio::time::timeout(timeout, query(nameserver, name)).await {
+            Ok(Ok(records)) => return Ok(records),
+            Ok(Err(err)) => err,
+            Err(_) => format!("{nameserver} didn't answer within {secs}s", secs = config.timeout_secs),
+        };
+
+        log::debug!("TXT lookup of {name} at {nameserver} failed: {reason}");
+    }
+
+    Err(reason)
+}
+
+/// Asks over UDP first and repeats the query over TCP if the answer didn't fit into a datagram.
+async fn query(nameserver: SocketAddr, name: &str) -> Result<Vec<String>, String> {
+    let mut id = [0u8; 2];
+    SystemRandom::new()
+        .fill(&mut id)
+        .map_err(|_| "Failed to generate query ID".to_owned())?;
+    let id = u16::from_be_bytes(id);
+
+    let request = encode_query(id, name)?;
+    let io_error = |err: std::io::Error| format!("Failed to query {nameserver}: {err}");
+
+    let local = match nameserver {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+
+    let socket = UdpSocket::bind(local).await.map_err(io_error)?;
+    socket.connect(nameserver).await.map_err(io_error)?;
+    socket.send(&request).await.map_err(io_error)?;
+
+    let mut response = [0u8; 4096];
+    let answer = loop {
+        let len = socket.recv(&mut response).await.map_err(io_error)?;
+
+        // Stray datagrams belonging to earlier queries are dropped
+        if response[..len].starts_with(&id.to_be_bytes()) {
+            break parse_response(id, name, &response[..len])?;
+        }
+    };
+
+    if let Some(records) = answer {
+        return Ok(records);
+    }
+
+    let mut stream = TcpStream::connect(nameserver).await.map_err(io_error)?;
+    stream.write_all(&(request.len() as u16).to_be_bytes()).await.map_err(io_error)?;
+    stream.write_all(&request).await.map_err(io_error)?;
+
+    let len = stream.read_u16().await.map_err(io_error)?;
+    let mut response = vec![0u8; len as usize];
+    stream.read_exact(&mut response).await.map_err(io_error)?;
+
+    parse_response(id, name, &response)?
+        .ok_or_else(|| format!("{nameserver} truncated its answer over TCP"))
+}
+
+fn encode_query(id: u16, name: &str) -> Result<Vec<u8>, String> {
+    let mut query = vec![];
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("{name} is not a valid DNS name"));
+        }
+
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+
+    if query.len() - 12 > 254 {
+        return Err(format!("{name} is too long for a DNS name"));
+    }
+
+    query.push(0);
+    query.extend_from_slice(&TYPE_TXT.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    Ok(query)
+}
+
+/// The TXT records of the name in the answer section, each with its character-strings joined. `None` if the answer was
+/// truncated. The response has to echo the question, and records owned by other names are ignored, unless a CNAME in
+/// the answer leads to them.
+fn parse_response(id: u16, name: &str, message: &[u8]) -> Result<Option<Vec<String>>, String> {
+    let malformed = || "Malformed DNS response".to_owned();
+    let u16_at = |offset: usize| message
+        .get(offset..offset + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(malformed);
+
+    let flags = u16_at(2)?;
+    if u16_at(0)? != id || flags & FLAG_RESPONSE == 0 {
+        return Err(malformed());
+    }
+
+    if flags & FLAG_TRUNCATED != 0 {
+        return Ok(None);
+    }
+
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+
+    let (question, mut offset) = read_name(message, 12).ok_or_else(malformed)?;
+    if questions != 1 || question != name || u16_at(offset)? != TYPE_TXT || u16_at(offset + 2)? != CLASS_IN {
+        return Err(format!("The response doesn't answer the query for TXT records of {name}"));
+    }
+    offset += 4;
+
+    match flags & 0x000f {
+        0 => {},
+        RCODE_NXDOMAIN => return Ok(Some(vec![])),
+        rcode => return Err(format!("The nameserver answered with RCODE {rcode}")),
+    }
+
+    // Owner, type, class and where the data starts, for every record
+    let mut records = vec![];
+    for _ in 0..answers {
+        let (owner, next) = read_name(message, offset).ok_or_else(malformed)?;
+        offset = next;
+
+        let kind = u16_at(offset)?;
+        let class = u16_at(offset + 2)?;
+        let len = u16_at(offset + 8)? as usize;
+        offset += 10;
+
+        message.get(offset..offset + len).ok_or_else(malformed)?;
+        records.push((owner, kind, class, offset..offset + len));
+        offset += len;
+    }
+
+    // The queried name and whatever it's an alias of
+    let mut names = vec![name];
+    while let Some(target) = records
+        .iter()
+        .filter(|(owner, kind, class, _)| *kind == TYPE_CNAME && *class == CLASS_IN && names.contains(owner))
+        .filter_map(|(_, _, _, data)| read_name(message, data.start))
+        .map(|(target, _)| target)
+        .find(|target| !names.contains(target)) {
+        names.push(target);
+    }
+
+    let mut txt = vec![];
+    for (owner, kind, class, data) in records {
+        if kind != TYPE_TXT || class != CLASS_IN {
+            continue;
+        }
+
+        if !names.contains(&owner) {
+            log::debug!("Ignoring TXT record of {owner} in the answer for {name}", name = names[0]);
+            continue;
+        }
+
+        let mut record = vec![];
+        let mut strings = &message[data];
+        while let Some((&len, rest)) = strings.split_first() {
+            let string = rest.get(..len as usize).ok_or_else(malformed)?;
+            record.extend_from_slice(string);
+            strings = &rest[len as usize..];
+        }
+
+        txt.push(String::from_utf8_lossy(&record).into_owned());
+    }
+
+    Ok(Some(txt))
+}
+
+/// The (possibly compressed) name at `offset`, lowercased, and the offset just past it. Compression pointers may only
+/// point backwards, so they can't loop.
+fn read_name(message: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = vec![];
+    let mut end = None;
+
+    loop {
+        let len = *message.get(offset)?;
+
+        match len {
+            0 => return Some((labels.join("."), end.unwrap_or(offset + 1))),
+            len if len & 0xc0 == 0xc0 => {
+                let pointer = usize::from(len & 0x3f) << 8 | usize::from(*message.get(offset + 1)?);
+                if pointer >= offset {
+                    return None;
+                }
+
+                end.get_or_insert(offset + 2);
+                offset = pointer;
+            },
+            len if len & 0xc0 != 0 => return None,
+            len => {
+                let label = message.get(offset + 1..offset + 1 + len as usize)?;
+                labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+                offset += 1 + len as usize;
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use common::{ChallengeConfig, ChallengeType, NameChallenge};
+    use tokio::net::TcpListener;
+    use crate::challenge::Check;
+
+    type Zone = Arc<Mutex<HashMap<String, Vec<Vec<String>>>>>;
+
+    /// An authoritative nameserver for the records in its zone, each of which is a list of character-strings. Names
+    /// outside the zone don't exist. Answers over UDP are truncated if they hold more than `udp_limit` records.
+    struct StandIn {
+        address: SocketAddr,
+        zone: Zone,
+    }
+
+    impl StandIn {
+        async fn start(udp_limit: usize) -> StandIn {
+            let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let address = udp.local_addr().unwrap();
+            let tcp = TcpListener::bind(address).await.unwrap();
+            let zone = Zone::default();
+
+            let udp_zone = zone.clone();
+            tokio::spawn(async move {
+                let mut query = [0u8; 512];
+                while let Ok((len, peer)) = udp.recv_from(&mut query).await {
+                    // A stray answer to some other query comes first, which has to be ignored
+                    let mut stray = answer(&udp_zone, &query[..len], usize::MAX);
+                    stray[0] ^= 0xff;
+                    udp.send_to(&stray, peer).await.unwrap();
+
+                    udp.send_to(&answer(&udp_zone, &query[..len], udp_limit), peer).await.unwrap();
+                }
+            });
+
+            let tcp_zone = zone.clone();
+            tokio::spawn(async move {
+                while let Ok((mut stream, _)) = tcp.accept().await {
+                    let len = stream.read_u16().await.unwrap();
+                    let mut query = vec![0u8; len as usize];
+                    stream.read_exact(&mut query).await.unwrap();
+
+                    let response = answer(&tcp_zone, &query, usize::MAX);
+                    stream.write_all(&(response.len() as u16).to_be_bytes()).await.unwrap();
+                    stream.write_all(&response).await.unwrap();
+                }
+            });
+
+            StandIn { address, zone }
+        }
+
+        fn publish(&self, name: &str, strings: &[&str]) {
+            let record = strings.iter().map(|string| string.to_string()).collect();
+            self.zone.lock().unwrap().entry(name.to_owned()).or_default().push(record);
+        }
+
+        fn config(&self) -> Dns01Config {
+            Dns01Config {
+                resolver: Some(self.address),
+                timeout_secs: 2,
+                ..Dns01Config::default()
+            }
+        }
+    }
+
+    fn answer(zone: &Zone, query: &[u8], limit: usize) -> Vec<u8> {
+        let (name, question_end) = read_name(query, 12).unwrap();
+        let question_end = question_end + 4;
+
+        let records = zone.lock().unwrap().get(&name).cloned();
+        let truncated = records.as_ref().is_some_and(|records| records.len() > limit);
+
+        let mut flags = FLAG_RESPONSE | FLAG_RECURSION_DESIRED;
+        if records.is_none() {
+            flags |= RCODE_NXDOMAIN;
+        }
+        if truncated {
+            flags |= FLAG_TRUNCATED;
+        }
+
+        let records = records.filter(|_| !truncated).unwrap_or_default();
+
+        // Every answer but the first is preceded by an A record, which has to be skipped
+        let mut answers = vec![];
+        for (index, strings) in records.iter().enumerate() {
+            if index > 0 {
+                answers.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 127, 0, 0, 1]);
+            }
+
+            let data = strings.iter()
+                .flat_map(|string| [&[string.len() as u8][..], string.as_bytes()].concat())
+                .collect::<Vec<_>>();
+
+            answers.extend_from_slice(&[0xc0, 12]);
+            answers.extend_from_slice(&TYPE_TXT.to_be_bytes());
+            answers.extend_from_slice(&CLASS_IN.to_be_bytes());
+            answers.extend_from_slice(&60u32.to_be_bytes());
+            answers.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            answers.extend_from_slice(&data);
+        }
+
+        let count = (records.len() * 2).saturating_sub(1) as u16;
+
+        let mut response = query[..2].to_vec();
+        response.extend_from_slice(&flags.to_be_bytes());
+        response.extend_from_slice(&[0, 1]);
+        response.extend_from_slice(&count.to_be_bytes());
+        response.extend_from_slice(&[0, 0, 0, 0]);
+        response.extend_from_slice(&query[12..question_end]);
+        response.extend_from_slice(&answers);
+        response
+    }
+
+    #[tokio::test]
+    async fn parses_txt_records() {
+        let server = StandIn::start(usize::MAX).await;
+        server.publish("_acme-challenge.app.test", &["first"]);
+        server.publish("_acme-challenge.app.test", &["split ", "across ", "strings"]);
+
+        let records = lookup_txt(&server.config(), "_acme-challenge.app.test").await.unwrap();
+        assert_eq!(records, vec!["first", "split across strings"]);
+    }
+
+    #[tokio::test]
+    async fn missing_names_have_no_records() {
+        let server = StandIn::start(usize::MAX).await;
+
+        assert_eq!(lookup_txt(&server.config(), "_acme-challenge.other.test").await.unwrap(), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_tcp_when_truncated() {
+        let server = StandIn::start(1).await;
+        server.publish("_acme-challenge.app.test", &["one"]);
+        server.publish("_acme-challenge.app.test", &["two"]);
+
+        let records = lookup_txt(&server.config(), "_acme-challenge.app.test").await.unwrap();
+        assert_eq!(records, vec!["one", "two"]);
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        assert!(encode_query(0, "app..test").is_err());
+        assert!(encode_query(0, &format!("{label}.test", label = "a".repeat(64))).is_err());
+        assert!(encode_query(0, "app.test.").is_ok());
+    }
+
+    #[tokio::test]
+    async fn dns01_is_pending_until_published() {
+        let server = StandIn::start(usize::MAX).await;
+        let config = ChallengeConfig {
+            dns01: server.config(),
+            ..ChallengeConfig::default()
+        };
+
+        let challenge = NameChallenge::issue("*.app.test", ChallengeType::Dns01).unwrap();
+        let record = challenge.dns01_record();
+        assert_eq!(record.name, "_acme-challenge.app.test");
+
+        let check = crate::challenge::check(&config, &challenge).await;
+        assert!(matches!(&check, Check::Pending(_)));
+        assert_eq!(check.clone().by_deadline(99, 100), check);
+        assert!(matches!(check.by_deadline(100, 100), Check::Failed(reason) if reason.ends_with("by the deadline")));
+
+        server.publish("_acme-challenge.app.test", &["unrelated"]);
+        assert!(matches!(crate::challenge::check(&config, &challenge).await, Check::Pending(_)));
+
+        server.publish("_acme-challenge.app.test", &[&record.value]);
+        assert_eq!(crate::challenge::check(&config, &challenge).await, Check::Passed);
+    }
+
+    fn encode_name(name: &str) -> Vec<u8> {
+        let mut encoded = name.split('.')
+            .flat_map(|label| [&[label.len() as u8][..], label.as_bytes()].concat())
+            .collect::<Vec<_>>();
+        encoded.push(0);
+        encoded
+    }
+
+    fn txt(string: &str) -> Vec<u8> {
+        [&[string.len() as u8][..], string.as_bytes()].concat()
+    }
+
+    /// A response with ID 1 to the question, holding the answers as given by owner, type and data.
+    fn response(question: &str, answers: &[(&str, u16, Vec<u8>)]) -> Vec<u8> {
+        let mut response = encode_query(1, question).unwrap();
+        response[2..4].copy_from_slice(&(FLAG_RESPONSE | FLAG_RECURSION_DESIRED).to_be_bytes());
+        response[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+
+        for (owner, kind, data) in answers {
+            response.extend_from_slice(&encode_name(owner));
+            response.extend_from_slice(&kind.to_be_bytes());
+            response.extend_from_slice(&CLASS_IN.to_be_bytes());
+            response.extend_from_slice(&60u32.to_be_bytes());
+            response.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            response.extend_from_slice(data);
+        }
+
+        response
+    }
+
+    #[test]
+    fn rejects_answers_to_other_questions() {
+        let name = "_acme-challenge.app.test";
+        let other = response("_acme-challenge.other.test", &[("_acme-challenge.other.test", TYPE_TXT, txt("forged"))]);
+        assert!(parse_response(1, name, &other).is_err());
+
+        let mut other_type = response(name, &[]);
+        let qtype = other_type.len() - 4;
+        other_type[qtype..qtype + 2].copy_from_slice(&1u16.to_be_bytes());
+        assert!(parse_response(1, name, &other_type).is_err());
+
+        let mut no_question = response(name, &[]);
+        no_question[4..6].copy_from_slice(&0u16.to_be_bytes());
+        assert!(parse_response(1, name, &no_question).is_err());
+
+        assert_eq!(parse_response(1, "_ACME-challenge.app.test.", &response(name, &[])).unwrap(), Some(vec![]));
+    }
+
+    #[test]
+    fn ignores_records_of_other_names() {
+        let name = "_acme-challenge.app.test";
+        let message = response(name, &[
+            ("_acme-challenge.other.test", TYPE_TXT, txt("forged")),
+            (name, TYPE_TXT, txt("genuine")),
+        ]);
+
+        assert_eq!(parse_response(1, name, &message).unwrap(), Some(vec!["genuine".to_owned()]));
+    }
+
+    #[test]
+    fn follows_cnames_in_the_answer() {
+        let name = "_acme-challenge.app.test";
+        let message = response(name, &[
+            (name, TYPE_CNAME, encode_name("app.validation.test")),
+            ("app.validation.test", TYPE_TXT, txt("delegated")),
+            ("other.validation.test", TYPE_TXT, txt("unrelated")),
+        ]);
+
+        assert_eq!(parse_response(1, name, &message).unwrap(), Some(vec!["delegated".to_owned()]));
+    }
+
+    #[test]
+    fn compression_pointers_only_point_backwards() {
+        let mut message = encode_name("app.test");
+        let pointer = message.len();
+        message.extend_from_slice(&[0xc0, 0]);
+        assert_eq!(read_name(&message, pointer), Some(("app.test".to_owned(), pointer + 2)));
+
+        assert_eq!(read_name(&[0xc0, 0], 0), None);
+        assert_eq!(read_name(&[0, 0xc0, 2, 0], 1), None);
+    }
+}
@@ -0,0 +1,182 @@
+use common::{EventBus, NewCsr};
+use common::debounce;
+use common::NEW_CSR_EVENT_GROUP;
+use common::Result;
+use notify::Watcher;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+/// # Receiver
+/// The receiver awaits directory changes and dispatches tasks according to the name of the item in the inbox.
+
+static SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Watches the inbox until one of its tasks fails, or until shutdown. Requests which were already queued are still
+/// dispatched before stopping, while the rest stay in the inbox for the next start.
+pub async fn run() -> Result<()> {
+    let config = common::get_config();
+
+    let (req_tx, req_rx) = mpsc::channel(100);
+    let (fs_tx, fs_rx) = mpsc::unbounded_channel();
+    let (reindex_tx, reindex_rx) = mpsc::channel(100);
+    let senders = (req_tx.clone(), fs_tx.clone(), reindex_tx.clone());
+
+    let mut watcher = init_watcher(fs_tx).await;
+
+    let _req_tx = req_tx.clone();
+    let __req_tx = req_tx.clone();
+    // Dropping the set aborts whatever tasks are left
+    let mut tasks = JoinSet::new();
+    tasks.spawn(read_inbox(_req_tx.clone()));
+    tasks.spawn(handle_events(fs_rx, reindex_tx));
+    tasks.spawn(watch_reindex(reindex_rx, __req_tx));
+    let mut dispatcher = tokio::spawn(dispatch_requests(req_rx));
+
+    watcher
+        .watch(config.inbox.inbox.as_path(), notify::RecursiveMode::Recursive)
+        .expect("Failed to start watcher");
+
+    let result = tokio::select! {
+        result = join_all(&mut tasks) => result,
+        result = &mut dispatcher => result.map_err(Into::into),
+        () = crate::shutdown::token().cancelled() => {
+            log::info!("Flushing inbox queue");
+
+            // Once every sender is gone, the dispatcher stops after the last queued request
+            drop(watcher);
+            drop(senders);
+            drop(req_tx);
+            tasks.shutdown().await;
+
+            return Ok(dispatcher.await?);
+        }
+    };
+
+    dispatcher.abort();
+
+    // Keep references alive because dropping them will cause the watcher to stop.
+    drop(senders.clone());
+    drop(watcher);
+
+    result
+}
+
+async fn join_all(tasks: &mut JoinSet<()>) -> Result<()> {
+    while let Some(result) = tasks.join_next().await {
+        result?;
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn init_watcher(fs_tx: mpsc::UnboundedSender<notify::Event>) -> impl Watcher {
+    let config = common::get_config();
+
+    tokio::fs::create_dir_all(config.inbox.inbox.as_path())
+        .await
+        .expect("Failed to create inbox");
+
+    notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let event = event.expect("Failed to get event");
+        let tx = fs_tx.clone();
+
+        tx.send(event).expect("Failed to send event");
+    })
+    .expect("Failed to watch inbox")
+}
+
+pub(crate) async fn read_inbox(sender: mpsc::Sender<PathBuf>) {
+    let config = common::get_config();
+
+    // CSRs placed in a subdirectory of the inbox are issued under the profile of the same name.
+    let mut dirs = vec![(config.inbox.inbox.clone(), true)];
+
+    while let Some((dir, is_root)) = dirs.pop() {
+        let mut dir = tokio::fs::read_dir(dir)
+            .await
+            .expect("Failed to read inbox");
+
+        while let Some(entry) = dir.next_entry().await.expect("Failed to read inbox") {
+            let file_type = entry.file_type().await.expect("Failed to stat file");
+
+            if file_type.is_dir() && is_root {
+                dirs.push((entry.path(), false));
+            } else if file_type.is_file() && entry.path().extension().is_some_and(|ext| ext == "csr") {
+                sender.send(entry.path()).await.expect("Failed to send request");
+            }
+        }
+    }
+}
+
+pub(crate) async fn watch_reindex(rx: mpsc::Receiver<()>, sender: mpsc::Sender<PathBuf>) {
+    let config = common::get_config();
+
+    let mut rx = debounce(rx, Duration::from_secs(config.inbox.rescan_interval));
+    while let Some(_) = rx.recv().await {
+        log::trace!("Reindexing...");
+
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            read_inbox(sender.clone()).await;
+        });
+    }
+}
+
+pub(crate) async fn handle_events(mut rx: mpsc::UnboundedReceiver<notify::Event>, reindex: mpsc::Sender<()>) {
+    while let Some(event) = rx.recv().await {
+        match &event.kind {
+            notify::EventKind::Create(_) | notify::EventKind::Modify(_) => if let Err(_) = reindex.send(()).await {},
+
+            _ => {}
+        }
+    }
+
+    log::trace!("No more events");
+}
+
+pub(crate) async fn dispatch_requests(mut rx: mpsc::Receiver<PathBuf>) {
+    let config = common::get_config();
+
+    let mut storage = config.connect_storage().await;
+
+    while let Some(path) = rx.recv().await {
+        log::info!("Received CSR: {path:?}");
+
+        let Some(str) = path.to_str() else {
+            log::warn!("Invalid request path: Contains non-UTF-8 characters - Skipping {path:?}");
+            continue;
+        };
+
+        let pem = tokio::fs::read_to_string(&path).await.expect("Failed to read request");
+
+        let profile = path.parent()
+            .filter(|parent| *parent != config.inbox.inbox.as_path())
+            .and_then(|parent| parent.file_name())
+            .and_then(|name| name.to_str())
+            .map(str::to_owned);
+
+        storage.dispatch_event(NewCsr {
+            pem,
+            client_id: SEQ.fetch_add(1, std::sync::atomic::Ordering::SeqCst),
+            profile,
+            challenge: None,
+            challenges: vec![],
+        })
+            .await.expect("Failed to dispatch request");
+
+        // let payload = ron::to_string(&NewCsr {
+        //     pem
+        // })
+        // .expect("Failed to serialize task");
+        //
+        // redis.xadd(&config.redis.task_stream_key, "*", &[(NEW_CSR_EVENT_GROUP, payload)])
+        //     .await.expect("Failed to dispatch request");
+
+        tokio::fs::remove_file(&path).await.expect("Failed to remove request");
+    }
+
+    log::trace!("Nothing more to dispatch");
+}
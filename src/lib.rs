@@ -0,0 +1,17 @@
+//! # Certmaster
+//! The modules making up the CA. The `certmaster` binary runs the enabled ones under a [supervisor], while `web` and
+//! `inbox` can also be started as binaries of their own.
+
+mod runner;
+mod challenge;
+mod dns;
+mod tls;
+#[cfg(test)]
+mod testing;
+
+pub mod web;
+pub mod inbox;
+pub mod supervisor;
+pub mod shutdown;
+pub mod pool;
+pub mod authority;
@@ -1,7 +1,5 @@
 use std::path::PathBuf;
 
-mod runner;
-
 #[derive(clap::Parser)]
 pub struct Args {
     #[clap(long, short, default_value = "./config.toml")]
@@ -16,12 +14,13 @@ pub async fn main() {
 
     let config = common::read_config().await;
 
-    let worker = tokio::spawn(async move {
-        runner::handle_redis_events()
+    if config.modules.ca || config.modules.web {
+        common::Authority::init(&config.ca)
             .await
-            .expect("Worker died");
-    });
+            .expect("Failed to load the CA key and certificate");
+    }
+
+    certmaster::shutdown::graceful(certmaster::supervisor::run()).await;
 
-    let (..) = tokio::join!(worker);
     drop(config);
-}
\ No newline at end of file
+}
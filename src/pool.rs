@@ -0,0 +1,76 @@
+//! # Worker Pool
+//! Runs jobs concurrently up to a limit. Jobs sharing a key run one after the other, in the order they were spawned.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+use tokio::sync::oneshot::error::TryRecvError;
+use tokio::sync::{oneshot, Semaphore};
+use tokio::task::JoinSet;
+use common::Result;
+
+pub struct KeyedPool<K> {
+    permits: Arc<Semaphore>,
+    /// Resolves once the last job spawned for the key has finished.
+    tails: HashMap<K, oneshot::Receiver<()>>,
+    tasks: JoinSet<Result<()>>,
+}
+
+impl<K: Hash + Eq> KeyedPool<K> {
+    pub fn new(concurrency: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(concurrency.max(1))),
+            tails: HashMap::new(),
+            tasks: JoinSet::new(),
+        }
+    }
+
+    /// Waits for a free slot and starts the job, which then waits for the previous job of the same key to finish. Jobs
+    /// without a key depend on nothing. Fails with the error of a job which has finished since.
+    pub async fn spawn(&mut self, key: Option<K>, job: impl Future<Output = Result<()>> + Send + 'static) -> Result<()> {
+        self.reap()?;
+
+        // A job waiting for its predecessor holds a slot, but so does the predecessor, so they can't lock each other out
+        let permit = self.permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Pool semaphore closed");
+
+        let (done, tail) = oneshot::channel::<()>();
+        let previous = key.and_then(|key| self.tails.insert(key, tail));
+
+        self.tasks.spawn(async move {
+            if let Some(previous) = previous {
+                // Whether the previous job finished or panicked, the sender is gone
+                let _ = previous.await;
+            }
+
+            let result = job.await;
+            drop(done);
+            drop(permit);
+            result
+        });
+
+        Ok(())
+    }
+
+    /// Waits for every job to finish. Fails with the first error, leaving the other jobs running.
+    pub async fn join_all(&mut self) -> Result<()> {
+        while let Some(result) = self.tasks.join_next().await {
+            result??;
+        }
+
+        self.tails.clear();
+        Ok(())
+    }
+
+    fn reap(&mut self) -> Result<()> {
+        while let Some(result) = self.tasks.try_join_next() {
+            result??;
+        }
+
+        self.tails.retain(|_, tail| matches!(tail.try_recv(), Err(TryRecvError::Empty)));
+        Ok(())
+    }
+}
@@ -1,24 +1,15 @@
 use std::{
+    collections::HashSet,
     io,
+    time::Duration,
+    time::Instant,
     time::SystemTime,
     time::UNIX_EPOCH
 };
-use rcgen::{
-    Issuer,
-    KeyPair,
-    SigningKey
-};
-use redis::{
-    streams::StreamReadOptions,
-    streams::StreamReadReply,
-    AsyncCommands,
-    FromRedisValue,
-    RedisResult
-};
 use common::{
     JobProgress,
     JobStatus,
-    Config,
+    Authority,
     Csr,
     NewCsr,
     Completion,
@@ -28,100 +19,398 @@ use common::{
     CHALLENGE_EVENT_GROUP,
     JOB_PROGRESS_EVENT_GROUP,
     FINISHED_EVENT_GROUP,
+    REVOCATION_EVENT_GROUP,
     CsrId,
+    Error,
     Result,
-    RedisUtils,
-    Status
+    Status,
+    CertificateStore,
+    IssuedCertificate,
+    Revocation,
+    RevocationRegistry,
+    RevokedCertificate,
+    ChallengeConfig,
+    ChallengeType,
+    DeadLetterQueue,
+    GarbageCollector,
+    NameChallenge,
+    ChallengeReview,
+    Review,
+    ReviewDecision,
+    Storage,
+    JobStore,
+    EventBus,
+    EventEntry,
+    CHALLENGE_REVIEW_EVENT_GROUP,
+    AcmeChallengeReady,
+    AcmeStatus,
+    AcmeStore,
+    ACME_CHALLENGE_EVENT_GROUP,
+    ChallengeValidation,
+    CHALLENGE_VALIDATION_EVENT_GROUP
 };
+use tokio::sync::mpsc;
+use crate::challenge::Check;
+use crate::pool::KeyedPool;
+
+/// How long a read waits for new events, which bounds how long a shutdown waits for an idle worker.
+const READ_BLOCK: Duration = Duration::from_millis(1000);
 
-pub(crate) async fn handle_redis_events() -> Result<()> {
+/// Handles events until shutdown, up to `worker.concurrency` at once. Events in hand are always handled and
+/// acknowledged before stopping.
+///
+/// Entries which another worker was handed but didn't acknowledge within `worker.claim_idle_secs` are claimed and
+/// retried, until they've been delivered `worker.max_deliveries` times and are moved to the dead-letter stream.
+///
+/// If the connection to Redis is lost, handling resumes once it's back, starting with the entries this consumer was
+/// handed but didn't get to acknowledge.
+pub(crate) async fn handle_events() -> Result<()> {
     let config = common::get_config();
-    let mut redis = config
-        .redis
-        .connect()
-        .await;
+    let shutdown = crate::shutdown::token();
 
-    match redis.xgroup_create_mkstream(&config.redis.task_stream_key, NEW_CSR_EVENT_GROUP, "0")
-        .await {
-        Ok(()) => {},
-        Err(err) => log::warn!("Failed to construct stream: {err:?} - ignoring error")
-    };
+    let consumer = consumer_name().await;
+    log::info!("Handling events as consumer '{consumer}'");
 
-    let _: RedisResult<NewCsr> = redis.xgroup_create(&config.redis.task_stream_key, NEW_CSR_EVENT_GROUP, "0")
-        .await;
-    
-    let consumer: u64 = redis.incr("new-csr-worker", 1)
-        .await?;
+    let mut pool = KeyedPool::new(config.worker.concurrency);
 
-    let options = StreamReadOptions::default()
-        .block(0)
-        .group(NEW_CSR_EVENT_GROUP, format!("worker-{consumer}"));
+    if !wait_for_storage().await {
+        return Ok(());
+    }
 
     loop {
-        let mut stream: StreamReadReply = redis
-            .xread_options(&[&config.redis.task_stream_key], &[">"], &options)
-            .await?;
-
-        for id in stream.keys.drain(..).flat_map(|k| k.ids) {
-            for (key, value) in id.map {
-                log::trace!("Received event '{key}'");
-
-                match key.as_str() {
-                    NEW_CSR_EVENT_GROUP => new_csr(FromRedisValue::from_redis_value(&value)?).await?,
-                    CHALLENGE_EVENT_GROUP => challenge(FromRedisValue::from_redis_value(&value)?).await?,
-                    JOB_PROGRESS_EVENT_GROUP => job_progress(FromRedisValue::from_redis_value(&value)?).await?,
-                    FINISHED_EVENT_GROUP => completion(FromRedisValue::from_redis_value(&value)?).await?,
-                    key => {
-                        log::warn!("Unknown job type {key} - skipping");
-                        continue;
-                    }
+        match consume_events(&consumer, &mut pool).await {
+            Err(err) if err.is_connection_error() && !shutdown.is_cancelled() => {
+                log::warn!("Lost the connection to Redis while handling events - Resuming once reconnected: {err}");
+
+                tokio::select! {
+                    _ = config.reconnect_storage() => {},
+                    () = shutdown.cancelled() => break,
+                }
+
+                // Whatever didn't get acknowledged is read back as pending, so it mustn't still be in progress then
+                while let Err(err) = pool.join_all().await {
+                    log::error!("Event failed while reconnecting: {err}");
                 }
+            },
+            result => {
+                result?;
+                break;
             }
+        }
+    }
+
+    log::debug!("Finishing events in progress");
+    pool.join_all().await?;
+
+    log::debug!("Stopped handling events");
+    Ok(())
+}
+
+/// Waits until the storage backend can be reached. `false` if shutdown came first, as nothing's in progress that
+/// would be worth waiting for.
+async fn wait_for_storage() -> bool {
+    let config = common::get_config();
+
+    tokio::select! {
+        _ = config.connect_storage() => true,
+        () = crate::shutdown::token().cancelled() => false,
+    }
+}
+
+/// Reads and dispatches events until shutdown or until reading fails. Events which aren't due yet are held back without
+/// taking up a place in the pool.
+async fn consume_events(consumer: &str, pool: &mut KeyedPool<OrderKey>) -> Result<()> {
+    let config = common::get_config();
+    let shutdown = crate::shutdown::token();
+    let mut storage = config.connect_storage().await;
+
+    storage.create_consumer_group().await?;
 
-            let _: () = redis.xack(&config.redis.task_stream_key, NEW_CSR_EVENT_GROUP, &[id.id])
-                .await?;
+    // Reads block, so they get a connection of their own, leaving the shared one to the handlers
+    let mut stream_storage = config.connect_storage_dedicated().await?;
+    let mut deferred = Deferred::new();
 
+    // Entries handed to this consumer before a restart, which it never got to acknowledge
+    for entry in storage.read_pending_events(consumer).await? {
+        let Some(entry) = deferred.admit(entry) else {
+            continue;
+        };
+
+        let mut storage = storage.clone();
+        pool.spawn(order_key(&entry), async move { retry_event(&mut storage, entry).await }).await?;
+    }
+
+    let claim_interval = Duration::from_secs(config.worker.claim_interval_secs);
+    let mut last_claim = Instant::now();
+
+    while !shutdown.is_cancelled() {
+        if last_claim.elapsed() >= claim_interval {
+            last_claim = Instant::now();
+
+            for entry in claim_idle_events(&mut storage, consumer).await? {
+                let Some(entry) = deferred.admit(entry) else {
+                    continue;
+                };
+
+                let mut storage = storage.clone();
+                pool.spawn(order_key(&entry), async move { retry_event(&mut storage, entry).await }).await?;
+            }
+        }
+
+        let entries = stream_storage.read_events(consumer, config.worker.concurrency, READ_BLOCK).await?;
+        for entry in deferred.due().into_iter().chain(entries.into_iter().filter_map(|entry| deferred.admit(entry))) {
+            let mut storage = storage.clone();
+            pool.spawn(order_key(&entry), async move { handle_event_isolated(&mut storage, entry).await }).await?;
         }
     }
+
+    Ok(())
 }
 
-async fn new_csr(csr: NewCsr) -> Result<()> {
+/// Events held back until their `not_before`. They stay pending meanwhile, so they're read back after a restart.
+struct Deferred {
+    /// The entries being held back, by ID, so one claimed again isn't held back twice
+    ids: HashSet<String>,
+    sender: mpsc::UnboundedSender<EventEntry>,
+    due: mpsc::UnboundedReceiver<EventEntry>,
+}
+
+impl Deferred {
+    fn new() -> Self {
+        let (sender, due) = mpsc::unbounded_channel();
+
+        Self {
+            ids: HashSet::new(),
+            sender,
+            due,
+        }
+    }
+
+    /// The entry if it's due, or else `None` while it's held back until it is.
+    fn admit(&mut self, entry: EventEntry) -> Option<EventEntry> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs() as i64);
+
+        let delay = match not_before(&entry) {
+            Some(not_before) if not_before > now => Duration::from_secs((not_before - now) as u64),
+            _ => return Some(entry),
+        };
+
+        if self.ids.insert(entry.id.clone()) {
+            log::debug!("Holding back event {id} for {delay:?}", id = entry.id);
+
+            let sender = self.sender.clone();
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => { let _ = sender.send(entry); },
+                    () = crate::shutdown::token().cancelled() => {},
+                }
+            });
+        }
+
+        None
+    }
+
+    /// The entries held back which are due by now.
+    fn due(&mut self) -> Vec<EventEntry> {
+        let mut due = vec![];
+        while let Ok(entry) = self.due.try_recv() {
+            self.ids.remove(&entry.id);
+            due.push(entry);
+        }
+
+        due
+    }
+}
+
+/// When the event may be handled. Challenges are checked again only after a while if they haven't passed yet.
+fn not_before(entry: &EventEntry) -> Option<i64> {
+    entry.fields.iter().find_map(|(key, value)| match key.as_str() {
+        CHALLENGE_VALIDATION_EVENT_GROUP => ron::from_str::<ChallengeValidation>(value).ok().map(|event| event.not_before),
+        ACME_CHALLENGE_EVENT_GROUP => ron::from_str::<AcmeChallengeReady>(value).ok().map(|event| event.not_before),
+        _ => None,
+    })
+}
+
+/// Events concerning the same key are handled in the order they were dispatched.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum OrderKey {
+    Job(CsrId),
+    /// Each revocation republishes the CRL, which must not be overtaken by an older one.
+    Revocations,
+}
+
+/// New CSRs don't have an ID yet, so they're independent of everything else.
+fn order_key(entry: &EventEntry) -> Option<OrderKey> {
+    entry.fields.iter().find_map(|(key, value)| match key.as_str() {
+        CHALLENGE_EVENT_GROUP => ron::from_str::<PendingChallenge>(value).ok().map(|event| OrderKey::Job(event.id)),
+        JOB_PROGRESS_EVENT_GROUP => ron::from_str::<JobProgress>(value).ok().map(|event| OrderKey::Job(event.id)),
+        FINISHED_EVENT_GROUP => ron::from_str::<Completion>(value).ok().map(|event| OrderKey::Job(event.id)),
+        CHALLENGE_REVIEW_EVENT_GROUP => ron::from_str::<ChallengeReview>(value).ok().map(|event| OrderKey::Job(event.id)),
+        CHALLENGE_VALIDATION_EVENT_GROUP => ron::from_str::<ChallengeValidation>(value).ok().map(|event| OrderKey::Job(event.id)),
+        REVOCATION_EVENT_GROUP => Some(OrderKey::Revocations),
+        _ => None,
+    })
+}
+
+/// The configured consumer name, or else the host name, which stays the same across restarts of a container.
+async fn consumer_name() -> String {
     let config = common::get_config();
-    let mut redis = config
-        .redis
-        .connect()
-        .await;
 
-    let csr_id: CsrId = redis.incr("csr_id", 1)
+    if let Some(consumer) = &config.worker.consumer {
+        return consumer.clone();
+    }
+
+    if let Ok(hostname) = std::env::var("HOSTNAME") && !hostname.trim().is_empty() {
+        return hostname.trim().to_owned();
+    }
+
+    match tokio::fs::read_to_string("/etc/hostname").await {
+        Ok(hostname) if !hostname.trim().is_empty() => hostname.trim().to_owned(),
+        _ => "worker".to_owned(),
+    }
+}
+
+/// Takes over every entry which has been pending for longer than the configured idle time, whichever consumer it was
+/// handed to.
+async fn claim_idle_events(storage: &mut Storage, consumer: &str) -> Result<Vec<EventEntry>> {
+    let config = common::get_config();
+    let claimed = storage.claim_idle_events(consumer, Duration::from_secs(config.worker.claim_idle_secs))
         .await?;
 
+    if !claimed.is_empty() {
+        log::info!("Claimed {count} idle events", count = claimed.len());
+    }
+
+    Ok(claimed)
+}
+
+/// Handles an entry which was delivered before, unless it has been delivered too often already.
+async fn retry_event(storage: &mut Storage, entry: EventEntry) -> Result<()> {
+    let config = common::get_config();
+    let deliveries = storage.delivery_count(&entry.id).await?;
+
+    if deliveries > config.worker.max_deliveries {
+        let error = format!("Delivered {deliveries} times without being acknowledged");
+        log::error!("Moving event {id} to the dead-letter stream: {error}", id = entry.id);
+        storage.dead_letter(&entry, error, deliveries).await?;
+
+        return Ok(());
+    }
+
+    log::warn!("Retrying event {id} (delivery {deliveries} of {max})", id = entry.id, max = config.worker.max_deliveries);
+    handle_event_isolated(storage, entry).await
+}
+
+/// Handles an entry, without letting an error in its handler stop the worker. An entry which failed stays pending to be
+/// claimed and retried, until it has failed `worker.max_deliveries` times and is moved to the dead-letter stream.
+async fn handle_event_isolated(storage: &mut Storage, entry: EventEntry) -> Result<()> {
+    let config = common::get_config();
+
+    let Err(err) = handle_event(storage, &entry).await else {
+        return Ok(());
+    };
+
+    let deliveries = storage.delivery_count(&entry.id).await?;
+    if deliveries >= config.worker.max_deliveries {
+        log::error!("Moving event {id} to the dead-letter stream after {deliveries} failed attempts: {err:?}", id = entry.id);
+        storage.dead_letter(&entry, err.to_string(), deliveries).await?;
+    } else {
+        log::error!("Event {id} failed (attempt {deliveries} of {max}): {err:?}", id = entry.id, max = config.worker.max_deliveries);
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn handle_event(storage: &mut Storage, entry: &EventEntry) -> Result<()> {
+    for (key, value) in &entry.fields {
+        log::trace!("Received event '{key}'");
+
+        match key.as_str() {
+            NEW_CSR_EVENT_GROUP => new_csr(ron::from_str(value)?).await?,
+            CHALLENGE_EVENT_GROUP => challenge(ron::from_str(value)?).await?,
+            JOB_PROGRESS_EVENT_GROUP => job_progress(ron::from_str(value)?).await?,
+            FINISHED_EVENT_GROUP => completion(ron::from_str(value)?).await?,
+            REVOCATION_EVENT_GROUP => revocation(ron::from_str(value)?).await?,
+            CHALLENGE_REVIEW_EVENT_GROUP => challenge_review(ron::from_str(value)?).await?,
+            ACME_CHALLENGE_EVENT_GROUP => acme_challenge(ron::from_str(value)?).await?,
+            CHALLENGE_VALIDATION_EVENT_GROUP => challenge_validation(ron::from_str(value)?).await?,
+            key => {
+                log::warn!("Unknown job type {key} - skipping");
+                continue;
+            }
+        }
+    }
+
+    storage.acknowledge_event(&entry.id).await?;
+
+    Ok(())
+}
+
+/// The job, which has to exist by the time an event refers to it.
+async fn job(storage: &mut Storage, id: CsrId) -> Result<Csr> {
+    match storage.get_job(id).await? {
+        Some(csr) => Ok(csr),
+        None => Error::custom(format!("No job with ID {id}")),
+    }
+}
+
+async fn client_job(storage: &mut Storage, alias: &str) -> Result<ClientJob> {
+    match storage.get_client_job(alias).await? {
+        Some(job) => Ok(job),
+        None => Error::custom(format!("No job with alias {alias}")),
+    }
+}
+
+async fn new_csr(csr: NewCsr) -> Result<()> {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let csr_id = storage.next_job_id().await?;
+
     log::trace!("Parsing CSR");
 
     let params = rcgen::CertificateSigningRequestParams::from_pem(&csr.pem)?;
 
-    // TODO: Check whether all parameters are acceptable. If not fail the CSR. If acceptable, dispatch a challenge job.
-    let _params = params;
+    let mut violations = config.policy.violations(&params);
+    if let Some(profile) = &csr.profile && !config.profiles.contains_key(profile) {
+        violations.push(format!("Unknown issuance profile '{profile}'"));
+    }
+
+    // Challenges issued elsewhere have to cover the requested names exactly, or names could go unvalidated
+    if !csr.challenges.is_empty() && let Err(reason) = NameChallenge::check_coverage(&params, &csr.challenges) {
+        violations.push(reason);
+    }
 
-    let primary_key = format!("csr:{csr_id}");
-    let _: () = redis.set(&primary_key, ron::to_string(&Csr::from(csr.clone()))?)
-        .await?;
+    let (job_status, client_status) = if violations.is_empty() {
+        (JobStatus::Pending, Status::Pending)
+    } else {
+        let reason = violations.join("; ");
+        log::warn!("CSR {csr_id} violates the issuance policy: {reason}");
+        (JobStatus::ChallengeFailed { reason: reason.clone() }, Status::Error { reason })
+    };
+
+    let mut job = Csr::from(csr.clone());
+    job.set_status(job_status);
+
+    storage.create_job(csr_id, &job).await?;
 
     let alt = common::get_alt_name(csr.client_id, &csr.pem);
     log::debug!("Received certificate: Aliasing to 'alt:{alt}'");
-    let _: () = redis.set(format!("alt:{alt}"), ron::to_string(&ClientJob {
+    storage.set_client_job(&ClientJob {
         alias: alt,
         client_id: csr.client_id,
         serial: csr_id,
-        status: Status::Pending
-    })?)
-        .await?; // 2. index it in a ZSET by timestamp
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)?
-        .as_secs_f64();
-    let _: () = redis.zadd(&config.redis.job_list_key, &primary_key, timestamp)
+        status: client_status
+    })
         .await?;
 
-    redis.dispatch_event(PendingChallenge {
+    if !violations.is_empty() {
+        return Ok(());
+    }
+
+    storage.dispatch_event(PendingChallenge {
         id: csr_id,
     }).await?;
 
@@ -130,48 +419,330 @@ async fn new_csr(csr: NewCsr) -> Result<()> {
 
 async fn challenge(challenge: PendingChallenge) -> Result<()> {
     let config = common::get_config();
-    let mut redis = config
-        .redis
-        .connect()
-        .await;
+    let mut storage = config.connect_storage().await;
 
     log::trace!("Initiating Challenge {id}", id=challenge.id);
-    let csr: Csr = redis.get(format!("csr:{id}", id=challenge.id)).await?;
+    let mut csr = job(&mut storage, challenge.id).await?;
 
     match csr.status {
         JobStatus::Pending => {},
         _ => return Err(io::Error::other("Request has already been processed.").into())
     }
 
+    if csr.challenges.is_empty() && let Some(kind) = csr.challenge.or(config.challenge.default_type) {
+        let issued = rcgen::CertificateSigningRequestParams::from_pem(csr.pem())
+            .map_err(Into::into)
+            .and_then(|params| NameChallenge::for_request(&params, kind));
+
+        match issued {
+            Ok(challenges) => csr.challenges = challenges,
+            Err(err) => {
+                log::warn!("Unable to issue {kind:?} challenges for job {id}: {err:?}", id=challenge.id);
+                storage.dispatch_event(JobProgress {
+                    id: challenge.id,
+                    status: JobStatus::ChallengeFailed {
+                        reason: err.to_string(),
+                    },
+                }).await?;
+
+                return Ok(());
+            }
+        }
+    }
+
+    csr.set_status(JobStatus::ChallengePending);
+    storage.set_job(challenge.id, &csr).await?;
+
+    if csr.challenges.is_empty() {
+        log::info!("Job {id} awaits a manual decision", id=challenge.id);
+        return Ok(());
+    }
+
+    // Approvals the profile requires are collected while the challenges are validated, and signing waits for both
+    let approvals = config.profile(csr.profile.as_deref()).map_or(0, |profile| profile.approvals);
+    if approvals > 0 {
+        log::info!("Job {id} awaits {approvals} approvals besides its challenges", id=challenge.id);
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .as_secs() as i64;
+
+    // The job's validation may take as long as its slowest challenge type allows
+    let deadline_secs = csr.challenges
+        .iter()
+        .map(|challenge| config.challenge.deadline_secs(challenge.kind))
+        .max()
+        .unwrap_or_default();
+
+    storage.dispatch_event(ChallengeValidation {
+        id: challenge.id,
+        deadline: now + deadline_secs as i64,
+        not_before: 0,
+    }).await?;
+
+    Ok(())
+}
+
+/// Checks every challenge of the job which hasn't passed yet, recording those which do. Missing dns-01 records are
+/// looked for again after `challenge.dns01.poll_interval_secs`, until the deadline.
+async fn challenge_validation(validation: ChallengeValidation) -> Result<()> {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let mut csr = job(&mut storage, validation.id).await?;
+
+    if csr.status != JobStatus::ChallengePending {
+        log::warn!("Job {id} is {status:?} - not validating its challenges", id=validation.id, status=csr.status);
+        return Ok(());
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .as_secs() as i64;
+
+    let mut failed = None;
+    let mut pending = false;
+
+    for challenge in csr.challenges.iter_mut().filter(|challenge| challenge.validated_at.is_none()) {
+        match crate::challenge::check(&config.challenge, challenge).await.by_deadline(now, validation.deadline) {
+            Check::Passed => {
+                log::debug!("{kind:?} challenge for {name} passed", kind=challenge.kind, name=challenge.name);
+                challenge.validated_at = Some(now);
+            },
+            Check::Failed(reason) => {
+                failed = Some(format!("Validation of {name} failed: {reason}", name=challenge.name));
+                break;
+            },
+            Check::Pending(reason) => {
+                log::debug!("{kind:?} challenge for {name} not passed yet: {reason}", kind=challenge.kind, name=challenge.name);
+                pending = true;
+            },
+        }
+    }
+
+    storage.set_job(validation.id, &csr).await?;
+
+    let status = match (failed, pending) {
+        (Some(reason), _) => JobStatus::ChallengeFailed { reason },
+        (None, true) => {
+            storage.dispatch_event(ChallengeValidation {
+                not_before: now + config.challenge.dns01.poll_interval_secs as i64,
+                ..validation
+            }).await?;
+            return Ok(());
+        },
+        (None, false) => {
+            let approvals = config.profile(csr.profile.as_deref()).map_or(0, |profile| profile.approvals);
+            let approvers = csr.approvers().len();
+
+            if approvers < approvals {
+                log::info!("Challenges of job {id} passed - waiting for {approvals} approvals, has {approvers}", id=validation.id);
+                return Ok(());
+            }
+
+            JobStatus::ChallengePassed
+        },
+    };
+
+    if let JobStatus::ChallengeFailed { reason } = &status {
+        log::warn!("Challenge {id} failed: {reason}", id=validation.id);
+    }
+
+    storage.dispatch_event(JobProgress {
+        id: validation.id,
+        status,
+    }).await?;
+
+    Ok(())
+}
+
+/// Validates an ACME challenge whose client is ready for it, and grants or denies its authorization accordingly
+/// ([RFC 8555 §7.5.1](https://www.rfc-editor.org/rfc/rfc8555#section-7.5.1)).
+async fn acme_challenge(ready: AcmeChallengeReady) -> Result<()> {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let Some(mut authz) = storage.get_authorization(&ready.authorization).await? else {
+        return Error::custom(format!("No authorization with ID {id}", id=ready.authorization));
+    };
+
+    let Some(challenge) = authz.challenges.get(ready.index).cloned() else {
+        return Error::custom(format!("Authorization {id} has no challenge {index}", id=ready.authorization, index=ready.index));
+    };
+
+    if challenge.status != AcmeStatus::Processing || authz.status != AcmeStatus::Pending {
+        log::warn!("Challenge {id}/{index} is {status:?} - not validating it", id=ready.authorization, index=ready.index, status=challenge.status);
+        return Ok(());
+    }
+
+    let Some(account) = storage.get_account(&authz.account).await? else {
+        return Error::custom(format!("No ACME account with ID {id}", id=authz.account));
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .as_secs() as i64;
+
+    // Wildcards are validated over dns-01, which looks up the name without its `*.` anyway
+    let key_authorization = common::key_authorization(&challenge.token, &account.jwk()?)?;
+    let expected = NameChallenge::new(authz.identifier.value.clone(), challenge.kind, challenge.token.clone(), key_authorization);
+
+    let check = match authz.expires < now {
+        true => Check::Failed("The authorization has expired".to_owned()),
+        false => crate::challenge::check(&config.challenge, &expected).await,
+    };
+
+    let result = match check.by_deadline(now, ready.deadline) {
+        Check::Passed => Ok(()),
+        Check::Failed(reason) => Err(reason),
+        Check::Pending(reason) => {
+            log::debug!("ACME challenge {id}/{index} not passed yet: {reason}", id=ready.authorization, index=ready.index);
+            storage.dispatch_event(AcmeChallengeReady {
+                not_before: now + config.challenge.dns01.poll_interval_secs as i64,
+                ..ready
+            }).await?;
+            return Ok(());
+        },
+    };
+
+    let challenge = &mut authz.challenges[ready.index];
+    match result {
+        Ok(()) => {
+            log::info!("ACME challenge {id}/{index} passed", id=ready.authorization, index=ready.index);
+            challenge.status = AcmeStatus::Valid;
+            challenge.validated = Some(now);
+            authz.status = AcmeStatus::Valid;
+        },
+        Err(reason) => {
+            let reason = format!("Validation of {name} failed: {reason}", name=expected.name);
+            log::info!("ACME challenge {id}/{index} failed: {reason}", id=ready.authorization, index=ready.index);
+            challenge.status = AcmeStatus::Invalid;
+            challenge.error = Some(reason);
+            authz.status = AcmeStatus::Invalid;
+        },
+    }
+
+    storage.store_authorization(&authz).await?;
+
+    Ok(())
+}
+
+/// Records the decision against the job. A single rejection fails the challenge, while passing it takes as many distinct
+/// approvals as the job's profile requires.
+async fn challenge_review(review: ChallengeReview) -> Result<()> {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let mut csr = job(&mut storage, review.id).await?;
+
+    if !matches!(csr.status, JobStatus::Pending | JobStatus::ChallengePending) {
+        log::warn!("Job {id} is {status:?} - ignoring review by {reviewer}", id=review.id, status=csr.status, reviewer=review.reviewer);
+        return Ok(());
+    }
+
+    if review.decision == ReviewDecision::Approve && csr.approvers().contains(review.reviewer.as_str()) {
+        log::warn!("{reviewer} has already approved job {id} - ignoring duplicate approval", reviewer=review.reviewer, id=review.id);
+        return Ok(());
+    }
+
+    log::info!("{reviewer} decided to {decision:?} job {id}: {reason}", reviewer=review.reviewer, decision=review.decision, id=review.id, reason=review.reason);
+
+    csr.reviews.push(Review {
+        reviewer: review.reviewer.clone(),
+        decision: review.decision,
+        reason: review.reason.clone(),
+        reviewed_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)?
+            .as_secs() as i64,
+    });
+    storage.set_job(review.id, &csr).await?;
+
+    let status = match review.decision {
+        ReviewDecision::Approve => {
+            let approvals = config.profile(csr.profile.as_deref()).map_or(0, |profile| profile.approvals);
+            let approvers = csr.approvers().len();
+
+            if approvers < approvals {
+                log::info!("Job {id} has {approvers} of {approvals} approvals", id=review.id);
+                return Ok(());
+            }
+
+            // Validation dispatches the job once its challenges pass, as the quorum is already met by then
+            if !csr.challenges_validated() {
+                log::info!("Job {id} is approved but its challenges haven't passed yet", id=review.id);
+                return Ok(());
+            }
+
+            JobStatus::ChallengePassed
+        },
+        ReviewDecision::Reject => JobStatus::ChallengeFailed {
+            reason: format!("Rejected by {reviewer}: {reason}", reviewer=review.reviewer, reason=review.reason),
+        },
+    };
+
+    storage.dispatch_event(JobProgress {
+        id: review.id,
+        status,
+    }).await?;
+
     Ok(())
 }
 
 async fn job_progress(update: JobProgress) -> Result<()> {
     let config = common::get_config();
-    let mut redis = config
-        .redis
-        .connect()
-        .await;
+    let mut storage = config.connect_storage().await;
 
-    let redis_key = format!("csr:{id}", id=update.id);
-    let mut csr: Csr = redis.get(&redis_key).await?;
+    let mut csr = job(&mut storage, update.id).await?;
+    let approvals = config.profile(csr.profile.as_deref()).map_or(0, |profile| profile.approvals);
 
-    csr.status = match update.status {
+    let status = match update.status {
         JobStatus::Pending | JobStatus::ChallengePending if csr.status != update.status => {
             log::warn!("Job {id} was changed to {status:?}. Changing a job back to pending can leave it in a non-recoverable state.", id=update.id, status=update.status);
             update.status
         },
+        JobStatus::ChallengePassed | JobStatus::ChallengeFailed { .. } if !matches!(csr.status, JobStatus::Pending | JobStatus::ChallengePending) => {
+            log::warn!("Job {id} is {status:?} - ignoring {update:?}", id=update.id, status=csr.status, update=update.status);
+            return Ok(());
+        },
+        JobStatus::ChallengePassed if csr.approvers().len() < approvals => {
+            log::warn!("Job {id} needs {approvals} approvals but has {approvers} - not signing", id=update.id, approvers=csr.approvers().len());
+            return Ok(());
+        },
+        JobStatus::ChallengePassed if !csr.challenges_validated() => {
+            log::warn!("Job {id} has challenges which haven't passed - not signing", id=update.id);
+            return Ok(());
+        },
+        JobStatus::ChallengeFailed { reason } => {
+            log::info!("Challenge {id} failed", id=update.id);
+
+            let client_job = client_job(&mut storage, &csr.client_alias).await?;
+            storage.set_client_job(&ClientJob {
+                status: Status::Error {
+                    reason: reason.clone(),
+                },
+                ..client_job
+            }).await?;
+
+            JobStatus::ChallengeFailed { reason }
+        },
         JobStatus::ChallengePassed => {
             log::info!("Challenge {id} passed", id=update.id);
             let signing = 'crt: {
-                let issuer = match get_issuer(&config).await {
-                    Ok(issuer) => issuer,
+                let authority = match Authority::current() {
+                    Ok(authority) => authority,
+                    Err(err) => break 'crt Err(err),
+                };
+
+                let profile = match config.profile(csr.profile.as_deref()) {
+                    Ok(profile) => profile,
                     Err(err) => break 'crt Err(err),
                 };
 
                 let params = match rcgen::CertificateSigningRequestParams::from_pem(csr.pem()) {
                     Ok(mut params) => {
                         params.params.serial_number.replace(update.id.into());
+                        profile.apply(&mut params.params, authority.not_after);
                         params
                     },
                     Err(err) => break 'crt Err(err.into()),
@@ -179,7 +750,7 @@ async fn job_progress(update: JobProgress) -> Result<()> {
 
                 log::info!("Signing certificate for {cn:?}", cn=params.params.subject_alt_names);
 
-                let result = match params.signed_by(&issuer) {
+                let result = match params.signed_by(&authority.issuer) {
                     Ok(result) => result,
                     Err(err) => break 'crt Err(err.into()),
                 };
@@ -187,10 +758,11 @@ async fn job_progress(update: JobProgress) -> Result<()> {
                 Ok(result)
             };
 
-            let new_status = match signing {
+            // Recorded right here, as Completion turns the job stale and a status dispatched now would be handled after it
+            match signing {
                 Ok(cert) => {
                     log::info!("Certificate for client signed.");
-                    redis.dispatch_event(Completion {
+                    storage.dispatch_event(Completion {
                         client_id: csr.client_id,
                         id: update.id,
                         certificate: cert.pem()
@@ -203,53 +775,480 @@ async fn job_progress(update: JobProgress) -> Result<()> {
                         reason: err.to_string(),
                     }
                 }
-            };
-
-            redis.dispatch_event(JobProgress {
-                id: update.id,
-                status: new_status.clone(),
-            }).await?;
-
-            new_status
+            }
         },
         status => status
     };
 
-    let _: () = redis.set(redis_key, ron::to_string(&csr)?).await?;
+    csr.set_status(status);
+
+    storage.set_job(update.id, &csr).await?;
 
     Ok(())
 }
 
 async fn completion(completion: Completion) -> Result<()> {
     let config = common::get_config();
-    let mut redis = config
-        .redis
-        .connect()
-        .await;
+    let mut storage = config.connect_storage().await;
 
-    let csr_key = format!("csr:{id}", id=completion.id);
-    let mut csr: Csr = redis.get(&csr_key).await?;
-    let cert_key = format!("alt:{alt}", alt=csr.client_alias);
-    let client_job: ClientJob = redis.get(&cert_key).await?;
+    let mut csr = job(&mut storage, completion.id).await?;
+    let client_job = client_job(&mut storage, &csr.client_alias).await?;
 
-    csr.status = JobStatus::Stale;
+    csr.set_status(JobStatus::Stale);
 
-    let _: () = redis.set(cert_key, ron::to_string(&ClientJob {
+    let issued = IssuedCertificate::from_pem(completion.id, csr.profile.clone(), completion.certificate.clone())?;
+    if !storage.store_certificate(&issued).await? {
+        log::warn!("A certificate with serial {id} has already been stored - keeping the existing one", id=completion.id);
+    }
+
+    storage.set_client_job(&ClientJob {
         status: Status::Success {
             certificate: completion.certificate
         },
         ..client_job
-    })?).await?;
-    let _: () = redis.set(csr_key, ron::to_string(&csr)?).await?;
+    }).await?;
+    storage.set_job(completion.id, &csr).await?;
 
     Ok(())
 }
 
-async fn get_issuer(config: &Config) -> Result<rcgen::Issuer<'_, impl SigningKey>> {
-    let cert = tokio::fs::read_to_string(&config.ca.certificate).await?;
-    let key = tokio::fs::read_to_string(&config.ca.key).await?;
+async fn revocation(revocation: Revocation) -> Result<()> {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    if storage.get_certificate(revocation.serial).await?.is_none() {
+        log::warn!("Cannot revoke {serial}: No certificate with this serial has been issued", serial=revocation.serial);
+        return Ok(());
+    }
 
-    let key = KeyPair::from_pem(&key)?;
+    if !revocation.reason.can_revoke() {
+        log::warn!("Cannot revoke {serial}: remove-from-crl is not a revocation reason", serial=revocation.serial);
+        return Ok(());
+    }
+
+    let revoked = storage.record_revocation(&RevokedCertificate {
+        serial: revocation.serial,
+        reason: revocation.reason,
+        revocation_date: SystemTime::now()
+            .duration_since(UNIX_EPOCH)?
+            .as_secs() as i64,
+        invalidity_date: revocation.invalidity_date,
+        requested_by: revocation.requested_by.clone(),
+    }).await?;
+
+    if revoked {
+        log::info!("Certificate {serial} revoked by {by} ({reason:?})", serial=revocation.serial, by=revocation.requested_by, reason=revocation.reason);
+        publish_crl().await?;
+    } else {
+        log::warn!("Certificate {serial} has already been revoked", serial=revocation.serial);
+    }
+
+    Ok(())
+}
+
+/// Signs a CRL covering every revocation in the registry and publishes it.
+pub(crate) async fn publish_crl() -> Result<()> {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let authority = Authority::current()?;
+    // The number is taken first, so a CRL with a higher number never lists fewer revocations
+    let crl_number = storage.next_crl_number().await?;
+    let revoked = storage.get_revocations().await?;
+
+    let crl = authority.sign_crl(&revoked, crl_number, time::Duration::seconds(config.crl.next_update_secs as i64))?;
+
+    if storage.publish_crl(crl_number, crl.der(), &crl.pem()?).await? {
+        log::debug!("Published CRL #{crl_number} listing {count} revoked certificates", count=revoked.len());
+    } else {
+        log::debug!("Not publishing CRL #{crl_number}, as a newer one has been published already");
+    }
+
+    Ok(())
+}
+
+/// Republishes the CRL after half of its validity period, so relying parties always find one that's still current.
+pub(crate) async fn publish_crl_periodically() -> Result<()> {
+    let config = common::get_config();
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs((config.crl.next_update_secs / 2).max(1)));
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            () = crate::shutdown::token().cancelled() => return Ok(()),
+        }
+
+        if !wait_for_storage().await {
+            return Ok(());
+        }
+
+        if let Err(err) = publish_crl().await {
+            log::error!("Failed to publish CRL: {err:?}");
+        }
+    }
+}
+
+/// Fails every job which has been waiting for its challenge, or for its reviewers, for longer than allowed.
+pub(crate) async fn expire_jobs() -> Result<()> {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let expiry = &config.challenge.expiry;
+    let shortest = [None, Some(ChallengeType::Http01), Some(ChallengeType::Dns01), Some(ChallengeType::TlsAlpn01)]
+        .into_iter()
+        .map(|kind| expiry.expiry_secs(kind))
+        .min()
+        .unwrap_or_default();
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .as_secs_f64();
+
+    let candidates = storage.jobs_submitted_before(now - shortest as f64)
+        .await?;
+
+    for (id, submitted) in candidates {
+        let Some(csr) = storage.get_job(id).await? else {
+            continue;
+        };
+
+        if !matches!(csr.status, JobStatus::Pending | JobStatus::ChallengePending) {
+            continue;
+        }
+
+        let kind = awaited_challenge(&csr, &config.challenge);
+        if submitted + expiry.expiry_secs(kind) as f64 > now {
+            continue;
+        }
+
+        match kind {
+            Some(kind) => log::info!("Job {id} has expired waiting for its {kind:?} challenge"),
+            None => log::info!("Job {id} has expired waiting for its reviewers"),
+        }
+        storage.dispatch_event(JobProgress {
+            id,
+            status: JobStatus::ChallengeFailed {
+                reason: "expired".to_owned(),
+            },
+        }).await?;
+    }
+
+    Ok(())
+}
 
-    Ok(Issuer::from_ca_cert_pem(&cert, key)?)
-}
\ No newline at end of file
+/// The type of challenge the job is waiting on, or `None` if it's waiting on its reviewers. That's the case for jobs
+/// without challenges, and for jobs whose challenges have all passed but which still lack approvals.
+fn awaited_challenge(csr: &Csr, config: &ChallengeConfig) -> Option<ChallengeType> {
+    if let Some(challenge) = csr.challenges.iter().find(|challenge| challenge.validated_at.is_none()) {
+        return Some(challenge.kind);
+    }
+
+    match csr.status {
+        // Challenges are only issued once the job reaches the challenge stage
+        JobStatus::Pending if csr.challenges.is_empty() => csr.challenge.or(config.default_type),
+        _ => None,
+    }
+}
+
+pub(crate) async fn expire_jobs_periodically() -> Result<()> {
+    let config = common::get_config();
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(config.challenge.expiry.interval_secs.max(1)));
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            () = crate::shutdown::token().cancelled() => return Ok(()),
+        }
+
+        if !wait_for_storage().await {
+            return Ok(());
+        }
+
+        if let Err(err) = expire_jobs().await {
+            log::error!("Failed to expire jobs: {err:?}");
+        }
+    }
+}
+
+pub(crate) async fn collect_garbage_periodically() -> Result<()> {
+    let config = common::get_config();
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(config.gc.interval_secs.max(1)));
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            () = crate::shutdown::token().cancelled() => return Ok(()),
+        }
+
+        if !wait_for_storage().await {
+            return Ok(());
+        }
+
+        let mut storage = config.connect_storage().await;
+
+        match storage.collect_garbage(&config.gc, config.gc.dry_run).await {
+            Ok(report) if report.dry_run => log::info!("GC would delete {jobs:?} and {aliases:?}, and trim the event stream before {min_id:?}", jobs=report.jobs, aliases=report.aliases, min_id=report.stream_min_id),
+            Ok(report) => log::debug!("GC deleted {jobs} jobs and trimmed {trimmed:?} events", jobs=report.jobs.len(), trimmed=report.stream_entries_trimmed),
+            Err(err) => log::error!("Failed to collect garbage: {err:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{environment, Environment};
+
+    #[tokio::test]
+    async fn issued_jobs_are_collected() {
+        let environment = environment().await;
+        let config = common::get_config();
+        let mut storage = environment.storage().await;
+
+        let key = rcgen::KeyPair::generate().unwrap();
+        let pem = rcgen::CertificateParams::new(vec!["app.test".to_owned()]).unwrap()
+            .serialize_request(&key).unwrap()
+            .pem().unwrap();
+
+        storage.dispatch_event(NewCsr {
+            client_id: 1,
+            pem: pem.clone(),
+            profile: Some("server".to_owned()),
+            challenge: None,
+            challenges: vec![],
+        }).await.unwrap();
+        environment.handle_all().await;
+
+        let alias = common::get_alt_name(1, &pem);
+        let id = storage.get_client_job(&alias).await.unwrap().unwrap().serial;
+        assert_eq!(job(&mut storage, id).await.unwrap().status, JobStatus::ChallengePending);
+
+        storage.dispatch_event(ChallengeReview {
+            id,
+            decision: ReviewDecision::Approve,
+            reason: "Requested through a ticket".to_owned(),
+            reviewer: "admin".to_owned(),
+        }).await.unwrap();
+        environment.handle_all().await;
+
+        assert_eq!(job(&mut storage, id).await.unwrap().status, JobStatus::Stale);
+        assert!(matches!(client_job(&mut storage, &alias).await.unwrap().status, Status::Success { .. }));
+
+        // The retention cutoff is in whole seconds, while jobs are submitted at fractions of one
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        let report = storage.collect_garbage(&config.gc, false).await.unwrap();
+        assert!(report.jobs.contains(&format!("csr:{id}")));
+
+        assert!(storage.get_job(id).await.unwrap().is_none());
+        assert!(storage.get_client_job(&alias).await.unwrap().is_none());
+        assert!(storage.get_certificate(id).await.unwrap().is_some(), "Issued certificates are kept");
+    }
+
+    fn unix_now() -> i64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+    }
+
+    fn validation_entry(id: &str, not_before: i64) -> EventEntry {
+        let event = ChallengeValidation {
+            id: 1,
+            deadline: unix_now() + 60,
+            not_before,
+        };
+
+        EventEntry {
+            id: id.to_owned(),
+            fields: vec![(CHALLENGE_VALIDATION_EVENT_GROUP.to_owned(), ron::to_string(&event).unwrap())],
+        }
+    }
+
+    #[tokio::test]
+    async fn events_are_held_back_until_due() {
+        let mut deferred = Deferred::new();
+
+        assert!(deferred.admit(validation_entry("1-0", 0)).is_some());
+        assert!(deferred.admit(validation_entry("2-0", unix_now() + 1)).is_none());
+        assert!(deferred.admit(validation_entry("2-0", unix_now() + 1)).is_none(), "Claimed again while held back");
+        assert!(deferred.due().is_empty());
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        let due = deferred.due();
+        assert_eq!(due.iter().map(|entry| entry.id.as_str()).collect::<Vec<_>>(), ["2-0"]);
+        assert!(deferred.due().is_empty());
+    }
+
+    /// The validation of an http-01 challenge may take as long as its attempts do, rather than as long as dns-01
+    /// records are looked for.
+    #[tokio::test]
+    async fn validation_deadline_follows_the_challenge_type() {
+        let environment = environment().await;
+        let config = common::get_config();
+        let mut storage = environment.storage().await;
+
+        let key = rcgen::KeyPair::generate().unwrap();
+        let pem = rcgen::CertificateParams::new(vec!["acme.test".to_owned()]).unwrap()
+            .serialize_request(&key).unwrap()
+            .pem().unwrap();
+
+        storage.dispatch_event(NewCsr {
+            client_id: 2,
+            pem,
+            profile: Some("server".to_owned()),
+            challenge: Some(ChallengeType::Http01),
+            challenges: vec![],
+        }).await.unwrap();
+
+        let validation = loop {
+            let entries = storage.read_events("test", 1, Duration::from_millis(50)).await.unwrap();
+            let entry = entries.first().expect("A validation is dispatched");
+            handle_event(&mut storage, entry).await.unwrap();
+
+            if let Some((_, value)) = entry.fields.iter().find(|(key, _)| key == CHALLENGE_VALIDATION_EVENT_GROUP) {
+                break ron::from_str::<ChallengeValidation>(value).unwrap();
+            }
+        };
+
+        let deadline_secs = config.challenge.deadline_secs(ChallengeType::Http01) as i64;
+        assert!(deadline_secs < config.challenge.dns01.deadline_secs as i64);
+        assert!(validation.deadline <= unix_now() + deadline_secs);
+        assert_eq!(validation.not_before, 0);
+
+        // Nothing answers the challenge, so the job fails
+        environment.handle_all().await;
+        assert!(matches!(job(&mut storage, validation.id).await.unwrap().status, JobStatus::ChallengeFailed { .. }));
+    }
+
+    fn approval(id: CsrId, reviewer: &str) -> ChallengeReview {
+        ChallengeReview {
+            id,
+            decision: ReviewDecision::Approve,
+            reason: "Requested through a ticket".to_owned(),
+            reviewer: reviewer.to_owned(),
+        }
+    }
+
+    /// Submits a job for `acme.test` under the `reviewed` profile, which takes two approvals, along with its http-01
+    /// challenge. Returns the ID of the job once it has been created, and the challenge.
+    async fn submit_reviewed(environment: &Environment, client_id: u64) -> (CsrId, NameChallenge) {
+        let mut storage = environment.storage().await;
+
+        let key = rcgen::KeyPair::generate().unwrap();
+        let pem = rcgen::CertificateParams::new(vec!["acme.test".to_owned()]).unwrap()
+            .serialize_request(&key).unwrap()
+            .pem().unwrap();
+        let challenge = NameChallenge::issue("acme.test", ChallengeType::Http01).unwrap();
+
+        storage.dispatch_event(NewCsr {
+            client_id,
+            pem: pem.clone(),
+            profile: Some("reviewed".to_owned()),
+            challenge: None,
+            challenges: vec![challenge.clone()],
+        }).await.unwrap();
+        environment.handle_next().await;
+
+        let alias = common::get_alt_name(client_id, &pem);
+        (storage.get_client_job(&alias).await.unwrap().unwrap().serial, challenge)
+    }
+
+    #[tokio::test]
+    async fn signing_waits_for_approvals_after_challenges() {
+        let environment = environment().await;
+        let mut storage = environment.storage().await;
+
+        let (id, challenge) = submit_reviewed(&environment, 3).await;
+        environment.serve_http01(&challenge.token, &challenge.key_authorization);
+        environment.handle_all().await;
+
+        let csr = job(&mut storage, id).await.unwrap();
+        assert!(csr.challenges_validated());
+        assert_eq!(csr.status, JobStatus::ChallengePending, "Waiting for approvals");
+
+        storage.dispatch_event(approval(id, "alice")).await.unwrap();
+        storage.dispatch_event(approval(id, "alice")).await.unwrap();
+        environment.handle_all().await;
+        assert_eq!(job(&mut storage, id).await.unwrap().status, JobStatus::ChallengePending, "A second approval by the same reviewer doesn't count");
+
+        storage.dispatch_event(approval(id, "bob")).await.unwrap();
+        environment.handle_all().await;
+        assert_eq!(job(&mut storage, id).await.unwrap().status, JobStatus::Stale);
+    }
+
+    #[tokio::test]
+    async fn signing_waits_for_challenges_after_approvals() {
+        let environment = environment().await;
+        let mut storage = environment.storage().await;
+
+        let (id, challenge) = submit_reviewed(&environment, 4).await;
+        storage.dispatch_event(approval(id, "alice")).await.unwrap();
+        storage.dispatch_event(approval(id, "bob")).await.unwrap();
+
+        // The challenge stage, then both approvals, leaving the validation
+        for _ in 0..3 {
+            environment.handle_next().await;
+        }
+
+        let csr = job(&mut storage, id).await.unwrap();
+        assert_eq!(csr.approvers().len(), 2);
+        assert_eq!(csr.status, JobStatus::ChallengePending, "Waiting for the challenge");
+
+        environment.serve_http01(&challenge.token, &challenge.key_authorization);
+        environment.handle_all().await;
+        assert_eq!(job(&mut storage, id).await.unwrap().status, JobStatus::Stale);
+    }
+
+    #[tokio::test]
+    async fn rejections_outweigh_approvals() {
+        let environment = environment().await;
+        let mut storage = environment.storage().await;
+
+        let (id, _) = submit_reviewed(&environment, 5).await;
+        storage.dispatch_event(approval(id, "alice")).await.unwrap();
+        storage.dispatch_event(ChallengeReview {
+            decision: ReviewDecision::Reject,
+            ..approval(id, "bob")
+        }).await.unwrap();
+
+        // The challenge stage and both reviews, before the challenge is validated
+        for _ in 0..3 {
+            environment.handle_next().await;
+        }
+        environment.handle_all().await;
+
+        let csr = job(&mut storage, id).await.unwrap();
+        assert!(matches!(csr.status, JobStatus::ChallengeFailed { reason } if reason.starts_with("Rejected by bob")));
+    }
+
+    #[test]
+    fn jobs_awaiting_challenges_expire_by_their_type() {
+        let config = ChallengeConfig::default();
+        let mut csr = Csr::from(String::new());
+        csr.challenge = Some(ChallengeType::Dns01);
+        assert_eq!(awaited_challenge(&csr, &config), Some(ChallengeType::Dns01), "Before the challenges are issued");
+
+        csr.set_status(JobStatus::ChallengePending);
+        csr.challenges = vec![
+            NameChallenge::issue("app.test", ChallengeType::Dns01).unwrap(),
+            NameChallenge::issue("www.app.test", ChallengeType::Dns01).unwrap(),
+        ];
+        csr.challenges[0].validated_at = Some(1);
+        assert_eq!(awaited_challenge(&csr, &config), Some(ChallengeType::Dns01));
+    }
+
+    #[test]
+    fn jobs_awaiting_approvals_expire_as_manual() {
+        let config = ChallengeConfig::default();
+        let mut csr = Csr::from(String::new());
+        csr.profile = Some("reviewed".to_owned());
+        csr.set_status(JobStatus::ChallengePending);
+        csr.challenges = vec![NameChallenge::issue("app.test", ChallengeType::Http01).unwrap()];
+        csr.challenges[0].validated_at = Some(1);
+
+        assert_eq!(awaited_challenge(&csr, &config), None);
+        assert_eq!(config.expiry.expiry_secs(awaited_challenge(&csr, &config)), config.expiry.manual_secs);
+
+        csr.challenges.clear();
+        assert_eq!(awaited_challenge(&csr, &config), None, "Jobs without challenges wait for their reviewers");
+    }
+}
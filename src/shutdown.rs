@@ -0,0 +1,63 @@
+//! # Shutdown
+//! On SIGTERM or SIGINT, modules stop taking new work and finish what they're in the middle of. Whatever hasn't
+//! finished by the end of the grace period is cut short.
+
+use std::pin::pin;
+use std::sync::LazyLock;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+static SHUTDOWN: LazyLock<CancellationToken> = LazyLock::new(CancellationToken::new);
+
+/// Cancelled once the process was asked to stop.
+pub fn token() -> &'static CancellationToken {
+    &SHUTDOWN
+}
+
+/// Resolves on the first SIGTERM or SIGINT.
+async fn signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => { terminate.recv().await; },
+            Err(err) => {
+                log::error!("Failed to listen for SIGTERM: {err:?}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => if let Err(err) = result {
+            log::error!("Failed to listen for SIGINT: {err:?}");
+            std::future::pending::<()>().await;
+        },
+        () = terminate => {},
+    }
+}
+
+/// Runs the future to completion, or until the grace period following a shutdown signal is over. `None` if it was cut
+/// short.
+pub async fn graceful<T>(future: impl Future<Output = T>) -> Option<T> {
+    let config = common::get_config();
+    let mut future = pin!(future);
+
+    tokio::select! {
+        output = &mut future => return Some(output),
+        () = signal() => {},
+    }
+
+    log::info!("Shutting down - Waiting up to {secs}s for work in progress", secs = config.shutdown.grace_period_secs);
+    SHUTDOWN.cancel();
+
+    match tokio::time::timeout(Duration::from_secs(config.shutdown.grace_period_secs), future).await {
+        Ok(output) => Some(output),
+        Err(_) => {
+            log::warn!("Work in progress didn't finish within the grace period");
+            None
+        }
+    }
+}
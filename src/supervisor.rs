@@ -0,0 +1,113 @@
+//! # Supervisor
+//! Runs every module enabled in the config as a task of its own within one process. Modules which stop, whether by
+//! failing or panicking, are restarted after a backoff. All of them are stopped together on shutdown.
+
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use common::{ModuleList, Result};
+use crate::{authority, inbox, runner, shutdown, web};
+
+type Task = fn() -> BoxFuture<'static, Result<()>>;
+
+/// The tasks making up the enabled modules.
+fn tasks(modules: &ModuleList) -> Vec<(&'static str, Task)> {
+    let mut tasks: Vec<(&'static str, Task)> = vec![];
+
+    if modules.ca || modules.web {
+        tasks.push(("authority", || authority::reload_on_change().boxed()));
+    }
+
+    if modules.ca {
+        tasks.push(("worker", || runner::handle_events().boxed()));
+        tasks.push(("crl", || runner::publish_crl_periodically().boxed()));
+        tasks.push(("expiry", || runner::expire_jobs_periodically().boxed()));
+    }
+
+    if modules.web {
+        tasks.push(("web", || web::run().boxed()));
+    }
+
+    if modules.inbox {
+        tasks.push(("inbox", || inbox::run().boxed()));
+    }
+
+    if modules.gc {
+        tasks.push(("gc", || runner::collect_garbage_periodically().boxed()));
+    }
+
+    if modules.cli {
+        log::warn!("The CLI is interactive and runs as a binary of its own - Ignoring the cli module");
+    }
+
+    if modules.hooks {
+        log::warn!("Hooks aren't supported yet - Ignoring the hooks module");
+    }
+
+    tasks
+}
+
+/// Runs the enabled modules until all of them have stopped after a shutdown signal.
+pub async fn run() {
+    let config = common::get_config();
+
+    let mut modules = JoinSet::new();
+    for (name, task) in tasks(&config.modules) {
+        log::info!("Starting {name}");
+        modules.spawn(supervise(name, task));
+    }
+
+    if modules.is_empty() {
+        log::warn!("No modules enabled");
+        return;
+    }
+
+    // Dropping the set at the end of the grace period aborts whatever modules are still running
+    while modules.join_next().await.is_some() {}
+}
+
+/// Runs a task over and over until shutdown. The backoff between restarts doubles up to the configured maximum, unless
+/// the task ran long enough to be considered healthy.
+async fn supervise(name: &'static str, task: Task) {
+    let config = &common::get_config().supervisor;
+    let shutdown = shutdown::token();
+
+    let initial = Duration::from_millis(config.restart_backoff_ms);
+    let max = Duration::from_secs(config.max_restart_backoff_secs).max(initial);
+    let healthy_after = Duration::from_secs(config.healthy_after_secs);
+
+    let mut backoff = initial;
+    loop {
+        let started = Instant::now();
+
+        // Running the task in place rather than spawning it means aborting the supervisor aborts the task as well
+        match AssertUnwindSafe(task()).catch_unwind().await {
+            Ok(Ok(())) if shutdown.is_cancelled() => {
+                log::info!("{name} stopped");
+                return;
+            },
+            Ok(Ok(())) => log::warn!("{name} stopped"),
+            Ok(Err(err)) => log::error!("{name} failed: {err:?}"),
+            Err(_) => log::error!("{name} panicked"),
+        }
+
+        if shutdown.is_cancelled() {
+            return;
+        }
+
+        if started.elapsed() >= healthy_after {
+            backoff = initial;
+        }
+
+        log::info!("Restarting {name} in {backoff:?}");
+        tokio::select! {
+            () = tokio::time::sleep(backoff) => {},
+            () = shutdown.cancelled() => return,
+        }
+
+        backoff = (backoff * 2).min(max);
+    }
+}
@@ -0,0 +1,172 @@
+//! # Test Environment
+//! What the tests of handlers share: the configuration, which can only be loaded once per process, a SQLite database
+//! of its own, the test CA and a listener answering http-01 challenges. Tests take turns using it, as they would read
+//! each other's events otherwise.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{MutexGuard, OnceCell};
+use common::{Authority, EventBus, Storage};
+
+/// The bearer token of the `admin` operator.
+pub(crate) const OPERATOR_TOKEN: &str = "password";
+
+/// Names resolving to the http-01 listener.
+pub(crate) const HTTP01_NAMES: &[&str] = &["acme.test", "other.test"];
+
+static ENVIRONMENT: OnceCell<tokio::sync::Mutex<Environment>> = OnceCell::const_new();
+
+pub(crate) struct Environment {
+    /// Key authorizations served by the http-01 listener, by token
+    http01: Arc<Mutex<HashMap<String, String>>>,
+}
+
+/// The environment, once the tests before have finished with it.
+pub(crate) async fn environment() -> MutexGuard<'static, Environment> {
+    ENVIRONMENT.get_or_init(async || tokio::sync::Mutex::new(Environment::create().await))
+        .await
+        .lock()
+        .await
+}
+
+impl Environment {
+    async fn create() -> Self {
+        let directory = std::env::temp_dir().join(format!("certmaster-tests-{pid}", pid = std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let http01 = Arc::new(Mutex::new(HashMap::new()));
+        let port = serve_http01(http01.clone());
+
+        let root = env!("CARGO_MANIFEST_DIR");
+        let operator = ring::digest::digest(&ring::digest::SHA256, OPERATOR_TOKEN.as_bytes())
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        let hosts = HTTP01_NAMES
+            .iter()
+            .map(|name| format!("\"{name}\" = \"127.0.0.1\""))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let path = directory.join("config.toml");
+        std::fs::write(&path, format!(r#"
+            [storage]
+            backend = "sqlite"
+
+            [storage.sqlite]
+            path = "./certmaster.db"
+
+            [redis]
+            url = "redis://localhost"
+
+            [ca]
+            certificate = "{root}/test/authority.crt"
+            key = "{root}/test/authority.key"
+
+            [web]
+            socket = "127.0.0.1:0"
+
+            [web.operators]
+            admin = "{operator}"
+
+            [acme]
+            base_url = "http://localhost"
+            profile = "server"
+
+            [challenge.http01]
+            port = {port}
+            attempts = 1
+            timeout_secs = 5
+
+            [challenge.hosts]
+            {hosts}
+
+            [gc]
+            retention_days = 0
+
+            [profiles.server]
+            lifetime_days = 90
+            key_usage = ["digital-signature"]
+            extended_key_usage = ["server-auth"]
+
+            [profiles.reviewed]
+            lifetime_days = 90
+            approvals = 2
+        "#)).unwrap();
+
+        let config = common::load_config(&path).await;
+        Authority::init(&config.ca).await.unwrap();
+        config.connect_storage().await.create_consumer_group().await.unwrap();
+
+        Self {
+            http01,
+        }
+    }
+
+    /// Answers http-01 challenges for the token with the key authorization from now on.
+    pub(crate) fn serve_http01(&self, token: &str, key_authorization: &str) {
+        self.http01.lock().unwrap().insert(token.to_owned(), key_authorization.to_owned());
+    }
+
+    pub(crate) async fn storage(&self) -> Storage {
+        common::get_config().connect_storage().await
+    }
+
+    /// Handles the next event the way the worker does.
+    pub(crate) async fn handle_next(&self) {
+        let mut storage = self.storage().await;
+        let entries = storage.read_events("test", 1, Duration::from_millis(50)).await.unwrap();
+        crate::runner::handle_event(&mut storage, entries.first().expect("An event to handle")).await.unwrap();
+    }
+
+    /// Handles events the way the worker does, until none are left.
+    pub(crate) async fn handle_all(&self) {
+        let mut storage = self.storage().await;
+
+        loop {
+            let entries = storage.read_events("test", 16, Duration::from_millis(50)).await.unwrap();
+            if entries.is_empty() {
+                return;
+            }
+
+            for entry in entries {
+                crate::runner::handle_event(&mut storage, &entry).await.unwrap();
+            }
+        }
+    }
+}
+
+/// Serves `/.well-known/acme-challenge/{token}` on a port of its own, from a thread so it outlives the runtime of the
+/// test which started it.
+fn serve_http01(tokens: Arc<Mutex<HashMap<String, String>>>) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    std::thread::spawn(move || for mut stream in listener.incoming().flatten() {
+        let mut lines = BufReader::new(&stream).lines();
+        let Some(Ok(request)) = lines.next() else {
+            continue;
+        };
+        // The rest of the head, so closing the connection doesn't reset it
+        lines.map_while(Result::ok).take_while(|line| !line.is_empty()).for_each(drop);
+
+        let body = request
+            .split_whitespace()
+            .nth(1)
+            .and_then(|path| path.strip_prefix("/.well-known/acme-challenge/"))
+            .and_then(|token| tokens.lock().unwrap().get(token).cloned());
+
+        let response = match body {
+            Some(body) => format!("HTTP/1.0 200 OK\r\nContent-Length: {len}\r\nConnection: close\r\n\r\n{body}", len = body.len()),
+            None => "HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned(),
+        };
+
+        let _ = stream.write_all(response.as_bytes());
+    });
+
+    port
+}
@@ -0,0 +1,114 @@
+//! # TLS
+//! Learns which certificate a server presents for a name and protocol. Any certificate is accepted, as tls-alpn-01
+//! responses are self-signed, but the handshake still has to complete, so the server has to hold the certificate's key.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{CertificateError, ClientConfig, DigitallySignedStruct, PeerMisbehaved, SignatureScheme};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use x509_parser::asn1_rs::{Any, FromDer};
+
+/// Accepts whichever certificate the server presents, while checking the handshake signatures made with its key. The
+/// signatures are checked against the certificate's public key directly, as webpki refuses to parse certificates with the
+/// critical acmeIdentifier extension.
+#[derive(Debug)]
+struct AnyCertificate {
+    provider: Arc<CryptoProvider>,
+}
+
+impl AnyCertificate {
+    fn verify_signature(&self, message: &[u8], certificate: &CertificateDer<'_>, signature: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let (_, certificate) = x509_parser::parse_x509_certificate(certificate)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+
+        let key = certificate.public_key();
+
+        // The AlgorithmIdentifier's contents, which is what the verification algorithms are keyed by
+        let algorithm = Any::from_der(key.raw)
+            .and_then(|(_, info)| Any::from_der(info.data))
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?
+            .1
+            .data;
+
+        let (_, algorithms) = self.provider.signature_verification_algorithms.mapping.iter()
+            .find(|(scheme, _)| *scheme == signature.scheme)
+            .ok_or(rustls::Error::PeerMisbehaved(PeerMisbehaved::SignedHandshakeWithUnadvertisedSigScheme))?;
+
+        algorithms.iter()
+            .filter(|verifier| verifier.public_key_alg_id().as_ref() == algorithm)
+            .any(|verifier| verifier.verify_signature(&key.subject_public_key.data, message, signature.signature()).is_ok())
+            .then(HandshakeSignatureValid::assertion)
+            .ok_or(rustls::Error::InvalidCertificate(CertificateError::BadSignature))
+    }
+}
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(&self, _: &CertificateDer<'_>, _: &[CertificateDer<'_>], _: &ServerName<'_>, _: &[u8], _: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], certificate: &CertificateDer<'_>, signature: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verify_signature(message, certificate, signature)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], certificate: &CertificateDer<'_>, signature: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        // TLS 1.3 dropped PKCS#1 v1.5 and SHA-1 signatures
+        let allowed = matches! {
+            signature.scheme,
+            SignatureScheme::ECDSA_NISTP256_SHA256 | SignatureScheme::ECDSA_NISTP384_SHA384 | SignatureScheme::ECDSA_NISTP521_SHA512 |
+            SignatureScheme::RSA_PSS_SHA256 | SignatureScheme::RSA_PSS_SHA384 | SignatureScheme::RSA_PSS_SHA512 |
+            SignatureScheme::ED25519 | SignatureScheme::ED448
+        };
+
+        if !allowed {
+            return Err(rustls::Error::PeerMisbehaved(PeerMisbehaved::SignedHandshakeWithUnadvertisedSigScheme));
+        }
+
+        self.verify_signature(message, certificate, signature)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Completes a TLS 1.2 or 1.3 handshake for `server_name`, offering only the given ALPN protocol, and returns the DER of
+/// the server's end-entity certificate. Fails unless the server selects that protocol.
+pub(crate) async fn peer_certificate(address: SocketAddr, server_name: &str, protocol: &str) -> Result<Vec<u8>, String> {
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+
+    let mut config = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|err| format!("Failed to set up TLS: {err}"))?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate { provider }))
+        .with_no_client_auth();
+    config.alpn_protocols = vec![protocol.as_bytes().to_vec()];
+
+    let name = ServerName::try_from(server_name.trim_end_matches('.').to_owned())
+        .map_err(|err| format!("{server_name} can't be used as TLS server name: {err}"))?;
+
+    let stream = TcpStream::connect(address)
+        .await
+        .map_err(|err| format!("Failed to connect to {address}: {err}"))?;
+
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(name, stream)
+        .await
+        .map_err(|err| format!("TLS handshake with {address} failed: {err}"))?;
+
+    let (_, connection) = stream.get_ref();
+
+    if connection.alpn_protocol() != Some(protocol.as_bytes()) {
+        return Err(format!("The server didn't select the {protocol} protocol"));
+    }
+
+    connection.peer_certificates()
+        .and_then(|certificates| certificates.first())
+        .map(|certificate| certificate.to_vec())
+        .ok_or_else(|| "The server didn't present a certificate".to_owned())
+}
@@ -0,0 +1,788 @@
+//! # Web API
+//! The REST API, together with the CRL, OCSP and ACME endpoints.
+
+mod acme;
+mod auth;
+
+use actix_cors::Cors;
+use actix_web::web;
+use actix_web::http::header;
+use actix_web::HttpResponse;
+use common::CertStatus;
+use common::CertificateStore;
+use common::DeadLetterQueue;
+use common::ChallengeReview;
+use common::OcspRequest;
+use common::OcspResponder;
+use common::OcspResponseStatus;
+use common::EventBus;
+use common::JobStore;
+use common::ReviewDecision;
+use common::Revocation;
+use common::RevocationReason;
+use common::RevocationRegistry;
+use common::Result;
+use common::SingleResponse;
+use common::StorageBackend;
+use common::CsrId;
+use serde::Deserialize;
+use serde::Serialize;
+use std::cell::LazyCell;
+use std::time::Duration;
+use std::time::SystemTime;
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+/// How long a health check waits for the first connection to Redis.
+const HEALTH_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Serves the web API until shutdown, letting requests in progress finish.
+pub async fn run() -> Result<()> {
+    let config = common::get_config();
+
+    let server = actix_web::HttpServer::new(|| {
+        let cors = Cors::default().allow_any_header().allow_any_method().allow_any_origin();
+
+        actix_web::App::new()
+            .wrap(cors)
+            .service(get_version)
+            .service(get_health)
+            .service(get_jobs)
+            .service(get_job)
+            .service(post_job)
+            .service(post_challenge)
+            .service(get_certificates)
+            .service(post_revoke)
+            .service(get_dead_letters)
+            .service(get_dead_letter)
+            .service(post_replay_dead_letter)
+            .service(delete_dead_letter)
+            .service(get_crl)
+            .service(get_crl_pem)
+            .service(get_ocsp)
+            .service(post_ocsp)
+            .configure(acme::configure)
+    })
+    .bind(config.web.socket)?
+    // Signals are handled by the shutdown module, so the server stops together with the rest of the process
+    .disable_signals()
+    .shutdown_timeout(config.shutdown.grace_period_secs)
+    .run();
+
+    let handle = server.handle();
+    let stop = tokio::spawn(async move {
+        crate::shutdown::token().cancelled().await;
+        log::debug!("Finishing requests in progress");
+        handle.stop(true).await;
+    });
+
+    let result = server.await;
+    stop.abort();
+    result?;
+
+    Ok(())
+}
+
+#[actix_web::get("/version")]
+pub async fn get_version() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json! {{
+        "success": true,
+        "service": "certmaster-api",
+        "version": env!("CARGO_PKG_VERSION").to_string(),
+    }})
+}
+
+/// Whether the storage backend can be reached. Answers with 503 while it can't.
+#[actix_web::get("/health")]
+pub async fn get_health() -> HttpResponse {
+    let config = common::get_config();
+
+    let (connected, backend, health) = match config.storage.backend {
+        StorageBackend::Redis => {
+            // Nothing may have needed Redis yet, in which case there's no connection to report on
+            let _ = tokio::time::timeout(HEALTH_CONNECT_TIMEOUT, config.redis.connect()).await;
+            let health = common::redis_health();
+
+            (health.connected, "redis", serde_json::json!(health))
+        },
+        StorageBackend::Sqlite => match config.connect_storage().await.ping().await {
+            Ok(()) => (true, "sqlite", serde_json::json! {{ "connected": true }}),
+            Err(err) => (false, "sqlite", serde_json::json! {{ "connected": false, "last_error": err.inner().to_string() }}),
+        },
+    };
+
+    let mut response = match connected {
+        true => HttpResponse::Ok(),
+        false => HttpResponse::ServiceUnavailable(),
+    };
+
+    response.json(serde_json::json! {{
+        "success": connected,
+        (backend): health,
+    }})
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Pagination {
+    page: Option<usize>,
+    page_size: Option<usize>,
+    cn: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DetailedCsr {
+    #[serde(flatten)]
+    csr: common::Csr,
+
+    cn: Option<String>,
+}
+
+#[actix_web::get("/get-enqueued-items")]
+pub async fn get_jobs(pagination: web::Query<Pagination>) -> actix_web::Result<HttpResponse> {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let page = pagination.page.unwrap_or(0);
+    let size = pagination.page_size.unwrap_or(DEFAULT_PAGE_SIZE).max(1);
+
+    let jobs = match storage.list_jobs(page * size, size).await {
+        Ok(jobs) => jobs,
+        Err(err) => {
+            return Ok(HttpResponse::InternalServerError().json(serde_json::json! {{
+                "success": false,
+                "error": err.to_string(),
+            }}));
+        }
+    };
+
+    let values = jobs
+        .into_iter()
+        .map(|csr| {
+            let decoded = LazyCell::new(|| rcgen::CertificateSigningRequestParams::from_pem(csr.pem())
+                .ok()
+                .map(|csr| csr.params));
+
+            DetailedCsr {
+                cn: pagination.cn.is_some_and(|i| i).then(|| {
+                    decoded.as_ref()
+                        .and_then(|i| i.distinguished_name
+                            .get(&rcgen::DnType::CommonName)
+                            .and_then(|i| match i {
+                                rcgen::DnValue::Utf8String(str) => Some(str.clone()),
+                                rcgen::DnValue::PrintableString(str) => Some(str.to_string()),
+                                _ => None
+                            }))
+                }).flatten(),
+                csr,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(serde_json::json! {{
+        "success": true,
+        "jobs": values
+    }}))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Selection {
+    jobs: String,
+}
+
+impl Selection {
+    fn jobs(&self) -> std::result::Result<Vec<String>, std::str::Utf8Error> {
+        self.jobs
+            .split('+')
+            .map(|id| percent_encoding::percent_decode_str(id).decode_utf8())
+            .map(|i| i.map(|i| i.to_string()))
+            .collect::<std::result::Result<Vec<_>, std::str::Utf8Error>>()
+    }
+}
+
+#[actix_web::get("/job")]
+pub async fn get_job(id: web::Query<Selection>) -> actix_web::Result<HttpResponse> {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let jobs = match id.jobs() {
+        Ok(jobs) => jobs,
+        Err(err) =>
+            return Ok(HttpResponse::BadRequest().json(serde_json::json! {{
+                "success": false,
+                "error": err.to_string()
+            }})),
+    };
+
+    let serials = match storage.get_client_jobs(jobs.iter()).await {
+        Ok(job_by_alias) => job_by_alias
+            .into_iter()
+            .map(|i| i.serial)
+            .collect::<Vec<_>>(),
+        Err(err) => {
+            return Ok(HttpResponse::NotFound().json(serde_json::json! {{
+                "success": false,
+                "error": err.to_string(),
+            }}));
+        }
+    };
+
+    let csr = match storage.get_jobs(&serials).await {
+        Ok(csr) => csr,
+        Err(err) => {
+            return Ok(HttpResponse::NotFound().json(serde_json::json! {{
+                "success": false,
+                "error": err.to_string(),
+            }}));
+        }
+    };
+
+    Ok(HttpResponse::Ok().json(serde_json::json! {{
+        "success": true,
+        "jobs": csr
+    }}))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Ack {
+    pub alt: String,
+}
+
+/// A job as submitted to `POST /job`. Its challenges are always issued by the runner, never taken from the requester.
+#[derive(Debug, Clone, Deserialize)]
+pub struct JobRequest {
+    pub client_id: u64,
+    pub pem: common::PEMString,
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub challenge: Option<common::ChallengeType>,
+}
+
+impl From<JobRequest> for common::NewCsr {
+    fn from(request: JobRequest) -> Self {
+        common::NewCsr {
+            client_id: request.client_id,
+            pem: request.pem,
+            profile: request.profile,
+            challenge: request.challenge,
+            challenges: vec![],
+        }
+    }
+}
+
+#[actix_web::post("/job")]
+pub async fn post_job(requests: web::Json<Vec<JobRequest>>) -> actix_web::Result<HttpResponse> {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let requests = requests.into_inner()
+        .into_iter()
+        .map(common::NewCsr::from)
+        .collect::<Vec<_>>();
+
+    for request in requests.iter() {
+        let alt = request.alt();
+
+        match storage.dispatch_event(request.clone()).await {
+            Ok(()) => (),
+            Err(err) => {
+                return Ok(HttpResponse::InternalServerError().json(serde_json::json! {{
+                    "success": false,
+                    "error": err.to_string(),
+                }}));
+            }
+        };
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json! {{
+        "success": true,
+        "jobs": requests
+            .iter()
+            .map(|i| Ack { alt: i.alt() })
+            .collect::<Vec<_>>()
+    }}))
+}
+
+/// Approves or rejects the challenge of every listed job. Both decisions need a reason, and are attributed to the
+/// authenticated operator.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OverrideChallenge {
+    jobs: Vec<String>,
+    decision: ReviewDecision,
+    reason: String,
+}
+
+#[actix_web::post("/challenge")]
+pub async fn post_challenge(caller: auth::Caller, id: web::Json<OverrideChallenge>) -> actix_web::Result<HttpResponse> {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    if id.reason.trim().is_empty() {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json! {{
+            "success": false,
+            "error": "A reason is required",
+        }}));
+    }
+
+    let reviewer = caller.name();
+
+    let job_by_alias = match storage.get_client_jobs(id.jobs.iter()).await {
+        Ok(job_by_alias) => job_by_alias,
+        Err(err) => {
+            return Ok(HttpResponse::NotFound().json(serde_json::json! {{
+                "success": false,
+                "error": err.to_string(),
+            }}));
+        }
+    };
+
+    if id.decision == ReviewDecision::Approve {
+        for job in &job_by_alias {
+            let csr = match storage.get_job(job.serial).await {
+                Ok(Some(csr)) => csr,
+                Ok(None) => return Ok(HttpResponse::NotFound().json(serde_json::json! {{
+                    "success": false,
+                    "error": format!("No job with ID {serial}", serial = job.serial),
+                }})),
+                Err(err) => return Ok(HttpResponse::InternalServerError().json(serde_json::json! {{
+                    "success": false,
+                    "error": err.to_string(),
+                }})),
+            };
+
+            // Anonymous approvals can't be told apart, so they can't make up a quorum
+            let approvals = config.profile(csr.profile.as_deref()).map_or(0, |profile| profile.approvals);
+            if !caller.is_authenticated() && approvals > 1 {
+                return Ok(HttpResponse::Forbidden().json(serde_json::json! {{
+                    "success": false,
+                    "error": format!("Job {alias} needs {approvals} approvals, which takes configuring `web.operators`", alias = job.alias),
+                }}));
+            }
+
+            if csr.approvers().contains(reviewer) {
+                return Ok(HttpResponse::Conflict().json(serde_json::json! {{
+                    "success": false,
+                    "error": format!("{reviewer} has already approved job {alias}", alias = job.alias),
+                    "reviews": csr.reviews,
+                }}));
+            }
+        }
+    }
+
+    for job in job_by_alias {
+        if let Err(err) = storage
+            .dispatch_event(ChallengeReview {
+                id: job.serial,
+                decision: id.decision,
+                reason: id.reason.trim().to_owned(),
+                reviewer: reviewer.to_owned(),
+            })
+            .await
+        {
+            return Ok(HttpResponse::InternalServerError().json(serde_json::json! {{
+                "success": false,
+                "error": err.to_string(),
+            }}));
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json! {{
+        "success": true,
+        "jobs": id.jobs
+    }}))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CertificateQuery {
+    serial: Option<u64>,
+    name: Option<String>,
+    issuer: Option<String>,
+    expires_after: Option<i64>,
+    expires_before: Option<i64>,
+}
+
+#[actix_web::get("/certificates")]
+pub async fn get_certificates(query: web::Query<CertificateQuery>) -> actix_web::Result<HttpResponse> {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let certificates = match &*query {
+        CertificateQuery { serial: Some(serial), .. } => storage.get_certificates(&[*serial]).await,
+        CertificateQuery { name: Some(name), .. } => storage.certificates_by_name(name).await,
+        CertificateQuery { issuer: Some(issuer), .. } => storage.certificates_by_issuer(issuer).await,
+        CertificateQuery { expires_after: None, expires_before: None, .. } =>
+            return Ok(HttpResponse::BadRequest().json(serde_json::json! {{
+                "success": false,
+                "error": "Expected one of serial, name, issuer, expires_after or expires_before",
+            }})),
+        CertificateQuery { expires_after, expires_before, .. } => storage
+            .certificates_expiring(expires_after.unwrap_or(i64::MIN), expires_before.unwrap_or(i64::MAX))
+            .await,
+    };
+
+    match certificates {
+        Ok(certificates) => Ok(HttpResponse::Ok().json(serde_json::json! {{
+            "success": true,
+            "certificates": certificates
+        }})),
+        Err(err) => Ok(HttpResponse::InternalServerError().json(serde_json::json! {{
+            "success": false,
+            "error": err.to_string(),
+        }})),
+    }
+}
+
+/// A revocation as submitted to `POST /revoke`. It's recorded as requested by the authenticated caller.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RevocationRequest {
+    pub serial: CsrId,
+    pub reason: RevocationReason,
+    #[serde(default)]
+    pub invalidity_date: Option<i64>,
+}
+
+#[actix_web::post("/revoke")]
+pub async fn post_revoke(caller: auth::Caller, revocation: web::Json<RevocationRequest>) -> actix_web::Result<HttpResponse> {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    if !revocation.reason.can_revoke() {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json! {{
+            "success": false,
+            "error": "remove-from-crl is not a revocation reason",
+        }}));
+    }
+
+    match storage.get_certificate(revocation.serial).await {
+        Ok(Some(_)) => {},
+        Ok(None) => return Ok(HttpResponse::NotFound().json(serde_json::json! {{
+            "success": false,
+            "error": format!("No certificate with serial {serial} has been issued", serial = revocation.serial),
+        }})),
+        Err(err) => return Ok(HttpResponse::InternalServerError().json(serde_json::json! {{
+            "success": false,
+            "error": err.to_string(),
+        }})),
+    };
+
+    match storage.get_revocation(revocation.serial).await {
+        Ok(None) => {},
+        Ok(Some(revoked)) => return Ok(HttpResponse::Conflict().json(serde_json::json! {{
+            "success": false,
+            "error": format!("Certificate {serial} has already been revoked", serial = revocation.serial),
+            "revocation": revoked,
+        }})),
+        Err(err) => return Ok(HttpResponse::InternalServerError().json(serde_json::json! {{
+            "success": false,
+            "error": err.to_string(),
+        }})),
+    };
+
+    let revocation = Revocation {
+        serial: revocation.serial,
+        reason: revocation.reason,
+        invalidity_date: revocation.invalidity_date,
+        requested_by: caller.name().to_owned(),
+    };
+
+    if let Err(err) = storage.dispatch_event(revocation.clone()).await {
+        return Ok(HttpResponse::InternalServerError().json(serde_json::json! {{
+            "success": false,
+            "error": err.to_string(),
+        }}));
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json! {{
+        "success": true,
+        "serial": revocation.serial
+    }}))
+}
+
+#[actix_web::get("/dead-letters")]
+pub async fn get_dead_letters(_caller: auth::Caller) -> actix_web::Result<HttpResponse> {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    match storage.get_dead_letters().await {
+        Ok(dead_letters) => Ok(HttpResponse::Ok().json(serde_json::json! {{
+            "success": true,
+            "dead_letters": dead_letters
+        }})),
+        Err(err) => Ok(HttpResponse::InternalServerError().json(serde_json::json! {{
+            "success": false,
+            "error": err.to_string(),
+        }})),
+    }
+}
+
+#[actix_web::get("/dead-letters/{id}")]
+pub async fn get_dead_letter(_caller: auth::Caller, id: web::Path<String>) -> actix_web::Result<HttpResponse> {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    match storage.get_dead_letter(&id).await {
+        Ok(Some(dead_letter)) => Ok(HttpResponse::Ok().json(serde_json::json! {{
+            "success": true,
+            "dead_letter": dead_letter
+        }})),
+        Ok(None) => Ok(HttpResponse::NotFound().json(serde_json::json! {{
+            "success": false,
+            "error": format!("No dead letter with ID {id}", id = id.as_str()),
+        }})),
+        Err(err) => Ok(HttpResponse::InternalServerError().json(serde_json::json! {{
+            "success": false,
+            "error": err.to_string(),
+        }})),
+    }
+}
+
+/// Puts the event back into the event stream, on behalf of the authenticated operator.
+#[actix_web::post("/dead-letters/{id}/replay")]
+pub async fn post_replay_dead_letter(caller: auth::Caller, id: web::Path<String>) -> actix_web::Result<HttpResponse> {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    match storage.replay_dead_letter(&id).await {
+        Ok(Some(event_id)) => {
+            log::info!("Dead letter {id} replayed as event {event_id} by {caller}", id = id.as_str(), caller = caller.name());
+            Ok(HttpResponse::Ok().json(serde_json::json! {{
+                "success": true,
+                "event_id": event_id
+            }}))
+        },
+        Ok(None) => Ok(HttpResponse::NotFound().json(serde_json::json! {{
+            "success": false,
+            "error": format!("No dead letter with ID {id}", id = id.as_str()),
+        }})),
+        Err(err) => Ok(HttpResponse::InternalServerError().json(serde_json::json! {{
+            "success": false,
+            "error": err.to_string(),
+        }})),
+    }
+}
+
+/// Drops the event for good, on behalf of the authenticated operator.
+#[actix_web::delete("/dead-letters/{id}")]
+pub async fn delete_dead_letter(caller: auth::Caller, id: web::Path<String>) -> actix_web::Result<HttpResponse> {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    match storage.discard_dead_letter(&id).await {
+        Ok(true) => {
+            log::info!("Dead letter {id} discarded by {caller}", id = id.as_str(), caller = caller.name());
+            Ok(HttpResponse::Ok().json(serde_json::json! {{
+                "success": true,
+                "id": id.as_str()
+            }}))
+        },
+        Ok(false) => Ok(HttpResponse::NotFound().json(serde_json::json! {{
+            "success": false,
+            "error": format!("No dead letter with ID {id}", id = id.as_str()),
+        }})),
+        Err(err) => Ok(HttpResponse::InternalServerError().json(serde_json::json! {{
+            "success": false,
+            "error": err.to_string(),
+        }})),
+    }
+}
+
+#[actix_web::get("/crl")]
+pub async fn get_crl() -> actix_web::Result<HttpResponse> {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    match storage.get_crl_der().await {
+        Ok(Some(crl)) => Ok(HttpResponse::Ok()
+            .content_type("application/pkix-crl")
+            .body(crl)),
+        Ok(None) => Ok(HttpResponse::NotFound().json(serde_json::json! {{
+            "success": false,
+            "error": "No CRL has been published yet",
+        }})),
+        Err(err) => Ok(HttpResponse::InternalServerError().json(serde_json::json! {{
+            "success": false,
+            "error": err.to_string(),
+        }})),
+    }
+}
+
+#[actix_web::get("/crl.pem")]
+pub async fn get_crl_pem() -> actix_web::Result<HttpResponse> {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    match storage.get_crl_pem().await {
+        Ok(Some(crl)) => Ok(HttpResponse::Ok()
+            .content_type("application/x-pem-file")
+            .body(crl)),
+        Ok(None) => Ok(HttpResponse::NotFound().json(serde_json::json! {{
+            "success": false,
+            "error": "No CRL has been published yet",
+        }})),
+        Err(err) => Ok(HttpResponse::InternalServerError().json(serde_json::json! {{
+            "success": false,
+            "error": err.to_string(),
+        }})),
+    }
+}
+
+/// OCSP over GET as per RFC 6960 Appendix A.1: The URL-encoded base64 of the DER request is appended to the path.
+#[actix_web::get("/ocsp/{request:.*}")]
+pub async fn get_ocsp(request: web::Path<String>) -> HttpResponse {
+    let request = percent_encoding::percent_decode_str(&request)
+        .decode_utf8()
+        .map_err(|err| common::Error::other(err.to_string()))
+        .and_then(common::decode_base64);
+
+    match request {
+        Ok(request) => ocsp(&request).await,
+        Err(err) => {
+            log::debug!("Malformed OCSP request: {err:?}");
+            ocsp_error(OcspResponseStatus::MalformedRequest)
+        },
+    }
+}
+
+#[actix_web::post("/ocsp")]
+pub async fn post_ocsp(request: web::Bytes) -> HttpResponse {
+    ocsp(&request).await
+}
+
+fn ocsp_error(status: OcspResponseStatus) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/ocsp-response")
+        .insert_header(header::CacheControl(vec![header::CacheDirective::NoCache, header::CacheDirective::NoStore]))
+        .body(status.to_der())
+}
+
+async fn ocsp(request: &[u8]) -> HttpResponse {
+    let request = match OcspRequest::from_der(request) {
+        Ok(request) if !request.certificates.is_empty() => request,
+        Ok(_) => return ocsp_error(OcspResponseStatus::MalformedRequest),
+        Err(err) => {
+            log::debug!("Malformed OCSP request: {err:?}");
+            return ocsp_error(OcspResponseStatus::MalformedRequest);
+        }
+    };
+
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let responder = match OcspResponder::current(&config.ocsp).await {
+        Ok(responder) => responder,
+        Err(err) => {
+            log::error!("Failed to load the OCSP responder: {err:?}");
+            return ocsp_error(OcspResponseStatus::InternalError);
+        }
+    };
+
+    let this_update = time::OffsetDateTime::now_utc();
+    let next_update = this_update + time::Duration::seconds(config.ocsp.next_update_secs as i64);
+
+    let mut responses = vec![];
+    for cert_id in request.certificates {
+        let status = match cert_id.serial().filter(|_| responder.is_issuer(&cert_id)) {
+            None => CertStatus::Unknown,
+            Some(serial) => match storage.get_revocation(serial).await {
+                Ok(Some(revoked)) => CertStatus::Revoked {
+                    revocation_date: revoked.revocation_date,
+                    reason: revoked.reason,
+                },
+                Ok(None) => match storage.get_certificate(serial).await {
+                    Ok(Some(_)) => CertStatus::Good,
+                    Ok(None) => CertStatus::Unknown,
+                    Err(err) => {
+                        log::error!("Failed to look up certificate {serial}: {err:?}");
+                        return ocsp_error(OcspResponseStatus::TryLater);
+                    }
+                },
+                Err(err) => {
+                    log::error!("Failed to look up revocation of {serial}: {err:?}");
+                    return ocsp_error(OcspResponseStatus::TryLater);
+                }
+            },
+        };
+
+        responses.push(SingleResponse {
+            cert_id,
+            status,
+            this_update,
+            next_update,
+        });
+    }
+
+    let response = match responder.sign(&responses, request.nonce.as_deref()) {
+        Ok(response) => response,
+        Err(err) => {
+            log::error!("Failed to sign OCSP response: {err:?}");
+            return ocsp_error(OcspResponseStatus::InternalError);
+        }
+    };
+
+    let mut builder = HttpResponse::Ok();
+    builder.content_type("application/ocsp-response");
+
+    if request.nonce.is_some() {
+        builder.insert_header(header::CacheControl(vec![header::CacheDirective::NoCache, header::CacheDirective::NoStore]));
+    } else {
+        builder
+            .insert_header(header::CacheControl(vec![
+                header::CacheDirective::MaxAge(config.ocsp.max_age_secs.min(config.ocsp.next_update_secs) as u32),
+                header::CacheDirective::Public,
+                header::CacheDirective::NoTransform,
+                header::CacheDirective::MustRevalidate,
+            ]))
+            .insert_header(header::LastModified(SystemTime::from(this_update).into()))
+            .insert_header(header::Expires(SystemTime::from(next_update).into()));
+    }
+
+    builder.body(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, App};
+    use actix_web::http::StatusCode;
+    use common::PendingChallenge;
+    use crate::testing::{environment, OPERATOR_TOKEN};
+    use super::*;
+
+    #[actix_web::test]
+    async fn dead_letters_need_an_operator() {
+        let environment = environment().await;
+        let mut storage = environment.storage().await;
+
+        storage.dispatch_event(PendingChallenge { id: CsrId::MAX }).await.unwrap();
+        let entries = storage.read_events("test", 1, Duration::from_millis(50)).await.unwrap();
+        let id = storage.dead_letter(&entries[0], "No such job".to_owned(), 1).await.unwrap();
+
+        let app = test::init_service(App::new()
+            .service(get_dead_letters)
+            .service(get_dead_letter)
+            .service(post_replay_dead_letter)
+            .service(delete_dead_letter)).await;
+        let replay = || test::TestRequest::post().uri(&format!("/dead-letters/{id}/replay"));
+
+        for request in [
+            test::TestRequest::get().uri("/dead-letters"),
+            test::TestRequest::get().uri(&format!("/dead-letters/{id}")),
+            replay(),
+            replay().insert_header((header::AUTHORIZATION, "Bearer guess")),
+            test::TestRequest::delete().uri(&format!("/dead-letters/{id}")),
+        ] {
+            let response = test::call_service(&app, request.to_request()).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        assert!(storage.get_dead_letter(&id).await.unwrap().is_some(), "Rejected requests leave the dead letter alone");
+
+        let request = replay().insert_header((header::AUTHORIZATION, format!("Bearer {OPERATOR_TOKEN}")));
+        let response = test::call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(storage.get_dead_letter(&id).await.unwrap().is_none());
+
+        // The replayed event refers to no job, so it's taken off the stream again rather than handled
+        for entry in storage.read_events("test", 16, Duration::from_millis(50)).await.unwrap() {
+            storage.acknowledge_event(&entry.id).await.unwrap();
+        }
+    }
+}
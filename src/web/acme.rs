@@ -0,0 +1,1301 @@
+//! # ACME
+//! An [RFC 8555](https://www.rfc-editor.org/rfc/rfc8555) server on top of the job pipeline. Finalizing an order submits
+//! its CSR as a regular job, which then passes through the usual challenge and signing stages. The order stays
+//! `processing` until the job completes.
+
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::http::{Method, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse};
+use common::{
+    AcmeAccount,
+    AcmeAuthorization,
+    AcmeChallenge,
+    AcmeChallengeReady,
+    AcmeOrder,
+    AcmeStatus,
+    AcmeStore,
+    Authority,
+    CertificateStore,
+    ChallengeType,
+    ClientJob,
+    Identifier,
+    IdentifierType,
+    Jws,
+    JwsHeader,
+    NameChallenge,
+    EventBus,
+    JobStore,
+    NewCsr,
+    Revocation,
+    RevocationReason,
+    RevocationRegistry,
+    Status,
+    Storage
+};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use time::format_description::well_known::Rfc3339;
+
+pub fn configure(config: &mut web::ServiceConfig) {
+    config.service(web::scope("/acme")
+        .service(get_directory)
+        .service(get_new_nonce)
+        .service(post_new_account)
+        .service(post_account)
+        .service(post_account_orders)
+        .service(post_key_change)
+        .service(post_new_order)
+        .service(post_order)
+        .service(post_finalize)
+        .service(post_authorization)
+        .service(post_challenge)
+        .service(post_certificate)
+        .service(post_revoke_cert));
+}
+
+/// An [RFC 7807](https://www.rfc-editor.org/rfc/rfc7807) problem document carrying one of the ACME error types.
+#[derive(Debug)]
+struct Problem {
+    /// `None` for problems no ACME error type covers, which are described by their status alone.
+    kind: Option<&'static str>,
+    status: StatusCode,
+    detail: String,
+}
+
+impl Problem {
+    fn new(kind: &'static str, status: StatusCode, detail: impl Into<String>) -> Self {
+        Self {
+            kind: Some(kind),
+            status,
+            detail: detail.into(),
+        }
+    }
+
+    fn malformed(detail: impl Into<String>) -> Self {
+        Self::new("malformed", StatusCode::BAD_REQUEST, detail)
+    }
+
+    fn unauthorized(detail: impl Into<String>) -> Self {
+        Self::new("unauthorized", StatusCode::FORBIDDEN, detail)
+    }
+
+    /// ACME has no error type for resources which don't exist, so these are plain `about:blank` problems.
+    fn not_found(detail: impl Into<String>) -> Self {
+        Self {
+            kind: None,
+            status: StatusCode::NOT_FOUND,
+            detail: detail.into(),
+        }
+    }
+
+    fn into_response(self) -> HttpResponse {
+        HttpResponse::build(self.status)
+            .content_type("application/problem+json")
+            .json(self.to_json())
+    }
+
+    fn to_json(&self) -> Value {
+        match self.kind {
+            Some(kind) => json!({
+                "type": format!("urn:ietf:params:acme:error:{kind}"),
+                "detail": self.detail,
+                "status": self.status.as_u16(),
+            }),
+            None => json!({
+                "type": "about:blank",
+                "title": self.status.canonical_reason(),
+                "detail": self.detail,
+                "status": self.status.as_u16(),
+            }),
+        }
+    }
+}
+
+impl From<common::Error> for Problem {
+    fn from(err: common::Error) -> Self {
+        log::error!("{err:?}");
+        Self::new("serverInternal", StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
+    }
+}
+
+impl From<std::io::Error> for Problem {
+    fn from(err: std::io::Error) -> Self {
+        common::Error::from(err).into()
+    }
+}
+
+type AcmeResult = Result<HttpResponse, Problem>;
+
+/// The absolute URL of an ACME resource.
+fn url(path: impl Display) -> String {
+    let config = common::get_config();
+    format!("{base}/acme/{path}", base = config.acme.base_url.trim_end_matches('/'))
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs() as i64)
+}
+
+fn timestamp(time: i64) -> String {
+    time::OffsetDateTime::from_unix_timestamp(time)
+        .ok()
+        .and_then(|time| time.format(&Rfc3339).ok())
+        .unwrap_or_default()
+}
+
+/// Every ACME response carries a fresh nonce and a link to the directory.
+async fn reply(result: AcmeResult) -> HttpResponse {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let mut response = result.unwrap_or_else(Problem::into_response);
+    let headers = response.headers_mut();
+
+    match storage.new_nonce().await {
+        Ok(nonce) => if let Ok(nonce) = HeaderValue::from_str(&nonce) {
+            headers.insert(HeaderName::from_static("replay-nonce"), nonce);
+        },
+        Err(err) => log::error!("Failed to issue nonce: {err:?}"),
+    }
+
+    if let Ok(link) = HeaderValue::from_str(&format!("<{url}>;rel=\"index\"", url = url("directory"))) {
+        headers.append(header::LINK, link);
+    }
+
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+
+    response
+}
+
+/// A request whose JWS has been verified and whose nonce has been used up.
+struct Request {
+    jws: Jws,
+    header: JwsHeader,
+    key: Value,
+    /// `None` if the request was signed with a `jwk` rather than an account's `kid`.
+    account: Option<AcmeAccount>,
+}
+
+impl Request {
+    fn account(&self) -> Result<&AcmeAccount, Problem> {
+        self.account
+            .as_ref()
+            .ok_or_else(|| Problem::malformed("This request must be signed by an account key referenced through 'kid'"))
+    }
+
+    fn payload<T: DeserializeOwned>(&self) -> Result<Option<T>, Problem> {
+        self.jws
+            .payload()
+            .map_err(|_| Problem::malformed("Invalid payload"))
+    }
+
+    fn require_payload<T: DeserializeOwned>(&self) -> Result<T, Problem> {
+        self.payload()?
+            .ok_or_else(|| Problem::malformed("Missing payload"))
+    }
+}
+
+fn verify(jws: &Jws, alg: &str, key: &Value) -> Result<(), Problem> {
+    match jws.verify(alg, key) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(Problem::malformed("Invalid JWS signature")),
+        Err(_) => Err(Problem::new("badSignatureAlgorithm", StatusCode::BAD_REQUEST, "Supported algorithms are ES256, ES384, RS256 and EdDSA")),
+    }
+}
+
+fn thumbprint(key: &Value) -> Result<String, Problem> {
+    common::jwk_thumbprint(key)
+        .map_err(|_| Problem::new("badPublicKey", StatusCode::BAD_REQUEST, "Unsupported account key"))
+}
+
+async fn authenticate(storage: &mut Storage, req: &HttpRequest, body: &[u8]) -> Result<Request, Problem> {
+    let config = common::get_config();
+
+    let jws: Jws = serde_json::from_slice(body)
+        .map_err(|err| Problem::malformed(format!("Invalid JWS: {err}")))?;
+    let header = jws.header()
+        .map_err(|_| Problem::malformed("Invalid JWS protected header"))?;
+
+    if header.url != format!("{base}{path}", base = config.acme.base_url.trim_end_matches('/'), path = req.path()) {
+        return Err(Problem::unauthorized(format!("The request was signed for {url}", url = header.url)));
+    }
+
+    let nonce_valid = match &header.nonce {
+        Some(nonce) => storage.consume_nonce(nonce).await?,
+        None => false,
+    };
+
+    if !nonce_valid {
+        return Err(Problem::new("badNonce", StatusCode::BAD_REQUEST, "Missing, unknown or reused nonce"));
+    }
+
+    let (key, account) = match (&header.jwk, &header.kid) {
+        (Some(jwk), None) => (jwk.clone(), None),
+        (None, Some(kid)) => {
+            let account = match kid.strip_prefix(url("account/").as_str()) {
+                Some(id) => storage.get_account(id).await?,
+                None => None,
+            };
+
+            let account = account
+                .ok_or_else(|| Problem::new("accountDoesNotExist", StatusCode::BAD_REQUEST, format!("Unknown account {kid}")))?;
+
+            if account.status != AcmeStatus::Valid {
+                return Err(Problem::unauthorized("The account has been deactivated"));
+            }
+
+            (account.jwk()?, Some(account))
+        },
+        _ => return Err(Problem::malformed("Exactly one of 'jwk' and 'kid' must be given")),
+    };
+
+    verify(&jws, &header.alg, &key)?;
+
+    Ok(Request {
+        jws,
+        header,
+        key,
+        account,
+    })
+}
+
+fn account_json(account: &AcmeAccount) -> Value {
+    json!({
+        "status": account.status,
+        "contact": account.contact,
+        "termsOfServiceAgreed": account.terms_of_service_agreed,
+        "orders": url(format!("account/{id}/orders", id = account.id)),
+    })
+}
+
+fn order_json(order: &AcmeOrder) -> Value {
+    let mut json = json!({
+        "status": order.status,
+        "expires": timestamp(order.expires),
+        "identifiers": order.identifiers,
+        "authorizations": order.authorizations
+            .iter()
+            .map(|id| url(format!("authz/{id}")))
+            .collect::<Vec<_>>(),
+        "finalize": url(format!("order/{id}/finalize", id = order.id)),
+    });
+
+    if order.status == AcmeStatus::Valid {
+        json["certificate"] = url(format!("cert/{id}", id = order.id)).into();
+    }
+
+    if let Some(error) = &order.error {
+        json["error"] = Problem::unauthorized(error).to_json();
+    }
+
+    json
+}
+
+fn authorization_json(authz: &AcmeAuthorization) -> Value {
+    let mut json = json!({
+        "identifier": authz.identifier,
+        "status": authz.status,
+        "expires": timestamp(authz.expires),
+        "challenges": authz.challenges
+            .iter()
+            .enumerate()
+            .map(|(index, challenge)| challenge_json(&authz.id, index, challenge))
+            .collect::<Vec<_>>(),
+    });
+
+    if authz.wildcard {
+        json["wildcard"] = true.into();
+    }
+
+    json
+}
+
+fn challenge_json(authz: &str, index: usize, challenge: &AcmeChallenge) -> Value {
+    let mut json = json!({
+        "type": challenge.kind,
+        "url": url(format!("chall/{authz}/{index}")),
+        "token": challenge.token,
+        "status": challenge.status,
+    });
+
+    if let Some(validated) = challenge.validated {
+        json["validated"] = timestamp(validated).into();
+    }
+
+    if let Some(error) = &challenge.error {
+        json["error"] = Problem::new("incorrectResponse", StatusCode::FORBIDDEN, error).to_json();
+    }
+
+    json
+}
+
+fn validate_contacts(contacts: &[String]) -> Result<(), Problem> {
+    match contacts.iter().find(|contact| !contact.starts_with("mailto:")) {
+        Some(contact) => Err(Problem::new("unsupportedContact", StatusCode::BAD_REQUEST, format!("Unsupported contact '{contact}'. Only mailto: contacts are accepted"))),
+        None => Ok(()),
+    }
+}
+
+/// Loads an order of the given account and brings its status up to date with its authorizations and its job.
+async fn owned_order(storage: &mut Storage, account: &AcmeAccount, id: &str) -> Result<AcmeOrder, Problem> {
+    let mut order = match storage.get_order(id).await? {
+        Some(order) if order.account == account.id => order,
+        Some(_) => return Err(Problem::unauthorized("The order belongs to another account")),
+        None => return Err(Problem::not_found(format!("Unknown order {id}"))),
+    };
+
+    let previous = (order.status, order.serial);
+
+    match order.status {
+        AcmeStatus::Pending => {
+            let authz = storage.get_authorizations(&order.authorizations).await?;
+
+            if authz.iter().any(|authz| authz.status != AcmeStatus::Pending && authz.status != AcmeStatus::Valid) {
+                order.status = AcmeStatus::Invalid;
+                order.error = Some("An authorization of this order failed".into());
+            } else if authz.iter().all(|authz| authz.status == AcmeStatus::Valid) {
+                order.status = AcmeStatus::Ready;
+            } else if order.expires < now() {
+                order.status = AcmeStatus::Invalid;
+                order.error = Some("The order has expired".into());
+            }
+        },
+        AcmeStatus::Processing => if let Some(alias) = &order.alias {
+            let job: Option<ClientJob> = storage.get_client_job(alias).await?;
+
+            if let Some(job) = job {
+                order.serial = Some(job.serial);
+
+                match job.status {
+                    Status::Success { .. } => order.status = AcmeStatus::Valid,
+                    Status::Error { reason } => {
+                        order.status = AcmeStatus::Invalid;
+                        order.error = Some(reason);
+                    },
+                    Status::Pending => {},
+                }
+            }
+        },
+        _ => {},
+    }
+
+    if (order.status, order.serial) != previous {
+        storage.store_order(&order).await?;
+    }
+
+    Ok(order)
+}
+
+async fn owned_authorization(storage: &mut Storage, account: &AcmeAccount, id: &str) -> Result<AcmeAuthorization, Problem> {
+    match storage.get_authorization(id).await? {
+        Some(authz) if authz.account == account.id => Ok(authz),
+        Some(_) => Err(Problem::unauthorized("The authorization belongs to another account")),
+        None => Err(Problem::not_found(format!("Unknown authorization {id}"))),
+    }
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let base64 = common::encode_base64(der);
+    let lines = base64
+        .as_bytes()
+        .chunks(64)
+        .map(|line| String::from_utf8_lossy(line))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!("-----BEGIN {label}-----\n{lines}\n-----END {label}-----\n")
+}
+
+#[actix_web::get("/directory")]
+pub async fn get_directory() -> HttpResponse {
+    let config = common::get_config();
+
+    let mut meta = json!({ "externalAccountRequired": false });
+    if let Some(terms_of_service) = &config.acme.terms_of_service {
+        meta["termsOfService"] = terms_of_service.clone().into();
+    }
+
+    HttpResponse::Ok().json(json!({
+        "newNonce": url("new-nonce"),
+        "newAccount": url("new-account"),
+        "newOrder": url("new-order"),
+        "revokeCert": url("revoke-cert"),
+        "keyChange": url("key-change"),
+        "meta": meta,
+    }))
+}
+
+#[actix_web::route("/new-nonce", method = "GET", method = "HEAD")]
+pub async fn get_new_nonce(req: HttpRequest) -> HttpResponse {
+    reply(Ok(match *req.method() {
+        Method::HEAD => HttpResponse::Ok().finish(),
+        _ => HttpResponse::NoContent().finish(),
+    })).await
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NewAccount {
+    #[serde(default)]
+    contact: Vec<String>,
+    #[serde(default)]
+    terms_of_service_agreed: bool,
+    #[serde(default)]
+    only_return_existing: bool,
+}
+
+#[actix_web::post("/new-account")]
+pub async fn post_new_account(req: HttpRequest, body: web::Bytes) -> HttpResponse {
+    reply(handle_new_account(&req, &body).await).await
+}
+
+async fn handle_new_account(req: &HttpRequest, body: &[u8]) -> AcmeResult {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let request = authenticate(&mut storage, req, body).await?;
+    if request.account.is_some() {
+        return Err(Problem::malformed("newAccount requests must carry the account key as 'jwk'"));
+    }
+
+    let payload: NewAccount = request.require_payload()?;
+    let thumbprint = thumbprint(&request.key)?;
+
+    if let Some(account) = storage.account_by_key(&thumbprint).await? {
+        return Ok(HttpResponse::Ok()
+            .insert_header((header::LOCATION, url(format!("account/{id}", id = account.id))))
+            .json(account_json(&account)));
+    }
+
+    if payload.only_return_existing {
+        return Err(Problem::new("accountDoesNotExist", StatusCode::BAD_REQUEST, "No account exists for this key"));
+    }
+
+    if config.acme.terms_of_service.is_some() && !payload.terms_of_service_agreed {
+        return Err(Problem::new("userActionRequired", StatusCode::FORBIDDEN, "The terms of service have to be agreed to"));
+    }
+
+    validate_contacts(&payload.contact)?;
+
+    let account = AcmeAccount {
+        id: common::random_token()?,
+        key: request.key.to_string(),
+        thumbprint,
+        status: AcmeStatus::Valid,
+        contact: payload.contact,
+        terms_of_service_agreed: payload.terms_of_service_agreed,
+        created_at: now(),
+    };
+
+    if !storage.create_account(&account).await? {
+        return Err(Problem::malformed("An account for this key has just been created"));
+    }
+
+    log::info!("Created ACME account {id}", id = account.id);
+
+    Ok(HttpResponse::Created()
+        .insert_header((header::LOCATION, url(format!("account/{id}", id = account.id))))
+        .json(account_json(&account)))
+}
+
+#[derive(Debug, Deserialize)]
+struct AccountUpdate {
+    contact: Option<Vec<String>>,
+    status: Option<AcmeStatus>,
+}
+
+#[actix_web::post("/account/{id}")]
+pub async fn post_account(req: HttpRequest, id: web::Path<String>, body: web::Bytes) -> HttpResponse {
+    reply(handle_account(&req, &id, &body).await).await
+}
+
+async fn handle_account(req: &HttpRequest, id: &str, body: &[u8]) -> AcmeResult {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let request = authenticate(&mut storage, req, body).await?;
+    let mut account = request.account()?.clone();
+
+    if account.id != id {
+        return Err(Problem::unauthorized("Accounts can only be managed with their own key"));
+    }
+
+    if let Some(update) = request.payload::<AccountUpdate>()? {
+        if let Some(contact) = update.contact {
+            validate_contacts(&contact)?;
+            account.contact = contact;
+        }
+
+        match update.status {
+            Some(AcmeStatus::Deactivated) => {
+                log::info!("Deactivating ACME account {id}");
+                account.status = AcmeStatus::Deactivated;
+            },
+            Some(status) => return Err(Problem::malformed(format!("Accounts can't be moved to {status:?}"))),
+            None => {},
+        }
+
+        storage.update_account(&account).await?;
+    }
+
+    Ok(HttpResponse::Ok().json(account_json(&account)))
+}
+
+#[actix_web::post("/account/{id}/orders")]
+pub async fn post_account_orders(req: HttpRequest, id: web::Path<String>, body: web::Bytes) -> HttpResponse {
+    reply(handle_account_orders(&req, &id, &body).await).await
+}
+
+async fn handle_account_orders(req: &HttpRequest, id: &str, body: &[u8]) -> AcmeResult {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let request = authenticate(&mut storage, req, body).await?;
+    let account = request.account()?;
+
+    if account.id != id {
+        return Err(Problem::unauthorized("Orders can only be listed by their own account"));
+    }
+
+    let orders = storage.orders_of_account(id).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "orders": orders
+            .iter()
+            .map(|order| url(format!("order/{id}", id = order.id)))
+            .collect::<Vec<_>>()
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KeyChange {
+    account: String,
+    old_key: Value,
+}
+
+#[actix_web::post("/key-change")]
+pub async fn post_key_change(req: HttpRequest, body: web::Bytes) -> HttpResponse {
+    reply(handle_key_change(&req, &body).await).await
+}
+
+/// Account key rollover as per RFC 8555 §7.3.5: The payload is a second JWS, signed by the new key.
+async fn handle_key_change(req: &HttpRequest, body: &[u8]) -> AcmeResult {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let request = authenticate(&mut storage, req, body).await?;
+    let mut account = request.account()?.clone();
+
+    let inner: Jws = request.require_payload()?;
+    let inner_header = inner.header()
+        .map_err(|_| Problem::malformed("Invalid inner JWS protected header"))?;
+
+    if inner_header.kid.is_some() || inner_header.nonce.is_some() {
+        return Err(Problem::malformed("The inner JWS must carry the new key as 'jwk' and no nonce"));
+    }
+
+    if inner_header.url != request.header.url {
+        return Err(Problem::malformed("The inner JWS was signed for another URL"));
+    }
+
+    let new_key = inner_header.jwk
+        .ok_or_else(|| Problem::malformed("The inner JWS must carry the new key as 'jwk'"))?;
+    verify(&inner, &inner_header.alg, &new_key)?;
+
+    let change: KeyChange = inner.payload()
+        .map_err(|_| Problem::malformed("Invalid inner payload"))?
+        .ok_or_else(|| Problem::malformed("Missing inner payload"))?;
+
+    if change.account != url(format!("account/{id}", id = account.id)) {
+        return Err(Problem::malformed("The key change names another account"));
+    }
+
+    if thumbprint(&change.old_key)? != account.thumbprint {
+        return Err(Problem::malformed("'oldKey' is not the current account key"));
+    }
+
+    let old_thumbprint = std::mem::replace(&mut account.thumbprint, thumbprint(&new_key)?);
+    account.key = new_key.to_string();
+
+    if !storage.change_account_key(&account, &old_thumbprint).await? {
+        let mut response = HttpResponse::Conflict();
+        if let Some(existing) = storage.account_by_key(&account.thumbprint).await? {
+            response.insert_header((header::LOCATION, url(format!("account/{id}", id = existing.id))));
+        }
+
+        return Ok(response
+            .content_type("application/problem+json")
+            .json(Problem::malformed("The new key already belongs to an account").to_json()));
+    }
+
+    log::info!("Rolled over the key of ACME account {id}", id = account.id);
+
+    Ok(HttpResponse::Ok().json(account_json(&account)))
+}
+
+#[derive(Debug, Deserialize)]
+struct NewOrder {
+    identifiers: Vec<Identifier>,
+}
+
+#[actix_web::post("/new-order")]
+pub async fn post_new_order(req: HttpRequest, body: web::Bytes) -> HttpResponse {
+    reply(handle_new_order(&req, &body).await).await
+}
+
+async fn handle_new_order(req: &HttpRequest, body: &[u8]) -> AcmeResult {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let request = authenticate(&mut storage, req, body).await?;
+    let account = request.account()?;
+    let payload: NewOrder = request.require_payload()?;
+
+    if payload.identifiers.is_empty() {
+        return Err(Problem::malformed("An order needs at least one identifier"));
+    }
+
+    let expires = now() + config.acme.order_lifetime_secs as i64;
+    let mut identifiers = vec![];
+    let mut authorizations = vec![];
+
+    for identifier in payload.identifiers {
+        let value = match identifier.kind {
+            IdentifierType::Dns => identifier.value.to_ascii_lowercase(),
+            IdentifierType::Ip => IpAddr::from_str(&identifier.value)
+                .map_err(|_| Problem::new("rejectedIdentifier", StatusCode::BAD_REQUEST, format!("'{value}' is not an IP address", value = identifier.value)))?
+                .to_string(),
+        };
+
+        let (name, wildcard) = match value.strip_prefix("*.") {
+            Some(name) if identifier.kind == IdentifierType::Dns => (name.to_owned(), true),
+            _ => (value.clone(), false),
+        };
+
+        if name.is_empty() || name.contains('*') {
+            return Err(Problem::new("rejectedIdentifier", StatusCode::BAD_REQUEST, format!("'{value}' is not a valid identifier")));
+        }
+
+        let challenges = match (identifier.kind, wildcard) {
+            (IdentifierType::Dns, true) => vec![ChallengeType::Dns01],
+            (IdentifierType::Dns, false) => vec![ChallengeType::Http01, ChallengeType::Dns01, ChallengeType::TlsAlpn01],
+            (IdentifierType::Ip, _) => vec![ChallengeType::Http01],
+        };
+
+        let authz = AcmeAuthorization {
+            id: common::random_token()?,
+            account: account.id.clone(),
+            identifier: Identifier {
+                kind: identifier.kind,
+                value: name,
+            },
+            wildcard,
+            status: AcmeStatus::Pending,
+            expires,
+            challenges: challenges
+                .into_iter()
+                .map(|kind| Ok(AcmeChallenge {
+                    kind,
+                    token: common::random_token()?,
+                    status: AcmeStatus::Pending,
+                    validated: None,
+                    error: None,
+                }))
+                .collect::<common::Result<Vec<_>>>()?,
+        };
+
+        storage.store_authorization(&authz).await?;
+
+        authorizations.push(authz.id);
+        identifiers.push(Identifier {
+            kind: identifier.kind,
+            value,
+        });
+    }
+
+    let order = AcmeOrder {
+        id: common::random_token()?,
+        account: account.id.clone(),
+        status: AcmeStatus::Pending,
+        identifiers,
+        authorizations,
+        expires,
+        alias: None,
+        serial: None,
+        error: None,
+    };
+
+    storage.store_order(&order).await?;
+
+    Ok(HttpResponse::Created()
+        .insert_header((header::LOCATION, url(format!("order/{id}", id = order.id))))
+        .json(order_json(&order)))
+}
+
+#[actix_web::post("/order/{id}")]
+pub async fn post_order(req: HttpRequest, id: web::Path<String>, body: web::Bytes) -> HttpResponse {
+    reply(handle_order(&req, &id, &body).await).await
+}
+
+async fn handle_order(req: &HttpRequest, id: &str, body: &[u8]) -> AcmeResult {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let request = authenticate(&mut storage, req, body).await?;
+    let order = owned_order(&mut storage, request.account()?, id).await?;
+
+    let mut response = HttpResponse::Ok();
+    if order.status == AcmeStatus::Processing {
+        response.insert_header((header::RETRY_AFTER, "5"));
+    }
+
+    Ok(response.json(order_json(&order)))
+}
+
+#[derive(Debug, Deserialize)]
+struct Finalize {
+    csr: String,
+}
+
+#[actix_web::post("/order/{id}/finalize")]
+pub async fn post_finalize(req: HttpRequest, id: web::Path<String>, body: web::Bytes) -> HttpResponse {
+    reply(handle_finalize(&req, &id, &body).await).await
+}
+
+/// Submits the CSR as a job, provided it asks for exactly the identifiers of the order. The job validates the names
+/// using the challenges the client responded to.
+async fn handle_finalize(req: &HttpRequest, id: &str, body: &[u8]) -> AcmeResult {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let request = authenticate(&mut storage, req, body).await?;
+    let mut order = owned_order(&mut storage, request.account()?, id).await?;
+
+    if order.status != AcmeStatus::Ready {
+        return Err(Problem::new("orderNotReady", StatusCode::FORBIDDEN, format!("The order is {status:?}", status = order.status)));
+    }
+
+    let payload: Finalize = request.require_payload()?;
+    let der = common::decode_base64url(&payload.csr)
+        .map_err(|_| Problem::new("badCSR", StatusCode::BAD_REQUEST, "The CSR is not valid base64url"))?;
+    let pem = pem("CERTIFICATE REQUEST", &der);
+
+    let params = rcgen::CertificateSigningRequestParams::from_pem(&pem)
+        .map_err(|err| Problem::new("badCSR", StatusCode::BAD_REQUEST, format!("Invalid CSR: {err}")))?;
+
+    let requested = params.params.subject_alt_names
+        .iter()
+        .map(|name| match name {
+            rcgen::SanType::DnsName(name) => Ok(Identifier {
+                kind: IdentifierType::Dns,
+                value: name.as_str().to_ascii_lowercase(),
+            }),
+            rcgen::SanType::IpAddress(ip) => Ok(Identifier {
+                kind: IdentifierType::Ip,
+                value: ip.to_string(),
+            }),
+            _ => Err(Problem::new("badCSR", StatusCode::BAD_REQUEST, "Only DNS and IP subject alternative names can be requested")),
+        })
+        .collect::<Result<HashSet<_>, _>>()?;
+
+    if requested != order.identifiers.iter().cloned().collect::<HashSet<_>>() {
+        return Err(Problem::new("badCSR", StatusCode::BAD_REQUEST, "The CSR must request exactly the identifiers of the order"));
+    }
+
+    let challenges = storage.get_authorizations(&order.authorizations)
+        .await?
+        .into_iter()
+        .flat_map(|authz| {
+            // The job's challenges have to cover the names as requested, wildcards included
+            let name = match authz.wildcard {
+                true => format!("*.{name}", name = authz.identifier.value),
+                false => authz.identifier.value,
+            };
+            authz.challenges
+                .into_iter()
+                .filter(|challenge| challenge.status == AcmeStatus::Valid)
+                .map(move |challenge| (name.clone(), challenge))
+        })
+        .map(|(name, challenge)| {
+            let key_authorization = common::key_authorization(&challenge.token, &request.key)?;
+            Ok(NameChallenge {
+                validated_at: challenge.validated,
+                ..NameChallenge::new(name, challenge.kind, challenge.token, key_authorization)
+            })
+        })
+        .collect::<common::Result<Vec<_>>>()?;
+
+    let hash = common::blake3::hash(order.id.as_bytes());
+    let csr = NewCsr {
+        client_id: u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap_or_default()),
+        pem,
+        profile: config.acme.profile.clone(),
+        challenge: None,
+        challenges,
+    };
+
+    order.alias = Some(csr.alt());
+    order.status = AcmeStatus::Processing;
+
+    storage.dispatch_event(csr).await?;
+    storage.store_order(&order).await?;
+
+    log::info!("ACME order {id} finalized as job {alias:?}", alias = order.alias);
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::LOCATION, url(format!("order/{id}"))))
+        .insert_header((header::RETRY_AFTER, "5"))
+        .json(order_json(&order)))
+}
+
+#[actix_web::post("/authz/{id}")]
+pub async fn post_authorization(req: HttpRequest, id: web::Path<String>, body: web::Bytes) -> HttpResponse {
+    reply(handle_authorization(&req, &id, &body).await).await
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthorizationUpdate {
+    status: AcmeStatus,
+}
+
+async fn handle_authorization(req: &HttpRequest, id: &str, body: &[u8]) -> AcmeResult {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let request = authenticate(&mut storage, req, body).await?;
+    let mut authz = owned_authorization(&mut storage, request.account()?, id).await?;
+
+    match request.payload::<AuthorizationUpdate>()? {
+        Some(AuthorizationUpdate { status: AcmeStatus::Deactivated }) => {
+            authz.status = AcmeStatus::Deactivated;
+            storage.store_authorization(&authz).await?;
+        },
+        Some(AuthorizationUpdate { status }) => return Err(Problem::malformed(format!("Authorizations can't be moved to {status:?}"))),
+        None => {},
+    }
+
+    Ok(HttpResponse::Ok().json(authorization_json(&authz)))
+}
+
+#[actix_web::post("/chall/{authz}/{index}")]
+pub async fn post_challenge(req: HttpRequest, path: web::Path<(String, usize)>, body: web::Bytes) -> HttpResponse {
+    let (authz, index) = path.into_inner();
+    reply(handle_challenge(&req, &authz, index, &body).await).await
+}
+
+/// A non-empty payload signals that the client is ready for the challenge. The challenge stays `processing` until the
+/// runner has validated it, which then grants or denies the authorization.
+async fn handle_challenge(req: &HttpRequest, id: &str, index: usize, body: &[u8]) -> AcmeResult {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let request = authenticate(&mut storage, req, body).await?;
+    let mut authz = owned_authorization(&mut storage, request.account()?, id).await?;
+
+    let ready = !request.jws.payload.is_empty();
+    let processing = authz.challenges.iter().any(|challenge| challenge.status == AcmeStatus::Processing);
+    let challenge = authz.challenges
+        .get_mut(index)
+        .ok_or_else(|| Problem::not_found(format!("Unknown challenge {id}/{index}")))?;
+
+    // Only one challenge of an authorization is validated at a time
+    if ready && challenge.status == AcmeStatus::Pending && authz.status == AcmeStatus::Pending && !processing {
+        challenge.status = AcmeStatus::Processing;
+        let deadline = now() + config.challenge.deadline_secs(challenge.kind) as i64;
+        storage.store_authorization(&authz).await?;
+
+        storage.dispatch_event(AcmeChallengeReady {
+            authorization: authz.id.clone(),
+            index,
+            deadline,
+            not_before: 0,
+        }).await?;
+    }
+
+    let challenge = &authz.challenges[index];
+    let mut response = HttpResponse::Ok();
+    response.append_header((header::LINK, format!("<{url}>;rel=\"up\"", url = url(format!("authz/{id}")))));
+
+    if challenge.status == AcmeStatus::Processing {
+        response.insert_header((header::RETRY_AFTER, "5"));
+    }
+
+    Ok(response.json(challenge_json(&authz.id, index, challenge)))
+}
+
+#[actix_web::post("/cert/{id}")]
+pub async fn post_certificate(req: HttpRequest, id: web::Path<String>, body: web::Bytes) -> HttpResponse {
+    reply(handle_certificate(&req, &id, &body).await).await
+}
+
+/// The certificate followed by the certificate of the authority currently loaded.
+async fn handle_certificate(req: &HttpRequest, id: &str, body: &[u8]) -> AcmeResult {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let request = authenticate(&mut storage, req, body).await?;
+    let order = owned_order(&mut storage, request.account()?, id).await?;
+
+    let issued = match (order.status, order.serial) {
+        (AcmeStatus::Valid, Some(serial)) => storage.get_certificate(serial).await?,
+        _ => None,
+    };
+
+    let Some(issued) = issued else {
+        return Err(Problem::not_found("No certificate has been issued for this order"));
+    };
+
+    let issuer = pem("CERTIFICATE", &Authority::current()?.certificate);
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/pem-certificate-chain")
+        .body(format!("{cert}\n{issuer}", cert = issued.pem.trim_end())))
+}
+
+#[derive(Debug, Deserialize)]
+struct RevokeCert {
+    certificate: String,
+    #[serde(default)]
+    reason: Option<u8>,
+}
+
+#[actix_web::post("/revoke-cert")]
+pub async fn post_revoke_cert(req: HttpRequest, body: web::Bytes) -> HttpResponse {
+    reply(handle_revoke_cert(&req, &body).await).await
+}
+
+/// Only the account that ordered a certificate may revoke it.
+async fn handle_revoke_cert(req: &HttpRequest, body: &[u8]) -> AcmeResult {
+    let config = common::get_config();
+    let mut storage = config.connect_storage().await;
+
+    let request = authenticate(&mut storage, req, body).await?;
+    let account = request.account
+        .as_ref()
+        .ok_or_else(|| Problem::unauthorized("Revocation must be requested by the account that ordered the certificate"))?;
+    let payload: RevokeCert = request.require_payload()?;
+
+    let der = common::decode_base64url(&payload.certificate)
+        .map_err(|_| Problem::malformed("The certificate is not valid base64url"))?;
+    let (_, cert) = x509_parser::parse_x509_certificate(&der)
+        .map_err(|_| Problem::malformed("Invalid certificate"))?;
+
+    let issued = match u64::try_from(&cert.serial) {
+        Ok(serial) => storage.get_certificate(serial).await?,
+        Err(_) => None,
+    };
+
+    let issued = issued
+        .filter(|issued| x509_parser::pem::parse_x509_pem(issued.pem.as_bytes()).is_ok_and(|(_, pem)| pem.contents == der))
+        .ok_or_else(|| Problem::not_found("The certificate was not issued by this CA"))?;
+
+    if !storage.orders_of_account(&account.id).await?.iter().any(|order| order.serial == Some(issued.serial)) {
+        return Err(Problem::unauthorized("The certificate was ordered by another account"));
+    }
+
+    if storage.get_revocation(issued.serial).await?.is_some() {
+        return Err(Problem::new("alreadyRevoked", StatusCode::BAD_REQUEST, "The certificate has already been revoked"));
+    }
+
+    let reason = RevocationReason::from_str(&payload.reason.unwrap_or(0).to_string())
+        .map_err(|err| Problem::new("badRevocationReason", StatusCode::BAD_REQUEST, err))?;
+
+    if !reason.can_revoke() {
+        return Err(Problem::new("badRevocationReason", StatusCode::BAD_REQUEST, "removeFromCRL is not a revocation reason"));
+    }
+
+    storage.dispatch_event(Revocation {
+        serial: issued.serial,
+        reason,
+        invalidity_date: None,
+        requested_by: format!("acme:{id}", id = account.id),
+    }).await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::dev::ServiceResponse;
+    use actix_web::{test, App};
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use crate::testing::{environment, Environment};
+    use super::*;
+
+    /// An ACME client with a P-256 account key.
+    struct Client {
+        key: EcdsaKeyPair,
+        jwk: Value,
+        /// The account URL, once the account exists
+        kid: Option<String>,
+    }
+
+    impl Client {
+        fn new() -> Self {
+            let random = SystemRandom::new();
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &random).unwrap();
+            let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &random).unwrap();
+
+            let point = key.public_key().as_ref();
+            let jwk = json!({
+                "kty": "EC",
+                "crv": "P-256",
+                "x": common::encode_base64url(&point[1..33]),
+                "y": common::encode_base64url(&point[33..]),
+            });
+
+            Self {
+                key,
+                jwk,
+                kid: None,
+            }
+        }
+
+        /// A client whose account has been created.
+        async fn registered() -> Self {
+            let mut client = Self::new();
+            let response = client.post("new-account", json!({ "termsOfServiceAgreed": true })).await;
+            assert_eq!(response.status(), StatusCode::CREATED);
+
+            client.kid = Some(location(&response));
+            client
+        }
+
+        /// A JWS over the payload as sent to `url`. An empty payload makes a POST-as-GET request.
+        fn sign(&self, url: &str, nonce: &str, payload: &str) -> Jws {
+            let mut header = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+            match &self.kid {
+                Some(kid) => header["kid"] = kid.clone().into(),
+                None => header["jwk"] = self.jwk.clone(),
+            }
+
+            let protected = common::encode_base64url(header.to_string());
+            let payload = match payload {
+                "" => String::new(),
+                payload => common::encode_base64url(payload),
+            };
+            let signature = self.key
+                .sign(&SystemRandom::new(), format!("{protected}.{payload}").as_bytes())
+                .unwrap();
+
+            Jws {
+                protected,
+                payload,
+                signature: common::encode_base64url(signature.as_ref()),
+            }
+        }
+
+        async fn signed(&self, path: &str, payload: &str) -> Jws {
+            let nonce = common::get_config().connect_storage().await.new_nonce().await.unwrap();
+            self.sign(&url(path), &nonce, payload)
+        }
+
+        async fn post(&self, path: &str, payload: Value) -> ServiceResponse {
+            send(path, &self.signed(path, &payload.to_string()).await).await
+        }
+
+        async fn post_as_get(&self, path: &str) -> ServiceResponse {
+            send(path, &self.signed(path, "").await).await
+        }
+
+        async fn order(&self, names: &[&str]) -> String {
+            let identifiers = names
+                .iter()
+                .map(|name| json!({ "type": "dns", "value": name }))
+                .collect::<Vec<_>>();
+
+            let response = self.post("new-order", json!({ "identifiers": identifiers })).await;
+            assert_eq!(response.status(), StatusCode::CREATED);
+
+            location(&response)
+        }
+
+        /// Completes the http-01 challenges of the order, which is then ready.
+        async fn authorize(&self, environment: &Environment, order: &str) {
+            let order: Value = test::read_body_json(self.post_as_get(path(order)).await).await;
+
+            for authz in order["authorizations"].as_array().unwrap() {
+                let authz: Value = test::read_body_json(self.post_as_get(path(authz.as_str().unwrap())).await).await;
+                let challenge = authz["challenges"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .find(|challenge| challenge["type"] == "http-01")
+                    .unwrap();
+
+                let token = challenge["token"].as_str().unwrap();
+                environment.serve_http01(token, &common::key_authorization(token, &self.jwk).unwrap());
+
+                let response = self.post(path(challenge["url"].as_str().unwrap()), json!({})).await;
+                assert_eq!(response.status(), StatusCode::OK);
+            }
+
+            environment.handle_all().await;
+        }
+
+        async fn finalize(&self, order: &str, names: &[&str]) -> ServiceResponse {
+            let mut params = rcgen::CertificateParams::new(names.iter().map(|&name| name.to_owned()).collect::<Vec<_>>()).unwrap();
+            params.distinguished_name = rcgen::DistinguishedName::new();
+            let csr = params.serialize_request(&rcgen::KeyPair::generate().unwrap()).unwrap();
+
+            self.post(&format!("{order}/finalize", order = path(order)), json!({ "csr": common::encode_base64url(csr.der()) })).await
+        }
+    }
+
+    async fn send(path: &str, jws: &Jws) -> ServiceResponse {
+        let app = test::init_service(App::new().configure(configure)).await;
+        let request = test::TestRequest::post()
+            .uri(&format!("/acme/{path}"))
+            .insert_header((header::CONTENT_TYPE, "application/jose+json"))
+            .set_payload(serde_json::to_vec(jws).unwrap())
+            .to_request();
+
+        test::call_service(&app, request).await
+    }
+
+    /// The path of an ACME URL below `/acme/`.
+    fn path(url: &str) -> &str {
+        url.strip_prefix("http://localhost/acme/").unwrap()
+    }
+
+    fn location(response: &ServiceResponse) -> String {
+        response.headers().get(header::LOCATION).unwrap().to_str().unwrap().to_owned()
+    }
+
+    async fn problem(response: ServiceResponse) -> (StatusCode, String) {
+        let status = response.status();
+        let problem: Value = test::read_body_json(response).await;
+
+        (status, problem["type"].as_str().unwrap().to_owned())
+    }
+
+    #[actix_web::test]
+    async fn orders_are_issued_and_revoked() {
+        let environment = environment().await;
+
+        let app = test::init_service(App::new().configure(configure)).await;
+        let response = test::call_service(&app, test::TestRequest::default().method(Method::HEAD).uri("/acme/new-nonce").to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().contains_key("replay-nonce"));
+
+        let client = Client::registered().await;
+        let order = client.order(&["acme.test"]).await;
+        client.authorize(&environment, &order).await;
+
+        let ready: Value = test::read_body_json(client.post_as_get(path(&order)).await).await;
+        assert_eq!(ready["status"], "ready");
+
+        let response = client.finalize(&order, &["acme.test"]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        environment.handle_all().await;
+
+        let valid: Value = test::read_body_json(client.post_as_get(path(&order)).await).await;
+        assert_eq!(valid["status"], "valid", "{valid}");
+
+        let response = client.post_as_get(path(valid["certificate"].as_str().unwrap())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let chain = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+
+        let blocks = x509_parser::pem::Pem::iter_from_buffer(chain.as_bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[1].contents, Authority::current().unwrap().certificate);
+
+        let (_, certificate) = x509_parser::parse_x509_certificate(&blocks[0].contents).unwrap();
+        assert!(certificate.subject_alternative_name().unwrap().unwrap().value.general_names
+            .iter()
+            .any(|name| matches!(name, x509_parser::extensions::GeneralName::DNSName("acme.test"))));
+
+        let revoke = json!({ "certificate": common::encode_base64url(&blocks[0].contents) });
+        let response = client.post("revoke-cert", revoke.clone()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        environment.handle_all().await;
+
+        let response = client.post("revoke-cert", revoke).await;
+        assert_eq!(problem(response).await, (StatusCode::BAD_REQUEST, "urn:ietf:params:acme:error:alreadyRevoked".to_owned()));
+    }
+
+    #[actix_web::test]
+    async fn bad_signatures_are_rejected() {
+        let _environment = environment().await;
+        let client = Client::new();
+
+        let mut jws = client.signed("new-account", "{}").await;
+        jws.payload = common::encode_base64url(r#"{"contact":["mailto:someone@example.com"]}"#);
+
+        let response = send("new-account", &jws).await;
+        assert_eq!(problem(response).await, (StatusCode::BAD_REQUEST, "urn:ietf:params:acme:error:malformed".to_owned()));
+    }
+
+    #[actix_web::test]
+    async fn nonces_are_used_once() {
+        let _environment = environment().await;
+        let client = Client::new();
+
+        let jws = client.signed("new-account", "{}").await;
+        assert_eq!(send("new-account", &jws).await.status(), StatusCode::CREATED);
+
+        let response = send("new-account", &jws).await;
+        assert_eq!(problem(response).await, (StatusCode::BAD_REQUEST, "urn:ietf:params:acme:error:badNonce".to_owned()));
+    }
+
+    #[actix_web::test]
+    async fn requests_signed_for_other_urls_are_rejected() {
+        let _environment = environment().await;
+        let client = Client::registered().await;
+
+        let jws = client.signed("new-order", r#"{"identifiers":[{"type":"dns","value":"acme.test"}]}"#).await;
+
+        let response = send(path(client.kid.as_deref().unwrap()), &jws).await;
+        assert_eq!(problem(response).await, (StatusCode::FORBIDDEN, "urn:ietf:params:acme:error:unauthorized".to_owned()));
+    }
+
+    #[actix_web::test]
+    async fn accounts_are_kept_apart() {
+        let _environment = environment().await;
+        let owner = Client::registered().await;
+        let other = Client::registered().await;
+        let order = owner.order(&["acme.test"]).await;
+
+        // Another account's kid, with the requester's own key
+        let impostor = Client {
+            kid: owner.kid.clone(),
+            ..Client::new()
+        };
+        let response = impostor.post_as_get(path(&order)).await;
+        assert_eq!(problem(response).await, (StatusCode::BAD_REQUEST, "urn:ietf:params:acme:error:malformed".to_owned()));
+
+        let response = other.post_as_get(path(&order)).await;
+        assert_eq!(problem(response).await, (StatusCode::FORBIDDEN, "urn:ietf:params:acme:error:unauthorized".to_owned()));
+
+        let response = other.post_as_get("order/unknown").await;
+        assert_eq!(problem(response).await, (StatusCode::NOT_FOUND, "about:blank".to_owned()));
+    }
+
+    #[actix_web::test]
+    async fn csrs_must_request_the_names_of_the_order() {
+        let environment = environment().await;
+        let client = Client::registered().await;
+        let order = client.order(&["acme.test"]).await;
+        client.authorize(&environment, &order).await;
+
+        for names in [&["other.test"][..], &["acme.test", "other.test"]] {
+            let response = client.finalize(&order, names).await;
+            assert_eq!(problem(response).await, (StatusCode::BAD_REQUEST, "urn:ietf:params:acme:error:badCSR".to_owned()));
+        }
+
+        let ready: Value = test::read_body_json(client.post_as_get(path(&order)).await).await;
+        assert_eq!(ready["status"], "ready");
+    }
+}
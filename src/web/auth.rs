@@ -0,0 +1,79 @@
+//! # Authentication
+//! Operators identify themselves with a bearer token listed in `web.operators`. Requests which act on someone's behalf
+//! take a [`Caller`], so what they record comes from the token rather than the request body. Dead letters hold raw
+//! event payloads, so even reading them takes a [`Caller`].
+
+use std::future::{ready, Ready};
+use actix_web::dev::Payload;
+use actix_web::error::InternalError;
+use actix_web::http::header;
+use actix_web::{FromRequest, HttpRequest, HttpResponse};
+use ring::digest::{digest, SHA256};
+
+/// Who made a request.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Caller {
+    /// An operator from `web.operators`, by name.
+    Operator(String),
+    /// No operators are configured, so requests can't be attributed to anyone.
+    Anonymous,
+}
+
+impl Caller {
+    pub fn name(&self) -> &str {
+        match self {
+            Caller::Operator(name) => name,
+            Caller::Anonymous => "anonymous",
+        }
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        matches!(self, Caller::Operator(_))
+    }
+}
+
+fn authenticate(req: &HttpRequest) -> Result<Caller, String> {
+    let config = common::get_config();
+    let operators = &config.web.operators;
+
+    let Some(authorization) = req.headers().get(header::AUTHORIZATION) else {
+        return match operators.is_empty() {
+            true => Ok(Caller::Anonymous),
+            false => Err("This request needs an operator's bearer token".to_owned()),
+        };
+    };
+
+    let token = authorization.to_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| "Expected a bearer token".to_owned())?;
+
+    let hash = digest(&SHA256, token.trim().as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+
+    operators.iter()
+        .find(|(_, expected)| expected.trim().eq_ignore_ascii_case(&hash))
+        .map(|(name, _)| Caller::Operator(name.clone()))
+        .ok_or_else(|| "Unknown bearer token".to_owned())
+}
+
+impl FromRequest for Caller {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(authenticate(req).map_err(|err| {
+            let response = HttpResponse::Unauthorized()
+                .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+                .json(serde_json::json! {{
+                    "success": false,
+                    "error": err,
+                }});
+
+            InternalError::from_response(err, response).into()
+        }))
+    }
+}
@@ -0,0 +1,75 @@
+#!/bin/sh
+# Starts local redis-server processes to run certmaster against Sentinel or a Redis Cluster.
+#
+#   sentinel - a master on 6380 with a replica on 6381, monitored as `certmaster` by sentinels on 26379-26381
+#   cluster  - three masters on 7000-7002
+#   tls      - a server on 6390 taking only TLS with a client certificate from ./authority.crt, and an ACL user
+#              `certmaster` whose password is in $REDIS_TOPOLOGY_DIR/password
+#   stop     - stops whatever of the above is running
+#
+# State lives in $REDIS_TOPOLOGY_DIR, /tmp/certmaster-redis by default.
+
+set -e
+
+dir="${REDIS_TOPOLOGY_DIR:-/tmp/certmaster-redis}"
+authority="$(cd "$(dirname "$0")" && pwd)"
+
+server() {
+    port="$1"; shift
+    mkdir -p "$dir/$port"
+    redis-server --port "$port" --dir "$dir/$port" --daemonize yes --logfile "$dir/$port/redis.log" --save '' "$@"
+}
+
+sentinel() {
+    port="$1"
+    mkdir -p "$dir/$port"
+    cat > "$dir/$port/sentinel.conf" <<EOF
+port $port
+dir $dir/$port
+logfile $dir/$port/sentinel.log
+daemonize yes
+sentinel monitor certmaster 127.0.0.1 6380 2
+sentinel down-after-milliseconds certmaster 2000
+sentinel failover-timeout certmaster 5000
+EOF
+    redis-sentinel "$dir/$port/sentinel.conf"
+}
+
+case "$1" in
+    sentinel)
+        server 6380
+        server 6381 --replicaof 127.0.0.1 6380
+        for port in 26379 26380 26381; do sentinel "$port"; done
+        echo "Fail over with: redis-cli -p 26379 sentinel failover certmaster"
+        ;;
+    cluster)
+        for port in 7000 7001 7002; do
+            server "$port" --cluster-enabled yes --cluster-config-file "$dir/$port/nodes.conf"
+        done
+        redis-cli --cluster create 127.0.0.1:7000 127.0.0.1:7001 127.0.0.1:7002 --cluster-replicas 0 --cluster-yes
+        ;;
+    tls)
+        mkdir -p "$dir/6390"
+        openssl ecparam -name prime256v1 -genkey -noout -out "$dir/6390/server.key"
+        openssl req -new -key "$dir/6390/server.key" -subj /CN=localhost -out "$dir/6390/server.csr"
+        printf 'subjectAltName=DNS:localhost,IP:127.0.0.1\n' > "$dir/6390/server.ext"
+        openssl x509 -req -in "$dir/6390/server.csr" -CA "$authority/authority.crt" -CAkey "$authority/authority.key" \
+            -CAcreateserial -CAserial "$dir/6390/authority.srl" -days 30 -extfile "$dir/6390/server.ext" -out "$dir/6390/server.crt"
+        openssl rand -hex 16 > "$dir/password"
+        server 6390 --port 0 --tls-port 6390 --tls-auth-clients yes \
+            --tls-cert-file "$dir/6390/server.crt" --tls-key-file "$dir/6390/server.key" --tls-ca-cert-file "$authority/authority.crt" \
+            --user default off --user certmaster on ">$(cat "$dir/password")" '~*' '&*' +@all
+        ;;
+    stop)
+        for port in 6380 6381 26379 26380 26381 7000 7001 7002; do
+            redis-cli -p "$port" shutdown nosave 2>/dev/null || true
+        done
+        redis-cli -p 6390 --tls --cacert "$authority/authority.crt" --cert "$dir/6390/server.crt" --key "$dir/6390/server.key" \
+            --user certmaster --pass "$(cat "$dir/password" 2>/dev/null)" shutdown nosave 2>/dev/null || true
+        rm -rf "$dir"
+        ;;
+    *)
+        echo "Usage: $0 <sentinel|cluster|tls|stop>" >&2
+        exit 1
+        ;;
+esac